@@ -0,0 +1,30 @@
+//! Builds the front-end into `static` with npm, as it's built into the binary - a fresh clone
+//! doesn't have it.
+
+use std::{path::Path, process::Command};
+
+fn npm(args: &[&str]) -> bool {
+  Command::new("npm")
+    .args(args)
+    .status()
+    .map(|status| status.success())
+    .unwrap_or(false)
+}
+
+fn main() {
+  println!("cargo:rerun-if-changed=static-src");
+  println!("cargo:rerun-if-changed=package.json");
+  println!("cargo:rerun-if-changed=rollup.config.ts");
+
+  let built = (Path::new("node_modules").is_dir() || npm(&["ci"])) && npm(&["run", "build"]);
+
+  // Without npm, like in the Dockerfile's Rust stage, whatever's already been built is used.
+  if !built && !Path::new("static").join("bundle.js").is_file() {
+    println!(
+      "cargo:warning=The front-end couldn't be built with npm, so it isn't built into the \
+       binary - run `npm ci && npm run build`, or put it in `static_directory`"
+    );
+
+    std::fs::create_dir_all("static").expect("Couldn't create `static`");
+  }
+}
@@ -0,0 +1,89 @@
+use std::{
+  ffi::OsStr,
+  path::{Component, Path, PathBuf},
+};
+
+use axum::{http::StatusCode, response::IntoResponse};
+
+use crate::{config::Config, role::Role, user::User};
+
+/// Pages under this directory belong to individual users, at `user/<name>`.
+pub const USER_NAMESPACE: &str = "user";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("Forbidden: You can't edit '{}'", .0.display())]
+  Forbidden(PathBuf),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> axum::response::Response {
+    (StatusCode::FORBIDDEN, self.to_string()).into_response()
+  }
+}
+
+/// The path of the wiki page that belongs to `user`.
+pub fn user_page(user: &User) -> PathBuf {
+  PathBuf::from(USER_NAMESPACE).join(&user.name)
+}
+
+/// If `path` is inside the user namespace, returns the name of the user that owns it.
+pub fn page_owner(path: &Path) -> Option<String> {
+  let mut components = path.components().filter_map(|c| match c {
+    Component::Normal(c) => Some(c),
+    _ => None,
+  });
+
+  if components.next()? != OsStr::new(USER_NAMESPACE) {
+    return None;
+  }
+
+  components.next().map(|name| name.to_string_lossy().to_string())
+}
+
+/// The page that `path` really is, relative to the pages directory - with loose path matching,
+/// `User/alice` could be `user/alice`, and it's the file that's being protected.
+pub fn resolve(path: &Path, config: &Config) -> PathBuf {
+  let found = match crate::page::find_file(path, config) {
+    Ok(file) => file.with_extension(""),
+    // A page that doesn't exist yet still goes in whichever directory matches.
+    Err(_) => config.path_matching.resolve_directories(&config.pages_directory, path),
+  };
+
+  match found.strip_prefix(&config.pages_directory) {
+    Ok(relative) => relative.to_path_buf(),
+    Err(_) => path.to_path_buf(),
+  }
+}
+
+pub fn can_edit(user: &User, path: &Path, config: &Config) -> bool {
+  if user.roles.contains(&Role::Administrator) {
+    return true;
+  }
+
+  let path = &resolve(path, config);
+
+  let settings = crate::namespace::Settings::for_path(path, config);
+  if !settings.can_read(Some(user)) {
+    return false;
+  }
+
+  // When there are `editors`, nobody else can edit at all.
+  let editors = settings.editors.as_ref().unwrap_or(&config.editors);
+  if !editors.is_empty() && !user.roles.iter().any(|role| editors.contains(role)) {
+    return false;
+  }
+
+  match page_owner(path) {
+    Some(owner) => owner == user.name,
+    None => true,
+  }
+}
+
+pub fn check_edit(user: &User, path: &Path, config: &Config) -> Result<(), Error> {
+  if can_edit(user, path, config) {
+    Ok(())
+  } else {
+    Err(Error::Forbidden(path.to_path_buf()))
+  }
+}
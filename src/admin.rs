@@ -0,0 +1,513 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+  extract::{multipart::MultipartError, Form, Multipart, Path, Query},
+  http::{header, StatusCode},
+  response::{Html, IntoResponse, Redirect, Response},
+  Extension,
+};
+use serde::Deserialize;
+
+use crate::{
+  audit::Action as Audit,
+  error::ErrorPage,
+  proxy::SameOrigin,
+  role::{Is, Role},
+  sessions::SessionIndex,
+  template::Template,
+  user::{ExportFormat, Plan, User, UserKey},
+  State,
+};
+
+/// How many users are shown on each page of the listing.
+const USERS_PER_PAGE: usize = 50;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  User(#[from] crate::user::Error),
+  #[error("There's no user with the email '{0}'")]
+  NotFound(String),
+  #[error("You can't remove your own administrator role")]
+  OwnRole,
+  #[error("You can't disable yourself")]
+  DisableSelf,
+  #[error(transparent)]
+  Sessions(#[from] crate::sessions::Error),
+  #[error(transparent)]
+  Config(#[from] crate::config::Error),
+  #[error(transparent)]
+  Multipart(#[from] MultipartError),
+  #[error("No users were uploaded")]
+  NoUpload,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    let code = match self {
+      Self::NotFound(email) => {
+        return ErrorPage::NotFound {
+          url: Some(format!("/meta/admin/users/{}", email)),
+        }
+        .into_response()
+      },
+      Self::OwnRole | Self::DisableSelf | Self::Multipart(_) | Self::NoUpload => {
+        StatusCode::BAD_REQUEST
+      },
+      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (code, self.to_string()).into_response()
+  }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct UserFilter {
+  /// Either `approved` or `unapproved` - anything else shows everyone.
+  status: String,
+  role: String,
+  /// Part of an email address.
+  email: String,
+  page: usize,
+}
+
+impl UserFilter {
+  fn matches(&self, user: &User) -> bool {
+    let status = match self.status.as_str() {
+      "approved" => user.approved,
+      "unapproved" => !user.approved,
+      _ => true,
+    };
+
+    let role = match Role::from_name(&self.role) {
+      Some(role) => user.roles.contains(&role),
+      None => true,
+    };
+
+    let email = user
+      .email
+      .to_lowercase()
+      .contains(&self.email.trim().to_lowercase());
+
+    status && role && email
+  }
+
+  /// A link to another page of results with the same filters.
+  fn page_url(&self, page: usize) -> String {
+    format!(
+      "/meta/admin/users?status={}&role={}&email={}&page={}",
+      urlencoding::encode(&self.status),
+      urlencoding::encode(&self.role),
+      urlencoding::encode(&self.email),
+      page,
+    )
+  }
+}
+
+pub async fn users_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Query(filter): Query<UserFilter>,
+  Extension(state): Extension<Arc<State>>,
+) -> Html<String> {
+  let mut users: Vec<User> = state
+    .users
+    .all()
+    .await
+    .into_iter()
+    .filter(|user| filter.matches(user))
+    .collect();
+
+  users.sort_by(|a, b| a.email.cmp(&b.email));
+
+  let pages = (users.len() + USERS_PER_PAGE - 1) / USERS_PER_PAGE;
+  let page = filter.page.max(1);
+
+  let content = maud::html! {
+    p {
+      a href="/meta/admin/invites" { "Invites" }
+      " · "
+      a href="/meta/admin/replace" { "Search and replace" }
+      " · "
+      a href="/meta/admin/import" { "Import" }
+      " · "
+      a href="/meta/admin/audit" { "Audit log" }
+      " · "
+      a href="/meta/admin/lockouts" { "Failed logins" }
+      " · "
+      a href="/meta/admin/quarantine" { "Quarantine" }
+      " · "
+      "Export users as "
+      a href="/meta/admin/export-users?format=ron" { "RON" }
+      " or "
+      a href="/meta/admin/export-users?format=json" { "JSON" }
+    }
+
+    form #import-users method="post" action="/meta/admin/import-users" enctype="multipart/form-data" {
+      input type="file" name="users" accept=".ron,.json" required;
+      label {
+        input type="checkbox" name="dry_run" value="true" checked;
+        " Only show what would change"
+      }
+      button type="submit" { "Import users" }
+    }
+
+    form #reload-config method="post" action="/meta/admin/reload" {
+      button type="submit" { "Reload config" }
+    }
+
+    form #user-filter method="get" action="/meta/admin/users" {
+      input type="search" name="email" placeholder="Email" value=(filter.email);
+      select name="status" {
+        option value="" selected[filter.status.is_empty()] { "Everyone" }
+        option value="approved" selected[filter.status == "approved"] { "Approved" }
+        option value="unapproved" selected[filter.status == "unapproved"] { "Unapproved" }
+      }
+      select name="role" {
+        option value="" selected[filter.role.is_empty()] { "Any role" }
+        @for role in Role::ALL {
+          option value=(role.name()) selected[filter.role == role.name()] { (role.name()) }
+        }
+      }
+      button type="submit" { "Filter" }
+    }
+
+    table #users {
+      thead {
+        tr {
+          th { "Name" }
+          th { "Email" }
+          th { "Approved" }
+          th { "Disabled" }
+          th { "Roles" }
+          th {}
+        }
+      }
+      tbody {
+        @for user in users.iter().skip((page - 1) * USERS_PER_PAGE).take(USERS_PER_PAGE) {
+          tr {
+            td { a href={ "/meta/profile/" (user.email) } { (user.name) } }
+            td { (user.email) }
+            td { @if user.approved { "yes" } @else { "no" } }
+            td { @if user.disabled { "yes" } @else { "no" } }
+            td {
+              @for role in &user.roles {
+                (role.name()) " "
+              }
+            }
+            td { a href={ "/meta/admin/users/" (user.email) } { "manage" } }
+          }
+        }
+      }
+    }
+
+    @if pages > 1 {
+      nav .pagination {
+        @if page > 1 {
+          a href=(filter.page_url(page - 1)) { "previous" }
+        }
+        " page " (page) " of " (pages) " "
+        @if page < pages {
+          a href=(filter.page_url(page + 1)) { "next" }
+        }
+      }
+    }
+  };
+
+  Template::new()
+    .title("Users")
+    .content(content)
+    .render(Some(admin), &state)
+}
+
+pub async fn user_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Path(key): Path<UserKey>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, Error> {
+  let user = state
+    .users
+    .get(&key)
+    .await
+    .ok_or_else(|| Error::NotFound(key.email().to_string()))?;
+
+  let is_admin = user.roles.contains(&Role::Administrator);
+
+  let content = maud::html! {
+    h1 { (user.name) }
+    ul {
+      li { a href={ "/meta/profile/" (user.email) } { "profile" } }
+      li {
+        @if crate::user::is_web_url(&user.url) {
+          a href=(user.url) { (user.url) }
+        } @else {
+          (user.url)
+        }
+      }
+      li { (user.email) }
+    }
+
+    form method="post" action={ "/meta/admin/users/" (user.email) } {
+      @if user.approved {
+        button type="submit" name="action" value="unapprove" { "Unapprove" }
+      } @else {
+        button type="submit" name="action" value="approve" { "Approve" }
+      }
+      @if is_admin {
+        button type="submit" name="action" value="revoke-admin" { "Remove administrator" }
+      } @else {
+        button type="submit" name="action" value="grant-admin" { "Make administrator" }
+      }
+      @if user.disabled {
+        button type="submit" name="action" value="enable" { "Enable" }
+      } @else {
+        button type="submit" name="action" value="disable" { "Disable" }
+      }
+    }
+  };
+
+  let html = Template::new()
+    .title(maud::html! { "Users - " (user.name) })
+    .content(content)
+    .render(Some(admin), &state);
+
+  Ok(html)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+  Approve,
+  Unapprove,
+  GrantAdmin,
+  RevokeAdmin,
+  Disable,
+  Enable,
+}
+
+#[derive(Deserialize)]
+pub struct ActionParams {
+  action: Action,
+}
+
+pub async fn user_action_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Path(key): Path<UserKey>,
+  Form(params): Form<ActionParams>,
+  Extension(index): Extension<SessionIndex>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Redirect, Error> {
+  let mut user = state
+    .users
+    .get(&key)
+    .await
+    .ok_or_else(|| Error::NotFound(key.email().to_string()))?;
+
+  let audit = match params.action {
+    Action::Approve => Audit::Approve,
+    Action::Unapprove => Audit::Unapprove,
+    Action::GrantAdmin => Audit::GrantRole,
+    Action::RevokeAdmin => Audit::RevokeRole,
+    Action::Disable => Audit::Disable,
+    Action::Enable => Audit::Enable,
+  };
+
+  match params.action {
+    Action::Approve => user.approved = true,
+    Action::Unapprove => user.approved = false,
+    Action::GrantAdmin => {
+      if !user.roles.contains(&Role::Administrator) {
+        user.roles.push(Role::Administrator);
+      }
+    },
+    Action::RevokeAdmin => {
+      if user.email == admin.email {
+        return Err(Error::OwnRole);
+      }
+
+      user.roles.retain(|role| *role != Role::Administrator);
+    },
+    Action::Disable => {
+      if user.email == admin.email {
+        return Err(Error::DisableSelf);
+      }
+
+      user.disabled = true;
+      // Log them out everywhere, too.
+      index.revoke_all(&user.email).await?;
+    },
+    Action::Enable => user.disabled = false,
+  }
+
+  state.users.set(user.clone()).await?;
+
+  // They're logged out everywhere, now their privileges have changed.
+  if matches!(
+    params.action,
+    Action::Approve | Action::Unapprove | Action::GrantAdmin | Action::RevokeAdmin
+  ) {
+    index.privileges_changed(&user.email).await?;
+  }
+
+  let detail = match params.action {
+    Action::GrantAdmin | Action::RevokeAdmin => Some(Role::Administrator.name().to_string()),
+    _ => None,
+  };
+  state
+    .audit
+    .record(&admin.email, audit, &user.email, detail)
+    .await;
+
+  Ok(Redirect::to(&format!("/meta/admin/users/{}", user.email)))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ExportParams {
+  format: ExportFormat,
+}
+
+/// Every user, unencrypted, to download.
+pub async fn export_users_handler(
+  Is(_): Is<{ Role::Administrator }>,
+  Query(params): Query<ExportParams>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Response, Error> {
+  let exported = crate::user::export(&state.users.all().await, params.format)?;
+
+  let response = (
+    [
+      (header::CONTENT_TYPE, String::from(params.format.mime())),
+      (
+        header::CONTENT_DISPOSITION,
+        format!(
+          "attachment; filename=\"users.{}\"",
+          params.format.extension()
+        ),
+      ),
+    ],
+    exported,
+  )
+    .into_response();
+
+  Ok(response)
+}
+
+/// Adds or updates the users in an uploaded export. Nothing is changed if any of them have
+/// problems, or if it's a dry run.
+pub async fn import_users_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Extension(index): Extension<SessionIndex>,
+  Extension(state): Extension<Arc<State>>,
+  mut multipart: Multipart,
+) -> Result<Html<String>, Error> {
+  let mut upload = None;
+  let mut dry_run = false;
+
+  while let Some(field) = multipart.next_field().await? {
+    let name = field.name().map(str::to_string);
+
+    match name.as_deref() {
+      Some("users") => {
+        let format =
+          ExportFormat::from_path(std::path::Path::new(field.file_name().unwrap_or_default()));
+        upload = Some((format, field.text().await?));
+      },
+      Some("dry_run") => dry_run = field.text().await? == "true",
+      _ => (),
+    }
+  }
+
+  let (format, text) = upload.ok_or(Error::NoUpload)?;
+
+  let existing: HashMap<UserKey, User> = state
+    .users
+    .all()
+    .await
+    .into_iter()
+    .map(|user| (user.key(), user))
+    .collect();
+
+  let plan = Plan::new(crate::user::parse(&text, format)?, &existing);
+  let applied = !dry_run && plan.problems.is_empty();
+
+  if applied {
+    for user in plan.changed() {
+      state.users.set(user.clone()).await?;
+    }
+
+    for user in &plan.updated {
+      let privileges_changed = existing
+        .get(&user.key())
+        .map(|current| current.approved != user.approved || current.roles != user.roles)
+        .unwrap_or(false);
+
+      if privileges_changed {
+        index.privileges_changed(&user.email).await?;
+      }
+    }
+
+    let detail = format!("{} added, {} updated", plan.added.len(), plan.updated.len());
+    state
+      .audit
+      .record(&admin.email, Audit::ImportUsers, "users", Some(detail))
+      .await;
+  }
+
+  let content = maud::html! {
+    @if !plan.problems.is_empty() {
+      p { "Nothing was imported, because of these problems:" }
+      ul .problems {
+        @for problem in &plan.problems {
+          li { (problem) }
+        }
+      }
+    } @else if applied {
+      p { "Imported the users." }
+    } @else {
+      p { "This was a dry run, so nothing has changed yet." }
+    }
+
+    h2 { "Added" }
+    ul {
+      @for user in &plan.added {
+        li { (user.email) }
+      }
+    }
+
+    h2 { "Updated" }
+    ul {
+      @for user in &plan.updated {
+        li { a href={ "/meta/admin/users/" (user.email) } { (user.email) } }
+      }
+    }
+
+    p { (plan.unchanged) " unchanged." }
+    p { a href="/meta/admin/users" { "Users" } }
+  };
+
+  let html = Template::new()
+    .title("Import users")
+    .content(content)
+    .render(Some(admin), &state);
+
+  Ok(html)
+}
+
+/// Reloads the config, like sending the process a `SIGHUP`.
+pub async fn reload_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Redirect, Error> {
+  state.reload_config()?;
+  state.navigation.refresh(state.clone()).await;
+  state
+    .audit
+    .record(&admin.email, Audit::ReloadConfig, "config", None)
+    .await;
+
+  Ok(Redirect::to("/meta/admin/users"))
+}
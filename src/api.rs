@@ -0,0 +1,57 @@
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+  extract::Path,
+  http::{header, StatusCode},
+  response::{IntoResponse, Response},
+  Extension,
+};
+
+use crate::{page::Page, user::User, State};
+
+/// `/api/v1/pages/*path` - the last segment of the path picks the representation.
+pub async fn pages_handler(
+  Path(path): Path<String>,
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Response, crate::page::Error> {
+  let path = path.trim_start_matches('/');
+
+  match path.rsplit_once('/') {
+    Some((path, "plain")) => plain_handler(PathBuf::from(path), user, state).await,
+    _ => Ok(StatusCode::NOT_FOUND.into_response()),
+  }
+}
+
+async fn plain_handler(
+  path: PathBuf,
+  user: Option<User>,
+  state: Arc<State>,
+) -> Result<Response, crate::page::Error> {
+  let config = state.config();
+  if !crate::namespace::is_visible(&crate::acl::resolve(&path, &config), user.as_ref(), &config) {
+    return Ok(StatusCode::NOT_FOUND.into_response());
+  }
+
+  let page = match Page::from_path(path, None, &config) {
+    Ok(page) => page,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+      return Ok(StatusCode::NOT_FOUND.into_response())
+    },
+    Err(err) => return Err(err.into()),
+  };
+
+  let html = page.renderer(state).await?.html().to_string();
+
+  let plain = tokio::task::spawn_blocking(move || crate::pandoc::html_to_plain(html))
+    .await
+    .unwrap()?;
+
+  let response = (
+    [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+    plain,
+  )
+    .into_response();
+
+  Ok(response)
+}
@@ -0,0 +1,71 @@
+//! The front-end bundle, logo and fonts, which are built into the binary so it runs without a
+//! `static_directory` - and fingerprinting the bundle, so browsers can keep it for as long as
+//! they like and still get the new one as soon as it changes.
+
+use std::{
+  collections::{hash_map::DefaultHasher, HashMap},
+  hash::{Hash, Hasher},
+  path::Path,
+};
+
+use include_dir::{include_dir, Dir};
+
+/// What `npm run build` put in `static` when the wiki was compiled.
+static EMBEDDED: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/static");
+
+/// The files in `static_directory` that pages link to, and so get a fingerprint.
+const FINGERPRINTED: &[&str] = &["bundle.js", "bundle.css", "slides.js", "slides.css"];
+
+#[derive(Default)]
+pub struct Assets {
+  fingerprints: HashMap<&'static str, String>,
+}
+
+impl Assets {
+  /// Fingerprints the bundle as it is now - it's not read again until the wiki restarts.
+  pub fn load(static_directory: &Path) -> Self {
+    let fingerprints = FINGERPRINTED
+      .iter()
+      .filter_map(|name| {
+        let contents = std::fs::read(static_directory.join(name))
+          .ok()
+          .or_else(|| embedded(Path::new(name)).map(<[u8]>::to_vec))?;
+
+        Some((*name, fingerprint(&contents)))
+      })
+      .collect();
+
+    Self { fingerprints }
+  }
+
+  /// Where to link to `name`, with its fingerprint if it has one.
+  pub fn url(&self, name: &str) -> String {
+    match self.fingerprints.get(name) {
+      Some(fingerprint) => format!("/{}?v={}", name, fingerprint),
+      None => format!("/{}", name),
+    }
+  }
+
+  /// Whether `version` is the fingerprint of the file at `path` - only then can it be cached
+  /// for good, as another version could be asked for with any URL.
+  pub fn is_current(&self, path: &Path, version: Option<&str>) -> bool {
+    let fingerprint = path.to_str().and_then(|name| self.fingerprints.get(name));
+
+    matches!((fingerprint, version), (Some(fingerprint), Some(version)) if fingerprint == version)
+  }
+}
+
+/// The built-in version of the static file at `path`, for when `static_directory` doesn't
+/// have one.
+pub fn embedded(path: &Path) -> Option<&'static [u8]> {
+  EMBEDDED.get_file(path).map(|file| file.contents())
+}
+
+/// A short hash of `contents`, which is also used for ETags. It only needs to change when the
+/// contents do, so it doesn't need to be cryptographic.
+pub fn fingerprint(contents: &[u8]) -> String {
+  let mut hasher = DefaultHasher::new();
+  contents.hash(&mut hasher);
+
+  format!("{:016x}", hasher.finish())
+}
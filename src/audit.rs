@@ -0,0 +1,459 @@
+//! An append-only record of who did what to the wiki's users and pages, and to the wiki itself -
+//! approvals, role changes, deletions, bulk changes, Micropub posts, config reloads, failed
+//! logins, and what's done with edits held as spam. Entries are only ever added, and administrators can read them
+//! at `/meta/admin/audit`.
+
+use std::{collections::VecDeque, path::PathBuf, sync::Arc};
+
+use axum::{
+  extract::Query,
+  http::StatusCode,
+  response::{Html, IntoResponse, Response},
+  Extension,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPool, Row};
+use time::OffsetDateTime;
+use tokio::{
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+  sync::Mutex,
+};
+
+use crate::{
+  config::Config,
+  date::Dates,
+  role::{Is, Role},
+  template::Template,
+  user::User,
+  State,
+};
+
+/// How many entries are shown on each page of the log.
+const ENTRIES_PER_PAGE: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  Sqlx(#[from] sqlx::Error),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+  }
+}
+
+/// Where the log is kept.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Store {
+  /// `audit.jsonl` in `cache_directory`, one entry to a line.
+  File,
+  /// The URL of a Postgres database, so several instances can share one log.
+  Postgres(String),
+}
+
+impl Default for Store {
+  fn default() -> Self {
+    Self::File
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+  Approve,
+  Unapprove,
+  GrantRole,
+  RevokeRole,
+  Disable,
+  Enable,
+  AddUser,
+  ImportUsers,
+  Delete,
+  Restore,
+  Replace,
+  ImportPages,
+  ReloadConfig,
+  FailedLogin,
+  Lockout,
+  Unlock,
+  Release,
+  Discard,
+  DeleteMedia,
+  MicropubCreate,
+  MicropubUpdate,
+}
+
+impl Action {
+  const ALL: [Self; 21] = [
+    Self::Approve,
+    Self::Unapprove,
+    Self::GrantRole,
+    Self::RevokeRole,
+    Self::Disable,
+    Self::Enable,
+    Self::AddUser,
+    Self::ImportUsers,
+    Self::Delete,
+    Self::Restore,
+    Self::Replace,
+    Self::ImportPages,
+    Self::ReloadConfig,
+    Self::FailedLogin,
+    Self::Lockout,
+    Self::Unlock,
+    Self::Release,
+    Self::Discard,
+    Self::DeleteMedia,
+    Self::MicropubCreate,
+    Self::MicropubUpdate,
+  ];
+
+  /// The identifier used in the log and in query strings.
+  fn id(&self) -> &'static str {
+    match self {
+      Self::Approve => "approve",
+      Self::Unapprove => "unapprove",
+      Self::GrantRole => "grant-role",
+      Self::RevokeRole => "revoke-role",
+      Self::Disable => "disable",
+      Self::Enable => "enable",
+      Self::AddUser => "add-user",
+      Self::ImportUsers => "import-users",
+      Self::Delete => "delete",
+      Self::Restore => "restore",
+      Self::Replace => "replace",
+      Self::ImportPages => "import-pages",
+      Self::ReloadConfig => "reload-config",
+      Self::FailedLogin => "failed-login",
+      Self::Lockout => "lockout",
+      Self::Unlock => "unlock",
+      Self::Release => "release",
+      Self::Discard => "discard",
+      Self::DeleteMedia => "delete-media",
+      Self::MicropubCreate => "micropub-create",
+      Self::MicropubUpdate => "micropub-update",
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Approve => "Approved",
+      Self::Unapprove => "Unapproved",
+      Self::GrantRole => "Granted role",
+      Self::RevokeRole => "Revoked role",
+      Self::Disable => "Disabled",
+      Self::Enable => "Enabled",
+      Self::AddUser => "Added user",
+      Self::ImportUsers => "Imported users",
+      Self::Delete => "Deleted",
+      Self::Restore => "Restored",
+      Self::Replace => "Searched and replaced",
+      Self::ImportPages => "Imported pages",
+      Self::ReloadConfig => "Reloaded the config",
+      Self::FailedLogin => "Failed to log in",
+      Self::Lockout => "Locked out",
+      Self::Unlock => "Unlocked",
+      Self::Release => "Let a held edit through",
+      Self::Discard => "Discarded a held edit",
+      Self::DeleteMedia => "Deleted media",
+      Self::MicropubCreate => "Posted with Micropub",
+      Self::MicropubUpdate => "Updated with Micropub",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+  #[serde(with = "time::serde::rfc3339")]
+  pub date: OffsetDateTime,
+  /// The email of whoever did it - or for failed logins, where they came from, if it's known.
+  pub actor: String,
+  pub action: Action,
+  /// What it was done to, like a user's email or a page's path.
+  pub target: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub detail: Option<String>,
+}
+
+enum Backend {
+  File {
+    path: PathBuf,
+    /// So entries written at the same time don't end up on the same line.
+    lock: Mutex<()>,
+  },
+  Postgres(PgPool),
+}
+
+pub struct AuditLog {
+  backend: Backend,
+}
+
+impl AuditLog {
+  pub async fn new(config: &Config) -> Result<Self, Error> {
+    let backend = match &config.audit {
+      Store::File => {
+        tokio::fs::create_dir_all(&config.cache_directory).await?;
+
+        Backend::File {
+          path: config.cache_directory.join("audit.jsonl"),
+          lock: Mutex::default(),
+        }
+      },
+      Store::Postgres(url) => {
+        let pool = PgPool::connect(url).await?;
+
+        sqlx::query(
+          "CREATE TABLE IF NOT EXISTS gitalite_audit (
+            id BIGSERIAL PRIMARY KEY,
+            data TEXT NOT NULL
+          )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Backend::Postgres(pool)
+      },
+    };
+
+    Ok(Self { backend })
+  }
+
+  /// Adds an entry to the log. The action has already happened, so if the log can't be
+  /// written to, that's logged rather than undoing it.
+  pub async fn record(
+    &self,
+    actor: &str,
+    action: Action,
+    target: impl Into<String>,
+    detail: Option<String>,
+  ) {
+    let entry = Entry {
+      date: OffsetDateTime::now_utc(),
+      actor: actor.to_string(),
+      action,
+      target: target.into(),
+      detail,
+    };
+
+    if let Err(err) = self.append(&entry).await {
+      log::error!(
+        "Couldn't add to the audit log: {} ({} {} {})",
+        err,
+        entry.actor,
+        entry.action.id(),
+        entry.target
+      );
+    }
+  }
+
+  async fn append(&self, entry: &Entry) -> Result<(), Error> {
+    let data = serde_json::to_string(entry)?;
+
+    match &self.backend {
+      Backend::File { path, lock } => {
+        let _lock = lock.lock().await;
+
+        let mut file = tokio::fs::OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(path)
+          .await?;
+
+        file.write_all(format!("{}\n", data).as_bytes()).await?;
+        file.flush().await?;
+      },
+      Backend::Postgres(pool) => {
+        sqlx::query("INSERT INTO gitalite_audit (data) VALUES ($1)")
+          .bind(data)
+          .execute(pool)
+          .await?;
+      },
+    }
+
+    Ok(())
+  }
+
+  /// How many entries `filter` matches, and up to `limit` of them after the first `skip`,
+  /// newest first.
+  async fn entries(
+    &self,
+    filter: &AuditFilter,
+    skip: usize,
+    limit: usize,
+  ) -> Result<(usize, Vec<Entry>), Error> {
+    match &self.backend {
+      Backend::File { path, .. } => {
+        let file = match tokio::fs::File::open(path).await {
+          Ok(file) => file,
+          Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((0, Vec::new())),
+          Err(err) => return Err(err.into()),
+        };
+
+        // The file is oldest first, so only the newest matches that could be shown are kept.
+        let keep = skip.saturating_add(limit);
+        let mut newest = VecDeque::new();
+        let mut total = 0;
+
+        let mut lines = BufReader::new(file).lines();
+        while let Some(line) = lines.next_line().await? {
+          if line.trim().is_empty() {
+            continue;
+          }
+
+          let entry: Entry = serde_json::from_str(&line)?;
+          if !filter.matches(&entry) {
+            continue;
+          }
+
+          total += 1;
+          newest.push_back(entry);
+          if newest.len() > keep {
+            newest.pop_front();
+          }
+        }
+
+        let entries = newest.into_iter().rev().skip(skip).take(limit).collect();
+
+        Ok((total, entries))
+      },
+      Backend::Postgres(pool) => {
+        // The same as `AuditFilter::matches`, so only the page being shown is read.
+        let matching = "FROM gitalite_audit
+          WHERE ($1 = '' OR data::json->>'action' = $1)
+          AND (POSITION($2 IN LOWER(data::json->>'actor')) > 0
+            OR POSITION($2 IN LOWER(data::json->>'target')) > 0)";
+        let search = filter.search.trim().to_lowercase();
+
+        let total: i64 = sqlx::query(&format!("SELECT COUNT(*) {}", matching))
+          .bind(&filter.action)
+          .bind(&search)
+          .fetch_one(pool)
+          .await?
+          .get(0);
+
+        let entries = sqlx::query(&format!(
+          "SELECT data {} ORDER BY id DESC LIMIT $3 OFFSET $4",
+          matching
+        ))
+        .bind(&filter.action)
+        .bind(&search)
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .bind(i64::try_from(skip).unwrap_or(i64::MAX))
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| serde_json::from_str(row.get(0)))
+        .collect::<Result<Vec<Entry>, _>>()?;
+
+        Ok((total as usize, entries))
+      },
+    }
+  }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AuditFilter {
+  /// One of the actions' identifiers - anything else shows every action.
+  action: String,
+  /// Part of the actor's email, or the target.
+  search: String,
+  page: usize,
+}
+
+impl AuditFilter {
+  fn matches(&self, entry: &Entry) -> bool {
+    let action = self.action.is_empty() || entry.action.id() == self.action;
+
+    let search = self.search.trim().to_lowercase();
+    let search =
+      entry.actor.to_lowercase().contains(&search) || entry.target.to_lowercase().contains(&search);
+
+    action && search
+  }
+
+  fn page_url(&self, page: usize) -> String {
+    format!(
+      "/meta/admin/audit?action={}&search={}&page={}",
+      urlencoding::encode(&self.action),
+      urlencoding::encode(&self.search),
+      page,
+    )
+  }
+}
+
+pub async fn handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Query(filter): Query<AuditFilter>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, Error> {
+  let config = state.config();
+  let dates = Dates::new(Some(&admin), &config.dates);
+
+  let page = filter.page.max(1);
+  let skip = (page - 1).saturating_mul(ENTRIES_PER_PAGE);
+
+  let (total, entries) = state.audit.entries(&filter, skip, ENTRIES_PER_PAGE).await?;
+
+  let pages = (total + ENTRIES_PER_PAGE - 1) / ENTRIES_PER_PAGE;
+
+  let content = maud::html! {
+    form #audit-filter method="get" action="/meta/admin/audit" {
+      input type="search" name="search" placeholder="Email or target" value=(filter.search);
+      select name="action" {
+        option value="" selected[filter.action.is_empty()] { "Any action" }
+        @for action in Action::ALL {
+          option value=(action.id()) selected[filter.action == action.id()] { (action.name()) }
+        }
+      }
+      button type="submit" { "Filter" }
+    }
+
+    table #audit {
+      thead {
+        tr {
+          th { "When" }
+          th { "Who" }
+          th { "What" }
+          th { "To" }
+          th {}
+        }
+      }
+      tbody {
+        @for entry in &entries {
+          tr {
+            td { (dates.render(entry.date)) }
+            td { (entry.actor) }
+            td { (entry.action.name()) }
+            td { (entry.target) }
+            td { @if let Some(detail) = &entry.detail { (detail) } }
+          }
+        }
+      }
+    }
+
+    @if pages > 1 {
+      nav .pagination {
+        @if page > 1 {
+          a href=(filter.page_url(page - 1)) { "previous" }
+        }
+        " page " (page) " of " (pages) " "
+        @if page < pages {
+          a href=(filter.page_url(page + 1)) { "next" }
+        }
+      }
+    }
+  };
+
+  let html = Template::new()
+    .title("Audit log")
+    .content(content)
+    .render(Some(admin), &state);
+
+  Ok(html)
+}
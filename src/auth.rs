@@ -1,27 +1,90 @@
-use std::{str::FromStr, string::FromUtf8Error, sync::Arc};
+use std::{string::FromUtf8Error, sync::Arc};
 
 use async_session::{Session, SessionStore};
-use async_sqlx_session::PostgresSessionStore;
 use axum::{
   async_trait,
   extract::{Extension, FromRequest, Query, RequestParts, TypedHeader},
-  headers::Cookie,
+  headers::{Cookie, UserAgent},
   http::StatusCode,
   response::{Html, IntoResponse, Redirect},
   Form,
 };
 use axum_extra::extract::cookie::{Cookie as CookieExt, CookieJar};
-use indieweb::standards::indieauth::{self, Client, Scopes};
-use oauth2::{url::Url, ClientId, RedirectUrl};
+use oauth2::url::Url;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+  audit::Action,
   config::Config,
+  error::{ErrorPage, Problem},
+  invite::{Invites, Registration},
+  lockout::Key,
+  proxy::Forwarded,
+  sessions::{SessionIndex, Store},
   template::Template,
   user::{User, UserKey},
   State,
 };
 
+mod github;
+mod indieauth;
+mod oauth;
+mod oidc;
+
+/// What we need to know about a user from whichever provider they logged in with.
+pub struct Profile {
+  pub name: String,
+  pub email: String,
+  pub url: Url,
+}
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+  fn name(&self) -> &'static str;
+
+  /// Whether the user needs to enter the URL of their own site to log in.
+  fn needs_url(&self) -> bool;
+
+  /// Returns the URL to send the user to, and a session holding whatever the provider needs
+  /// under the `login` key.
+  async fn authenticate(&self, url: Option<&Url>, config: &Config) -> Result<(Url, Session), Error>;
+
+  async fn callback(
+    &self,
+    session: &Session,
+    code: String,
+    auth_state: String,
+    config: &Config,
+  ) -> Result<Profile, Error>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Provider {
+  IndieAuth,
+  Oidc(oidc::Oidc),
+  GitHub(github::GitHub),
+}
+
+impl Default for Provider {
+  fn default() -> Self {
+    Provider::IndieAuth
+  }
+}
+
+impl Provider {
+  pub fn build(&self) -> Box<dyn AuthProvider> {
+    match self.clone() {
+      Provider::IndieAuth => Box::new(indieauth::IndieAuth),
+      Provider::Oidc(oidc) => Box::new(oidc),
+      Provider::GitHub(github) => Box::new(github),
+    }
+  }
+}
+
+pub fn redirect_uri(config: &Config) -> String {
+  format!("{}/meta/login-callback", config.base_url())
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
   #[error(transparent)]
@@ -40,19 +103,41 @@ pub enum Error {
   MissingField(&'static str),
   #[error(transparent)]
   User(#[from] crate::user::Error),
+  #[error(transparent)]
+  Http(#[from] reqwest::Error),
+  #[error("OAuth error: {0}")]
+  OAuth(String),
+  #[error("The login state doesn't match")]
+  CsrfMismatch,
+  #[error("This account has been disabled")]
+  Disabled,
+  #[error("Your email address hasn't been verified")]
+  UnverifiedEmail,
+  #[error(transparent)]
+  Sessions(#[from] crate::sessions::Error),
+  #[error(transparent)]
+  Invite(#[from] crate::invite::Error),
+  #[error(transparent)]
+  LockedOut(#[from] crate::lockout::Error),
 }
 
 impl IntoResponse for Error {
   fn into_response(self) -> axum::response::Response {
-    let code = match self {
-      Self::Utf8(_) => StatusCode::BAD_REQUEST,
-      Self::MissingAuthEndpoint => StatusCode::BAD_REQUEST,
-      Self::MissingTokenEndpoint => StatusCode::BAD_REQUEST,
-      Self::MissingField(_) => StatusCode::BAD_REQUEST,
-      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    let (status, code) = match self {
+      Self::Utf8(_) => (StatusCode::BAD_REQUEST, "invalid-utf8"),
+      Self::MissingAuthEndpoint => (StatusCode::BAD_REQUEST, "missing-auth-endpoint"),
+      Self::MissingTokenEndpoint => (StatusCode::BAD_REQUEST, "missing-token-endpoint"),
+      Self::MissingField(_) => (StatusCode::BAD_REQUEST, "missing-field"),
+      Self::CsrfMismatch => (StatusCode::BAD_REQUEST, "csrf-mismatch"),
+      Self::Disabled => (StatusCode::FORBIDDEN, "disabled"),
+      Self::UnverifiedEmail => (StatusCode::FORBIDDEN, "unverified-email"),
+      Self::OAuth(_) => (StatusCode::INTERNAL_SERVER_ERROR, "oauth"),
+      Self::Invite(err) => return err.into_response(),
+      Self::LockedOut(err) => return err.into_response(),
+      _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
     };
 
-    (code, self.to_string()).into_response()
+    Problem::new(status, code, self).into_response()
   }
 }
 
@@ -62,28 +147,72 @@ pub struct Params {
   state: String,
 }
 
-pub async fn login_handler() -> Result<Html<String>, crate::page::Error> {
+#[derive(Debug, serde::Deserialize)]
+pub struct LoginParams {
+  next: Option<String>,
+  invite: Option<String>,
+}
+
+/// Only allow redirecting to paths on this site, so the login flow can't be used as an open
+/// redirect. Browsers treat `\` like `/` and drop tabs and newlines, so `/\evil.com` and
+/// `/\t/evil.com` would go off-site too - neither is allowed.
+pub fn safe_redirect(next: &str) -> Option<&str> {
+  let is_local = next.starts_with('/')
+    && !next.starts_with("//")
+    && !next.contains(|c: char| c == '\\' || c.is_control());
+
+  if is_local && !next.starts_with("/meta/login") {
+    Some(next)
+  } else {
+    None
+  }
+}
+
+pub fn login_url(next: &str) -> String {
+  format!("/meta/login?next={}", urlencoding::encode(next))
+}
+
+pub async fn login_handler(
+  Query(params): Query<LoginParams>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, crate::page::Error> {
+  let next = params.next.as_deref().and_then(safe_redirect);
+
   let content = maud::html! {
     form action="/meta/login" method="post" {
-      input type="url" name="url" placeholder="example.com";
-      input type="submit" value="sign in";
+      @if state.auth.needs_url() {
+        input type="url" name="url" placeholder="example.com";
+      }
+      @if let Some(next) = next {
+        input type="hidden" name="next" value=(next);
+      }
+      @if let Some(invite) = &params.invite {
+        input type="hidden" name="invite" value=(invite);
+      }
+      input type="submit" value={ "sign in with " (state.auth.name()) };
     }
   };
 
-  let html = Template::new().title("Login").content(content).render(None);
+  let html = Template::new()
+    .title("Login")
+    .content(content)
+    .render(None, &state);
 
   Ok(html)
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct AuthenticateParams {
-  url: Url,
+  url: Option<Url>,
+  next: Option<String>,
+  invite: Option<String>,
 }
 
 pub async fn authenticate_handler(
+  forwarded: Forwarded,
   Form(params): Form<AuthenticateParams>,
   mut jar: CookieJar,
-  store: Extension<PostgresSessionStore>,
+  Extension(store): Extension<Store>,
   Extension(state): Extension<Arc<State>>,
 ) -> Result<impl IntoResponse, Error> {
   // Get session from the cookie
@@ -104,11 +233,40 @@ pub async fn authenticate_handler(
     jar = jar.remove(cookie);
   }
 
-  let (redirect, session) = authenticate(&params.url, &state.config).await?;
+  let next = params
+    .next
+    .as_deref()
+    .and_then(safe_redirect)
+    .map(|next| next.to_string());
+
+  let config = state.config();
+  state
+    .lockouts
+    .check(&Key::login(forwarded.client, None), &config.lockout)?;
+
+  let (redirect, mut session) = state
+    .auth
+    .authenticate(params.url.as_ref(), &config)
+    .await?;
+
+  if let Some(next) = next {
+    session.insert("next", next)?;
+  }
+
+  if let Some(invite) = params.invite {
+    session.insert("invite", invite)?;
+  }
+
+  {
+    use time::ext::NumericalStdDuration;
+
+    session.expire_in(1.std_hours());
+  }
   let cookie = store.store_session(session).await.unwrap().unwrap();
 
   let cookie = CookieExt::build(SESSION_COOKIE_NAME, cookie)
     .path("/")
+    .secure(forwarded.is_secure(&state.config()))
     .finish();
 
   jar = jar.add(cookie);
@@ -119,7 +277,11 @@ pub async fn authenticate_handler(
 pub async fn callback_handler(
   Query(params): Query<Params>,
   mut jar: CookieJar,
-  store: Extension<PostgresSessionStore>,
+  user_agent: Option<TypedHeader<UserAgent>>,
+  forwarded: Forwarded,
+  Extension(store): Extension<Store>,
+  Extension(index): Extension<SessionIndex>,
+  Extension(invites): Extension<Invites>,
   Extension(state): Extension<Arc<State>>,
 ) -> Result<impl IntoResponse, Error> {
   // Get session from the cookie
@@ -178,175 +340,181 @@ pub async fn callback_handler(
     },
   };
 
-  let user = authenticate_callback(&session, params.code, params.state, &state).await?;
+  let config = state.config();
+  state
+    .lockouts
+    .check(&Key::login(forwarded.client, None), &config.lockout)?;
+
+  // Failures only count against an account once the login provider has said who it is -
+  // anyone can claim to be anyone, and shouldn't be able to lock them out.
+  let mut account = None;
+  let invite = session.get::<String>("invite");
+  let login = async {
+    let profile = state
+      .auth
+      .callback(&session, params.code, params.state, &config)
+      .await?;
+
+    account = Some(profile.email.clone());
+    state.lockouts.check(
+      &Key::login(forwarded.client, account.as_deref()),
+      &config.lockout,
+    )?;
+
+    authenticate_callback(profile, invite.as_deref(), &invites, &state).await
+  };
+  let result = login.await;
+  let keys = Key::login(forwarded.client, account.as_deref());
+
+  let user = match result {
+    Ok(user) => user,
+    Err(err) => {
+      let client = forwarded
+        .client
+        .map(|client| client.to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+
+      let target = account.as_deref().unwrap_or("login");
+      state
+        .audit
+        .record(&client, Action::FailedLogin, target, Some(err.to_string()))
+        .await;
+
+      for key in state.lockouts.fail(&keys, &config.lockout) {
+        log::warn!("{} is locked out after too many failed logins", key);
+        state
+          .audit
+          .record(&client, Action::Lockout, key.to_string(), None)
+          .await;
+      }
+
+      return Err(err);
+    },
+  };
+
+  state.lockouts.succeed(&keys);
+
+  let next = session
+    .get::<String>("next")
+    .unwrap_or_else(|| String::from("/"));
 
   // Here we've authenticated successfully, so we can remove the `login` cookie...
   store.destroy_session(session).await.unwrap();
   jar = jar.remove(cookie);
 
+  // The user-session is always a new one, with an ID nobody could have known about before they
+  // logged in - nothing from the login session is carried over.
   let session = user.key().to_session();
+  let session_id = session.id().to_string();
   // ...and add the user-session cookie!
   let cookie = store.store_session(session).await.unwrap().unwrap();
 
+  let user_agent = user_agent.as_ref().map(|TypedHeader(agent)| agent.as_str());
+  index.record(&session_id, &user.email, user_agent).await?;
+
+  match forwarded.client {
+    Some(client) => log::info!("{} logged in from {}", user.email, client),
+    None => log::info!("{} logged in", user.email),
+  }
+
   let cookie = CookieExt::build(SESSION_COOKIE_NAME, cookie)
     .path("/")
+    .secure(forwarded.is_secure(&state.config()))
     .finish();
 
   jar = jar.add(cookie);
 
-  return Ok((jar, Redirect::to("/")));
+  return Ok((jar, Redirect::to(&next)));
 }
 
-const SESSION_COOKIE_NAME: &str = "gitalite_session";
+pub async fn logout_handler(
+  mut jar: CookieJar,
+  Extension(store): Extension<Store>,
+  Extension(index): Extension<SessionIndex>,
+  user: User,
+) -> Result<impl IntoResponse, Error> {
+  if let Some(cookie) = jar.get(SESSION_COOKIE_NAME).cloned() {
+    let cookie_value = urlencoding::decode(cookie.value())?.to_string();
+
+    if let Some(session) = store.load_session(cookie_value).await? {
+      index.revoke(session.id(), &user.email).await?;
+      store.destroy_session(session).await?;
+    }
 
-#[derive(Serialize, Deserialize)]
-pub struct Login {
-  authorization_endpoint: Url,
-  token_endpoint: Url,
-  verifier: String,
-  challenge: String,
-  url: Url,
-  csrf_token: String,
+    jar = jar.remove(cookie);
+  }
+
+  Ok((jar, Redirect::to("/")))
 }
 
-pub async fn setup(app: axum::Router, state: Arc<State>) -> Result<axum::Router, Error> {
-  let store = PostgresSessionStore::new(&state.config.postgresql)
-    .await
-    .unwrap();
-  store.migrate().await.unwrap();
+pub const SESSION_COOKIE_NAME: &str = "gitalite_session";
+
+/// The ID of the session the request was made with, if there is one.
+pub fn current_session_id(jar: &CookieJar) -> Option<String> {
+  let cookie = jar.get(SESSION_COOKIE_NAME)?;
+  let cookie_value = urlencoding::decode(cookie.value()).ok()?;
 
-  Ok(app.layer(Extension(store)))
+  Session::id_from_cookie_value(&cookie_value).ok()
 }
 
-pub async fn authenticate(url: &Url, config: impl AsRef<Config>) -> Result<(Url, Session), Error> {
-  let config = config.as_ref();
-  let http_client = indieweb::http::ureq::Client::default();
-
-  let discovered = indieauth::discover(&http_client, url).await.unwrap();
-
-  let client_id = &config.client_id;
-  let client_id = ClientId::new(client_id.into());
-
-  let redirect_uri = format!("{}/meta/login-callback", config.client_id);
-  let redirect_uri = RedirectUrl::new(redirect_uri.into()).unwrap();
-
-  let scope = Scopes::from_str("profile email").unwrap();
-
-  let authorization_endpoint = discovered
-    .authorization_endpoints
-    .map(|end| end.first().cloned())
-    .flatten()
-    .ok_or(Error::MissingAuthEndpoint)?;
-  let authorization_endpoint = indieauth::AuthUrl::from_url(authorization_endpoint);
-
-  let token_endpoint = discovered
-    .token_endpoints
-    .map(|end| end.first().cloned())
-    .flatten()
-    .ok_or(Error::MissingTokenEndpoint)?;
-  let token_endpoint = indieauth::TokenUrl::from_url(token_endpoint);
-
-  let client = indieauth::StockClient::from((
-    client_id,
-    authorization_endpoint.clone(),
-    token_endpoint.clone(),
-  ));
-
-  let (verifier, challenge, url, csrf_token) = match client.dispatch(
-    &http_client,
-    indieauth::Request::BuildAuthorizationUrl {
-      scope: Some(scope),
-      redirect_uri: Some(redirect_uri),
-      me: Some(url.clone()),
-    },
-  )? {
-    indieauth::Response::AuthenticationUrl {
-      verifier,
-      challenge,
-      url,
-      csrf_token,
-    } => (verifier, challenge, url, csrf_token),
-    _ => unreachable!(),
-  };
+/// The sessions and invites, which every wiki in the process shares.
+#[derive(Clone)]
+pub struct Logins {
+  store: Store,
+  index: SessionIndex,
+  invites: Invites,
+}
 
-  let mut session = Session::new();
-
-  session.insert(
-    "login",
-    Login {
-      authorization_endpoint: authorization_endpoint.url().clone(),
-      token_endpoint: token_endpoint.url().clone(),
-      verifier,
-      challenge,
-      url: url.clone(),
-      csrf_token,
-    },
-  )?;
+impl Logins {
+  pub async fn new(config: &Config) -> Result<Self, Error> {
+    let (store, index) = config.session_backend().build().await?;
+    let invites = Invites::load(config.users.invites.clone()).await?;
 
-  {
-    use time::ext::NumericalStdDuration;
+    tokio::spawn(crate::sessions::prune_periodically(store.clone(), index.clone()));
 
-    session.expire_in(1.std_hours());
+    Ok(Self {
+      store,
+      index,
+      invites,
+    })
   }
+}
 
-  Ok((url, session))
+pub fn setup(app: axum::Router, logins: Logins) -> axum::Router {
+  app
+    .layer(Extension(logins.store))
+    .layer(Extension(logins.index))
+    .layer(Extension(logins.invites))
 }
 
+/// The user the login provider said `profile` is, added if they're new.
 pub async fn authenticate_callback(
-  session: &Session,
-  code: String,
-  auth_state: String,
+  profile: Profile,
+  invite: Option<&str>,
+  invites: &Invites,
   state: &Arc<State>,
 ) -> Result<User, Error> {
-  let http_client = indieweb::http::ureq::Client::default();
-
-  let login: Login = session.get("login").unwrap();
-
-  let client_id = &state.config.client_id;
-  let client_id = ClientId::new(client_id.into());
-
-  let redirect_uri = format!("{}/meta/login-callback", state.config.client_id);
-  let redirect_uri = RedirectUrl::new(redirect_uri.into()).unwrap();
-
-  let client = indieauth::StockClient::from((
-    client_id,
-    indieauth::AuthUrl::from_url(login.authorization_endpoint.clone()),
-    indieauth::TokenUrl::from_url(login.token_endpoint.clone()),
-  ));
-
-  let profile = match client.dispatch(
-    &http_client,
-    indieauth::Request::CompleteAuthorization {
-      resource: indieauth::DesiredResourceAuthorization::Profile,
-      code: indieauth::AuthorizationCode::new(code),
-      code_verifier: login.verifier,
-      redirect_uri: Some(redirect_uri),
-    },
-  )? {
-    indieauth::Response::Profile(profile) => profile,
-    _ => unreachable!(),
-  };
-
-  let email = profile.email.ok_or(Error::MissingField("email"))?;
-  let name = profile.name.ok_or(Error::MissingField("name"))?;
-  let url = profile.url.ok_or(Error::MissingField("url"))?;
+  let Profile { name, email, url } = profile;
 
   let user = {
     let key = UserKey::from(email.clone());
-    let mut users = state.users.lock().unwrap();
 
-    match users.get(&key) {
+    match state.users.fetch(&key).await? {
+      Some(user) if user.disabled => return Err(Error::Disabled),
       Some(user) => {
         let mut new_user = user.clone();
 
-        if new_user.name != name {
-          log::info!("Updating name for {}", &email);
-          new_user.name = name;
-        }
-
-        if new_user.url != url {
-          log::info!("Updating url for {}", &email);
-          new_user.url = url;
+        // Once someone has edited their profile, it's theirs to keep up to date.
+        if !new_user.edited_profile {
+          if new_user.name != name {
+            log::info!("Updating name for {}", &email);
+            new_user.name = name;
+          }
+
+          if new_user.url != url {
+            log::info!("Updating url for {}", &email);
+            new_user.url = url;
+          }
         }
 
         if new_user.email != email {
@@ -354,22 +522,51 @@ pub async fn authenticate_callback(
           new_user.email = email;
         }
 
-        if new_user != *user {
-          users.set(new_user.clone())?;
+        if new_user != user {
+          state.users.set(new_user.clone()).await?;
         }
 
         new_user
       },
       None => {
+        let redeemed = match invite {
+          Some(token) => Some(invites.redeem(token, &email).await),
+          None => None,
+        };
+
+        // An invite is as good as being approved by whichever admin made it. Anyone can sign
+        // up to an open wiki, so one that isn't valid only means waiting to be approved.
+        let invited_by = match (state.config().registration, redeemed) {
+          (_, Some(Ok(invite))) => {
+            log::info!("{} was invited by {}", &email, invite.created_by);
+
+            Some(invite.created_by)
+          },
+          (Registration::Open, Some(Err(crate::invite::Error::Invalid))) => {
+            log::info!("{} signed up with an invite that isn't valid", &email);
+
+            None
+          },
+          (Registration::Open, None) => None,
+          (_, Some(Err(err))) => return Err(err.into()),
+          (Registration::InviteOnly, None) => return Err(crate::invite::Error::Invalid.into()),
+        };
+
         let user = User {
           name,
           email,
-          url: url.into(),
-          approved: false,
+          url,
+          approved: invited_by.is_some(),
           roles: Vec::new(),
+          timezone: None,
+          locale: None,
+          edited_profile: false,
+          disabled: false,
+          notifications: Default::default(),
         };
 
-        users.set(user.clone())?;
+        state.users.set(user.clone()).await?;
+        crate::notify::new_user(state, &user, invited_by.as_deref()).await;
         user
       },
     }
@@ -386,18 +583,25 @@ pub enum UserExtractError {
   UserCookie,
   #[error(transparent)]
   Utf8(#[from] FromUtf8Error),
+  #[error(transparent)]
+  User(#[from] crate::user::Error),
   #[error("Unauthorised")]
   Unauthorised,
+  #[error("This account has been disabled")]
+  Disabled,
 }
 
 impl IntoResponse for UserExtractError {
   fn into_response(self) -> axum::response::Response {
-    let code = match self {
-      Self::Unauthorised => StatusCode::UNAUTHORIZED,
-      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    let (status, code) = match self {
+      Self::Unauthorised | Self::UserCookie => {
+        return ErrorPage::Unauthorized { role: None }.into_response()
+      },
+      Self::Disabled => (StatusCode::FORBIDDEN, "disabled"),
+      _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
     };
 
-    (code, self.to_string()).into_response()
+    Problem::new(status, code, self).into_response()
   }
 }
 
@@ -409,9 +613,12 @@ where
   type Rejection = UserExtractError;
 
   async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-    let Extension(store) = Extension::<PostgresSessionStore>::from_request(req)
+    let Extension(store) = Extension::<Store>::from_request(req)
+      .await
+      .expect("`Store` extension missing");
+    let Extension(index) = Extension::<SessionIndex>::from_request(req)
       .await
-      .expect("`PostgresSessionStore` extension missing");
+      .expect("`SessionIndex` extension missing");
     let Extension(state) = Extension::<Arc<State>>::from_request(req)
       .await
       .expect("`State` extension missing");
@@ -437,10 +644,26 @@ where
       .flatten()
       .ok_or(UserExtractError::Unauthorised)?;
 
-    let users = state.users.lock().unwrap();
-    users
-      .get(&UserKey::from_session(&session)?)
-      .ok_or(UserExtractError::Unauthorised)
-      .cloned()
+    // Sessions that have been revoked are no longer in the index.
+    match index.touch(session.id()).await {
+      Ok(true) => {},
+      Ok(false) => return Err(UserExtractError::Unauthorised),
+      Err(err) => {
+        log::warn!("Couldn't check session: {}", err);
+        return Err(UserExtractError::Unauthorised);
+      },
+    }
+
+    let user = state
+      .users
+      .fetch(&UserKey::from_session(&session)?)
+      .await?
+      .ok_or(UserExtractError::Unauthorised)?;
+
+    if user.disabled {
+      return Err(UserExtractError::Disabled);
+    }
+
+    Ok(user)
   }
 }
@@ -0,0 +1,101 @@
+use async_session::Session;
+use axum::async_trait;
+use oauth2::url::Url;
+use serde::{Deserialize, Serialize};
+
+use super::{oauth::OAuthClient, AuthProvider, Error, Profile};
+use crate::config::Config;
+
+const AUTHORIZATION_ENDPOINT: &str = "https://github.com/login/oauth/authorize";
+const TOKEN_ENDPOINT: &str = "https://github.com/login/oauth/access_token";
+const API: &str = "https://api.github.com";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GitHub {
+  pub client_id: String,
+  pub client_secret: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+  login: String,
+  name: Option<String>,
+  html_url: Url,
+}
+
+#[derive(Deserialize)]
+struct GitHubEmail {
+  email: String,
+  primary: bool,
+  verified: bool,
+}
+
+impl GitHub {
+  fn client(&self) -> OAuthClient {
+    OAuthClient {
+      client_id: &self.client_id,
+      client_secret: &self.client_secret,
+      authorization_endpoint: Url::parse(AUTHORIZATION_ENDPOINT).unwrap(),
+      token_endpoint: Url::parse(TOKEN_ENDPOINT).unwrap(),
+      scopes: vec![String::from("read:user"), String::from("user:email")],
+    }
+  }
+
+  async fn get<T: serde::de::DeserializeOwned>(path: &str, token: &str) -> Result<T, Error> {
+    let value = reqwest::Client::new()
+      .get(format!("{}{}", API, path))
+      .bearer_auth(token)
+      // GitHub rejects requests without a user agent.
+      .header(reqwest::header::USER_AGENT, "gitalite")
+      .send()
+      .await?
+      .error_for_status()?
+      .json()
+      .await?;
+
+    Ok(value)
+  }
+}
+
+#[async_trait]
+impl AuthProvider for GitHub {
+  fn name(&self) -> &'static str {
+    "GitHub"
+  }
+
+  fn needs_url(&self) -> bool {
+    false
+  }
+
+  async fn authenticate(&self, _: Option<&Url>, config: &Config) -> Result<(Url, Session), Error> {
+    self.client().authenticate(config)
+  }
+
+  async fn callback(
+    &self,
+    session: &Session,
+    code: String,
+    auth_state: String,
+    config: &Config,
+  ) -> Result<Profile, Error> {
+    let token = self
+      .client()
+      .callback(session, code, auth_state, config)
+      .await?;
+
+    let user: GitHubUser = Self::get("/user", &token).await?;
+    let emails: Vec<GitHubEmail> = Self::get("/user/emails", &token).await?;
+
+    let email = emails
+      .into_iter()
+      .find(|email| email.primary && email.verified)
+      .map(|email| email.email)
+      .ok_or(Error::MissingField("email"))?;
+
+    Ok(Profile {
+      name: user.name.unwrap_or(user.login),
+      email,
+      url: user.html_url,
+    })
+  }
+}
@@ -0,0 +1,144 @@
+use std::str::FromStr;
+
+use async_session::Session;
+use axum::async_trait;
+use indieweb::standards::indieauth::{self, Client, Scopes};
+use oauth2::{url::Url, ClientId, RedirectUrl};
+use serde::{Deserialize, Serialize};
+
+use super::{AuthProvider, Error, Profile};
+use crate::config::Config;
+
+pub struct IndieAuth;
+
+#[derive(Serialize, Deserialize)]
+pub struct Login {
+  authorization_endpoint: Url,
+  token_endpoint: Url,
+  verifier: String,
+  challenge: String,
+  url: Url,
+  csrf_token: String,
+}
+
+#[async_trait]
+impl AuthProvider for IndieAuth {
+  fn name(&self) -> &'static str {
+    "IndieAuth"
+  }
+
+  fn needs_url(&self) -> bool {
+    true
+  }
+
+  async fn authenticate(&self, url: Option<&Url>, config: &Config) -> Result<(Url, Session), Error> {
+    let url = url.ok_or(Error::MissingField("url"))?;
+    let http_client = indieweb::http::ureq::Client::default();
+
+    let discovered = indieauth::discover(&http_client, url).await.unwrap();
+
+    let client_id = &config.client_id;
+    let client_id = ClientId::new(client_id.into());
+
+    let redirect_uri = super::redirect_uri(config);
+    let redirect_uri = RedirectUrl::new(redirect_uri.into()).unwrap();
+
+    let scope = Scopes::from_str("profile email").unwrap();
+
+    let authorization_endpoint = discovered
+      .authorization_endpoints
+      .map(|end| end.first().cloned())
+      .flatten()
+      .ok_or(Error::MissingAuthEndpoint)?;
+    let authorization_endpoint = indieauth::AuthUrl::from_url(authorization_endpoint);
+
+    let token_endpoint = discovered
+      .token_endpoints
+      .map(|end| end.first().cloned())
+      .flatten()
+      .ok_or(Error::MissingTokenEndpoint)?;
+    let token_endpoint = indieauth::TokenUrl::from_url(token_endpoint);
+
+    let client = indieauth::StockClient::from((
+      client_id,
+      authorization_endpoint.clone(),
+      token_endpoint.clone(),
+    ));
+
+    let (verifier, challenge, url, csrf_token) = match client.dispatch(
+      &http_client,
+      indieauth::Request::BuildAuthorizationUrl {
+        scope: Some(scope),
+        redirect_uri: Some(redirect_uri),
+        me: Some(url.clone()),
+      },
+    )? {
+      indieauth::Response::AuthenticationUrl {
+        verifier,
+        challenge,
+        url,
+        csrf_token,
+      } => (verifier, challenge, url, csrf_token),
+      _ => unreachable!(),
+    };
+
+    let mut session = Session::new();
+
+    session.insert(
+      "login",
+      Login {
+        authorization_endpoint: authorization_endpoint.url().clone(),
+        token_endpoint: token_endpoint.url().clone(),
+        verifier,
+        challenge,
+        url: url.clone(),
+        csrf_token,
+      },
+    )?;
+
+    Ok((url, session))
+  }
+
+  async fn callback(
+    &self,
+    session: &Session,
+    code: String,
+    _auth_state: String,
+    config: &Config,
+  ) -> Result<Profile, Error> {
+    let http_client = indieweb::http::ureq::Client::default();
+
+    let login: Login = session.get("login").unwrap();
+
+    let client_id = &config.client_id;
+    let client_id = ClientId::new(client_id.into());
+
+    let redirect_uri = super::redirect_uri(config);
+    let redirect_uri = RedirectUrl::new(redirect_uri.into()).unwrap();
+
+    let client = indieauth::StockClient::from((
+      client_id,
+      indieauth::AuthUrl::from_url(login.authorization_endpoint.clone()),
+      indieauth::TokenUrl::from_url(login.token_endpoint.clone()),
+    ));
+
+    let profile = match client.dispatch(
+      &http_client,
+      indieauth::Request::CompleteAuthorization {
+        resource: indieauth::DesiredResourceAuthorization::Profile,
+        code: indieauth::AuthorizationCode::new(code),
+        code_verifier: login.verifier,
+        redirect_uri: Some(redirect_uri),
+      },
+    )? {
+      indieauth::Response::Profile(profile) => profile,
+      _ => unreachable!(),
+    };
+
+    Ok(Profile {
+      email: profile.email.ok_or(Error::MissingField("email"))?,
+      name: profile.name.ok_or(Error::MissingField("name"))?,
+      url: profile.url.ok_or(Error::MissingField("url"))?.into(),
+    })
+  }
+}
@@ -0,0 +1,102 @@
+//! The authorization code flow (with PKCE) shared by the OAuth2-based providers.
+
+use async_session::Session;
+use oauth2::{
+  basic::BasicClient,
+  reqwest::async_http_client,
+  url::Url,
+  AuthUrl,
+  AuthorizationCode,
+  ClientId,
+  ClientSecret,
+  CsrfToken,
+  PkceCodeChallenge,
+  PkceCodeVerifier,
+  RedirectUrl,
+  Scope,
+  TokenResponse,
+  TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+use crate::config::Config;
+
+#[derive(Serialize, Deserialize)]
+pub struct Login {
+  verifier: String,
+  csrf_token: String,
+}
+
+pub struct OAuthClient<'a> {
+  pub client_id: &'a str,
+  pub client_secret: &'a str,
+  pub authorization_endpoint: Url,
+  pub token_endpoint: Url,
+  pub scopes: Vec<String>,
+}
+
+impl<'a> OAuthClient<'a> {
+  fn client(&self, config: &Config) -> Result<BasicClient, Error> {
+    let redirect_uri = RedirectUrl::new(super::redirect_uri(config))
+      .map_err(|err| Error::OAuth(err.to_string()))?;
+
+    let client = BasicClient::new(
+      ClientId::new(self.client_id.to_string()),
+      Some(ClientSecret::new(self.client_secret.to_string())),
+      AuthUrl::from_url(self.authorization_endpoint.clone()),
+      Some(TokenUrl::from_url(self.token_endpoint.clone())),
+    )
+    .set_redirect_uri(redirect_uri);
+
+    Ok(client)
+  }
+
+  pub fn authenticate(&self, config: &Config) -> Result<(Url, Session), Error> {
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let (url, csrf_token) = self
+      .client(config)?
+      .authorize_url(CsrfToken::new_random)
+      .add_scopes(self.scopes.iter().cloned().map(Scope::new))
+      .set_pkce_challenge(challenge)
+      .url();
+
+    let mut session = Session::new();
+
+    session.insert(
+      "login",
+      Login {
+        verifier: verifier.secret().clone(),
+        csrf_token: csrf_token.secret().clone(),
+      },
+    )?;
+
+    Ok((url, session))
+  }
+
+  /// Exchanges the code for an access token.
+  pub async fn callback(
+    &self,
+    session: &Session,
+    code: String,
+    auth_state: String,
+    config: &Config,
+  ) -> Result<String, Error> {
+    let login: Login = session.get("login").ok_or(Error::MissingField("login"))?;
+
+    if login.csrf_token != auth_state {
+      return Err(Error::CsrfMismatch);
+    }
+
+    let token = self
+      .client(config)?
+      .exchange_code(AuthorizationCode::new(code))
+      .set_pkce_verifier(PkceCodeVerifier::new(login.verifier))
+      .request_async(async_http_client)
+      .await
+      .map_err(|err| Error::OAuth(err.to_string()))?;
+
+    Ok(token.access_token().secret().clone())
+  }
+}
@@ -0,0 +1,132 @@
+use async_session::Session;
+use axum::async_trait;
+use oauth2::url::Url;
+use serde::{Deserialize, Serialize};
+
+use super::{oauth::OAuthClient, AuthProvider, Error, Profile};
+use crate::config::Config;
+
+/// A generic OpenID Connect provider, configured through discovery.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Oidc {
+  /// The issuer URL - `.well-known/openid-configuration` is fetched from here.
+  pub issuer: Url,
+  pub client_id: String,
+  pub client_secret: String,
+  #[serde(default = "default_scopes")]
+  pub scopes: Vec<String>,
+}
+
+fn default_scopes() -> Vec<String> {
+  vec![
+    String::from("openid"),
+    String::from("profile"),
+    String::from("email"),
+  ]
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+  authorization_endpoint: Url,
+  token_endpoint: Url,
+  userinfo_endpoint: Url,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+  email: Option<String>,
+  #[serde(default)]
+  email_verified: bool,
+  name: Option<String>,
+  preferred_username: Option<String>,
+  profile: Option<Url>,
+  website: Option<Url>,
+}
+
+impl Oidc {
+  async fn discover(&self) -> Result<Discovery, Error> {
+    // Without a trailing slash, `join` would replace the issuer's last path segment.
+    let mut issuer = self.issuer.clone();
+    if !issuer.path().ends_with('/') {
+      issuer.set_path(&format!("{}/", issuer.path()));
+    }
+
+    let url = issuer
+      .join(".well-known/openid-configuration")
+      .map_err(|err| Error::OAuth(err.to_string()))?;
+
+    let discovery = reqwest::get(url).await?.error_for_status()?.json().await?;
+
+    Ok(discovery)
+  }
+
+  fn client(&self, discovery: Discovery) -> OAuthClient {
+    OAuthClient {
+      client_id: &self.client_id,
+      client_secret: &self.client_secret,
+      authorization_endpoint: discovery.authorization_endpoint,
+      token_endpoint: discovery.token_endpoint,
+      scopes: self.scopes.clone(),
+    }
+  }
+}
+
+#[async_trait]
+impl AuthProvider for Oidc {
+  fn name(&self) -> &'static str {
+    "OpenID Connect"
+  }
+
+  fn needs_url(&self) -> bool {
+    false
+  }
+
+  async fn authenticate(&self, _: Option<&Url>, config: &Config) -> Result<(Url, Session), Error> {
+    let discovery = self.discover().await?;
+
+    self.client(discovery).authenticate(config)
+  }
+
+  async fn callback(
+    &self,
+    session: &Session,
+    code: String,
+    auth_state: String,
+    config: &Config,
+  ) -> Result<Profile, Error> {
+    let discovery = self.discover().await?;
+    let userinfo_endpoint = discovery.userinfo_endpoint.clone();
+
+    let token = self
+      .client(discovery)
+      .callback(session, code, auth_state, config)
+      .await?;
+
+    let info: UserInfo = reqwest::Client::new()
+      .get(userinfo_endpoint)
+      .bearer_auth(token)
+      .send()
+      .await?
+      .error_for_status()?
+      .json()
+      .await?;
+
+    let email = info.email.ok_or(Error::MissingField("email"))?;
+    // Otherwise anyone could sign up to the provider with someone else's email, and log in as
+    // them.
+    if !info.email_verified {
+      return Err(Error::UnverifiedEmail);
+    }
+    let name = info
+      .name
+      .or(info.preferred_username)
+      .ok_or(Error::MissingField("name"))?;
+    let url = info
+      .profile
+      .filter(crate::user::is_web_url)
+      .or(info.website.filter(crate::user::is_web_url))
+      .unwrap_or_else(|| self.issuer.clone());
+
+    Ok(Profile { name, email, url })
+  }
+}
@@ -0,0 +1,297 @@
+//! Bundling everything a wiki needs to be brought back - the pages repository, the users, and
+//! the config - into one encrypted archive, and restoring from it.
+
+use std::{
+  collections::HashMap,
+  io::{BufRead, BufReader, Read, Write},
+  path::{Path, PathBuf},
+};
+
+use cocoon::Cocoon;
+
+use crate::{
+  config::Config,
+  user::{Backend, User, UserKey},
+};
+
+const CONFIG: &str = "config.ron";
+const PASSWORD: &str = "password";
+const USERS: &str = "users.ron";
+const PAGES: &str = "pages";
+
+/// How much of the archive is encrypted at a time, so it's never all in memory at once.
+const CHUNK: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Ron(#[from] ron::Error),
+  #[error(transparent)]
+  User(#[from] crate::user::Error),
+  #[error(transparent)]
+  Config(#[from] crate::config::Error),
+  #[error("The archive doesn't have a `{0}`")]
+  Missing(&'static str),
+  #[error("{0} already exists, so it won't be overwritten")]
+  Exists(PathBuf),
+}
+
+/// Writes an archive of the wiki described by `config`, which was read from `config_path`,
+/// to `output`, encrypted with `key`.
+pub async fn backup(
+  config: &Config,
+  config_path: &Path,
+  output: &Path,
+  key: &[u8],
+) -> Result<(), Error> {
+  let store = config.users.store.build(&config.users).await?;
+  let users = store.load().await?;
+
+  let file = std::fs::File::create(output)?;
+  let mut archive = tar::Builder::new(Encrypting::new(key, file));
+
+  append(&mut archive, CONFIG, &std::fs::read(config_path)?)?;
+  append(&mut archive, USERS, ron::to_string(&users)?.as_bytes())?;
+
+  // The `File` store can't be read without it.
+  if config.users.password.exists() {
+    append(
+      &mut archive,
+      PASSWORD,
+      &std::fs::read(&config.users.password)?,
+    )?;
+  }
+
+  // This includes `.git`, so the whole history comes too.
+  archive.append_dir_all(PAGES, &config.pages_directory)?;
+
+  archive.into_inner()?.finish()?;
+
+  log::info!(
+    "backed up {} users and {} to {}",
+    users.len(),
+    config.pages_directory.display(),
+    output.display()
+  );
+
+  Ok(())
+}
+
+/// Restores an archive made by [`backup`]. The config is written to `config_path`, and the
+/// pages and users go wherever it says - none of them can already exist.
+pub async fn restore(input: &Path, config_path: &Path, key: &[u8]) -> Result<(), Error> {
+  let open = || -> Result<_, Error> {
+    let file = BufReader::new(std::fs::File::open(input)?);
+    Ok(tar::Archive::new(Decrypting::new(key, file)))
+  };
+
+  let mut files = HashMap::new();
+  let mut archive = open()?;
+
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    let path = entry.path()?.to_path_buf();
+
+    // The pages come last, and are unpacked once it's known where they go.
+    if path.starts_with(PAGES) {
+      break;
+    }
+
+    let mut contents = Vec::new();
+    entry.read_to_end(&mut contents)?;
+
+    files.insert(path, contents);
+  }
+
+  let get = |name: &'static str| files.get(Path::new(name)).ok_or(Error::Missing(name));
+
+  let config_file = get(CONFIG)?;
+  // It's kept as it was, so it's in whichever format `config_path` is.
+  let config = Config::parse(&String::from_utf8_lossy(config_file), config_path)?
+    .with_overrides(std::env::vars())?;
+  let password = get(PASSWORD).ok();
+
+  let is_empty = |path: &Path| match std::fs::read_dir(path) {
+    Ok(mut entries) => entries.next().is_none(),
+    Err(_) => true,
+  };
+
+  // Nothing is written until it's known that nothing will be overwritten.
+  if config_path.exists() {
+    return Err(Error::Exists(config_path.to_path_buf()));
+  }
+
+  if !is_empty(&config.pages_directory) {
+    return Err(Error::Exists(config.pages_directory.clone()));
+  }
+
+  if password.is_some() && config.users.password.exists() {
+    return Err(Error::Exists(config.users.password.clone()));
+  }
+
+  if matches!(config.users.store, Backend::File) && config.users.database.exists() {
+    return Err(Error::Exists(config.users.database.clone()));
+  }
+
+  std::fs::write(config_path, config_file)?;
+
+  if let Some(password) = password {
+    std::fs::write(&config.users.password, password)?;
+  }
+
+  // The pages are unpacked next to where they go, so `unpack_in` can check that nothing ends up
+  // outside them, and then moved into place.
+  let parent = match config.pages_directory.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent,
+    _ => Path::new("."),
+  };
+  std::fs::create_dir_all(parent)?;
+  let unpacked = tempfile::tempdir_in(parent)?;
+
+  let mut archive = open()?;
+
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    let path = entry.path()?.to_path_buf();
+
+    if path.starts_with(PAGES) && !entry.unpack_in(unpacked.path())? {
+      log::warn!("skipped {}, as it's outside the pages", path.display());
+    }
+  }
+
+  let pages = unpacked.path().join(PAGES);
+  if pages.exists() {
+    std::fs::rename(&pages, &config.pages_directory)?;
+  }
+
+  let users: HashMap<UserKey, User> = ron::de::from_bytes(get(USERS)?)?;
+  let everyone: Vec<User> = users.values().cloned().collect();
+
+  let store = config.users.store.build(&config.users).await?;
+  store.save(&everyone, &users).await?;
+
+  log::info!(
+    "restored {} users and {}",
+    users.len(),
+    config.pages_directory.display()
+  );
+
+  Ok(())
+}
+
+fn append<W: Write>(
+  archive: &mut tar::Builder<W>,
+  name: &str,
+  contents: &[u8],
+) -> Result<(), Error> {
+  let mut header = tar::Header::new_gnu();
+  header.set_size(contents.len() as u64);
+  header.set_mode(0o600);
+  header.set_cksum();
+
+  archive.append_data(&mut header, name, contents)?;
+
+  Ok(())
+}
+
+fn cocoon_error(err: cocoon::Error) -> std::io::Error {
+  std::io::Error::new(
+    std::io::ErrorKind::InvalidData,
+    format!("Cocoon error: {:?}", err),
+  )
+}
+
+/// Encrypts what's written to it a [`CHUNK`] at a time, each in a Cocoon container of its own.
+struct Encrypting<'k, W: Write> {
+  key: &'k [u8],
+  writer: W,
+  chunk: Vec<u8>,
+}
+
+impl<'k, W: Write> Encrypting<'k, W> {
+  fn new(key: &'k [u8], writer: W) -> Self {
+    Self {
+      key,
+      writer,
+      chunk: Vec::new(),
+    }
+  }
+
+  fn dump(&mut self) -> std::io::Result<()> {
+    let chunk = std::mem::take(&mut self.chunk);
+
+    Cocoon::new(self.key)
+      .dump(chunk, &mut self.writer)
+      .map_err(cocoon_error)
+  }
+
+  /// Encrypts whatever's left over.
+  fn finish(mut self) -> std::io::Result<()> {
+    if !self.chunk.is_empty() {
+      self.dump()?;
+    }
+
+    self.writer.flush()
+  }
+}
+
+impl<W: Write> Write for Encrypting<'_, W> {
+  fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+    let length = data.len().min(CHUNK - self.chunk.len());
+    self.chunk.extend_from_slice(&data[..length]);
+
+    if self.chunk.len() == CHUNK {
+      self.dump()?;
+    }
+
+    Ok(length)
+  }
+
+  /// Chunks are only written once they're full, or by [`Encrypting::finish`].
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Reads an archive written by [`Encrypting`] - or one from before archives were chunked,
+/// which is all one chunk.
+struct Decrypting<'k, R: BufRead> {
+  key: &'k [u8],
+  reader: R,
+  chunk: Vec<u8>,
+  position: usize,
+}
+
+impl<'k, R: BufRead> Decrypting<'k, R> {
+  fn new(key: &'k [u8], reader: R) -> Self {
+    Self {
+      key,
+      reader,
+      chunk: Vec::new(),
+      position: 0,
+    }
+  }
+}
+
+impl<R: BufRead> Read for Decrypting<'_, R> {
+  fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+    while self.position == self.chunk.len() {
+      if self.reader.fill_buf()?.is_empty() {
+        return Ok(0);
+      }
+
+      self.chunk = Cocoon::new(self.key)
+        .parse(&mut self.reader)
+        .map_err(cocoon_error)?;
+      self.position = 0;
+    }
+
+    let length = buffer.len().min(self.chunk.len() - self.position);
+    buffer[..length].copy_from_slice(&self.chunk[self.position..self.position + length]);
+    self.position += length;
+
+    Ok(length)
+  }
+}
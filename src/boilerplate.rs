@@ -0,0 +1,126 @@
+//! Starting points for new pages, kept in the `_templates` directory of the pages repository.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{config::Config, format::Format, front_matter::FrontMatter, user::User};
+
+pub const DIRECTORY: &str = "_templates";
+
+pub struct Boilerplate {
+  /// The template's file name, without its extension.
+  pub name: String,
+  pub filepath: PathBuf,
+  pub format: Format,
+}
+
+/// Every template that's in a format that can be edited in the browser.
+pub fn list(config: &Config) -> Vec<Boilerplate> {
+  let entries = match std::fs::read_dir(config.pages_directory.join(DIRECTORY)) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new(),
+  };
+
+  let mut templates: Vec<_> = entries
+    .filter_map(|entry| {
+      let filepath = entry.ok()?.path();
+
+      let format = Format::from_extension(filepath.extension()?.to_str()?)?;
+      let name = filepath.file_stem()?.to_string_lossy().to_string();
+
+      (!format.binary).then(|| Boilerplate {
+        name,
+        filepath,
+        format,
+      })
+    })
+    .collect();
+
+  templates.sort_by(|a, b| a.name.cmp(&b.name));
+  templates
+}
+
+pub fn find(name: &str, config: &Config) -> Option<Boilerplate> {
+  list(config)
+    .into_iter()
+    .find(|template| template.name == name)
+}
+
+/// Where the `+++` front matter at the start of `contents` ends, if it has any.
+fn front_matter_end(contents: &str) -> Option<usize> {
+  let mut lines = contents.split_inclusive('\n');
+
+  let first = lines.next()?;
+  if first.trim_end() != FrontMatter::DELIMITER {
+    return None;
+  }
+
+  let mut end = first.len();
+  for line in lines {
+    end += line.len();
+
+    if line.trim_end() == FrontMatter::DELIMITER {
+      return Some(end);
+    }
+  }
+
+  None
+}
+
+/// `value`, escaped to go between the double quotes of a TOML string.
+fn toml_escaped(value: &str) -> String {
+  // JSON's escapes are all valid in TOML's basic strings.
+  let quoted = serde_json::to_string(value).unwrap_or_default();
+
+  quoted
+    .strip_prefix('"')
+    .and_then(|quoted| quoted.strip_suffix('"'))
+    .unwrap_or_default()
+    .to_string()
+}
+
+/// A template, filled in for a new page.
+#[derive(Serialize)]
+pub struct Filled {
+  pub format: &'static str,
+  pub body: String,
+}
+
+impl Boilerplate {
+  /// Replaces `{{title}}`, `{{path}}`, `{{date}}` and `{{author}}` with details about the
+  /// page at `path` that `author` is creating. In `+++` front matter they're escaped, so they
+  /// belong inside double quotes, like `title = "{{title}}"`.
+  pub async fn fill(&self, path: &Path, author: Option<&User>) -> std::io::Result<Filled> {
+    let contents = tokio::fs::read_to_string(&self.filepath).await?;
+
+    let title = path
+      .file_stem()
+      .map(|stem| stem.to_string_lossy().replace(['-', '_'], " "))
+      .unwrap_or_default();
+
+    let date = OffsetDateTime::now_utc()
+      .format(format_description!("[year]-[month]-[day]"))
+      .unwrap_or_default();
+
+    let author = author.map(|user| user.name.as_str()).unwrap_or("");
+    let path = path.to_string_lossy();
+
+    let fill = |text: &str, escape: fn(&str) -> String| {
+      text
+        .replace("{{title}}", &escape(&title))
+        .replace("{{path}}", &escape(&path))
+        .replace("{{date}}", &escape(&date))
+        .replace("{{author}}", &escape(author))
+    };
+
+    let (front_matter, rest) = contents.split_at(front_matter_end(&contents).unwrap_or(0));
+    let body = fill(front_matter, toml_escaped) + &fill(rest, str::to_string);
+
+    Ok(Filled {
+      format: self.format.name,
+      body,
+    })
+  }
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, path::Path};
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Every page is tagged with this key too, so the whole wiki can be purged at once.
+const ALL_PAGES_KEY: &str = "pages";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CacheControl {
+  /// `max-age` in seconds for pages viewed anonymously - logged-in views are never cached.
+  /// Caching is turned off when this isn't set.
+  pub max_age: Option<u64>,
+  /// Called with `method` for every surrogate key of a page after it's edited,
+  /// with `{key}` replaced by the key.
+  pub purge_url: Option<String>,
+  pub purge_method: PurgeMethod,
+  /// Extra headers sent with purge requests, e.g. an API token for the CDN.
+  pub purge_headers: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum PurgeMethod {
+  Post,
+  Purge,
+}
+
+impl Default for PurgeMethod {
+  fn default() -> Self {
+    Self::Post
+  }
+}
+
+pub fn surrogate_keys(path: &Path) -> Vec<String> {
+  vec![
+    String::from(ALL_PAGES_KEY),
+    format!("page:{}", path.display()).replace(' ', "%20"),
+  ]
+}
+
+/// The caching headers for a page view.
+pub fn headers(config: &Config, path: &Path, logged_in: bool) -> HeaderMap {
+  let mut headers = HeaderMap::new();
+
+  let max_age = match config.cache_control.max_age {
+    Some(max_age) => max_age,
+    None => return headers,
+  };
+
+  // The same URL is rendered differently for logged-in users.
+  headers.insert(header::VARY, HeaderValue::from_static("Cookie"));
+
+  if logged_in {
+    headers.insert(
+      header::CACHE_CONTROL,
+      HeaderValue::from_static("private, no-store"),
+    );
+
+    return headers;
+  }
+
+  let cache_control = format!("public, max-age={}", max_age);
+  if let Ok(value) = HeaderValue::from_str(&cache_control) {
+    headers.insert(header::CACHE_CONTROL, value);
+  }
+
+  if let Ok(value) = HeaderValue::from_str(&surrogate_keys(path).join(" ")) {
+    headers.insert("Surrogate-Key", value);
+  }
+
+  headers
+}
+
+/// Tells the CDN to forget about a page that's just changed.
+///
+/// This happens in the background, as a failed purge shouldn't fail the edit.
+pub fn purge(config: &Config, path: &Path) {
+  let url = match &config.cache_control.purge_url {
+    Some(url) => url.clone(),
+    None => return,
+  };
+
+  let method = match config.cache_control.purge_method {
+    PurgeMethod::Post => reqwest::Method::POST,
+    PurgeMethod::Purge => reqwest::Method::from_bytes(b"PURGE").unwrap(),
+  };
+
+  let headers = config.cache_control.purge_headers.clone();
+  let keys = surrogate_keys(path);
+
+  tokio::spawn(async move {
+    let client = reqwest::Client::new();
+
+    // Only the page itself has changed, not every page.
+    for key in keys.iter().filter(|key| *key != ALL_PAGES_KEY) {
+      let mut request = client.request(method.clone(), url.replace("{key}", key));
+
+      for (name, value) in &headers {
+        request = request.header(name, value);
+      }
+
+      match request.send().await.and_then(|res| res.error_for_status()) {
+        Ok(_) => log::info!("Purged `{}` from the cache", key),
+        Err(err) => log::warn!("Couldn't purge `{}` from the cache: {}", key, err),
+      }
+    }
+  });
+}
@@ -0,0 +1,443 @@
+//! Which pages are in which categories. Reading every page to find out is slow on a big wiki,
+//! so the index is kept in memory, saved in the cache directory, and only the pages changed by
+//! each commit are read again.
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+};
+
+use axum::{
+  extract::{self, Extension, Form},
+  http::StatusCode,
+  response::{Html, IntoResponse, Redirect, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+  format::Format,
+  front_matter::FrontMatter,
+  page::Page,
+  proxy::SameOrigin,
+  role::{Is, Role},
+  user::User,
+  State,
+};
+
+/// The index's file, in `cache_directory`.
+const FILE: &str = "categories.json";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  FrontMatter(#[from] crate::front_matter::Error),
+  #[error("Give a new name for the category")]
+  NoName,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Page(err) => err.into_response(),
+      Self::NoName => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+      Self::FrontMatter(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
+    }
+  }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Index {
+  /// The commit the index was up to date with.
+  commit: Option<String>,
+  /// Each page's categories, by its path without an extension.
+  pages: HashMap<String, Vec<String>>,
+}
+
+#[derive(Default)]
+pub struct CategoryIndex {
+  index: RwLock<Option<Index>>,
+}
+
+impl CategoryIndex {
+  /// How many of the pages `user` can see are in each category.
+  pub async fn counts(
+    &self,
+    user: Option<&User>,
+    state: &State,
+  ) -> Result<BTreeMap<String, usize>, crate::page::Error> {
+    let config = state.config();
+    let index = self.load(state).await?;
+    let mut counts = BTreeMap::new();
+
+    let visible = index
+      .pages
+      .iter()
+      .filter(|(path, _)| crate::namespace::is_visible(Path::new(path), user, &config));
+
+    for category in visible.flat_map(|(_, categories)| categories) {
+      *counts.entry(category.clone()).or_insert(0) += 1;
+    }
+
+    Ok(counts)
+  }
+
+  /// The paths of the pages in `category` that `user` can see, in order.
+  pub async fn pages(
+    &self,
+    category: &str,
+    user: Option<&User>,
+    state: &State,
+  ) -> Result<Vec<String>, crate::page::Error> {
+    let config = state.config();
+    let index = self.load(state).await?;
+
+    let mut pages: Vec<_> = index
+      .pages
+      .into_iter()
+      .filter(|(_, categories)| categories.iter().any(|c| c == category))
+      .filter(|(path, _)| crate::namespace::is_visible(Path::new(path), user, &config))
+      .map(|(path, _)| path)
+      .collect();
+    pages.sort();
+
+    Ok(pages)
+  }
+
+  /// Renames `from` to `to` in the index straight away, rather than waiting for the commit to be
+  /// read. If there's already a `to`, the two are merged.
+  fn rename(&self, from: &str, to: &str) {
+    if let Some(index) = &mut *self.index.write().unwrap() {
+      for categories in index.pages.values_mut() {
+        rename(categories, from, to);
+      }
+    }
+  }
+
+  /// Keeps the index up to date with every commit.
+  pub async fn watch(state: Arc<State>) {
+    let mut changes = state.git.subscribe();
+
+    loop {
+      match changes.recv().await {
+        Ok(change) => {
+          if let Err(err) = state
+            .categories
+            .update(&change.files, &change.hash, &state)
+            .await
+          {
+            log::warn!("Couldn't update the category index: {}", err);
+          }
+        },
+        // Some commits were missed, so it's read from scratch next time.
+        Err(RecvError::Lagged(_)) => *state.categories.index.write().unwrap() = None,
+        Err(RecvError::Closed) => break,
+      }
+    }
+  }
+
+  /// The index, read from the cache directory if it's up to date, or from every page if not.
+  async fn load(&self, state: &State) -> Result<Index, crate::page::Error> {
+    let cached = self.index.read().unwrap().clone();
+    if let Some(index) = cached {
+      return Ok(index);
+    }
+
+    let config = state.config();
+    let head = state
+      .git
+      .recent_changes()
+      .first()
+      .map(|change| change.hash.clone());
+
+    let saved = tokio::fs::read(config.cache_directory.join(FILE))
+      .await
+      .ok()
+      .and_then(|file| serde_json::from_slice::<Index>(&file).ok())
+      .filter(|index| index.commit.is_some() && index.commit == head);
+
+    let index = match saved {
+      Some(index) => index,
+      None => {
+        let mut index = Index {
+          commit: head,
+          pages: HashMap::new(),
+        };
+
+        // Binary pages don't have front matter, so they're never in a category.
+        for page in Page::all(&config).filter(|page| !page.is_binary()) {
+          let path = page.relative_path(&config)?;
+          let file = page.raw(state).await?;
+
+          index.pages.insert(key(&path), categories(&file));
+        }
+
+        save(&index, state).await;
+
+        index
+      },
+    };
+
+    *self.index.write().unwrap() = Some(index.clone());
+
+    Ok(index)
+  }
+
+  /// Reads the pages at `files` again, after they were changed by `commit`.
+  async fn update(
+    &self,
+    files: &[PathBuf],
+    commit: &str,
+    state: &State,
+  ) -> Result<(), crate::page::Error> {
+    let mut index = match self.index.read().unwrap().clone() {
+      Some(index) => index,
+      // It'll be read from scratch when it's needed.
+      None => return Ok(()),
+    };
+
+    let config = state.config();
+
+    for file in files {
+      let is_text_page = file
+        .extension()
+        .and_then(|ext| Format::from_extension(&ext.to_string_lossy()))
+        .map(|format| !format.binary)
+        .unwrap_or(false);
+
+      if !is_text_page || file.starts_with(crate::boilerplate::DIRECTORY) {
+        continue;
+      }
+
+      match state.pages.read(&config.pages_directory.join(file)).await {
+        Ok(contents) => {
+          index.pages.insert(key(file), categories(&contents));
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+          index.pages.remove(&key(file));
+        },
+        Err(err) => return Err(err.into()),
+      }
+    }
+
+    index.commit = Some(commit.to_string());
+
+    save(&index, state).await;
+    *self.index.write().unwrap() = Some(index);
+
+    Ok(())
+  }
+}
+
+/// Renames `from` to `to`, keeping its place in the list, and only keeping one `to`.
+fn rename(categories: &mut Vec<String>, from: &str, to: &str) -> bool {
+  let position = match categories.iter().position(|category| category == from) {
+    Some(position) => position,
+    None => return false,
+  };
+
+  categories[position] = to.to_string();
+
+  let mut seen = false;
+  categories.retain(|category| {
+    if category != to {
+      return true;
+    }
+
+    let first = !seen;
+    seen = true;
+    first
+  });
+
+  true
+}
+
+fn key(path: &Path) -> String {
+  path.with_extension("").to_string_lossy().to_string()
+}
+
+fn categories(file: &str) -> Vec<String> {
+  // Broken front matter is shown on the doctor report, it shouldn't break the index too.
+  let (front_matter, _, _) = FrontMatter::split(file);
+
+  front_matter.categories.unwrap_or_default()
+}
+
+/// Saves the index for next time - it can always be read from the pages again, so it's fine if
+/// this fails.
+async fn save(index: &Index, state: &State) {
+  let path = state.config().cache_directory.join(FILE);
+
+  let saved = match serde_json::to_vec(index) {
+    Ok(json) => tokio::fs::write(&path, json)
+      .await
+      .map_err(|err| err.to_string()),
+    Err(err) => Err(err.to_string()),
+  };
+
+  if let Err(err) = saved {
+    log::warn!(
+      "Couldn't save the category index to {}: {}",
+      path.display(),
+      err
+    );
+  }
+}
+
+/// Font sizes for the tag cloud, in `em`.
+const SMALLEST: f64 = 0.8;
+const LARGEST: f64 = 2.4;
+
+pub async fn categories_handler(
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, crate::page::Error> {
+  let counts = state.categories.counts(user.as_ref(), &state).await?;
+  let most = counts.values().copied().max().unwrap_or(1);
+
+  // Sizes go up with the log of the count, so one huge category doesn't shrink the rest.
+  let size = |count: usize| {
+    let weight = match most {
+      1 => 0.0,
+      _ => (count as f64).ln() / (most as f64).ln(),
+    };
+
+    SMALLEST + (LARGEST - SMALLEST) * weight
+  };
+
+  let administrator = user
+    .as_ref()
+    .map(|user| user.roles.contains(&Role::Administrator))
+    .unwrap_or(false);
+
+  let content = maud::html! {
+    ul #categories .tag-cloud {
+      @for (category, count) in &counts {
+        li style={ "font-size: " (format!("{:.2}", size(*count))) "em" } {
+          a href={ "/meta/category/" (category) } title={ (count) " pages" } { (category) }
+        }
+      }
+    }
+
+    @if administrator && !counts.is_empty() {
+      form #rename-category method="post" action="/meta/admin/categories" {
+        select name="from" {
+          @for category in counts.keys() {
+            option value=(category) { (category) }
+          }
+        }
+        " → "
+        input type="text" name="to" placeholder="New name" required;
+        button type="submit" { "Rename or merge" }
+      }
+    }
+  };
+
+  let template = crate::template::Template::new()
+    .title("Categories")
+    .content(content)
+    .render(user, &state);
+
+  Ok(template)
+}
+
+pub async fn category_handler(
+  extract::Path(category): extract::Path<String>,
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, crate::page::Error> {
+  let pages = state
+    .categories
+    .pages(&category, user.as_ref(), &state)
+    .await?;
+
+  let content = maud::html! {
+    @if pages.is_empty() {
+      p { "There aren't any pages in this category." }
+    } @else {
+      ul {
+        @for page in &pages {
+          li { a href={ "/" (page) } { (page) } }
+        }
+      }
+    }
+  };
+
+  let template = crate::template::Template::new()
+    .title(&category)
+    .content(content)
+    .render(user, &state);
+
+  Ok(template)
+}
+
+#[derive(Deserialize)]
+pub struct RenameForm {
+  from: String,
+  to: String,
+}
+
+/// Renames a category on every page that's in it, in a single commit - or merges it into
+/// another one, if there's already a category with the new name.
+pub async fn rename_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+  Form(form): Form<RenameForm>,
+) -> Result<Redirect, Error> {
+  let to = form.to.trim();
+  if to.is_empty() {
+    return Err(Error::NoName);
+  }
+
+  if to == form.from {
+    return Ok(Redirect::to("/meta/categories"));
+  }
+
+  let config = state.config();
+  let merging = state
+    .categories
+    .counts(Some(&admin), &state)
+    .await?
+    .contains_key(to);
+  let mut changes = Vec::new();
+
+  for path in state
+    .categories
+    .pages(&form.from, Some(&admin), &state)
+    .await?
+  {
+    let page =
+      Page::from_path(PathBuf::from(path), None, &config).map_err(crate::page::Error::from)?;
+    let file = page.raw(&state).await?;
+    let (mut front_matter, data, error) = page.split_front_matter(&file);
+
+    // The front matter would be lost, and the doctor report already points these out.
+    if error.is_some() {
+      continue;
+    }
+
+    let categories = front_matter.categories.get_or_insert_with(Vec::new);
+    if rename(categories, &form.from, to) {
+      changes.push((page, format!("{}{}", front_matter.render()?, data)));
+    }
+  }
+
+  if !changes.is_empty() {
+    let message = match merging {
+      true => format!("Merge the category \"{}\" into \"{}\"", form.from, to),
+      false => format!("Rename the category \"{}\" to \"{}\"", form.from, to),
+    };
+
+    Page::update_all(&changes, &message, &admin, state.clone()).await?;
+    state.categories.rename(&form.from, to);
+  }
+
+  Ok(Redirect::to(&format!(
+    "/meta/category/{}",
+    urlencoding::encode(to)
+  )))
+}
@@ -0,0 +1,262 @@
+//! The commands for setting up and looking after a wiki without going through the web
+//! interface - so there's a way back in when nobody can log in.
+
+use std::{path::Path, sync::Arc};
+
+use eyre::{bail, eyre};
+
+use crate::{
+  audit::{Action, AuditLog},
+  config::{Command, Config, ConfigCommand, IndexCommand, UserCommand},
+  notify::Preferences,
+  role::Role,
+  user::{DatabaseLock, ExportFormat, Plan, User, UserKey},
+};
+
+/// Who the audit log says changes made here were made by.
+const ACTOR: &str = "command line";
+
+/// Runs any `command` other than `serve` and `restore`, which need the server and no config
+/// respectively.
+pub async fn run(command: Command, config: Config, config_path: &Path) -> Result<(), eyre::Report> {
+  match command {
+    Command::User { command } => user(command, &config).await?,
+    Command::Index {
+      command: IndexCommand::Rebuild { force },
+    } => {
+      let count = crate::git::rebuild_index(&config, force)?;
+      log::info!("indexed {} files", count);
+    },
+    Command::Config {
+      command: ConfigCommand::Validate,
+    } => {
+      let problems = config.problems();
+
+      for problem in &problems {
+        println!("{}", problem);
+      }
+
+      if !problems.is_empty() {
+        bail!("{} has {} problems", config_path.display(), problems.len());
+      }
+
+      println!("{} is valid", config_path.display());
+    },
+    Command::MigrateUsers => {
+      let count = crate::user::migrate(&config.users).await?;
+      log::info!("copied {} users", count);
+    },
+    Command::Backup { output, key } => {
+      let key = tokio::fs::read(key).await?;
+      crate::backup::backup(&config, config_path, &output, &key).await?;
+    },
+    Command::Import {
+      source,
+      input,
+      email,
+    } => {
+      let store = config.users.store.build(&config.users).await?;
+      let user = store
+        .load()
+        .await?
+        .remove(&UserKey::from(email.clone()))
+        .ok_or_else(|| eyre!("There isn't a user with the email {}", email))?;
+
+      let files = crate::import::convert(source, crate::import::read(&input)?, &config)?;
+
+      let config = Arc::new(config);
+      let git = crate::git::Git::new(config.clone())?;
+      let imported = crate::import::commit(source, files, &config, &git, &user).await?;
+
+      for path in &imported.skipped {
+        println!("skipped {} - it already exists", path.display());
+      }
+
+      let detail = format!("{} files from {:?}", imported.files, source);
+      AuditLog::new(&config)
+        .await?
+        .record(ACTOR, Action::ImportPages, "pages", Some(detail))
+        .await;
+
+      log::info!(
+        "imported {} files in {} commits",
+        imported.files,
+        imported.commits
+      );
+    },
+    Command::Serve | Command::Restore { .. } => {
+      bail!("`cli::run` can't run {:?}", command)
+    },
+  }
+
+  Ok(())
+}
+
+async fn user(command: UserCommand, config: &Config) -> Result<(), eyre::Report> {
+  // Only reading the users is safe while the wiki is running.
+  let _lock = match &command {
+    UserCommand::List | UserCommand::Export { .. } => None,
+    _ => Some(DatabaseLock::acquire(&config.users)?),
+  };
+
+  // The users are changed in the store directly, so nothing is left waiting to be saved.
+  let store = config.users.store.build(&config.users).await?;
+  let mut users = store.load().await?;
+
+  let find = |email: &str| {
+    users
+      .get(&UserKey::from(email.to_string()))
+      .cloned()
+      .ok_or_else(|| eyre!("There isn't a user with the email {}", email))
+  };
+
+  let (user, action) = match command {
+    UserCommand::Add {
+      email,
+      name,
+      url,
+      admin,
+    } => {
+      if find(&email).is_ok() {
+        bail!("There's already a user with the email {}", email);
+      }
+
+      let user = User {
+        name,
+        email,
+        url,
+        approved: true,
+        roles: if admin {
+          vec![Role::Administrator]
+        } else {
+          Vec::new()
+        },
+        timezone: None,
+        locale: None,
+        edited_profile: false,
+        disabled: false,
+        notifications: Preferences::default(),
+      };
+
+      (user, Action::AddUser)
+    },
+    UserCommand::Approve { email } => {
+      let user = User {
+        approved: true,
+        ..find(&email)?
+      };
+
+      (user, Action::Approve)
+    },
+    UserCommand::Promote { email } => {
+      let mut user = find(&email)?;
+
+      if !user.roles.contains(&Role::Administrator) {
+        user.roles.push(Role::Administrator);
+      }
+
+      (user, Action::GrantRole)
+    },
+    UserCommand::List => {
+      let mut users: Vec<_> = users.values().collect();
+      users.sort_by(|a, b| a.email.cmp(&b.email));
+
+      for user in users {
+        let roles: Vec<_> = user.roles.iter().map(Role::name).collect();
+        let status = match (user.disabled, user.approved) {
+          (true, _) => "disabled",
+          (false, true) => "approved",
+          (false, false) => "waiting",
+        };
+
+        println!(
+          "{}\t{}\t{}\t{}",
+          user.email,
+          user.name,
+          status,
+          roles.join(", ")
+        );
+      }
+
+      return Ok(());
+    },
+    UserCommand::Export { output, format } => {
+      let format = format
+        .or_else(|| output.as_deref().map(ExportFormat::from_path))
+        .unwrap_or_default();
+
+      let users: Vec<User> = users.values().cloned().collect();
+      let exported = crate::user::export(&users, format)?;
+
+      match output {
+        Some(output) => tokio::fs::write(output, exported).await?,
+        None => println!("{}", exported),
+      }
+
+      return Ok(());
+    },
+    UserCommand::Import { input, dry_run } => {
+      let text = tokio::fs::read_to_string(&input).await?;
+      let imported = crate::user::parse(&text, ExportFormat::from_path(&input))?;
+      let plan = Plan::new(imported, &users);
+
+      for user in &plan.added {
+        println!("add\t{}", user.email);
+      }
+      for user in &plan.updated {
+        println!("update\t{}", user.email);
+      }
+      println!("{} unchanged", plan.unchanged);
+
+      for problem in &plan.problems {
+        println!("{}", problem);
+      }
+
+      if !plan.problems.is_empty() {
+        bail!(
+          "{} has {} problems, so nothing was imported",
+          input.display(),
+          plan.problems.len()
+        );
+      }
+
+      if !dry_run {
+        let changed: Vec<User> = plan.changed().cloned().collect();
+
+        for user in &changed {
+          users.insert(user.key(), user.clone());
+        }
+
+        store.save(&changed, &users).await?;
+
+        let detail = format!("{} added, {} updated", plan.added.len(), plan.updated.len());
+        AuditLog::new(config)
+          .await?
+          .record(ACTOR, Action::ImportUsers, "users", Some(detail))
+          .await;
+      }
+
+      return Ok(());
+    },
+  };
+
+  users.insert(user.key(), user.clone());
+  store.save(&[user.clone()], &users).await?;
+
+  // They're logged out everywhere, now their privileges have changed.
+  if matches!(action, Action::Approve | Action::GrantRole) {
+    let (_, index) = config.session_backend().build().await?;
+    index.privileges_changed(&user.email).await?;
+  }
+
+  let detail = match action {
+    Action::GrantRole => Some(Role::Administrator.name().to_string()),
+    _ => None,
+  };
+  AuditLog::new(config)
+    .await?
+    .record(ACTOR, action, &user.email, detail)
+    .await;
+
+  Ok(())
+}
@@ -0,0 +1,357 @@
+//! Editing a page together - when `collaboration` is on, everyone with the editor open is
+//! connected over a WebSocket, and their changes are merged as they type with a CRDT
+//! ([Yjs](https://yjs.dev), through `yrs`). Saving commits the merged page once, crediting
+//! everyone who took part.
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+    Mutex,
+  },
+};
+
+use axum::{
+  extract::{
+    ws::{Message, WebSocket, WebSocketUpgrade},
+    Extension,
+    Query,
+  },
+  http::{HeaderMap, StatusCode},
+  response::{IntoResponse, Redirect, Response},
+};
+use git2::Oid;
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use yrs::{updates::decoder::Decode, Doc, StateVector, Update};
+
+use crate::{
+  git::CoAuthor,
+  page::Page,
+  proxy::{Forwarded, SameOrigin},
+  quota::{Activity, Who},
+  spam::Submission,
+  user::User,
+  State,
+};
+
+/// The name of the shared text in the Yjs document.
+const TEXT: &str = "content";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  Acl(#[from] crate::acl::Error),
+  #[error("Couldn't read an update: {0}")]
+  Update(String),
+  #[error("Collaborative editing isn't turned on")]
+  Disabled,
+  #[error("Nobody is editing this page")]
+  NoSession,
+  #[error(transparent)]
+  Spam(#[from] crate::spam::Error),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Page(err) => err.into_response(),
+      Self::Acl(err) => err.into_response(),
+      Self::Update(_) => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+      Self::Disabled | Self::NoSession => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+      Self::Spam(err) => err.into_response(),
+    }
+  }
+}
+
+/// Everyone editing one page.
+struct Session {
+  /// The document so far, as a single Yjs update. `yrs` documents can't be sent between
+  /// threads, so one is only made when it's needed.
+  document: Mutex<Vec<u8>>,
+  /// The commit the page was at when the session started, so anything committed outside of
+  /// it is merged in when it's saved.
+  base: Mutex<Option<Oid>>,
+  /// Everyone who's changed the document since it was last saved.
+  authors: Mutex<Vec<CoAuthor>>,
+  /// Each update, and which connection it came from.
+  updates: broadcast::Sender<(usize, Vec<u8>)>,
+  connections: AtomicUsize,
+}
+
+impl Session {
+  fn new(contents: &str, base: Option<Oid>) -> Self {
+    let doc = Doc::new();
+    let mut txn = doc.transact();
+    let text = txn.get_text(TEXT);
+    text.push(&mut txn, contents);
+
+    let document = txn.encode_state_as_update_v1(&StateVector::default());
+
+    Self {
+      document: Mutex::new(document),
+      base: Mutex::new(base),
+      authors: Mutex::new(Vec::new()),
+      updates: broadcast::channel(256).0,
+      connections: AtomicUsize::new(0),
+    }
+  }
+
+  /// Merges in an `update` made by `user`.
+  fn apply(&self, update: &[u8], user: &User) -> Result<(), Error> {
+    let mut document = self.document.lock().unwrap();
+
+    let doc = Doc::new();
+    let mut txn = doc.transact();
+    txn.apply_update(decode(&document)?);
+    txn.apply_update(decode(update)?);
+
+    *document = txn.encode_state_as_update_v1(&StateVector::default());
+
+    let author = CoAuthor {
+      name: user.name.clone(),
+      email: user.email.clone(),
+    };
+
+    let mut authors = self.authors.lock().unwrap();
+    if !authors.contains(&author) {
+      authors.push(author);
+    }
+
+    Ok(())
+  }
+
+  /// The page as it is now.
+  fn text(&self) -> Result<String, Error> {
+    let document = self.document.lock().unwrap();
+
+    let doc = Doc::new();
+    let mut txn = doc.transact();
+    txn.apply_update(decode(&document)?);
+
+    Ok(txn.get_text(TEXT).to_string())
+  }
+}
+
+fn decode(update: &[u8]) -> Result<Update, Error> {
+  Update::decode_v1(update).map_err(|err| Error::Update(err.to_string()))
+}
+
+#[derive(Default)]
+pub struct Collaboration {
+  sessions: Mutex<HashMap<PathBuf, Arc<Session>>>,
+  next_connection: AtomicUsize,
+}
+
+impl Collaboration {
+  /// The session for `page`, starting one if nobody's editing it yet.
+  async fn join(&self, page: &Page, state: &Arc<State>) -> Result<Arc<Session>, Error> {
+    if let Some(session) = self.get(&page.path) {
+      session.connections.fetch_add(1, Ordering::SeqCst);
+      return Ok(session);
+    }
+
+    let contents = page.raw(state).await?;
+    let base = {
+      let path = page.relative_path(&state.config())?;
+      let state = Arc::clone(state);
+
+      tokio::task::spawn_blocking(move || state.git.last_commit(&path, &*state.users))
+        .await
+        .unwrap()
+        .map_err(crate::page::Error::from)?
+        .and_then(|commit| Oid::from_str(&commit.hash).ok())
+    };
+
+    // Someone else might have started one while the page was being read.
+    let session = self
+      .sessions
+      .lock()
+      .unwrap()
+      .entry(page.path.clone())
+      .or_insert_with(|| Arc::new(Session::new(&contents, base)))
+      .clone();
+
+    session.connections.fetch_add(1, Ordering::SeqCst);
+
+    Ok(session)
+  }
+
+  fn get(&self, path: &Path) -> Option<Arc<Session>> {
+    self.sessions.lock().unwrap().get(path).cloned()
+  }
+
+  /// Ends the session once the last person has left. Anything that wasn't saved is lost, like
+  /// closing the editor.
+  fn leave(&self, path: &Path, session: &Arc<Session>) {
+    if session.connections.fetch_sub(1, Ordering::SeqCst) != 1 {
+      return;
+    }
+
+    let mut sessions = self.sessions.lock().unwrap();
+    if sessions
+      .get(path)
+      .map(|current| Arc::ptr_eq(current, session))
+      == Some(true)
+    {
+      sessions.remove(path);
+    }
+  }
+}
+
+/// Browsers don't stop other sites opening WebSockets, so only the wiki's own pages can.
+pub async fn socket_handler(
+  ws: WebSocketUpgrade,
+  page: Page,
+  user: User,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Response, Error> {
+  if !state.config().collaboration {
+    return Err(Error::Disabled);
+  }
+
+  crate::acl::check_edit(&user, &page.path, &state.config())?;
+  page.check_archived(None, &user, &state).await?;
+
+  let session = state.collab.join(&page, &state).await?;
+
+  Ok(ws.on_upgrade(move |socket| connect(socket, session, page.path, user, state)))
+}
+
+/// Sends the document to a new editor, then passes updates between it and everyone else.
+async fn connect(
+  mut socket: WebSocket,
+  session: Arc<Session>,
+  path: PathBuf,
+  user: User,
+  state: Arc<State>,
+) {
+  let id = state.collab.next_connection.fetch_add(1, Ordering::Relaxed);
+  let mut updates = session.updates.subscribe();
+
+  let document = session.document.lock().unwrap().clone();
+
+  if socket.send(Message::Binary(document)).await.is_ok() {
+    loop {
+      tokio::select! {
+        message = socket.recv() => match message {
+          Some(Ok(Message::Binary(update))) => {
+            if let Err(err) = session.apply(&update, &user) {
+              log::warn!("{} sent a bad update for {}: {}", user.email, path.display(), err);
+              break;
+            }
+
+            // Nobody might be listening, which is fine.
+            let _ = session.updates.send((id, update));
+          },
+          Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+          Some(Ok(_)) => {},
+        },
+        update = updates.recv() => match update {
+          Ok((from, update)) if from != id => {
+            if socket.send(Message::Binary(update)).await.is_err() {
+              break;
+            }
+          },
+          Ok(_) => {},
+          // It's missed some updates, so it can't be kept in sync - the editor reconnects.
+          Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => break,
+        },
+      }
+    }
+  }
+
+  state.collab.leave(&path, &session);
+}
+
+#[derive(Deserialize)]
+pub struct SaveQuery {
+  summary: Option<String>,
+}
+
+/// Commits the document as `user`, with everyone else who changed it as co-authors.
+pub async fn save_handler(
+  page: Page,
+  Query(query): Query<SaveQuery>,
+  user: User,
+  _: SameOrigin,
+  forwarded: Forwarded,
+  headers: HeaderMap,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Response, Error> {
+  crate::acl::check_edit(&user, &page.path, &state.config())?;
+
+  let config = state.config();
+  state
+    .usage
+    .take(
+      Who::user(&user),
+      Activity::Edit,
+      config.quotas.quota(Some(&user)),
+    )
+    .map_err(crate::page::Error::from)?;
+
+  let session = state.collab.get(&page.path).ok_or(Error::NoSession)?;
+
+  let contents = session.text()?;
+  let base = *session.base.lock().unwrap();
+  let mut co_authors = session.authors.lock().unwrap().clone();
+  // Whoever's saving is the author, not a co-author.
+  co_authors.retain(|author| !author.email.eq_ignore_ascii_case(&user.email));
+  let drafted = crate::draft::is_drafted(&user, &state.config());
+
+  let submission = Submission {
+    path: page.path.clone(),
+    name: user.name.clone(),
+    email: Some(user.email.clone()),
+    summary: query.summary.clone(),
+    body: contents.clone(),
+    before: page.raw(&state).await?,
+    address: forwarded.client,
+    user_agent: crate::spam::user_agent(&headers),
+    base,
+    created: None,
+  };
+
+  if crate::spam::screen_user(submission, &user, &state).await? {
+    return Ok((StatusCode::ACCEPTED, crate::spam::HELD_MESSAGE).into_response());
+  }
+
+  page
+    .update_with(
+      contents,
+      query.summary.as_deref(),
+      base,
+      co_authors,
+      &user,
+      state.clone(),
+    )
+    .await?;
+
+  session.authors.lock().unwrap().clear();
+
+  // The next save only has to merge in what's been committed since this one.
+  if !drafted {
+    let path = page.relative_path(&state.config())?;
+    let last_commit = state
+      .git
+      .last_commit(&path, &*state.users)
+      .map_err(crate::page::Error::from)?;
+
+    *session.base.lock().unwrap() = last_commit.and_then(|commit| Oid::from_str(&commit.hash).ok());
+  }
+
+  state.locks.release(&page.path, &user);
+
+  if drafted {
+    Ok(Redirect::to("/meta/drafts").into_response())
+  } else {
+    Ok(Redirect::to(&page.url_path()).into_response())
+  }
+}
@@ -0,0 +1,126 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+};
+
+use git2::{Oid, Repository};
+
+/// Which commits touched each path, and which commits each author made, newest first - so
+/// looking up a page's history doesn't mean diffing every commit in the repository. Paths are
+/// relative to the pages, and commits that don't touch them aren't indexed.
+#[derive(Default)]
+pub struct CommitIndex {
+  /// Where the pages are in the repository.
+  subdir: PathBuf,
+  /// The commit the index is up to date with.
+  head: Option<Oid>,
+  paths: HashMap<PathBuf, Vec<Oid>>,
+  authors: HashMap<String, Vec<Oid>>,
+}
+
+impl CommitIndex {
+  pub fn new(subdir: &Path) -> Self {
+    Self {
+      subdir: subdir.to_path_buf(),
+      ..Self::default()
+    }
+  }
+
+  pub fn paths(&self, path: &Path) -> &[Oid] {
+    self.paths.get(path).map(Vec::as_slice).unwrap_or_default()
+  }
+
+  pub fn authors(&self, email: &str) -> &[Oid] {
+    self
+      .authors
+      .get(email)
+      .map(Vec::as_slice)
+      .unwrap_or_default()
+  }
+
+  /// Indexes any commits made since the index was last updated, whether they were made by
+  /// the wiki or pulled in from elsewhere.
+  pub fn update(&mut self, repository: &Repository) -> Result<(), git2::Error> {
+    // A freshly created repository won't have a `HEAD` yet.
+    let head = match repository.head().ok().and_then(|head| head.target()) {
+      Some(head) => head,
+      None => return Ok(()),
+    };
+
+    if self.head == Some(head) {
+      return Ok(());
+    }
+
+    let mut revwalk = repository.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TIME)?;
+    revwalk.push(head)?;
+
+    match self.head {
+      // Only the new commits need to be looked at.
+      Some(old) if repository.graph_descendant_of(head, old)? => revwalk.hide(old)?,
+      // The history has been rewritten, so start again.
+      _ => *self = Self::new(&self.subdir.clone()),
+    }
+
+    let mut paths: HashMap<PathBuf, Vec<Oid>> = HashMap::new();
+    let mut authors: HashMap<String, Vec<Oid>> = HashMap::new();
+
+    for id in revwalk {
+      let commit = repository.find_commit(id?)?;
+
+      let tree = commit.tree()?;
+      let parent_tree = match commit.parent_count() {
+        0 => None,
+        _ => Some(commit.parent(0)?.tree()?),
+      };
+
+      let diff = repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+      let mut touched = Vec::new();
+
+      for delta in diff.deltas() {
+        let old = delta.old_file().path();
+        let new = delta.new_file().path();
+
+        let changed = [new, old.filter(|old| Some(*old) != new)]
+          .into_iter()
+          .flatten()
+          .filter_map(|path| path.strip_prefix(&self.subdir).ok());
+
+        touched.extend(changed.map(Path::to_path_buf));
+      }
+
+      // Anything outside the pages is someone else's business.
+      if touched.is_empty() {
+        continue;
+      }
+
+      if let Some(email) = commit.author().email() {
+        authors
+          .entry(email.to_string())
+          .or_default()
+          .push(commit.id());
+      }
+
+      for path in touched {
+        paths.entry(path).or_default().push(commit.id());
+      }
+    }
+
+    // The new commits are newer than everything that's already indexed.
+    for (path, mut ids) in paths {
+      let existing = self.paths.entry(path).or_default();
+      ids.append(existing);
+      *existing = ids;
+    }
+
+    for (email, mut ids) in authors {
+      let existing = self.authors.entry(email).or_default();
+      ids.append(existing);
+      *existing = ids;
+    }
+
+    self.head = Some(head);
+
+    Ok(())
+  }
+}
@@ -0,0 +1,113 @@
+use axum::{http::StatusCode, response::IntoResponse};
+use once_cell::sync::{Lazy, OnceCell};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+/// The variables in a template, like `{path}`.
+static VARIABLE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{(path|title|summary)\}").unwrap());
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Regex(#[from] regex::Error),
+  #[error("The summary '{summary}' doesn't match the required format '{pattern}'")]
+  InvalidSummary { summary: String, pattern: String },
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> axum::response::Response {
+    let code = match self {
+      Self::InvalidSummary { .. } => StatusCode::BAD_REQUEST,
+      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (code, self.to_string()).into_response()
+  }
+}
+
+/// Templates for the messages of commits made through the wiki.
+///
+/// `{path}`, `{title}` and `{summary}` are replaced with the page's path, its title,
+/// and the summary given by the user (or nothing, if there isn't one).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CommitMessages {
+  pub create: String,
+  pub update: String,
+  pub delete: String,
+  /// If set, user-provided summaries have to match this regex.
+  pub summary_pattern: Option<String>,
+  /// `summary_pattern`, compiled the first time it's needed - once each time the config is
+  /// loaded.
+  #[serde(skip)]
+  summary_regex: OnceCell<Result<Regex, regex::Error>>,
+}
+
+impl Default for CommitMessages {
+  fn default() -> Self {
+    Self {
+      create: String::from("[create] {path}\n\n{summary}"),
+      update: String::from("[update] {path}\n\n{summary}"),
+      delete: String::from("[delete] {path}\n\n{summary}"),
+      summary_pattern: None,
+      summary_regex: OnceCell::new(),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+  Create,
+  Update,
+  Delete,
+}
+
+pub struct Vars<'a> {
+  pub path: &'a str,
+  pub title: &'a str,
+  pub summary: Option<&'a str>,
+}
+
+impl CommitMessages {
+  pub fn check_summary(&self, summary: &str) -> Result<(), Error> {
+    if let Some(pattern) = &self.summary_pattern {
+      let regex = self
+        .summary_regex
+        .get_or_init(|| Regex::new(pattern))
+        .as_ref()
+        .map_err(Clone::clone)?;
+
+      if !regex.is_match(summary) {
+        return Err(Error::InvalidSummary {
+          summary: summary.to_string(),
+          pattern: pattern.clone(),
+        });
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn render(&self, kind: Kind, vars: Vars) -> Result<String, Error> {
+    let summary = vars.summary.map(str::trim).filter(|s| !s.is_empty());
+
+    if let Some(summary) = summary {
+      self.check_summary(summary)?;
+    }
+
+    let template = match kind {
+      Kind::Create => &self.create,
+      Kind::Update => &self.update,
+      Kind::Delete => &self.delete,
+    };
+
+    // In one go, so a title with `{summary}` in it isn't filled in too.
+    let message = VARIABLE.replace_all(template, |captures: &Captures| match &captures[1] {
+      "path" => vars.path,
+      "title" => vars.title,
+      _ => summary.unwrap_or(""),
+    });
+
+    Ok(message.trim_end().to_string())
+  }
+}
@@ -0,0 +1,113 @@
+//! Letting browsers that already have a page skip downloading it again, with `ETag` and
+//! `Last-Modified`, and `304 Not Modified` when it hasn't changed.
+
+use std::{sync::Arc, time::SystemTime};
+
+use axum::{
+  headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified},
+  http::{HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+};
+use git2::{ObjectType, Oid};
+
+use crate::{
+  assets::fingerprint,
+  page::{Error, Page},
+  State,
+};
+
+pub struct Validators {
+  etag: ETag,
+  last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+  /// For the file behind `page`, as it is.
+  pub async fn raw(page: &Page, state: &Arc<State>) -> Result<Self, Error> {
+    Self::new(page, None, state).await
+  }
+
+  /// For `page` once it's rendered, which also depends on the config and on the sidebar and
+  /// navigation around it. Pages aren't tagged for people who are logged in, as their
+  /// preferences, roles and watched pages all change how the page looks.
+  pub async fn rendered(page: &Page, state: &Arc<State>) -> Result<Option<Self>, Error> {
+    if page.user.is_some() {
+      return Ok(None);
+    }
+
+    let config = state.config();
+    let mut chrome = format!(
+      "{}{:?}{:?}{:?}",
+      config.generation,
+      state.navigation.sidebar().map(|markup| markup.0),
+      state.navigation.navigation().map(|markup| markup.0),
+      crate::views::popular_list(None, state).map(|markup| markup.0),
+    );
+
+    // Pages shown inside this one change it too.
+    let path = page.relative_path(&config)?;
+    for file in state.transclusions.included_in(&path, state).await? {
+      if let Ok(blob) = Oid::hash_file(ObjectType::Blob, config.pages_directory.join(file)) {
+        chrome.push_str(&blob.to_string());
+      }
+    }
+
+    Self::new(page, Some(fingerprint(chrome.as_bytes())), state)
+      .await
+      .map(Some)
+  }
+
+  async fn new(page: &Page, variant: Option<String>, state: &Arc<State>) -> Result<Self, Error> {
+    // The same ID git gives the file, whether or not it's been committed yet.
+    let blob = Oid::hash_file(ObjectType::Blob, &page.filepath).map_err(crate::git::Error::from)?;
+
+    // Rendered pages aren't byte-for-byte the same as each other, so their tags are weak.
+    let etag = match variant {
+      Some(variant) => format!("W/\"{}-{}\"", blob, variant),
+      None => format!("\"{}\"", blob),
+    };
+    let etag = etag.parse().expect("ETag should be valid");
+
+    let last_commit = {
+      let path = page.relative_path(&state.config())?;
+      let state = Arc::clone(state);
+
+      tokio::task::spawn_blocking(move || state.git.last_commit(&path, &*state.users))
+        .await
+        .unwrap()?
+    };
+
+    Ok(Self {
+      etag,
+      last_modified: last_commit.map(|commit| SystemTime::from(commit.date)),
+    })
+  }
+
+  /// Whether the browser's copy, described by its request `headers`, is still up to date.
+  pub fn is_fresh(&self, headers: &HeaderMap) -> bool {
+    // `If-None-Match` wins when there are both.
+    if let Some(if_none_match) = headers.typed_get::<IfNoneMatch>() {
+      return !if_none_match.precondition_passes(&self.etag);
+    }
+
+    match (headers.typed_get::<IfModifiedSince>(), self.last_modified) {
+      (Some(since), Some(last_modified)) => !since.is_modified(last_modified),
+      _ => false,
+    }
+  }
+
+  pub fn headers(&self) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.typed_insert(self.etag.clone());
+
+    if let Some(last_modified) = self.last_modified {
+      headers.typed_insert(LastModified::from(last_modified));
+    }
+
+    headers
+  }
+
+  pub fn not_modified(&self) -> Response {
+    (StatusCode::NOT_MODIFIED, self.headers()).into_response()
+  }
+}
@@ -1,17 +1,217 @@
 use std::{
   collections::{HashMap, HashSet},
-  net::SocketAddr,
-  path::PathBuf,
+  net::IpAddr,
+  path::{Path, PathBuf},
 };
 
 use oauth2::url::Url;
 
+use crate::{
+  auth::Provider,
+  cache::CacheControl,
+  commit_message::CommitMessages,
+  date::DateSettings,
+  interwiki::Interwiki,
+  invite::Registration,
+  listen::{Listen, Tls},
+  lockout::Lockout,
+  micropub::Micropub,
+  notify::Email,
+  page::PathMatching,
+  prose::Prose,
+  role::Role,
+  sanitize::SanitizeHtml,
+  sessions,
+  signing::Signing,
+  slug::SlugRules,
+  template::{Branding, Inject},
+  user,
+  views::Views,
+};
+
+/// Environment variables starting with this override settings from the config file.
+const ENV_PREFIX: &str = "GITALITE_";
+
+/// Settings that are only read when the wiki starts, so changing them needs a restart rather
+/// than a reload.
+const RESTART_ONLY: &[&str] = &[
+  "listen_on",
+  "tls",
+  "client_id",
+  "auth",
+  "static_directory",
+  "pages_directory",
+  "pages_git",
+  "templates_directory",
+  "cache_directory",
+  "postgresql",
+  "session_store",
+  "users",
+  "views",
+  "audit",
+  "wikis",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Ron(#[from] ron::Error),
+  #[error(transparent)]
+  Toml(#[from] toml::de::Error),
+  #[error(transparent)]
+  Yaml(#[from] serde_yaml::Error),
+  #[error("Couldn't apply the environment variable overrides: {0}")]
+  Override(#[from] serde_json::Error),
+  #[error("`{0}` can't be overridden, as it isn't a group of settings")]
+  NotATable(String),
+  #[error("`{0}` isn't a setting")]
+  UnknownSetting(String),
+  #[error("`{0}` can't be set to that: {1}")]
+  InvalidOverride(String, serde_json::Error),
+  #[error("The config wasn't read from a file, so it can't be reloaded")]
+  NoFile,
+  #[error("There isn't a wiki called '{0}' in `wikis` any more")]
+  NoWiki(String),
+  #[error("The config has problems:\n{}", .0.join("\n"))]
+  Problems(Vec<String>),
+}
+
+/// The setting at `keys` in a config, if it's there.
+fn lookup<'a>(config: &'a serde_json::Value, keys: &[&str]) -> Option<&'a serde_json::Value> {
+  keys.iter().try_fold(config, |table, key| table.get(key))
+}
+
+/// Sets the setting at `keys` in a config, filling in groups of settings that are `None`.
+fn set(config: &mut serde_json::Value, keys: &[&str], value: serde_json::Value) -> Option<()> {
+  let (last, parents) = keys.split_last()?;
+  let mut table = config;
+
+  for key in parents {
+    table = table
+      .as_object_mut()?
+      .entry(key.to_string())
+      .or_insert(serde_json::Value::Null);
+
+    if table.is_null() {
+      *table = serde_json::Value::Object(Default::default());
+    }
+  }
+
+  table.as_object_mut()?.insert(last.to_string(), value);
+
+  Some(())
+}
+
 #[derive(clap::Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
-  /// Path to the config file
+  /// Path to the config file - RON, or TOML or YAML with a `.toml` or `.yaml` extension
   #[clap(short, long)]
   pub config: PathBuf,
+  #[clap(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+  /// Run the wiki - this is what happens when there's no command
+  Serve,
+  /// Manage users without going through the wiki
+  User {
+    #[clap(subcommand)]
+    command: UserCommand,
+  },
+  /// Manage the pages repository's index
+  Index {
+    #[clap(subcommand)]
+    command: IndexCommand,
+  },
+  /// Check the config file
+  Config {
+    #[clap(subcommand)]
+    command: ConfigCommand,
+  },
+  /// Copy the users from the encrypted `database` file into the configured `store`
+  MigrateUsers,
+  /// Bundle the pages repository, the users, and the config into one encrypted archive
+  Backup {
+    /// Where to write the archive
+    output: PathBuf,
+    /// A file holding the key the archive is encrypted with
+    #[clap(short, long)]
+    key: PathBuf,
+  },
+  /// Import pages from a MediaWiki XML dump, a gollum repository, or an Obsidian vault
+  Import {
+    #[clap(arg_enum)]
+    source: crate::import::Source,
+    /// The dump, or the repository or vault - either as a directory or a `.tar` archive of one
+    input: PathBuf,
+    /// The email of the user the pages are committed as
+    #[clap(short, long)]
+    email: String,
+  },
+  /// Restore an archive made by `backup`, writing the config to `--config`
+  Restore {
+    /// The archive to restore
+    input: PathBuf,
+    /// A file holding the key the archive was encrypted with
+    #[clap(short, long)]
+    key: PathBuf,
+  },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum UserCommand {
+  /// Add an approved user
+  Add {
+    email: String,
+    name: String,
+    /// Their IndieAuth profile URL
+    url: Url,
+    /// Make them an administrator
+    #[clap(long)]
+    admin: bool,
+  },
+  /// Approve a user who's waiting to be approved
+  Approve { email: String },
+  /// Make a user an administrator
+  Promote { email: String },
+  /// List every user
+  List,
+  /// Write every user, unencrypted, to a file - or print them if there isn't one
+  Export {
+    output: Option<PathBuf>,
+    /// Defaults to whatever `output` ends with, or RON
+    #[clap(long, arg_enum)]
+    format: Option<crate::user::ExportFormat>,
+  },
+  /// Add or update users from a file written by `export` - JSON if it ends in `.json`, RON
+  /// otherwise
+  Import {
+    input: PathBuf,
+    /// Check the file and show what would change, without changing anything
+    #[clap(long)]
+    dry_run: bool,
+  },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum IndexCommand {
+  /// Reset the pages repository's index to match its latest commit
+  Rebuild {
+    /// Throw away any changes staged in the index
+    #[clap(long)]
+    force: bool,
+  },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum ConfigCommand {
+  /// Check that the config can be used, without starting the wiki
+  Validate,
 }
 
 impl Args {
@@ -20,11 +220,66 @@ impl Args {
   }
 }
 
+/// Another wiki served by the same process, for requests to its own host names. It has its
+/// own pages, repository and editors, and everything else comes from the main config - so it
+/// has the same users and logins.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Wiki {
+  /// Its caches are kept in `wikis/<name>` in the main `cache_directory`.
+  pub name: String,
+  /// Like `docs.example.com`.
+  pub hosts: Vec<String>,
+  pub pages_directory: PathBuf,
+  pub pages_git: Git,
+  /// Where links that leave the wiki, like in emails, point to.
+  #[serde(default)]
+  pub base_url: Option<String>,
+  /// The main wiki's, unless it has its own.
+  #[serde(default)]
+  pub static_directory: Option<PathBuf>,
+  #[serde(default)]
+  pub templates_directory: Option<PathBuf>,
+  /// Like the main config's `editors`, but only for this wiki.
+  #[serde(default)]
+  pub editors: Vec<Role>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Git {
   pub repository: String,
   pub private_key: PathBuf,
   pub public_key: Option<PathBuf>,
+  #[serde(default)]
+  pub signing: Option<Signing>,
+  /// Who commits are recorded as committed by - the user making the change is always the
+  /// author. When this isn't set, the user is the committer too.
+  #[serde(default)]
+  pub committer: Option<Identity>,
+  /// Other repositories that every commit is pushed to as well, like a copy on GitHub.
+  #[serde(default)]
+  pub mirrors: Vec<Mirror>,
+  /// Where the pages are in the repository, when they're only part of it - `pages_directory`
+  /// has to end with this, and the repository is checked out in the directory above.
+  #[serde(default)]
+  pub subdir: PathBuf,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Mirror {
+  /// The remote's name in the pages repository - it's added if it isn't there already.
+  pub name: String,
+  pub url: String,
+  /// Keys for this mirror, if they aren't the same as the main repository's.
+  #[serde(default)]
+  pub private_key: Option<PathBuf>,
+  #[serde(default)]
+  pub public_key: Option<PathBuf>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Identity {
+  pub name: String,
+  pub email: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -39,27 +294,500 @@ pub struct Users {
   pub initial: InitialUser,
   pub password: PathBuf,
   pub database: PathBuf,
+  #[serde(default)]
+  pub store: user::Backend,
+  /// Invites that haven't been used yet.
+  #[serde(default = "default_invites")]
+  pub invites: PathBuf,
+}
+
+fn default_invites() -> PathBuf {
+  PathBuf::from("./invites.ron")
+}
+
+/// An external command that reads AsciiDoc on stdin and writes HTML to stdout.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AsciiDoc {
+  pub command: String,
+  #[serde(default)]
+  pub args: Vec<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Config {
-  pub listen_on: SocketAddr,
+  pub listen_on: Listen,
+  /// Serve HTTPS directly, instead of leaving it to a reverse proxy.
+  #[serde(default)]
+  pub tls: Option<Tls>,
   pub client_id: String,
+  /// Where the wiki is reached, for building links - `client_id` is used if it's not set.
+  #[serde(default)]
+  pub base_url: Option<String>,
+  /// Trusts the `X-Forwarded-*` headers, which only a reverse proxy in front of the wiki
+  /// should be able to set.
+  #[serde(default)]
+  pub trust_forwarded_headers: bool,
+  /// The reverse proxies in front of the wiki. When there are any, `X-Forwarded-*` headers are
+  /// only trusted from them, and they're skipped over when finding the client's address.
+  #[serde(default)]
+  pub trusted_proxies: Vec<IpAddr>,
+  #[serde(default)]
+  pub auth: Provider,
   pub allowed_mime_types: HashSet<String>,
   pub static_directory: PathBuf,
   pub pages_directory: PathBuf,
   pub pages_git: Git,
   pub templates_directory: PathBuf,
+  /// Generated files, like images extracted from notebooks.
+  #[serde(default = "default_cache_directory")]
+  pub cache_directory: PathBuf,
   pub katex_macros: HashMap<String, String>,
-  pub postgresql: String,
+  /// Values like the site's name, used as `{{ site.name }}` in pages and themes.
+  #[serde(default)]
+  pub site_vars: HashMap<String, String>,
+  /// The wiki's name, logo and footer.
+  #[serde(default)]
+  pub branding: Branding,
+  /// Markup, stylesheets and scripts added to every page.
+  #[serde(default)]
+  pub inject: Inject,
+  /// Link prefixes like `wikipedia:`, and the sites they link to.
+  #[serde(default)]
+  pub interwiki: HashMap<String, Interwiki>,
+  /// Kept for older configs - the same as setting `session_store` to `Postgres(..)`.
+  #[serde(default)]
+  pub postgresql: Option<String>,
+  #[serde(default)]
+  pub session_store: Option<sessions::Backend>,
   pub users: Users,
+  #[serde(default)]
+  pub registration: Registration,
+  #[serde(default)]
+  pub email: Option<Email>,
+  #[serde(default)]
+  pub micropub: Option<Micropub>,
+  #[serde(default)]
+  pub commit_messages: CommitMessages,
+  #[serde(default = "default_csv_row_limit")]
+  pub csv_row_limit: usize,
+  #[serde(default)]
+  pub sanitize_html: SanitizeHtml,
+  #[serde(default)]
+  pub asciidoc: Option<AsciiDoc>,
+  /// Spelling and style checks for `/meta/reports/prose`.
+  #[serde(default)]
+  pub prose: Option<Prose>,
+  /// Pandoc extensions turned on for Markdown pages, like `footnotes`.
+  #[serde(default = "default_markdown_extensions")]
+  pub markdown_extensions: Vec<String>,
+  #[serde(default)]
+  pub cache_control: CacheControl,
+  #[serde(default)]
+  pub dates: DateSettings,
+  /// Commits edits from anyone who isn't an administrator to a draft branch of their own,
+  /// which an administrator merges or rejects from `/meta/drafts`.
+  #[serde(default)]
+  pub drafts: bool,
+  /// Lets people who aren't logged in suggest edits, which are committed to draft branches
+  /// for an administrator to merge or reject.
+  #[serde(default)]
+  pub guest_editing: bool,
+  /// How guests' edits, and edits from accounts that haven't been approved, are checked for spam.
+  #[serde(default)]
+  pub spam: crate::spam::Spam,
+  /// How many edits, new pages and uploads each role can make.
+  #[serde(default)]
+  pub quotas: crate::quota::Quotas,
+  /// Lets several people edit the same page at once, with their changes merged as they type.
+  #[serde(default)]
+  pub collaboration: bool,
+  /// What's done to the paths of new pages.
+  #[serde(default)]
+  pub slugs: SlugRules,
+  /// Whether `/home` finds `Home.md`.
+  #[serde(default)]
+  pub path_matching: PathMatching,
+  /// Counts how many times each page is viewed, for the popular pages list.
+  #[serde(default)]
+  pub views: Option<Views>,
+  /// Where the audit log of administrative and destructive actions is kept.
+  #[serde(default)]
+  pub audit: crate::audit::Store,
+  /// How failed logins are slowed down and locked out.
+  #[serde(default)]
+  pub lockout: Lockout,
+  /// Only people with one of these roles, and administrators, can edit. Anyone can when it's
+  /// empty.
+  #[serde(default)]
+  pub editors: Vec<Role>,
+  /// Settings for the pages matching each glob, like who can see them. `_config.ron` files in
+  /// the pages' directories override these.
+  #[serde(default)]
+  pub namespaces: Vec<crate::namespace::Namespace>,
+  /// Other wikis to serve alongside this one.
+  #[serde(default)]
+  pub wikis: Vec<Wiki>,
+  /// The file the config was read from, so it can be reloaded.
+  #[serde(skip)]
+  pub path: Option<PathBuf>,
+  /// Which of the main config's `wikis` this is the config for, if it isn't the main one.
+  #[serde(skip)]
+  pub wiki: Option<String>,
+  /// How many times the config has been reloaded, so anything made from it can tell it's
+  /// changed.
+  #[serde(skip)]
+  pub generation: u64,
+}
+
+fn default_cache_directory() -> PathBuf {
+  PathBuf::from("./cache")
+}
+
+fn default_csv_row_limit() -> usize {
+  1000
+}
+
+fn default_markdown_extensions() -> Vec<String> {
+  ["footnotes", "definition_lists", "task_lists"]
+    .map(String::from)
+    .to_vec()
 }
 
 impl Config {
+  /// Reads the config file at `path`, and applies any overrides from `GITALITE_` environment
+  /// variables.
+  pub fn load(path: &Path) -> Result<Self, Error> {
+    let contents = std::fs::read_to_string(path)?;
+
+    let mut config = Self::parse(&contents, path)?.with_overrides(std::env::vars())?;
+    config.path = Some(path.to_path_buf());
+
+    Ok(config)
+  }
+
+  /// Reads the file this config came from again. Anything in [`RESTART_ONLY`] is kept as it
+  /// is, with a warning if it's been changed.
+  pub fn reloaded(&self) -> Result<Self, Error> {
+    let path = self.path.as_ref().ok_or(Error::NoFile)?;
+
+    let mut next = Self::load(path)?;
+    next.check()?;
+
+    if let Some(name) = &self.wiki {
+      let wiki = next
+        .wikis
+        .iter()
+        .find(|wiki| &wiki.name == name)
+        .ok_or_else(|| Error::NoWiki(name.clone()))?;
+
+      next = next.for_wiki(wiki)?;
+    }
+
+    next.canonicalize()?;
+
+    let current = serde_json::to_value(self)?;
+    let mut next = serde_json::to_value(next)?;
+
+    for key in RESTART_ONLY {
+      if next[*key] != current[*key] {
+        log::warn!(
+          "`{}` has changed, but it won't be used until a restart",
+          key
+        );
+        next[*key] = current[*key].clone();
+      }
+    }
+
+    let mut next: Self = serde_json::from_value(next)?;
+    next.path = Some(path.clone());
+    next.wiki = self.wiki.clone();
+    next.generation = self.generation + 1;
+
+    Ok(next)
+  }
+
+  /// The config for `wiki` - this one, with the wiki's own settings swapped in.
+  pub fn for_wiki(&self, wiki: &Wiki) -> Result<Self, Error> {
+    let mut config = serde_json::to_value(self)?;
+    let own = serde_json::to_value(wiki)?;
+
+    for key in ["pages_directory", "pages_git", "base_url", "editors"] {
+      config[key] = own[key].clone();
+    }
+
+    for key in ["static_directory", "templates_directory"] {
+      if !own[key].is_null() {
+        config[key] = own[key].clone();
+      }
+    }
+
+    // IndieAuth wants the login callback to be on the same site as the `client_id`.
+    if let Some(base_url) = &wiki.base_url {
+      config["client_id"] = format!("{}/", base_url.trim_end_matches('/')).into();
+    }
+
+    config["cache_directory"] =
+      serde_json::to_value(self.cache_directory.join("wikis").join(&wiki.name))?;
+    config["wikis"] = serde_json::Value::Array(Vec::new());
+
+    let mut config: Self = serde_json::from_value(config)?;
+    config.path = self.path.clone();
+    config.wiki = Some(wiki.name.clone());
+
+    Ok(config)
+  }
+
+  /// Parses `contents` as TOML or YAML if `path` has one of their extensions, and RON
+  /// otherwise.
+  pub fn parse(contents: &str, path: &Path) -> Result<Self, Error> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+
+    let config = match extension {
+      Some("toml") => toml::from_str(contents)?,
+      Some("yaml" | "yml") => serde_yaml::from_str(contents)?,
+      _ => ron::from_str(contents)?,
+    };
+
+    Ok(config)
+  }
+
+  /// Each variable like `GITALITE_LISTEN_ON` sets `listen_on`, and nested settings are separated
+  /// by `__`, like `GITALITE_PAGES_GIT__REPOSITORY`. Values are read as whatever type the setting
+  /// is - JSON for numbers, booleans and lists, and plain text for strings - and variables that
+  /// aren't settings are refused.
+  pub fn with_overrides(self, vars: impl Iterator<Item = (String, String)>) -> Result<Self, Error> {
+    let overrides: Vec<_> = vars
+      .filter_map(|(name, value)| {
+        let name = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+        Some((name, value))
+      })
+      .collect();
+
+    if overrides.is_empty() {
+      return Ok(self);
+    }
+
+    let mut config = serde_json::to_value(self)?;
+
+    for (name, value) in overrides {
+      let keys: Vec<_> = name.split("__").collect();
+
+      // Strings stay strings, even when they look like numbers, and anything else is JSON.
+      // Settings that aren't set yet could be either, so whichever fits is used.
+      let json = serde_json::from_str::<serde_json::Value>(&value);
+      let candidates = match lookup(&config, &keys) {
+        Some(serde_json::Value::String(_)) => vec![serde_json::Value::String(value)],
+        Some(serde_json::Value::Null) | None => std::iter::once(serde_json::Value::String(value))
+          .chain(json.ok())
+          .collect(),
+        Some(_) => vec![json.map_err(|err| Error::InvalidOverride(name.clone(), err))?],
+      };
+
+      let mut error = Error::UnknownSetting(name.clone());
+      let mut overridden = None;
+
+      for candidate in candidates {
+        let mut next = config.clone();
+        set(&mut next, &keys, candidate).ok_or_else(|| Error::NotATable(name.clone()))?;
+
+        // Anything that isn't a setting is dropped on the way through.
+        match serde_json::from_value::<Self>(next).and_then(serde_json::to_value) {
+          Ok(next) if lookup(&next, &keys).is_some() => {
+            overridden = Some(next);
+            break;
+          },
+          Ok(_) => error = Error::UnknownSetting(name.clone()),
+          Err(err) => error = Error::InvalidOverride(name.clone(), err),
+        }
+      }
+
+      config = overridden.ok_or(error)?;
+      log::info!("Overriding `{}` from the environment", name);
+    }
+
+    Ok(serde_json::from_value(config)?)
+  }
+
+  /// Where the wiki is reached, without a trailing slash, for building links.
+  pub fn base_url(&self) -> &str {
+    self
+      .base_url
+      .as_deref()
+      .unwrap_or(&self.client_id)
+      .trim_end_matches('/')
+  }
+
+  /// Where the pages repository is checked out - `pages_directory`, unless the pages are in a
+  /// `subdir` of it.
+  pub fn repository_directory(&self) -> PathBuf {
+    let mut directory = self.pages_directory.clone();
+
+    for _ in self.pages_git.subdir.components() {
+      directory.pop();
+    }
+
+    directory
+  }
+
+  pub fn session_backend(&self) -> sessions::Backend {
+    match (&self.session_store, &self.postgresql) {
+      (Some(backend), _) => backend.clone(),
+      (None, Some(url)) => sessions::Backend::Postgres(url.clone()),
+      (None, None) => sessions::Backend::Memory,
+    }
+  }
+
+  /// Fails with the [`Config::problems`], if there are any.
+  pub fn check(&self) -> Result<(), Error> {
+    let problems = self.problems();
+
+    match problems.is_empty() {
+      true => Ok(()),
+      false => Err(Error::Problems(problems)),
+    }
+  }
+
+  /// Anything that would stop the wiki from starting, or make part of it not work.
+  pub fn problems(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut exists = |path: &Path, what: &str| {
+      if !path.exists() {
+        problems.push(format!("{} {} doesn't exist", what, path.display()));
+      }
+    };
+
+    exists(&self.pages_git.private_key, "The private key");
+    if let Some(public_key) = &self.pages_git.public_key {
+      exists(public_key, "The public key");
+    }
+    for mirror in &self.pages_git.mirrors {
+      let keys = [&mirror.private_key, &mirror.public_key];
+      for key in keys.into_iter().flatten() {
+        exists(key, &format!("The key for the mirror '{}'", mirror.name));
+      }
+    }
+    for wiki in &self.wikis {
+      exists(
+        &wiki.pages_git.private_key,
+        &format!("The private key for the wiki '{}'", wiki.name),
+      );
+    }
+    if let Some(tls) = &self.tls {
+      exists(&tls.certificate, "The TLS certificate");
+      exists(&tls.key, "The TLS key");
+    }
+    if let user::Backend::File = self.users.store {
+      exists(&self.users.password, "The users' password file");
+    }
+
+    let subdir = &self.pages_git.subdir;
+    if subdir.is_absolute() || !self.pages_directory.ends_with(subdir) {
+      problems.push(format!(
+        "`pages_directory` {} doesn't end with `pages_git.subdir` {}",
+        self.pages_directory.display(),
+        subdir.display()
+      ));
+    }
+
+    for (i, mirror) in self.pages_git.mirrors.iter().enumerate() {
+      if mirror.name == "origin" {
+        problems.push("A mirror can't be called 'origin'".to_string());
+      } else if self.pages_git.mirrors[..i]
+        .iter()
+        .any(|other| other.name == mirror.name)
+      {
+        problems.push(format!(
+          "There's more than one mirror called '{}'",
+          mirror.name
+        ));
+      }
+    }
+
+    for namespace in &self.namespaces {
+      if let Err(err) = globset::Glob::new(&namespace.glob) {
+        problems.push(format!("`{}` isn't a glob: {}", namespace.glob, err));
+      }
+    }
+
+    for (i, wiki) in self.wikis.iter().enumerate() {
+      let others = &self.wikis[..i];
+
+      if wiki.hosts.is_empty() {
+        problems.push(format!("The wiki '{}' doesn't have any `hosts`", wiki.name));
+      }
+
+      // It's used as a directory name for the wiki's caches.
+      if wiki.name.is_empty() || wiki.name.contains(['/', '\\']) || wiki.name.contains("..") {
+        problems.push(format!("'{}' can't be the name of a wiki", wiki.name));
+      }
+
+      if others.iter().any(|other| other.name == wiki.name) {
+        problems.push(format!("There's more than one wiki called '{}'", wiki.name));
+      }
+
+      for host in &wiki.hosts {
+        let taken = others
+          .iter()
+          .flat_map(|other| &other.hosts)
+          .any(|other| other.eq_ignore_ascii_case(host));
+
+        if taken {
+          problems.push(format!("More than one wiki has the host '{}'", host));
+        }
+      }
+
+      let shared = wiki.pages_directory == self.pages_directory
+        || others
+          .iter()
+          .any(|other| other.pages_directory == wiki.pages_directory);
+
+      if shared {
+        problems.push(format!(
+          "The wiki '{}' has the same `pages_directory` as another wiki",
+          wiki.name
+        ));
+      }
+    }
+
+    if let (Listen::Unix { .. }, Some(_)) = (&self.listen_on, &self.tls) {
+      problems.push("`tls` can't be used when `listen_on` is a Unix socket".to_string());
+    }
+
+    if Url::parse(&self.client_id).is_err() {
+      problems.push(format!("`client_id` {} isn't a URL", self.client_id));
+    }
+
+    if let Some(timezone) = &self.dates.timezone {
+      if crate::date::timezone(timezone).is_none() {
+        problems.push(format!("'{}' isn't a timezone", timezone));
+      }
+    }
+
+    if let Some(format) = &self.dates.format {
+      if let Err(err) = time::format_description::parse(format) {
+        problems.push(format!("The date format `{}` is invalid: {}", format, err));
+      }
+    }
+
+    if let Some(pattern) = &self.commit_messages.summary_pattern {
+      if let Err(err) = regex::Regex::new(pattern) {
+        problems.push(format!("`summary_pattern` is invalid: {}", err));
+      }
+    }
+
+    if let Err(err) = crate::theme::Theme::load(&self.templates_directory) {
+      problems.push(format!("The templates can't be loaded: {}", err));
+    }
+
+    problems
+  }
+
   pub fn canonicalize(&mut self) -> Result<(), std::io::Error> {
     self.pages_directory = self.pages_directory.canonicalize()?;
     self.static_directory = self.static_directory.canonicalize()?;
     self.templates_directory = self.templates_directory.canonicalize()?;
+    self.cache_directory = self.cache_directory.canonicalize()?;
 
     Ok(())
   }
@@ -0,0 +1,147 @@
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use time::{
+  format_description::{self, well_known::Rfc3339, FormatItem},
+  macros::format_description,
+  OffsetDateTime,
+};
+use time_tz::{timezones, OffsetDateTimeExt, Tz};
+
+use crate::user::User;
+
+const DAY_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]");
+
+const DEFAULT_FORMAT: &[FormatItem<'static>] = format_description!(
+  "[year]-[month]-[day] [hour]:[minute] [offset_hour sign:mandatory]:[offset_minute]"
+);
+
+/// How dates are shown across the wiki.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DateSettings {
+  /// An IANA timezone name, for anyone who hasn't picked their own.
+  pub timezone: Option<String>,
+  /// A [`time` format description](https://time-rs.github.io/book/api/format-description.html).
+  pub format: Option<String>,
+  /// Show dates like "3 hours ago", with the full date as a tooltip, rather than the other
+  /// way around.
+  pub relative: bool,
+}
+
+impl Default for DateSettings {
+  fn default() -> Self {
+    Self {
+      timezone: None,
+      format: None,
+      relative: true,
+    }
+  }
+}
+
+const UNITS: [(&str, i64); 5] = [
+  ("year", 60 * 60 * 24 * 365),
+  ("month", 60 * 60 * 24 * 30),
+  ("day", 60 * 60 * 24),
+  ("hour", 60 * 60),
+  ("minute", 60),
+];
+
+pub fn timezone(name: &str) -> Option<&'static Tz> {
+  timezones::get_by_name(name)
+}
+
+fn parse_format(format: &str) -> Option<Vec<FormatItem<'_>>> {
+  match format_description::parse(format) {
+    Ok(format) => Some(format),
+    Err(err) => {
+      log::warn!("Couldn't use the date format `{}`: {}", format, err);
+      None
+    },
+  }
+}
+
+/// Formats dates for whoever is viewing the page, in their own timezone.
+pub struct Dates<'a> {
+  timezone: Option<&'static Tz>,
+  format: Option<Vec<FormatItem<'a>>>,
+  relative: bool,
+  now: OffsetDateTime,
+}
+
+impl<'a> Dates<'a> {
+  pub fn new(user: Option<&User>, settings: &'a DateSettings) -> Self {
+    let timezone = user
+      .and_then(|user| user.timezone.as_deref())
+      .or(settings.timezone.as_deref())
+      .and_then(timezone);
+
+    let format = settings.format.as_deref().and_then(parse_format);
+
+    Self {
+      timezone,
+      format,
+      relative: settings.relative,
+      now: OffsetDateTime::now_utc(),
+    }
+  }
+
+  pub fn from_timestamp(timestamp: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(timestamp).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+  }
+
+  /// The date in the viewer's timezone, or the wiki's, or UTC if neither has been picked.
+  pub fn absolute(&self, date: OffsetDateTime) -> String {
+    let date = match self.timezone {
+      Some(timezone) => date.to_timezone(timezone),
+      None => date.to_offset(time::UtcOffset::UTC),
+    };
+
+    date
+      .format(self.format.as_deref().unwrap_or(DEFAULT_FORMAT))
+      .unwrap_or_default()
+  }
+
+  /// Just the day, in the viewer's timezone, for grouping things by when they happened.
+  pub fn day(&self, date: OffsetDateTime) -> String {
+    let date = match self.timezone {
+      Some(timezone) => date.to_timezone(timezone),
+      None => date.to_offset(time::UtcOffset::UTC),
+    };
+
+    date.format(DAY_FORMAT).unwrap_or_default()
+  }
+
+  /// How long ago the date was, like "3 hours ago".
+  pub fn relative(&self, date: OffsetDateTime) -> String {
+    let seconds = (self.now - date).whole_seconds();
+    let magnitude = seconds.abs();
+
+    let (count, unit) = match UNITS.iter().find(|(_, size)| magnitude >= *size) {
+      Some((unit, size)) => (magnitude / size, *unit),
+      None => return String::from("just now"),
+    };
+
+    let plural = if count == 1 { "" } else { "s" };
+
+    if seconds >= 0 {
+      format!("{} {}{} ago", count, unit, plural)
+    } else {
+      format!("in {} {}{}", count, unit, plural)
+    }
+  }
+
+  /// A relative date with the absolute date as a tooltip, or the other way around.
+  pub fn render(&self, date: OffsetDateTime) -> Markup {
+    let (shown, tooltip) = if self.relative {
+      (self.relative(date), self.absolute(date))
+    } else {
+      (self.absolute(date), self.relative(date))
+    };
+
+    html! {
+      time datetime=(date.format(&Rfc3339).unwrap_or_default()) title=(tooltip) {
+        (shown)
+      }
+    }
+  }
+}
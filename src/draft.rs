@@ -0,0 +1,196 @@
+//! Reviewing edits before they go live - when `drafts` is on, edits from anyone who isn't an
+//! administrator are committed to a branch of their own instead of the main branch. Guests'
+//! edits always are.
+
+use std::sync::Arc;
+
+use axum::{
+  extract::{Extension, Form},
+  http::StatusCode,
+  response::{Html, IntoResponse, Redirect, Response},
+};
+use serde::Deserialize;
+
+use crate::{
+  config::Config,
+  date::Dates,
+  git::{Author, DRAFTS_PREFIX},
+  navigation::Navigation,
+  proxy::SameOrigin,
+  role::Role,
+  template::Template,
+  user::User,
+  State,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Git(#[from] crate::git::Error),
+  #[error("'{0}' isn't a draft")]
+  NotADraft(String),
+  #[error("Only administrators can merge drafts, or reject other people's")]
+  Forbidden,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Git(err) => err.into_response(),
+      Self::NotADraft(_) => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+      Self::Forbidden => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+    }
+  }
+}
+
+/// Whether `user`'s edits go to their draft branch, instead of straight to the wiki.
+pub fn is_drafted(user: &User, config: &Config) -> bool {
+  crate::guest::is_guest(user) || (config.drafts && !user.roles.contains(&Role::Administrator))
+}
+
+/// The name of `user`'s draft branch. Emails can have characters that aren't allowed in branch
+/// names, so anything but a letter or digit is written as `_` and its hex bytes - that way no two
+/// emails share a branch.
+pub fn branch(user: &User) -> String {
+  let mut name = String::new();
+
+  for byte in user.email.to_lowercase().bytes() {
+    if byte.is_ascii_alphanumeric() {
+      name.push(byte as char);
+    } else {
+      name.push_str(&format!("_{:02x}", byte));
+    }
+  }
+
+  format!("{}{}", DRAFTS_PREFIX, name)
+}
+
+pub async fn drafts_handler(
+  user: User,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, Error> {
+  let administrator = user.roles.contains(&Role::Administrator);
+  let own = branch(&user);
+
+  let drafts = tokio::task::spawn_blocking({
+    let state = state.clone();
+    move || state.git.drafts(&*state.users)
+  })
+  .await
+  .unwrap()?;
+
+  let drafts: Vec<_> = drafts
+    .into_iter()
+    .filter(|draft| administrator || draft.branch == own)
+    .collect();
+
+  let config = state.config();
+  let dates = Dates::new(Some(&user), &config.dates);
+
+  let content = maud::html! {
+    @if drafts.is_empty() {
+      p { "There aren't any drafts waiting to be reviewed." }
+    }
+
+    @for draft in &drafts {
+      section .draft {
+        h2 { code { (draft.branch) } }
+        p {
+          "Last changed "
+          (dates.render(draft.date))
+          " by "
+          @match &draft.author {
+            Author::User(user) => {
+              a href={ "/meta/profile/" (user.email) } { (user.name) }
+            },
+            Author::NonUser { name, .. } => (name),
+          }
+        }
+        ul {
+          @for message in &draft.messages {
+            li { (message) }
+          }
+        }
+        (crate::git::render_diff(&draft.patch))
+        form method="post" action="/meta/drafts" {
+          input type="hidden" name="branch" value=(draft.branch);
+          @if administrator {
+            button type="submit" name="action" value="merge" { "Merge" }
+            button type="submit" name="action" value="reject" { "Reject" }
+          } @else {
+            button type="submit" name="action" value="reject" { "Withdraw" }
+          }
+        }
+      }
+    }
+  };
+
+  let html = Template::new()
+    .title("Drafts")
+    .content(content)
+    .render(Some(user), &state);
+
+  Ok(html)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+  Merge,
+  Reject,
+}
+
+#[derive(Deserialize)]
+pub struct ActionParams {
+  branch: String,
+  action: Action,
+}
+
+pub async fn action_handler(
+  user: User,
+  _: SameOrigin,
+  Form(params): Form<ActionParams>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Redirect, Error> {
+  if !params.branch.starts_with(DRAFTS_PREFIX) {
+    return Err(Error::NotADraft(params.branch));
+  }
+
+  let administrator = user.roles.contains(&Role::Administrator);
+
+  match params.action {
+    Action::Merge if administrator => {
+      let files = tokio::task::spawn_blocking({
+        let state = state.clone();
+        let branch = params.branch.clone();
+        move || -> Result<_, crate::git::Error> {
+          let _writing = state.git.writing().blocking_lock();
+          let files = state.git.merge_draft(&branch, &user)?;
+          state.git.push()?;
+
+          Ok(files)
+        }
+      })
+      .await
+      .unwrap()?;
+
+      for file in &files {
+        crate::cache::purge(&state.config(), file);
+      }
+
+      if files
+        .iter()
+        .any(|file| Navigation::is_navigation(&file.with_extension("")))
+      {
+        state.navigation.refresh(state.clone()).await;
+      }
+    },
+    // People can withdraw their own drafts.
+    Action::Reject if administrator || params.branch == branch(&user) => {
+      state.git.delete_draft(&params.branch)?;
+    },
+    _ => return Err(Error::Forbidden),
+  }
+
+  Ok(Redirect::to("/meta/drafts"))
+}
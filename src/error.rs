@@ -1,18 +1,221 @@
+//! Errors, as people and programs see them. Errors are rendered through [`Problem`], and once
+//! the handler is done, [`negotiate`] turns them into whatever the request's `Accept` header
+//! asks for - JSON like `{"code", "message", "details"}` for API clients, the themed error page
+//! for browsers, and plain text for anything else.
+
+use std::sync::Arc;
+
 use axum::{
-  extract::RawQuery,
-  response::{Html, IntoResponse, Redirect, Response},
+  body::Body,
+  extract::{FromRequest, RawQuery, RequestParts},
+  http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+  middleware::Next,
+  response::{Html, IntoResponse, Response},
+  Extension,
+  Json,
 };
+use maud::Markup;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+  pandoc::Diagnostic,
+  sessions::{SessionIndex, Store},
   template::{PrettyPrint, Template},
   user::User,
+  State,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+/// An error on its way to whoever made the request.
+#[derive(Clone, Debug, Serialize)]
+pub struct Problem {
+  #[serde(skip)]
+  pub status: StatusCode,
+  /// Stays the same even if the message changes, so programs can match on it - like
+  /// `"conflict"`.
+  pub code: &'static str,
+  pub message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub details: Option<serde_json::Value>,
+}
+
+impl Problem {
+  pub fn new(status: StatusCode, code: &'static str, message: impl ToString) -> Self {
+    Self {
+      status,
+      code,
+      message: message.to_string(),
+      details: None,
+    }
+  }
+
+  pub fn details(mut self, details: serde_json::Value) -> Self {
+    self.details = Some(details);
+    self
+  }
+}
+
+impl IntoResponse for Problem {
+  fn into_response(self) -> Response {
+    let mut response = (self.status, self.message.clone()).into_response();
+    // Left for `negotiate`, which knows what the request wanted.
+    response.extensions_mut().insert(self);
+
+    response
+  }
+}
+
+/// What an error can be rendered as.
+#[derive(Clone, Copy, PartialEq)]
+enum Accept {
+  Json,
+  Html,
+  Text,
+}
+
+impl Accept {
+  /// Whichever of JSON and HTML the `Accept` header prefers, going by their quality values.
+  /// Wildcards don't count, so clients that don't say get plain text, like they always have.
+  fn from_headers(headers: &HeaderMap) -> Self {
+    let mut json = 0.0;
+    let mut html = 0.0;
+
+    let ranges = headers
+      .get_all(header::ACCEPT)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','));
+
+    for range in ranges {
+      let mut params = range.split(';').map(str::trim);
+      let media = params.next().unwrap_or_default().to_ascii_lowercase();
+      let quality = params
+        .find_map(|param| param.strip_prefix("q="))
+        .and_then(|quality| quality.parse::<f32>().ok())
+        .unwrap_or(1.0);
+
+      match media.as_str() {
+        "application/json" | "application/problem+json" => json = quality.max(json),
+        "text/html" | "application/xhtml+xml" => html = quality.max(html),
+        _ => {},
+      }
+    }
+
+    if json > 0.0 && json > html {
+      Self::Json
+    } else if html > 0.0 {
+      Self::Html
+    } else {
+      Self::Text
+    }
+  }
+}
+
+/// Renders errors the way the request asked for them.
+pub async fn negotiate(req: Request<Body>, next: Next<Body>) -> Response {
+  let accept = Accept::from_headers(req.headers());
+
+  // Kept so whoever's logged in can be worked out again for the error page.
+  let headers = req.headers().clone();
+  let store = req.extensions().get::<Store>().cloned();
+  let index = req.extensions().get::<SessionIndex>().cloned();
+  let state = req.extensions().get::<Arc<State>>().cloned();
+
+  let response = next.run(req).await;
+
+  let problem = match response.extensions().get::<Problem>() {
+    Some(problem) if accept != Accept::Text => problem.clone(),
+    _ => return response,
+  };
+
+  let mut rendered = match accept {
+    Accept::Json => (problem.status, Json(&problem)).into_response(),
+    Accept::Html => {
+      let state = match state {
+        Some(state) => state,
+        None => return response,
+      };
+
+      let user = user(headers, store, index, state.clone()).await;
+
+      let content = match response.extensions().get::<ErrorPage>() {
+        Some(page) => page.content(),
+        None => maud::html! { p { (problem.message) } },
+      };
+
+      let html = Template::new()
+        .title(problem.status.canonical_reason().unwrap_or("Error"))
+        .content(content)
+        .render(user, &state);
+
+      (problem.status, html).into_response()
+    },
+    Accept::Text => return response,
+  };
+
+  if let Some(retry_after) = response.headers().get(header::RETRY_AFTER) {
+    rendered
+      .headers_mut()
+      .insert(header::RETRY_AFTER, retry_after.clone());
+  }
+
+  rendered
+}
+
+/// Whoever made the request, going by its headers.
+async fn user(
+  headers: HeaderMap,
+  store: Option<Store>,
+  index: Option<SessionIndex>,
+  state: Arc<State>,
+) -> Option<User> {
+  let mut req = Request::new(Body::empty());
+  *req.headers_mut() = headers;
+  req.extensions_mut().insert(state);
+  req.extensions_mut().insert(store?);
+  req.extensions_mut().insert(index?);
+
+  Option::<User>::from_request(&mut RequestParts::new(req))
+    .await
+    .ok()
+    .flatten()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ErrorPage {
-  ReservedPage { url: String },
+  ReservedPage {
+    url: String,
+  },
+  ForbiddenFileType {
+    url: String,
+    mime: String,
+  },
+  QuotaExceeded {
+    limit: String,
+    minutes: u64,
+  },
+  /// `url` is the page or file that was asked for, if it's known.
+  NotFound {
+    url: Option<String>,
+  },
+  /// Someone who isn't logged in, or doesn't have `role`.
+  Unauthorized {
+    role: Option<String>,
+  },
+  /// Pandoc, or the AsciiDoc converter, couldn't render a page.
+  RenderFailed {
+    stderr: String,
+    #[serde(default)]
+    diagnostics: Vec<Diagnostic>,
+  },
+  /// Someone else got there first.
+  Conflict {
+    message: String,
+  },
+  /// Too many tries, like failed logins.
+  RateLimited {
+    seconds: u64,
+  },
   Unknown,
 }
 
@@ -26,15 +229,148 @@ impl ErrorPage {
     // Should be good to unwrap here because we control the input!
     serde_qs::to_string(&ErrorPageWrapper { error: self }).unwrap()
   }
+
+  fn problem(&self) -> Problem {
+    match self {
+      Self::ReservedPage { url } => Problem::new(
+        StatusCode::FORBIDDEN,
+        "reserved-page",
+        format!("The page at {} is reserved for future internal use", url),
+      )
+      .details(serde_json::json!({ "url": url })),
+      Self::ForbiddenFileType { url, mime } => Problem::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "forbidden-file-type",
+        format!(
+          "The file at {} has the type {}, which isn't allowed",
+          url, mime
+        ),
+      )
+      .details(serde_json::json!({ "url": url, "mime": mime })),
+      Self::QuotaExceeded { limit, minutes } => Problem::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "quota-exceeded",
+        format!(
+          "You've reached your limit of {} - try again in {} minutes",
+          limit, minutes
+        ),
+      )
+      .details(serde_json::json!({ "limit": limit, "minutes": minutes })),
+      Self::NotFound { url } => Problem::new(
+        StatusCode::NOT_FOUND,
+        "not-found",
+        match url {
+          Some(url) => format!("There's nothing at {}", url),
+          None => String::from("That couldn't be found"),
+        },
+      )
+      .details(serde_json::json!({ "url": url })),
+      Self::Unauthorized { role } => Problem::new(
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        match role {
+          Some(role) => format!("Only users with the role '{}' can do that", role),
+          None => String::from("You need to log in to do that"),
+        },
+      )
+      .details(serde_json::json!({ "role": role })),
+      Self::RenderFailed {
+        stderr,
+        diagnostics,
+      } => Problem::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "render-failed",
+        "The page couldn't be rendered",
+      )
+      .details(serde_json::json!({ "stderr": stderr, "diagnostics": diagnostics })),
+      Self::Conflict { message } => Problem::new(StatusCode::CONFLICT, "conflict", message),
+      Self::RateLimited { seconds } => Problem::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate-limited",
+        format!(
+          "Too many tries - wait {} seconds before trying again",
+          seconds
+        ),
+      )
+      .details(serde_json::json!({ "seconds": seconds })),
+      Self::Unknown => Problem::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "unknown",
+        "Unknown error",
+      ),
+    }
+  }
+
+  /// How long to wait before trying again, if it's worth trying again.
+  fn retry_after(&self) -> Option<u64> {
+    match self {
+      Self::QuotaExceeded { minutes, .. } => Some(minutes * 60),
+      Self::RateLimited { seconds } => Some(*seconds),
+      _ => None,
+    }
+  }
+
+  fn content(&self) -> Markup {
+    maud::html! {
+      @match self {
+        Self::ReservedPage { url } => {
+          "You can't make the page at " (url) " because it's reserved for future internal use, sorry!"
+        },
+        Self::ForbiddenFileType { url, mime } => {
+          "The file at " (url) " has the type " code { (mime) } ", which isn't allowed to be viewed, sorry!"
+        },
+        Self::QuotaExceeded { limit, minutes } => {
+          "You've reached your limit of " (limit) ", so you'll have to wait about " (minutes)
+          " minutes before you can do that again, sorry!"
+        },
+        Self::NotFound { url: Some(url) } => {
+          "There's nothing at " code { (url) } ", sorry!"
+        },
+        Self::NotFound { url: None } => { "That couldn't be found, sorry!" },
+        Self::Unauthorized { role: Some(role) } => {
+          "Only users with the role " code { (role) } " can do that, sorry!"
+        },
+        Self::Unauthorized { role: None } => {
+          "You need to " a href="/meta/login" { "log in" } " to do that."
+        },
+        Self::RenderFailed { stderr, .. } => {
+          p { "The page couldn't be rendered, sorry! This is what went wrong:" }
+          pre { (stderr) }
+        },
+        Self::Conflict { message } => { (message) },
+        Self::RateLimited { seconds } => {
+          "There have been too many tries, so you'll have to wait " (seconds)
+          " seconds before trying again, sorry!"
+        },
+        Self::Unknown => { "An unknown error occured, sorry!" },
+      }
+    }
+  }
 }
 
 impl IntoResponse for ErrorPage {
   fn into_response(self) -> Response {
-    Redirect::to(&format!("/meta/error?{}", self.query_string())).into_response()
+    let mut response = self.problem().into_response();
+
+    if let Some(seconds) = self.retry_after() {
+      response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    }
+
+    // For `negotiate`, so browsers get the page for this error.
+    response.extensions_mut().insert(self);
+
+    response
   }
 }
 
-pub async fn handler(RawQuery(query): RawQuery, user: Option<User>) -> Html<String> {
+/// Error pages that are linked to, rather than rendered where the error happened.
+pub async fn handler(
+  RawQuery(query): RawQuery,
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Html<String> {
   let error = if let Some(query) = query {
     match serde_qs::from_str(&query) {
       Ok(ErrorPageWrapper { error }) => error,
@@ -45,15 +381,13 @@ pub async fn handler(RawQuery(query): RawQuery, user: Option<User>) -> Html<Stri
   };
 
   let content = maud::html! {
-    @match &error {
-      ErrorPage::ReservedPage { url } => {
-        "You can't make the page at " (url) " because it's reserved for future internal use, sorry!"
-      },
-      ErrorPage::Unknown => { "An unknown error occured, sorry!" },
-    }
+    (error.content())
 
     pre { (PrettyPrint(error)) }
   };
 
-  Template::new().title("Error").content(content).render(user)
+  Template::new()
+    .title("Error")
+    .content(content)
+    .render(user, &state)
 }
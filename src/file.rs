@@ -0,0 +1,175 @@
+//! Sending files straight from disk a piece at a time, so big images and videos don't have to
+//! fit in memory - and only the part the browser asks for, when it sends `Range`.
+
+use std::{io::SeekFrom, ops::Range, path::Path, time::UNIX_EPOCH};
+
+use axum::{
+  body::StreamBody,
+  headers::{HeaderMapExt, LastModified},
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Response},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// What the browser asked for.
+enum Requested {
+  Whole,
+  Part(Range<u64>),
+  /// The range starts after the end of the file.
+  Unsatisfiable,
+}
+
+/// Serves the file at `path`, with `cache_control`.
+pub async fn serve(
+  path: &Path,
+  request_headers: &HeaderMap,
+  cache_control: &'static str,
+) -> std::io::Result<Response> {
+  let mime = mime_guess::from_path(path).first_or_text_plain();
+
+  let mut file = tokio::fs::File::open(path).await?;
+  let metadata = file.metadata().await?;
+  let length = metadata.len();
+  let modified = metadata.modified().ok();
+
+  // The file isn't read to make this, so it comes from what's in its metadata instead.
+  let etag = format!(
+    "\"{:x}-{:x}\"",
+    length,
+    modified
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .map(|since| since.as_secs())
+      .unwrap_or_default()
+  );
+
+  let mut headers = HeaderMap::new();
+  let mut insert = |name, value: String| {
+    if let Ok(value) = HeaderValue::from_str(&value) {
+      headers.insert(name, value);
+    }
+  };
+
+  insert(header::CONTENT_TYPE, mime.essence_str().to_string());
+  insert(header::CACHE_CONTROL, cache_control.to_string());
+  insert(header::ETAG, etag.clone());
+  insert(header::ACCEPT_RANGES, String::from("bytes"));
+
+  if let Some(modified) = modified {
+    headers.typed_insert(LastModified::from(modified));
+  }
+
+  if is_unchanged(request_headers, &etag) {
+    return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+  }
+
+  let (status, range) = match requested(request_headers, &etag, length) {
+    Requested::Whole => (StatusCode::OK, 0..length),
+    Requested::Part(range) => {
+      let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, length);
+      headers.insert(
+        header::CONTENT_RANGE,
+        HeaderValue::from_str(&content_range).unwrap(),
+      );
+
+      (StatusCode::PARTIAL_CONTENT, range)
+    },
+    Requested::Unsatisfiable => {
+      let content_range = format!("bytes */{}", length);
+      headers.insert(
+        header::CONTENT_RANGE,
+        HeaderValue::from_str(&content_range).unwrap(),
+      );
+
+      return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+    },
+  };
+
+  file.seek(SeekFrom::Start(range.start)).await?;
+
+  let size = range.end - range.start;
+  headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+
+  let body = StreamBody::new(ReaderStream::new(file.take(size)));
+
+  Ok((status, headers, body).into_response())
+}
+
+/// Serves one of the files built into the binary. They're small, so they're sent whole.
+pub fn serve_embedded(
+  path: &Path,
+  contents: &'static [u8],
+  request_headers: &HeaderMap,
+  cache_control: &'static str,
+) -> Response {
+  let mime = mime_guess::from_path(path).first_or_text_plain();
+  let etag = format!("\"{}\"", crate::assets::fingerprint(contents));
+
+  let headers = [
+    (header::CONTENT_TYPE, mime.essence_str().to_string()),
+    (header::CACHE_CONTROL, cache_control.to_string()),
+    (header::ETAG, etag.clone()),
+  ];
+
+  if is_unchanged(request_headers, &etag) {
+    return (StatusCode::NOT_MODIFIED, headers).into_response();
+  }
+
+  (headers, contents).into_response()
+}
+
+/// Whether the browser's copy, from `If-None-Match`, is the one tagged `etag`.
+fn is_unchanged(request_headers: &HeaderMap, etag: &str) -> bool {
+  request_headers
+    .get(header::IF_NONE_MATCH)
+    .and_then(|value| value.to_str().ok())
+    .map(|value| {
+      value
+        .split(',')
+        .any(|tag| tag.trim() == etag || tag.trim() == "*")
+    })
+    .unwrap_or(false)
+}
+
+/// Reads the `Range` header. Only a single range is supported - when there are several, the
+/// whole file is sent, which browsers have to accept.
+fn requested(request_headers: &HeaderMap, etag: &str, length: u64) -> Requested {
+  let get = |name: header::HeaderName| {
+    request_headers
+      .get(name)
+      .and_then(|value| value.to_str().ok())
+  };
+
+  // The range is only for the version of the file the browser already has part of.
+  if let Some(if_range) = get(header::IF_RANGE) {
+    if if_range.trim() != etag {
+      return Requested::Whole;
+    }
+  }
+
+  let spec = match get(header::RANGE).and_then(|range| range.strip_prefix("bytes=")) {
+    Some(spec) if !spec.contains(',') => spec.trim(),
+    _ => return Requested::Whole,
+  };
+
+  let (start, end) = match spec.split_once('-') {
+    Some(bounds) => bounds,
+    None => return Requested::Whole,
+  };
+
+  match (start.parse::<u64>(), end.parse::<u64>()) {
+    // `bytes=-500` is the last 500 bytes.
+    (Err(_), Ok(suffix)) if start.is_empty() => {
+      if suffix == 0 || length == 0 {
+        Requested::Unsatisfiable
+      } else {
+        Requested::Part(length.saturating_sub(suffix)..length)
+      }
+    },
+    (Ok(start), _) if start >= length => Requested::Unsatisfiable,
+    // `bytes=500-` is everything from byte 500.
+    (Ok(start), Err(_)) if end.is_empty() => Requested::Part(start..length),
+    (Ok(start), Ok(end)) if start <= end => Requested::Part(start..(end + 1).min(length)),
+    _ => Requested::Whole,
+  }
+}
@@ -0,0 +1,473 @@
+use std::fmt;
+
+use ::pandoc::InputFormat;
+use serde::{Deserialize, Deserializer};
+
+use crate::{config::Config, front_matter::FrontMatter};
+
+/// What turns a page in this format into HTML.
+#[derive(Debug)]
+pub enum Renderer {
+  Pandoc(InputFormat),
+  /// Pandoc can't read AsciiDoc, so it's handled by an external command.
+  AsciiDoc,
+}
+
+#[derive(Debug)]
+pub struct FormatInfo {
+  /// The identifier used in forms and query strings.
+  pub name: &'static str,
+  pub display_name: &'static str,
+  /// File extensions for this format - the first is used when creating new pages.
+  pub extensions: &'static [&'static str],
+  pub renderer: Renderer,
+  pub mime: &'static str,
+  /// Binary formats can't be edited in the browser.
+  pub binary: bool,
+  /// A hint for the editor's syntax highlighting.
+  pub editor_mode: &'static str,
+  /// Whether the format is offered when creating and editing pages.
+  pub listed: bool,
+  /// Some formats depend on optional configuration.
+  pub enabled: fn(&Config) -> bool,
+}
+
+fn always(_: &Config) -> bool {
+  true
+}
+
+fn asciidoc_enabled(config: &Config) -> bool {
+  config.asciidoc.is_some()
+}
+
+pub struct FormatRegistry {
+  formats: &'static [FormatInfo],
+}
+
+pub static REGISTRY: FormatRegistry = FormatRegistry {
+  formats: &[
+    FormatInfo {
+      name: "markdown",
+      display_name: "Markdown",
+      extensions: &["md", "markdown"],
+      renderer: Renderer::Pandoc(InputFormat::Markdown),
+      mime: "text/markdown",
+      binary: false,
+      editor_mode: "markdown",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "markdown_strict",
+      display_name: "Markdown (strict)",
+      extensions: &["md"],
+      renderer: Renderer::Pandoc(InputFormat::MarkdownStrict),
+      mime: "text/markdown",
+      binary: false,
+      editor_mode: "markdown",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "markdown_phpextra",
+      display_name: "Markdown (PHP Markdown Extra)",
+      extensions: &["md"],
+      renderer: Renderer::Pandoc(InputFormat::MarkdownPhpextra),
+      mime: "text/markdown",
+      binary: false,
+      editor_mode: "markdown",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "markdown_github",
+      display_name: "Markdown (GitHub)",
+      extensions: &["md"],
+      renderer: Renderer::Pandoc(InputFormat::MarkdownGithub),
+      mime: "text/markdown",
+      binary: false,
+      editor_mode: "markdown",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "commonmark",
+      display_name: "CommonMark",
+      extensions: &["md"],
+      renderer: Renderer::Pandoc(InputFormat::Commonmark),
+      mime: "text/markdown",
+      binary: false,
+      editor_mode: "markdown",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "native",
+      display_name: "Pandoc native",
+      extensions: &["lhs"],
+      renderer: Renderer::Pandoc(InputFormat::Native),
+      mime: "text/plain",
+      binary: false,
+      editor_mode: "plain",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "json",
+      display_name: "Pandoc JSON",
+      extensions: &["json"],
+      renderer: Renderer::Pandoc(InputFormat::Json),
+      mime: "application/json",
+      binary: false,
+      editor_mode: "json",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "rst",
+      display_name: "reStructuredText",
+      extensions: &["rst"],
+      renderer: Renderer::Pandoc(InputFormat::Rst),
+      mime: "text/x-rst",
+      binary: false,
+      editor_mode: "rst",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "html",
+      display_name: "HTML",
+      extensions: &["html"],
+      renderer: Renderer::Pandoc(InputFormat::Html),
+      mime: "text/html",
+      binary: false,
+      editor_mode: "html",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "latex",
+      display_name: "LaTeX",
+      extensions: &["tex"],
+      renderer: Renderer::Pandoc(InputFormat::Latex),
+      mime: "application/x-tex",
+      binary: false,
+      editor_mode: "latex",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "mediawiki",
+      display_name: "MediaWiki",
+      extensions: &["wiki"],
+      renderer: Renderer::Pandoc(InputFormat::MediaWiki),
+      mime: "text/plain",
+      binary: false,
+      editor_mode: "mediawiki",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "textile",
+      display_name: "Textile",
+      extensions: &["textile"],
+      renderer: Renderer::Pandoc(InputFormat::Textile),
+      mime: "text/plain",
+      binary: false,
+      editor_mode: "textile",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "org",
+      display_name: "Emacs Org-Mode",
+      extensions: &["org"],
+      renderer: Renderer::Pandoc(InputFormat::Org),
+      mime: "text/org",
+      binary: false,
+      editor_mode: "org",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "opml",
+      display_name: "OPML",
+      extensions: &["opml"],
+      renderer: Renderer::Pandoc(InputFormat::Opml),
+      mime: "text/x-opml",
+      binary: false,
+      editor_mode: "xml",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "docx",
+      display_name: ".docx",
+      extensions: &["docx"],
+      renderer: Renderer::Pandoc(InputFormat::Docx),
+      mime: "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+      binary: true,
+      editor_mode: "plain",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "haddock",
+      display_name: "Haddock",
+      extensions: &["hs"],
+      renderer: Renderer::Pandoc(InputFormat::Haddock),
+      mime: "text/x-haskell",
+      binary: false,
+      editor_mode: "haskell",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "epub",
+      display_name: "EPUB",
+      extensions: &["epub"],
+      renderer: Renderer::Pandoc(InputFormat::Epub),
+      mime: "application/epub+zip",
+      binary: true,
+      editor_mode: "plain",
+      listed: false,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "docbook",
+      display_name: "DocBook",
+      extensions: &["dbk"],
+      renderer: Renderer::Pandoc(InputFormat::DocBook),
+      mime: "application/docbook+xml",
+      binary: false,
+      editor_mode: "xml",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "t2t",
+      display_name: "txt2tags",
+      extensions: &["t2t"],
+      renderer: Renderer::Pandoc(InputFormat::T2t),
+      mime: "text/plain",
+      binary: false,
+      editor_mode: "plain",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "twiki",
+      display_name: "TWiki",
+      extensions: &["twiki"],
+      renderer: Renderer::Pandoc(InputFormat::Twiki),
+      mime: "text/plain",
+      binary: false,
+      editor_mode: "plain",
+      listed: true,
+      enabled: always,
+    },
+    FormatInfo {
+      name: "asciidoc",
+      display_name: "AsciiDoc",
+      extensions: &["adoc", "asciidoc"],
+      renderer: Renderer::AsciiDoc,
+      mime: "text/asciidoc",
+      binary: false,
+      editor_mode: "asciidoc",
+      listed: true,
+      enabled: asciidoc_enabled,
+    },
+  ],
+};
+
+impl FormatRegistry {
+  pub fn all(&self) -> impl Iterator<Item = Format> {
+    self.formats.iter().map(Format)
+  }
+
+  pub fn by_name(&self, name: &str) -> Option<Format> {
+    self.all().find(|format| format.name == name)
+  }
+
+  pub fn by_extension(&self, extension: &str) -> Option<Format> {
+    self
+      .all()
+      .find(|format| format.extensions.contains(&extension))
+  }
+
+  /// The formats that can be picked in the editor.
+  pub fn editor_formats<'a>(&'a self, config: &'a Config) -> impl Iterator<Item = Format> + 'a {
+    self
+      .all()
+      .filter(move |format| format.listed && !format.binary && (format.enabled)(config))
+  }
+}
+
+#[derive(Clone, Copy)]
+pub struct Format(&'static FormatInfo);
+
+impl Format {
+  pub fn from_extension(extension: &str) -> Option<Self> {
+    REGISTRY.by_extension(extension)
+  }
+
+  pub fn extension(&self) -> &'static str {
+    self.extensions[0]
+  }
+
+  /// Whether pages in this format can start with front matter. Binary files can't, and a Pandoc
+  /// JSON document's leading `{` is the document itself.
+  pub fn has_front_matter(&self) -> bool {
+    !self.binary && !matches!(self.renderer, Renderer::Pandoc(InputFormat::Json))
+  }
+
+  pub fn is_markdown(&self) -> bool {
+    self.mime == "text/markdown"
+  }
+
+  /// Works out a page's format from what's in it, for when there's no extension or choice to
+  /// go by - the `format` in its front matter if there is one, or else what its syntax looks
+  /// like. Anything that doesn't look like another format is Markdown.
+  pub fn detect(doc: &str, config: &Config) -> Self {
+    let (front_matter, body, _) = FrontMatter::split(doc);
+
+    let named = front_matter
+      .format
+      .as_deref()
+      .and_then(|name| REGISTRY.by_name(name));
+
+    named
+      .or_else(|| REGISTRY.by_name(sniff(&body)?))
+      .filter(|format| (format.enabled)(config))
+      .or_else(|| REGISTRY.by_name("markdown"))
+      .unwrap()
+  }
+}
+
+/// The name of the format `body` is written in, going by syntax that only that format uses.
+/// Markdown pages can have HTML, maths and wiki-ish links in them, so those aren't counted.
+fn sniff(body: &str) -> Option<&'static str> {
+  let lines: Vec<&str> = body.lines().collect();
+  let starts = |prefix: &str| lines.iter().any(|line| line.starts_with(prefix));
+
+  let start = body.trim_start().to_ascii_lowercase();
+  if start.starts_with("<!doctype") || start.starts_with("<html") {
+    return Some("html");
+  }
+
+  if starts("\\documentclass") {
+    return Some("latex");
+  }
+
+  let org = ["#+TITLE:", "#+title:", "#+BEGIN_", "#+begin_"];
+  if org.iter().any(|prefix| starts(prefix)) {
+    return Some("org");
+  }
+
+  // reStructuredText's directives, like `.. image:: cat.png`.
+  let directive = lines
+    .iter()
+    .any(|line| line.starts_with(".. ") && line.contains("::"));
+  if directive {
+    return Some("rst");
+  }
+
+  // `== Heading ==`, which isn't Markdown's `=====` underline.
+  let mediawiki_heading = lines.iter().any(|line| {
+    let line = line.trim_end();
+    line.len() > 4
+      && line.starts_with("== ")
+      && line.ends_with(" ==")
+      && line.contains(|c: char| c != '=' && !c.is_whitespace())
+  });
+  if mediawiki_heading {
+    return Some("mediawiki");
+  }
+
+  let textile = ["h1. ", "h2. ", "h3. ", "bq. "];
+  if textile.iter().any(|prefix| starts(prefix)) {
+    return Some("textile");
+  }
+
+  // AsciiDoc's attributes - a title on its own looks just like Markdown.
+  if starts(":toc:") {
+    return Some("asciidoc");
+  }
+
+  None
+}
+
+/// A format picked in the editor, where `"auto"` means it's worked out from the page itself.
+#[derive(Clone, Copy, Debug)]
+pub enum Choice {
+  Auto,
+  Format(Format),
+}
+
+impl Choice {
+  pub fn resolve(self, doc: &str, config: &Config) -> Format {
+    match self {
+      Self::Auto => Format::detect(doc, config),
+      Self::Format(format) => format,
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Choice {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let name = String::deserialize(deserializer)?;
+
+    match name.as_str() {
+      "auto" => Ok(Self::Auto),
+      name => REGISTRY
+        .by_name(name)
+        .map(Self::Format)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown format `{}`", name))),
+    }
+  }
+}
+
+impl std::ops::Deref for Format {
+  type Target = FormatInfo;
+
+  fn deref(&self) -> &Self::Target {
+    self.0
+  }
+}
+
+impl fmt::Debug for Format {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_tuple("Format").field(&self.name).finish()
+  }
+}
+
+impl<'de> Deserialize<'de> for Format {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    deserializer.deserialize_str(FormatVisitor)
+  }
+}
+
+struct FormatVisitor;
+
+impl<'de> serde::de::Visitor<'de> for FormatVisitor {
+  type Value = Format;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("a format that gitalite recognises")
+  }
+
+  fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+  where
+    E: serde::de::Error,
+  {
+    REGISTRY
+      .by_name(v)
+      .ok_or_else(|| E::custom(format!("unknown format `{}`", v)))
+  }
+}
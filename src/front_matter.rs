@@ -1,9 +1,271 @@
-#[derive(serde::Deserialize, Debug, Default)]
+use std::{collections::BTreeMap, fmt};
+
+use extract_frontmatter::{config::Splitter, Extractor};
+use serde::{de, Deserializer, Serializer};
+use time::{
+  format_description::well_known::Rfc3339,
+  macros::format_description,
+  Date,
+  OffsetDateTime,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  TomlDe(#[from] toml::de::Error),
+  #[error(transparent)]
+  TomlSer(#[from] toml::ser::Error),
+  #[error(transparent)]
+  Yaml(#[from] serde_yaml::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+}
+
+/// Which language the front matter is written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+  /// Between `+++` lines.
+  Toml,
+  /// Between `---` lines.
+  Yaml,
+  /// A JSON object at the very start of the page.
+  Json,
+}
+
+impl Default for Dialect {
+  fn default() -> Self {
+    Self::Toml
+  }
+}
+
+impl Dialect {
+  fn detect(file: &str) -> Option<Self> {
+    let first_line = file.lines().next().map(str::trim_end);
+
+    if first_line == Some(FrontMatter::DELIMITER) {
+      Some(Self::Toml)
+    } else if first_line == Some(FrontMatter::YAML_DELIMITER) {
+      Some(Self::Yaml)
+    } else if file.starts_with('{') {
+      Some(Self::Json)
+    } else {
+      None
+    }
+  }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
 pub struct FrontMatter {
+  /// Kept so that edits are written back the same way.
+  #[serde(skip)]
+  pub dialect: Dialect,
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub title: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub categories: Option<Vec<String>>,
+  /// A short summary, used for link previews.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  /// Who wrote the page, if it isn't whoever last changed it.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub author: Option<String>,
+  /// A short name for the page, for anything that wants one that isn't its path.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub slug: Option<String>,
+  /// When the page was written - either a date, or a date and time.
+  #[serde(
+    default,
+    skip_serializing_if = "Option::is_none",
+    deserialize_with = "deserialize_date",
+    serialize_with = "serialize_date"
+  )]
+  pub date: Option<OffsetDateTime>,
+  /// Pages with a lower weight are sorted first.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub weight: Option<i64>,
+  /// The path or URL the page sends people to instead of being shown.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub redirect_to: Option<String>,
+  /// Archived pages are kept as they are - only administrators can change them.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub archived: bool,
+  /// How the page is shown - `"slides"` shows it as a slideshow.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub layout: Option<String>,
+  /// The page's format, like `"rst"`, for when its extension doesn't say.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub format: Option<String>,
+  /// Stylesheets and scripts added to the page, on top of the wiki's own - only used when the
+  /// page was last changed by someone trusted.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub extra_css: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub extra_js: Option<Vec<String>>,
+  /// Anything else, for templates to use.
+  #[serde(flatten)]
+  pub extra: BTreeMap<String, serde_json::Value>,
 }
 
 impl FrontMatter {
   pub const DELIMITER: &'static str = "+++";
+  pub const YAML_DELIMITER: &'static str = "---";
+
+  /// Splits the front matter from the rest of the page. If the front matter can't be parsed,
+  /// the default is used and the error is returned alongside, so the page can still be shown.
+  pub fn split(file: &str) -> (Self, String, Option<Error>) {
+    let dialect = match Dialect::detect(file) {
+      Some(dialect) => dialect,
+      None => return (Self::default(), file.to_string(), None),
+    };
+
+    let parsed = match dialect {
+      Dialect::Toml => {
+        let (front_matter, data) =
+          Extractor::new(Splitter::EnclosingLines(Self::DELIMITER)).extract(file);
+
+        toml::from_str(&front_matter)
+          .map(|front_matter| (front_matter, data.to_string()))
+          .map_err(|err| (Error::from(err), data.to_string()))
+      },
+      Dialect::Yaml => {
+        // A Markdown page can start with a `---` rule, so it's only front matter if there's
+        // another `---` to close it, with YAML's `key: value` pairs in between.
+        let closed = file
+          .lines()
+          .skip(1)
+          .any(|line| line.trim_end() == Self::YAML_DELIMITER);
+        if !closed {
+          return (Self::default(), file.to_string(), None);
+        }
+
+        let (front_matter, data) =
+          Extractor::new(Splitter::EnclosingLines(Self::YAML_DELIMITER)).extract(file);
+
+        if !matches!(
+          serde_yaml::from_str(&front_matter),
+          Ok(serde_yaml::Value::Mapping(_))
+        ) {
+          return (Self::default(), file.to_string(), None);
+        }
+
+        serde_yaml::from_str(&front_matter)
+          .map(|front_matter| (front_matter, data.to_string()))
+          .map_err(|err| (Error::from(err), data.to_string()))
+      },
+      Dialect::Json => {
+        let mut values = serde_json::Deserializer::from_str(file)
+          .into_iter::<serde_json::Map<String, serde_json::Value>>();
+
+        match values.next() {
+          Some(Ok(object)) => {
+            let data = file[values.byte_offset()..].trim_start_matches(['\r', '\n']);
+
+            serde_json::from_value(serde_json::Value::Object(object))
+              .map(|front_matter| (front_matter, data.to_string()))
+              .map_err(|err| (Error::from(err), data.to_string()))
+          },
+          // Plenty of pages start with a `{` without it being front matter.
+          _ => return (Self::default(), file.to_string(), None),
+        }
+      },
+    };
+
+    match parsed {
+      Ok((front_matter, data)) => (
+        Self {
+          dialect,
+          ..front_matter
+        },
+        data,
+        None,
+      ),
+      Err((err, data)) => (Self::default(), data, Some(err)),
+    }
+  }
+
+  /// The front matter as it appears at the start of a page, including any delimiters.
+  pub fn render(&self) -> Result<String, Error> {
+    let rendered = match self.dialect {
+      Dialect::Toml => format!("{0}\n{1}{0}\n", Self::DELIMITER, toml::to_string(self)?),
+      Dialect::Yaml => format!(
+        "{0}\n{1}{0}\n",
+        Self::YAML_DELIMITER,
+        serde_yaml::to_string(self)?
+      ),
+      Dialect::Json => format!("{}\n", serde_json::to_string_pretty(self)?),
+    };
+
+    Ok(rendered)
+  }
+}
+
+fn parse_date(date: &str) -> Option<OffsetDateTime> {
+  OffsetDateTime::parse(date, &Rfc3339).ok().or_else(|| {
+    Date::parse(date, format_description!("[year]-[month]-[day]"))
+      .ok()
+      .map(|date| date.midnight().assume_utc())
+  })
+}
+
+/// Accepts dates as strings, or as TOML's own dates, which come through as a map.
+fn deserialize_date<'de, D>(deserializer: D) -> Result<Option<OffsetDateTime>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  struct DateVisitor;
+
+  impl<'de> de::Visitor<'de> for DateVisitor {
+    type Value = Option<OffsetDateTime>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+      formatter.write_str("a date, like 2022-08-09 or 2022-08-09T12:00:00Z")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+      E: de::Error,
+    {
+      parse_date(v)
+        .map(Some)
+        .ok_or_else(|| E::custom(format!("`{}` isn't a date", v)))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+      A: de::MapAccess<'de>,
+    {
+      match map.next_entry::<String, String>()? {
+        Some((_, date)) => self.visit_str(&date),
+        None => Ok(None),
+      }
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+      Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+      Ok(None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+      D: Deserializer<'de>,
+    {
+      deserializer.deserialize_any(self)
+    }
+  }
+
+  deserializer.deserialize_any(DateVisitor)
+}
+
+fn serialize_date<S>(date: &Option<OffsetDateTime>, serializer: S) -> Result<S::Ok, S::Error>
+where
+  S: Serializer,
+{
+  match date.map(|date| date.format(&Rfc3339)) {
+    Some(Ok(date)) => serializer.serialize_str(&date),
+    Some(Err(err)) => Err(serde::ser::Error::custom(err)),
+    None => serializer.serialize_none(),
+  }
 }
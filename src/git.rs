@@ -1,4 +1,6 @@
 use std::{
+  cell::RefCell,
+  collections::{HashMap, VecDeque},
   ops::Deref,
   path::{Path, PathBuf},
   string::FromUtf8Error,
@@ -6,8 +8,8 @@ use std::{
 };
 
 use axum::{
-  http::StatusCode,
-  response::{Html, IntoResponse},
+  http::{header, StatusCode},
+  response::{Html, IntoResponse, Response},
 };
 use git2::{
   Cred,
@@ -18,12 +20,18 @@ use git2::{
   Repository,
   Signature,
 };
+use tokio::sync::broadcast;
 
 use crate::{
+  commit_index::CommitIndex,
   config::Config,
+  date::Dates,
+  error::{ErrorPage, Problem},
   page::{Page, PageTab},
+  recent_changes::{RecentChange, RECENT_CHANGES},
+  services::{GitService, UserService},
   template::Template,
-  user::{User, UserDb, UserKey},
+  user::{User, UserKey},
   State,
 };
 
@@ -33,17 +41,75 @@ pub enum Error {
   Git(#[from] git2::Error),
   #[error(transparent)]
   Utf8(#[from] FromUtf8Error),
+  #[error(transparent)]
+  Signing(#[from] crate::signing::Error),
+  #[error("The wiki's repository refused the push: {0}")]
+  Rejected(String),
+  #[error("Someone else has changed the same part of the page since you started editing it")]
+  Conflict,
+  #[error("The page has been deleted since you started editing it")]
+  Deleted,
+  #[error("The index has {0} staged changes - commit them first, or use --force to lose them")]
+  Staged(usize),
+  #[error("'{0}' isn't a revision")]
+  NoRevision(String),
 }
 
 impl IntoResponse for Error {
   fn into_response(self) -> axum::response::Response {
-    (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    match self {
+      Self::Conflict | Self::Deleted | Self::Rejected(_) => ErrorPage::Conflict {
+        message: self.to_string(),
+      }
+      .into_response(),
+      Self::NoRevision(_) => {
+        Problem::new(StatusCode::BAD_REQUEST, "revision", self).into_response()
+      },
+      _ => Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "git", self).into_response(),
+    }
   }
 }
 
+/// Someone who changed a page while someone else was editing it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CoAuthor {
+  pub name: String,
+  pub email: String,
+}
+
+/// A page's contents, after being merged with the changes made since editing started.
+pub struct Merged {
+  pub contents: String,
+  /// Who made the changes that were merged in, for `Co-authored-by` trailers.
+  pub co_authors: Vec<CoAuthor>,
+}
+
+/// Draft branches are named this, followed by who they belong to.
+pub const DRAFTS_PREFIX: &str = "drafts/";
+
+/// Someone's edits that are waiting to be reviewed.
+pub struct Draft {
+  pub branch: String,
+  /// Who made the most recent change.
+  pub author: Author,
+  pub date: time::OffsetDateTime,
+  /// The summary of each commit, newest first.
+  pub messages: Vec<String>,
+  /// Everything the draft changes, as a unified diff.
+  pub patch: String,
+}
+
 pub struct Git {
   repository: Arc<Mutex<Repository>>,
   config: Arc<Config>,
+  recent: Mutex<VecDeque<RecentChange>>,
+  events: broadcast::Sender<RecentChange>,
+  index: Mutex<CommitIndex>,
+  /// Whether each signed commit that's been looked at has a good signature.
+  verified: Mutex<HashMap<Oid, bool>>,
+  /// Held while pushing to the mirrors, so only one push to them happens at a time.
+  mirroring: Arc<Mutex<()>>,
+  writing: tokio::sync::Mutex<()>,
 }
 
 #[derive(serde::Serialize)]
@@ -53,23 +119,24 @@ pub enum Author {
 }
 
 impl Author {
-  pub fn from_signature(signature: &Signature, users: impl Deref<Target = UserDb>) -> Self {
-    let users = users.deref();
-
+  pub fn from_signature(signature: &Signature, users: &dyn UserService) -> Self {
     signature
       .email()
       .map(|email| {
-        let user = users.get(&UserKey::from(email.to_string()))?;
+        let user = users.blocking_get(&UserKey::from(email.to_string()))?;
 
-        Some(Author::User(user.clone()))
+        Some(Author::User(user))
       })
       .flatten()
-      .unwrap_or_else(|| {
-        let name = signature.name().unwrap_or("Unknown").to_string();
-        let email = signature.email().map(|email| email.to_string());
+      .unwrap_or_else(|| Author::non_user(signature))
+  }
 
-        Author::NonUser { name, email }
-      })
+  /// Whoever made `signature`, who isn't one of the wiki's users.
+  fn non_user(signature: &Signature) -> Self {
+    let name = signature.name().unwrap_or("Unknown").to_string();
+    let email = signature.email().map(|email| email.to_string());
+
+    Author::NonUser { name, email }
   }
 
   pub fn email(&self) -> Option<&str> {
@@ -84,19 +151,34 @@ impl Author {
 pub struct Commit {
   pub author: Author,
   pub hash: String,
-  pub date: String,
+  #[serde(with = "time::serde::rfc3339")]
+  pub date: time::OffsetDateTime,
   pub message: String,
   pub files: Vec<PathBuf>,
+  /// Whether the commit is signed by a trusted key.
+  pub verified: bool,
 }
 
 impl Commit {
   fn from_repository(
     id: Oid,
     repository: &impl Deref<Target = Repository>,
-    users: impl Deref<Target = UserDb>,
+    subdir: &Path,
+    users: &dyn UserService,
   ) -> Result<Commit, Error> {
     let commit = repository.find_commit(id)?;
+    let author = Author::from_signature(&commit.author(), users);
 
+    Self::with_author(&commit, &**repository, subdir, author)
+  }
+
+  /// For when the author is already known, so the users don't need to be looked at again.
+  fn with_author(
+    commit: &git2::Commit,
+    repository: &Repository,
+    subdir: &Path,
+    author: Author,
+  ) -> Result<Commit, Error> {
     let files = match commit.parent_count() {
       0 => Vec::new(),
       _ => {
@@ -109,7 +191,8 @@ impl Commit {
 
         diff
           .deltas()
-          .map(|delta| delta.new_file().path().unwrap().to_path_buf())
+          .filter_map(|delta| delta.new_file().path()?.strip_prefix(subdir).ok())
+          .map(Path::to_path_buf)
           .collect()
       },
     };
@@ -117,13 +200,7 @@ impl Commit {
     let message = commit.message().unwrap().to_string();
     let hash = commit.id().to_string();
 
-    let date = commit.time();
-    let date = time::OffsetDateTime::from_unix_timestamp(date.seconds()).unwrap();
-    let date = date
-      .format(&time::format_description::well_known::Rfc3339)
-      .unwrap();
-
-    let author = Author::from_signature(&commit.author(), users);
+    let date = Dates::from_timestamp(commit.time().seconds());
 
     Ok(Commit {
       author,
@@ -131,23 +208,149 @@ impl Commit {
       date,
       message,
       files,
+      verified: false,
     })
   }
 }
 
+/// How many times pushing to the wiki's repository is tried, pulling in whatever was pushed
+/// from elsewhere each time it's rejected.
+const PUSH_ATTEMPTS: usize = 3;
+
+/// The most recent changes to the pages in `subdir`, newest first.
+fn load_recent(repository: &Repository, subdir: &Path) -> Result<VecDeque<RecentChange>, Error> {
+  let mut recent = VecDeque::with_capacity(RECENT_CHANGES);
+
+  // A freshly created repository won't have a `HEAD` yet.
+  if let Ok(revwalk) = repository.revwalk().and_then(|mut revwalk| {
+    revwalk.set_sorting(git2::Sort::TIME)?;
+    revwalk.push_head()?;
+    Ok(revwalk)
+  }) {
+    for id in revwalk {
+      if recent.len() == RECENT_CHANGES {
+        break;
+      }
+
+      let commit = repository.find_commit(id?)?;
+      let change = RecentChange::from_commit(&commit, repository, subdir)?;
+
+      // In a bigger repository, only changes to the pages count.
+      if change.files.is_empty() && !subdir.as_os_str().is_empty() {
+        continue;
+      }
+
+      recent.push_back(change);
+    }
+  }
+
+  Ok(recent)
+}
+
+/// Callbacks that log in to a remote with an SSH key.
+fn credentials<'a>(public_key: Option<&'a Path>, private_key: &'a Path) -> RemoteCallbacks<'a> {
+  let mut callbacks = RemoteCallbacks::new();
+
+  callbacks.credentials(move |_, username_from_url, _| {
+    // An `https` URL might not have a user in it.
+    Cred::ssh_key(
+      username_from_url.unwrap_or("git"),
+      public_key,
+      private_key,
+      None,
+    )
+  });
+
+  callbacks
+}
+
+/// The name of the branch `HEAD` points to.
+fn branch_name(repository: &Repository) -> Result<String, Error> {
+  let head = repository.head()?;
+  let head = head.resolve()?;
+
+  let branch_name = head.shorthand().ok_or(git2::Error::new(
+    git2::ErrorCode::NotFound,
+    git2::ErrorClass::Repository,
+    "reference 'HEAD' doesn't point to a branch?",
+  ))?;
+
+  Ok(branch_name.to_string())
+}
+
+/// The remote for `mirror`, added to the repository if it isn't there, or pointed at the
+/// mirror's URL if that's changed.
+fn mirror_remote<'a>(
+  repository: &'a Repository,
+  mirror: &crate::config::Mirror,
+) -> Result<git2::Remote<'a>, Error> {
+  match repository.find_remote(&mirror.name) {
+    Ok(remote) if remote.url() == Some(mirror.url.as_str()) => Ok(remote),
+    Ok(_) => {
+      repository.remote_set_url(&mirror.name, &mirror.url)?;
+      Ok(repository.find_remote(&mirror.name)?)
+    },
+    Err(_) => Ok(repository.remote(&mirror.name, &mirror.url)?),
+  }
+}
+
+/// Pushes the current branch to `remote`. If the remote has commits that aren't here, the
+/// push is [`Error::Rejected`].
+fn push_to(
+  repository: &Repository,
+  remote: &mut git2::Remote,
+  public_key: Option<&Path>,
+  private_key: &Path,
+) -> Result<(), Error> {
+  let rejected = RefCell::new(None);
+  let mut callbacks = credentials(public_key, private_key);
+
+  callbacks.push_update_reference(|_, status| {
+    if let Some(status) = status {
+      *rejected.borrow_mut() = Some(status.to_string());
+    }
+
+    Ok(())
+  });
+
+  let mut options = git2::PushOptions::new();
+
+  options.remote_callbacks(callbacks);
+
+  let branch_name = branch_name(repository)?;
+
+  match remote.push(
+    &[format!(
+      "refs/heads/{}:refs/heads/{}",
+      branch_name, branch_name
+    )],
+    Some(&mut options),
+  ) {
+    Ok(()) => (),
+    Err(err) if err.code() == git2::ErrorCode::NotFastForward => {
+      return Err(Error::Rejected(err.message().to_string()))
+    },
+    Err(err) => return Err(err.into()),
+  }
+
+  drop(options);
+
+  match rejected.into_inner() {
+    Some(status) => Err(Error::Rejected(status)),
+    None => Ok(()),
+  }
+}
+
 impl Git {
   pub fn new(config: Arc<Config>) -> Result<Git, Error> {
-    match git2::Repository::open(&config.pages_directory) {
+    match git2::Repository::open(config.repository_directory()) {
       Ok(repository) => {
         let remotes = repository.remotes()?;
         remotes
           .iter()
           .for_each(|r| log::info!("found remote: {:?}", r));
 
-        return Ok(Git {
-          repository: Arc::new(Mutex::new(repository)),
-          config,
-        });
+        return Git::from_repository(repository, config);
       },
       Err(err)
         if (err.class(), err.code()) == (Class::Os, Code::NotFound)
@@ -159,16 +362,10 @@ impl Git {
     }
 
     // Prepare callbacks.
-    let mut callbacks = RemoteCallbacks::new();
-
-    callbacks.credentials(|_, username_from_url, _| {
-      Cred::ssh_key(
-        username_from_url.unwrap(),
-        config.pages_git.public_key.as_deref(),
-        &config.pages_git.private_key,
-        None,
-      )
-    });
+    let callbacks = credentials(
+      config.pages_git.public_key.as_deref(),
+      &config.pages_git.private_key,
+    );
 
     // Prepare builder.
     let repository = git2::build::RepoBuilder::new()
@@ -177,26 +374,321 @@ impl Git {
         opts.remote_callbacks(callbacks);
         opts
       })
-      .clone(&config.pages_git.repository, &config.pages_directory)?;
+      .clone(&config.pages_git.repository, &config.repository_directory())?;
+
+    Git::from_repository(repository, config)
+  }
+
+  fn from_repository(repository: Repository, config: Arc<Config>) -> Result<Git, Error> {
+    let recent = load_recent(&repository, &config.pages_git.subdir)?;
+
+    let (events, _) = broadcast::channel(16);
+
+    let mut index = CommitIndex::new(&config.pages_git.subdir);
+    index.update(&repository)?;
 
     Ok(Git {
       repository: Arc::new(Mutex::new(repository)),
       config,
+      recent: Mutex::new(recent),
+      events,
+      index: Mutex::new(index),
+      verified: Mutex::new(HashMap::new()),
+      mirroring: Arc::new(Mutex::new(())),
+      writing: tokio::sync::Mutex::new(()),
     })
   }
 
-  pub fn add_file(&self, path: &Path) -> Result<(), Error> {
+  /// Whether the commit is signed by a trusted key. Commits can't change, so this is only
+  /// checked once for each.
+  fn is_verified(&self, repository: &Repository, id: Oid) -> bool {
+    let signing = match &self.config.pages_git.signing {
+      Some(signing) => signing,
+      None => return false,
+    };
+
+    if let Some(verified) = self.verified.lock().unwrap().get(&id) {
+      return *verified;
+    }
+
+    let verified = match repository.extract_signature(&id, None) {
+      Ok((signature, data)) => {
+        let email = repository
+          .find_commit(id)
+          .ok()
+          .and_then(|commit| commit.author().email().map(str::to_string));
+
+        match (signature.as_str(), data.as_str()) {
+          (Some(signature), Some(data)) => signing
+            .verify(signature, data, email.as_deref())
+            .unwrap_or_else(|err| {
+              log::warn!("Couldn't check the signature of {}: {}", id, err);
+              false
+            }),
+          _ => false,
+        }
+      },
+      // The commit isn't signed.
+      Err(_) => false,
+    };
+
+    self.verified.lock().unwrap().insert(id, verified);
+
+    verified
+  }
+
+  /// Commits `tree`, as the configured committer and signed if signing is set up, and moves
+  /// `reference` along to the new commit.
+  fn create_commit(
+    &self,
+    repository: &Repository,
+    reference: &str,
+    author: &Signature,
+    message: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+  ) -> Result<Oid, Error> {
+    let committer = match &self.config.pages_git.committer {
+      Some(committer) => Signature::now(&committer.name, &committer.email)?,
+      None => author.to_owned(),
+    };
+
+    let oid = match &self.config.pages_git.signing {
+      None => repository.commit(Some(reference), author, &committer, message, tree, parents)?,
+      Some(signing) => {
+        let buffer = repository.commit_create_buffer(author, &committer, message, tree, parents)?;
+        let content = String::from_utf8(buffer.to_vec())?;
+
+        let signature = signing.sign(&content)?;
+        let oid = repository.commit_signed(&content, &signature, None)?;
+
+        // Unlike `commit`, `commit_signed` doesn't move the reference along.
+        match reference {
+          "HEAD" => {
+            repository.head()?.resolve()?.set_target(oid, message)?;
+          },
+          reference => {
+            repository.reference(reference, oid, true, message)?;
+          },
+        }
+
+        oid
+      },
+    };
+
+    Ok(oid)
+  }
+
+  /// Brings the current branch up to date with `remote`'s after a push to it was rejected, by
+  /// fetching it and replaying the commits that haven't been pushed yet on top. Each one is
+  /// committed again, so it's still signed. If they can't be replayed cleanly, the branch is
+  /// left as it was.
+  fn pull(&self, repository: &Repository, remote: &mut git2::Remote) -> Result<(), Error> {
+    let config = &self.config.pages_git;
+    let branch_name = branch_name(repository)?;
+    let tracking = format!(
+      "refs/remotes/{}/{}",
+      remote.name().unwrap_or("origin"),
+      branch_name
+    );
+
+    let mut options = git2::FetchOptions::new();
+    options.remote_callbacks(credentials(
+      config.public_key.as_deref(),
+      &config.private_key,
+    ));
+
+    remote.fetch(
+      &[format!("+refs/heads/{}:{}", branch_name, tracking)],
+      Some(&mut options),
+      None,
+    )?;
+
+    let upstream = repository.find_reference(&tracking)?.peel_to_commit()?;
+    let head = find_last_commit(repository)?;
+
+    if repository.merge_base(head.id(), upstream.id())? == upstream.id() {
+      return Ok(());
+    }
+
+    // The commits that haven't been pushed yet, oldest first. A merged draft is replayed as
+    // one commit.
+    let mut revwalk = repository.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.simplify_first_parent()?;
+    revwalk.push(head.id())?;
+    revwalk.hide(upstream.id())?;
+    let unpushed = revwalk.collect::<Result<Vec<Oid>, _>>()?;
+
+    repository
+      .head()?
+      .resolve()?
+      .set_target(upstream.id(), "pull: fast-forward")?;
+
+    for id in unpushed {
+      let commit = repository.find_commit(id)?;
+      let onto = find_last_commit(repository)?;
+      let mainline = if commit.parent_count() > 1 { 1 } else { 0 };
+
+      let mut index = repository.cherrypick_commit(&commit, &onto, mainline, None)?;
+
+      if index.has_conflicts() {
+        repository
+          .head()?
+          .resolve()?
+          .set_target(head.id(), "pull: conflict")?;
+
+        return Err(Error::Conflict);
+      }
+
+      let tree = repository.find_tree(index.write_tree_to(repository)?)?;
+
+      self.create_commit(
+        repository,
+        "HEAD",
+        &commit.author(),
+        commit.message().unwrap_or_default(),
+        &tree,
+        &[&onto],
+      )?;
+    }
+
+    // The pages on disk need to match, and the replayed commits have new hashes.
+    repository.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+    self.index.lock().unwrap().update(repository)?;
+    *self.recent.lock().unwrap() = load_recent(repository, &config.subdir)?;
+
+    // Everything that's new here - what was pulled, and the commits replayed on top of it - is
+    // passed on like any other change, so the indexes and the navigation catch up.
+    let mut revwalk = repository.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.push_head()?;
+    revwalk.hide(head.id())?;
+
+    for id in revwalk {
+      let change =
+        RecentChange::from_commit(&repository.find_commit(id?)?, repository, &config.subdir)?;
+
+      for file in &change.files {
+        crate::cache::purge(&self.config, file);
+      }
+
+      // It's fine if nobody is listening.
+      let _ = self.events.send(change);
+    }
+
+    Ok(())
+  }
+
+  /// Adds a commit that's just been made to `HEAD` to the recent changes and the index.
+  fn record(&self, repository: &Repository, oid: Oid) -> Result<(), Error> {
+    let change = RecentChange::from_commit(
+      &repository.find_commit(oid)?,
+      repository,
+      &self.config.pages_git.subdir,
+    )?;
+
+    self.index.lock().unwrap().update(repository)?;
+
+    {
+      let mut recent = self.recent.lock().unwrap();
+      recent.push_front(change.clone());
+      recent.truncate(RECENT_CHANGES);
+    }
+
+    // It's fine if nobody is listening.
+    let _ = self.events.send(change);
+
+    Ok(())
+  }
+
+  /// Where `path`, relative to the pages, is in the repository.
+  fn in_repository(&self, path: &Path) -> PathBuf {
+    self.config.pages_git.subdir.join(path)
+  }
+
+  /// The commits that touched `path`, newest first.
+  fn commits_for_path(&self, repository: &Repository, path: &Path) -> Result<Vec<Oid>, Error> {
+    let mut index = self.index.lock().unwrap();
+    index.update(repository)?;
+
+    Ok(index.paths(path).to_vec())
+  }
+
+  /// The commits made by whoever has `email`, newest first.
+  fn commits_by_author(&self, repository: &Repository, email: &str) -> Result<Vec<Oid>, Error> {
+    let mut index = self.index.lock().unwrap();
+    index.update(repository)?;
+
+    Ok(index.authors(email).to_vec())
+  }
+
+  /// The commits `ids`, which were all made by `user`, skipping the first `skip`.
+  fn authored_commits(
+    &self,
+    repository: &Repository,
+    ids: &[Oid],
+    user: &UserKey,
+    skip: usize,
+    limit: Option<usize>,
+    users: &dyn UserService,
+  ) -> Result<Vec<Commit>, Error> {
+    // Every commit has the same author, so they only need looking up once.
+    let known = users.blocking_get(user);
+
+    ids
+      .iter()
+      .skip(skip)
+      .take(limit.unwrap_or(usize::MAX))
+      .map(|id| {
+        let commit = repository.find_commit(*id)?;
+        let author = match &known {
+          Some(user) => Author::User(user.clone()),
+          None => Author::from_signature(&commit.author(), users),
+        };
+
+        Commit::with_author(&commit, repository, &self.config.pages_git.subdir, author)
+      })
+      .collect()
+  }
+}
+
+impl GitService for Git {
+  fn recent_changes(&self) -> Vec<RecentChange> {
+    self.recent.lock().unwrap().iter().cloned().collect()
+  }
+
+  fn subscribe(&self) -> broadcast::Receiver<RecentChange> {
+    self.events.subscribe()
+  }
+
+  fn writing(&self) -> &tokio::sync::Mutex<()> {
+    &self.writing
+  }
+
+  fn add_file(&self, path: &Path) -> Result<(), Error> {
+    let repository = self.repository.lock().unwrap();
+
+    let mut index = repository.index()?;
+
+    index.add_path(&self.in_repository(path))?;
+    index.write()?;
+
+    Ok(())
+  }
+
+  fn remove_file(&self, path: &Path) -> Result<(), Error> {
     let repository = self.repository.lock().unwrap();
 
     let mut index = repository.index()?;
 
-    index.add_path(path)?;
+    index.remove_path(&self.in_repository(path))?;
     index.write()?;
 
     Ok(())
   }
 
-  pub fn commit(&self, subject: &str, user: &User) -> Result<(), Error> {
+  fn commit(&self, subject: &str, user: &User) -> Result<(), Error> {
     let repository = self.repository.lock().unwrap();
 
     let mut index = repository.index()?;
@@ -208,212 +700,939 @@ impl Git {
     let parent_commit = find_last_commit(&repository)?;
     let tree = repository.find_tree(oid)?;
 
-    repository.commit(
-      Some("HEAD"),      // point HEAD to our new commit
-      &user,             // author
-      &user,             // committer
-      subject,           // commit message
-      &tree,             // tree
-      &[&parent_commit], // parent commit
+    let oid = self.create_commit(
+      &repository,
+      "HEAD",
+      &user,
+      subject,
+      &tree,
+      &[&parent_commit],
     )?;
 
-    Ok(())
+    self.record(&repository, oid)
   }
 
-  pub fn push(&self) -> Result<(), Error> {
+  fn push(&self) -> Result<(), Error> {
     let repository = self.repository.lock().unwrap();
+    let config = &self.config.pages_git;
+
+    let mut origin = repository.find_remote("origin")?;
+    let mut attempt = 1;
+
+    // Someone may have pushed to the repository from elsewhere.
+    loop {
+      let pushed = push_to(
+        &repository,
+        &mut origin,
+        config.public_key.as_deref(),
+        &config.private_key,
+      );
+
+      match pushed {
+        Err(Error::Rejected(status)) if attempt < PUSH_ATTEMPTS => {
+          log::warn!(
+            "Pushing was rejected ({}), pulling and trying again",
+            status
+          );
+
+          self.pull(&repository, &mut origin)?;
+          attempt += 1;
+        },
+        pushed => break pushed?,
+      }
+    }
 
-    dbg!(repository.head()?.resolve()?.shorthand());
+    drop(repository);
+    self.push_to_mirrors();
 
-    let mut remote = repository.find_remote("origin")?;
+    Ok(())
+  }
 
-    let mut callbacks = RemoteCallbacks::new();
+  /// Pushes to each of the mirrors in the background, with the mirrors' own handle on the
+  /// repository, so a slow mirror doesn't hold up anyone saving. The wiki's own repository is
+  /// the one that matters - a mirror being down shouldn't stop anyone saving, so each one's
+  /// errors are only logged.
+  fn push_to_mirrors(&self) {
+    if self.config.pages_git.mirrors.is_empty() {
+      return;
+    }
 
-    callbacks.credentials(|_, username_from_url, _| {
-      Cred::ssh_key(
-        username_from_url.unwrap(),
-        self.config.pages_git.public_key.as_deref(),
-        &self.config.pages_git.private_key,
-        None,
-      )
-    });
+    let config = self.config.clone();
+    let mirroring = self.mirroring.clone();
+
+    std::thread::spawn(move || {
+      let _mirroring = mirroring.lock().unwrap();
+      let git = &config.pages_git;
+
+      let repository = match Repository::open(config.repository_directory()) {
+        Ok(repository) => repository,
+        Err(err) => {
+          log::error!(
+            "Couldn't open the repository to push to the mirrors: {}",
+            err
+          );
+          return;
+        },
+      };
+
+      for mirror in &git.mirrors {
+        let pushed = mirror_remote(&repository, mirror).and_then(|mut remote| {
+          push_to(
+            &repository,
+            &mut remote,
+            mirror.public_key.as_deref().or(git.public_key.as_deref()),
+            mirror.private_key.as_deref().unwrap_or(&git.private_key),
+          )
+        });
 
-    callbacks.push_update_reference(|_, status| match status {
-      Some(err) => Err(git2::Error::new(
-        git2::ErrorCode::GenericError,
-        git2::ErrorClass::Repository,
-        dbg!(err),
-      )),
-      None => Ok(()),
+        if let Err(err) = pushed {
+          log::error!("Couldn't push to the mirror '{}': {}", mirror.name, err);
+        }
+      }
     });
+  }
 
-    let mut options = git2::PushOptions::new();
+  fn get_file(&self, path: &Path, commit: git2::Oid) -> Result<String, Error> {
+    let repository = self.repository.lock().unwrap();
 
-    options.remote_callbacks(callbacks);
+    let path = path.strip_prefix(&self.config.pages_directory).unwrap();
+    let path = self.in_repository(path);
 
-    let head = repository.head()?;
-    let head = head.resolve()?;
+    let commit = repository.find_commit(commit)?;
 
-    let branch_name = head.shorthand().ok_or(git2::Error::new(
-      git2::ErrorCode::NotFound,
-      git2::ErrorClass::Repository,
-      "reference 'HEAD' doesn't point to a branch?",
-    ))?;
+    let blob = commit.tree()?.get_path(&path)?.to_object(&repository)?;
+    let blob = blob.as_blob().unwrap().content().to_vec();
 
-    remote.push(
-      &[format!(
-        "refs/heads/{}:refs/heads/{}",
-        branch_name, branch_name
-      )],
-      Some(&mut options),
-    )?;
+    let contents = String::from_utf8(blob)?;
 
-    Ok(())
+    Ok(contents)
   }
 
-  pub fn get_file(&self, path: &Path, commit: git2::Oid) -> Result<String, Error> {
+  fn file_history(&self, path: &Path, users: &dyn UserService) -> Result<Vec<Commit>, Error> {
     let repository = self.repository.lock().unwrap();
+    let subdir = &self.config.pages_git.subdir;
+
+    // Pages are mostly written by a few people, so each of them is only looked up once.
+    let mut known: HashMap<String, Option<User>> = HashMap::new();
+
+    self
+      .commits_for_path(&repository, path)?
+      .into_iter()
+      .map(|id| {
+        let found = repository.find_commit(id)?;
+        let signature = found.author();
+
+        let user = signature.email().and_then(|email| {
+          known
+            .entry(email.to_string())
+            .or_insert_with(|| users.blocking_get(&UserKey::from(email.to_string())))
+            .clone()
+        });
+        let author = match user {
+          Some(user) => Author::User(user),
+          None => Author::non_user(&signature),
+        };
 
-    let path = path.strip_prefix(&self.config.pages_directory).unwrap();
+        let mut commit = Commit::with_author(&found, &repository, subdir, author)?;
+        commit.verified = self.is_verified(&repository, id);
+
+        Ok(commit)
+      })
+      .collect()
+  }
+
+  fn last_commit(&self, path: &Path, users: &dyn UserService) -> Result<Option<Commit>, Error> {
+    let repository = self.repository.lock().unwrap();
+
+    match self.commits_for_path(&repository, path)?.first() {
+      Some(id) => Ok(Some(Commit::from_repository(
+        *id,
+        &repository,
+        &self.config.pages_git.subdir,
+        users,
+      )?)),
+      None => Ok(None),
+    }
+  }
+
+  fn last_commits(
+    &self,
+    paths: &[PathBuf],
+    users: &dyn UserService,
+  ) -> Result<Vec<Option<Commit>>, Error> {
+    let repository = self.repository.lock().unwrap();
+    let mut index = self.index.lock().unwrap();
+    index.update(&repository)?;
+
+    paths
+      .iter()
+      .map(|path| {
+        index
+          .paths(path)
+          .first()
+          .map(|id| Commit::from_repository(*id, &repository, &self.config.pages_git.subdir, users))
+          .transpose()
+      })
+      .collect()
+  }
+
+  fn last_author_email(&self, path: &Path) -> Result<Option<String>, Error> {
+    let repository = self.repository.lock().unwrap();
+
+    let email = match self.commits_for_path(&repository, path)?.first() {
+      Some(id) => {
+        let commit = repository.find_commit(*id)?;
+        let author = commit.author();
+
+        author.email().map(|email| email.to_string())
+      },
+      None => None,
+    };
+
+    Ok(email)
+  }
+
+  fn author_email(&self, commit: Oid) -> Result<Option<String>, Error> {
+    let repository = self.repository.lock().unwrap();
 
     let commit = repository.find_commit(commit)?;
+    let email = commit.author().email().map(|email| email.to_string());
 
-    let blob = commit.tree()?.get_path(path)?.to_object(&repository)?;
-    let blob = blob.as_blob().unwrap().content().to_vec();
+    Ok(email)
+  }
 
-    let contents = String::from_utf8(blob)?;
+  fn author_emails(&self, path: &Path, commit: Option<Oid>) -> Result<Vec<Option<String>>, Error> {
+    let repository = self.repository.lock().unwrap();
 
-    Ok(contents)
+    let mut emails = Vec::new();
+    for id in self.commits_for_path(&repository, path)? {
+      let written = match commit {
+        Some(commit) => id == commit || repository.graph_descendant_of(commit, id)?,
+        None => true,
+      };
+
+      if written {
+        let email = repository
+          .find_commit(id)?
+          .author()
+          .email()
+          .map(str::to_string);
+
+        if !emails.contains(&email) {
+          emails.push(email);
+        }
+      }
+    }
+
+    Ok(emails)
   }
 
-  pub fn file_history(&self, path: &Path, state: &State) -> Result<Vec<Commit>, Error> {
+  fn parent(&self, commit: Oid) -> Result<Option<Oid>, Error> {
     let repository = self.repository.lock().unwrap();
 
-    let mut revwalk = repository.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TIME)?;
-    revwalk.push_head()?;
+    let commit = repository.find_commit(commit)?;
 
-    let mut commits = Vec::new();
+    Ok(commit.parent_ids().next())
+  }
 
-    for id in revwalk {
-      let id = id?;
-      let users = state.users.lock().unwrap();
-      let commit = Commit::from_repository(id, &repository, users)?;
-
-      if commit
-        .files
-        .iter()
-        .find(|commit_path| **commit_path == path)
-        .is_some()
-      {
-        commits.push(commit);
+  fn export_history(&self, path: &Path) -> Result<Vec<u8>, Error> {
+    let repository = self.repository.lock().unwrap();
+    let ids = self.commits_for_path(&repository, path)?;
+    let path = self.in_repository(path);
+
+    let mut patches = Vec::new();
+
+    for id in ids.into_iter().rev() {
+      let commit = repository.find_commit(id)?;
+
+      let tree = commit.tree()?;
+      let parent_tree = match commit.parent_count() {
+        0 => None,
+        _ => Some(commit.parent(0)?.tree()?),
+      };
+
+      // Binary pages, like spreadsheets, need their contents in the patch to be applied.
+      let mut options = git2::DiffOptions::new();
+      options.pathspec(&path).show_binary(true);
+
+      let diff =
+        repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(&mut options))?;
+
+      if diff.deltas().len() > 0 {
+        patches.push((commit, diff));
       }
     }
 
-    Ok(commits)
+    let total = patches.len();
+    let mut series = Vec::new();
+
+    for (n, (commit, diff)) in patches.iter().enumerate() {
+      series.extend(format_patch(commit, diff, n + 1, total)?);
+    }
+
+    Ok(series)
   }
 
-  pub fn user_history(
-    &self,
-    user: &UserKey,
-    limit: Option<usize>,
-    state: &State,
-  ) -> Result<Vec<Commit>, Error> {
+  fn merge(&self, path: &Path, base: Oid, contents: &str) -> Result<Merged, Error> {
     let repository = self.repository.lock().unwrap();
 
-    let mut revwalk = repository.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TIME)?;
-    revwalk.push_head()?;
+    let changes: Vec<_> = self
+      .commits_for_path(&repository, path)?
+      .into_iter()
+      .take_while(|id| *id != base)
+      .collect();
+
+    // Nobody else has changed the page.
+    if changes.is_empty() {
+      return Ok(Merged {
+        contents: contents.to_string(),
+        co_authors: Vec::new(),
+      });
+    }
 
-    let mut commits = Vec::new();
+    let path = self.in_repository(path);
+    let ancestor = repository.find_commit(base)?.tree()?;
+    let theirs = find_last_commit(&repository)?.tree()?;
 
-    for id in revwalk {
-      match limit {
-        Some(limit) if limit == commits.len() => return Ok(commits),
-        _ => (),
+    let ours = {
+      let mut index = git2::Index::new()?;
+      index.read_tree(&theirs)?;
+
+      let entry = index.get_path(&path, 0).ok_or(Error::Deleted)?;
+      index.add_frombuffer(&entry, contents.as_bytes())?;
+
+      repository.find_tree(index.write_tree_to(&repository)?)?
+    };
+
+    let merged = repository.merge_trees(&ancestor, &ours, &theirs, None)?;
+
+    if merged.has_conflicts() {
+      return Err(Error::Conflict);
+    }
+
+    let entry = merged.get_path(&path, 0).ok_or(Error::Deleted)?;
+    let contents = String::from_utf8(repository.find_blob(entry.id)?.content().to_vec())?;
+
+    let mut co_authors = Vec::new();
+
+    for id in changes {
+      let commit = repository.find_commit(id)?;
+      let author = commit.author();
+
+      let co_author = CoAuthor {
+        name: author.name().unwrap_or("Unknown").to_string(),
+        email: author.email().unwrap_or("").to_string(),
+      };
+
+      if !co_authors.contains(&co_author) {
+        co_authors.push(co_author);
       }
+    }
+
+    Ok(Merged {
+      contents,
+      co_authors,
+    })
+  }
+
+  fn compare(&self, path: &Path, from: Oid, to: Oid) -> Result<String, Error> {
+    let repository = self.repository.lock().unwrap();
+
+    let tree = |id: Oid| match repository.find_commit(id) {
+      Ok(commit) => Ok(commit.tree()?),
+      Err(err) if err.code() == git2::ErrorCode::NotFound => Err(Error::NoRevision(id.to_string())),
+      Err(err) => Err(Error::from(err)),
+    };
+
+    let from = tree(from)?;
+    let to = tree(to)?;
+
+    let mut options = git2::DiffOptions::new();
+    options.pathspec(self.in_repository(path));
+
+    let diff = repository.diff_tree_to_tree(Some(&from), Some(&to), Some(&mut options))?;
+
+    // The page is already known, so the `diff --git` lines would only be noise.
+    patch_text(&diff, false)
+  }
+
+  fn commit_draft(
+    &self,
+    branch: &str,
+    path: &Path,
+    contents: &str,
+    subject: &str,
+    user: &User,
+  ) -> Result<(), Error> {
+    let repository = self.repository.lock().unwrap();
+
+    let reference = format!("refs/heads/{}", branch);
+    let path = self.in_repository(path);
+    let head = find_last_commit(&repository)?;
 
-      let id = id?;
-      let users = state.users.lock().unwrap();
-      let commit = Commit::from_repository(id, &repository, users)?;
+    // A new draft starts from the wiki as it is now.
+    let (parent, contents) = match repository.find_reference(&reference) {
+      Ok(reference) => {
+        let parent = reference.peel_to_commit()?;
 
-      match commit.author.email() {
-        Some(email) if email == user.email() => commits.push(commit),
-        _ => (),
+        // The edit was made to the page as it is now, so it's merged with any earlier changes
+        // to it in the draft, rather than replacing them. Only the page itself is merged, so the
+        // draft being behind the wiki elsewhere doesn't matter.
+        let base = repository.find_commit(repository.merge_base(head.id(), parent.id())?)?;
+        let ancestor = only_file(&repository, &path, file_at(&repository, &base, &path)?)?;
+        let drafted = only_file(&repository, &path, file_at(&repository, &parent, &path)?)?;
+        let edited = only_file(&repository, &path, Some(contents.as_bytes().to_vec()))?;
+
+        let merged = repository.merge_trees(&ancestor, &drafted, &edited, None)?;
+
+        if merged.has_conflicts() {
+          return Err(Error::Conflict);
+        }
+
+        let entry = merged.get_path(&path, 0).ok_or(Error::Deleted)?;
+        let contents = String::from_utf8(repository.find_blob(entry.id)?.content().to_vec())?;
+
+        (parent, contents)
+      },
+      Err(err) if err.code() == Code::NotFound => (head, contents.to_string()),
+      Err(err) => return Err(err.into()),
+    };
+
+    let tree = {
+      let mut index = git2::Index::new()?;
+      index.read_tree(&parent.tree()?)?;
+
+      let entry = index.get_path(&path, 0).unwrap_or_else(|| new_entry(&path));
+      index.add_frombuffer(&entry, contents.as_bytes())?;
+
+      repository.find_tree(index.write_tree_to(&repository)?)?
+    };
+
+    let author = Signature::now(&user.name, &user.email)?;
+    self.create_commit(&repository, &reference, &author, subject, &tree, &[&parent])?;
+
+    Ok(())
+  }
+
+  fn drafts(&self, users: &dyn UserService) -> Result<Vec<Draft>, Error> {
+    let repository = self.repository.lock().unwrap();
+
+    let head = find_last_commit(&repository)?;
+    let mut drafts = Vec::new();
+
+    for branch in repository.branches(Some(git2::BranchType::Local))? {
+      let (branch, _) = branch?;
+
+      let name = match branch.name()? {
+        Some(name) if name.starts_with(DRAFTS_PREFIX) => name.to_string(),
+        _ => continue,
       };
+
+      let tip = branch.get().peel_to_commit()?;
+      let base = repository.merge_base(head.id(), tip.id())?;
+
+      // Everything in it has already been merged.
+      if base == tip.id() {
+        continue;
+      }
+
+      let mut revwalk = repository.revwalk()?;
+      revwalk.push(tip.id())?;
+      revwalk.hide(base)?;
+
+      let messages = revwalk
+        .map(|id| {
+          let commit = repository.find_commit(id?)?;
+          Ok(commit.summary().unwrap_or("").to_string())
+        })
+        .collect::<Result<_, Error>>()?;
+
+      let base_tree = repository.find_commit(base)?.tree()?;
+      let diff = repository.diff_tree_to_tree(Some(&base_tree), Some(&tip.tree()?), None)?;
+
+      drafts.push(Draft {
+        branch: name,
+        author: Author::from_signature(&tip.author(), users),
+        date: Dates::from_timestamp(tip.time().seconds()),
+        messages,
+        patch: patch_text(&diff, true)?,
+      });
     }
 
-    Ok(commits)
+    Ok(drafts)
   }
 
-  pub async fn history_handler(
-    self: Arc<Self>,
-    page: &Page,
-    revision: String,
-    state: Arc<State>,
-  ) -> Result<Html<String>, crate::page::Error> {
-    let oid = git2::Oid::from_str(&revision).map_err(Error::Git)?;
-    let file = state.git.get_file(&page.filepath, oid)?;
+  fn merge_draft(&self, branch: &str, user: &User) -> Result<Vec<PathBuf>, Error> {
+    let repository = self.repository.lock().unwrap();
+
+    let mut draft = repository.find_branch(branch, git2::BranchType::Local)?;
+    let tip = draft.get().peel_to_commit()?;
+    let head = find_last_commit(&repository)?;
+
+    let mut index = repository.merge_commits(&head, &tip, None)?;
+
+    if index.has_conflicts() {
+      return Err(Error::Conflict);
+    }
+
+    let tree = repository.find_tree(index.write_tree_to(&repository)?)?;
 
-    let mut renderer = page.renderer_with(&file, state).await?;
-    renderer.context_mut().revision = Some(revision);
+    let files = repository
+      .diff_tree_to_tree(Some(&head.tree()?), Some(&tree), None)?
+      .deltas()
+      .filter_map(|delta| {
+        let path = delta.new_file().path()?;
+        path.strip_prefix(&self.config.pages_git.subdir).ok()
+      })
+      .map(Path::to_path_buf)
+      .collect();
+
+    let author = Signature::now(&user.name, &user.email)?;
+    let message = format!("Merge {}", branch);
+
+    let oid = self.create_commit(
+      &repository,
+      "HEAD",
+      &author,
+      &message,
+      &tree,
+      &[&head, &tip],
+    )?;
+
+    // The pages on disk need to match what was merged.
+    repository.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+    draft.delete()?;
 
-    let html = renderer.render().await?;
+    self.record(&repository, oid)?;
 
-    Ok(html)
+    Ok(files)
   }
 
-  pub async fn history_listing_handler(
-    self: Arc<Self>,
-    page: &Page,
-    state: Arc<State>,
-  ) -> Result<Html<String>, crate::page::Error> {
-    let (context, _) = page.context().await?;
+  fn delete_draft(&self, branch: &str) -> Result<(), Error> {
+    let repository = self.repository.lock().unwrap();
 
-    let path = page
-      .filepath
-      .canonicalize()?
-      .strip_prefix(&self.config.pages_directory)?
-      .to_owned();
+    repository
+      .find_branch(branch, git2::BranchType::Local)?
+      .delete()?;
 
-    let commits = tokio::task::spawn_blocking(move || self.file_history(&path, &state))
-      .await
-      .unwrap()?;
-
-    let content = maud::html! {
-      ol #commits {
-        @for commit in commits {
-          li {
-            .date { (commit.date) }
-            .author {
-              @match commit.author {
-                Author::User(user) => {
-                  a href={ "/meta/profile/" (user.email) } {
-                    (user.name) "⟨" (user.email) "⟩"
-                  }
-                },
-                Author::NonUser { name, email } => {
-                  (name) @if let Some(email) = email { "⟨" (email) "⟩" }
-                },
-              }
+    Ok(())
+  }
+
+  fn user_history(
+    &self,
+    user: &UserKey,
+    skip: usize,
+    limit: Option<usize>,
+    users: &dyn UserService,
+  ) -> Result<Vec<Commit>, Error> {
+    let repository = self.repository.lock().unwrap();
+    let ids = self.commits_by_author(&repository, user.email())?;
+
+    self.authored_commits(&repository, &ids, user, skip, limit, users)
+  }
+
+  fn user_contributions(
+    &self,
+    user: &UserKey,
+    skip: usize,
+    limit: usize,
+    users: &dyn UserService,
+  ) -> Result<(usize, Vec<Commit>), Error> {
+    let repository = self.repository.lock().unwrap();
+    let ids = self.commits_by_author(&repository, user.email())?;
+    let commits = self.authored_commits(&repository, &ids, user, skip, Some(limit), users)?;
+
+    Ok((ids.len(), commits))
+  }
+}
+
+pub async fn history_handler(
+  page: &Page,
+  revision: String,
+  state: Arc<State>,
+) -> Result<Html<String>, crate::page::Error> {
+  let oid = git2::Oid::from_str(&revision).map_err(Error::Git)?;
+  let file = state.git.get_file(&page.filepath, oid)?;
+
+  let authors = {
+    let path = page.relative_path(&state.config())?;
+
+    state.git.author_emails(&path, Some(oid))?
+  };
+  let trusted = state
+    .config()
+    .sanitize_html
+    .is_trusted_authors(&authors, &state)
+    .await;
+
+  let mut renderer = page.renderer_with(&file, state.clone()).await?;
+  renderer.context_mut().revision = Some(revision);
+
+  if !trusted {
+    renderer.sanitize();
+  }
+
+  let html = renderer.render(&state).await?;
+
+  Ok(html)
+}
+
+pub async fn export_history_handler(
+  page: &Page,
+  state: Arc<State>,
+) -> Result<Response, crate::page::Error> {
+  let path = page
+    .filepath
+    .canonicalize()?
+    .strip_prefix(&state.config().pages_directory)?
+    .to_owned();
+
+  // It's quoted in the `Content-Disposition` header.
+  let filename = match path.file_name() {
+    Some(name) => format!("{}.patch", name.to_string_lossy())
+      .replace('\\', "\\\\")
+      .replace('"', "\\\""),
+    None => String::from("history.patch"),
+  };
+
+  let series = tokio::task::spawn_blocking(move || state.git.export_history(&path))
+    .await
+    .unwrap()?;
+
+  let response = (
+    [
+      (header::CONTENT_TYPE, String::from("text/plain; charset=utf-8")),
+      (
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", filename),
+      ),
+    ],
+    series,
+  )
+    .into_response();
+
+  Ok(response)
+}
+
+pub async fn history_listing_handler(
+  page: &Page,
+  state: Arc<State>,
+) -> Result<Html<String>, crate::page::Error> {
+  let (context, _) = page.context(&state).await?;
+
+  let path = page
+    .filepath
+    .canonicalize()?
+    .strip_prefix(&state.config().pages_directory)?
+    .to_owned();
+
+  let commits = tokio::task::spawn_blocking({
+    let state = state.clone();
+    move || state.git.file_history(&path, &*state.users)
+  })
+  .await
+  .unwrap()?;
+
+  let config = state.config();
+  let dates = Dates::new(context.user.as_ref(), &config.dates);
+
+  let content = maud::html! {
+    ol #commits {
+      @for (i, commit) in commits.iter().enumerate() {
+        li {
+          @if commits.len() > 1 {
+            .compare {
+              input type="checkbox" value=(commit.hash) title="Compare" checked[i < 2];
             }
-            .message {
-              a href={"/" (context.path) "?revision=" (commit.hash)} { (commit.message) }
+          }
+          .date { (dates.render(commit.date)) }
+          .author {
+            @match &commit.author {
+              Author::User(user) => {
+                a href={ "/meta/profile/" (user.email) } {
+                  (user.name) "⟨" (user.email) "⟩"
+                }
+              },
+              Author::NonUser { name, email } => {
+                (name) @if let Some(email) = email { "⟨" (email) "⟩" }
+              },
+            }
+          }
+          .message {
+            a href={"/" (context.path) "?revision=" (commit.hash)} { (commit.message) }
+            @if commit.verified {
+              " " span .badge.verified title="Signed by a trusted key" { "verified" }
             }
           }
         }
       }
-    };
+    }
+  };
+
+  let content = maud::html! {
+    a href={ "/meta/export-history/" (context.path) } { "export history as patches" }
+    form #compare method="get" action={ "/meta/compare/" (context.path) } {
+      @if commits.len() > 1 {
+        // The two newest revisions, until others are picked.
+        input type="hidden" name="a" value=(commits[1].hash);
+        input type="hidden" name="b" value=(commits[0].hash);
+        button type="submit" { "Compare selected revisions" }
+      }
+      (content)
+    }
+  };
+
+  // Only two revisions can be picked, and `a` is always the older of them.
+  let script = r#"
+    const form = document.getElementById('compare');
+    const boxes = [...form.querySelectorAll('.compare input')];
+    let picked = boxes.filter((box) => box.checked);
+
+    for (const box of boxes) {
+      box.addEventListener('change', () => {
+        picked = picked.filter((other) => other !== box);
+        if (box.checked) {
+          picked.push(box);
+        }
+        while (picked.length > 2) {
+          picked.shift().checked = false;
+        }
+
+        const [newer, older] = [...picked].sort((x, y) => boxes.indexOf(x) - boxes.indexOf(y));
+        form.elements.b.value = newer ? newer.value : '';
+        form.elements.a.value = older ? older.value : '';
+        form.querySelector('button').disabled = picked.length < 2;
+      });
+    }
+  "#;
+
+  let tabs = PageTab::History.render(context.path);
 
-    let tabs = PageTab::History.render(context.path);
+  let html = Template::new()
+    .tabs(tabs)
+    .content(content)
+    .script(script)
+    .title(maud::html! { (context.title) " - History" })
+    .render(context.user, &state);
 
-    let html = Template::new()
-      .tabs(tabs)
-      .content(content)
-      .title(maud::html! { (context.title) " - History" })
-      .render(context.user);
+  Ok(html)
+}
+
+pub async fn compare_handler(
+  page: &Page,
+  from: String,
+  to: String,
+  state: Arc<State>,
+) -> Result<Html<String>, crate::page::Error> {
+  let (context, _) = page.context(&state).await?;
+
+  let path = page
+    .filepath
+    .canonicalize()?
+    .strip_prefix(&state.config().pages_directory)?
+    .to_owned();
+
+  let patch = {
+    let revision =
+      |hash: &str| git2::Oid::from_str(hash).map_err(|_| Error::NoRevision(hash.to_string()));
+    let from = revision(&from)?;
+    let to = revision(&to)?;
+    let state = state.clone();
+
+    tokio::task::spawn_blocking(move || state.git.compare(&path, from, to))
+      .await
+      .unwrap()?
+  };
+
+  let short = |hash: &str| hash.chars().take(7).collect::<String>();
+
+  let content = maud::html! {
+    p {
+      "Changes from "
+      a href={ "/" (context.path) "?revision=" (from) } { code { (short(&from)) } }
+      " to "
+      a href={ "/" (context.path) "?revision=" (to) } { code { (short(&to)) } }
+    }
+    @if patch.is_empty() {
+      "There are no differences between these revisions."
+    } @else {
+      (render_diff(&patch))
+    }
+  };
+
+  let tabs = PageTab::History.render(&context.path);
+
+  let html = Template::new()
+    .tabs(tabs)
+    .breadcrumbs(&context.path)
+    .content(content)
+    .title(maud::html! { (context.title) " - Compare" })
+    .render(context.user, &state);
+
+  Ok(html)
+}
+
+/// Resets the pages repository's index - what's staged for the next commit - to match its
+/// latest commit, and returns how many files are in it. Anything staged in it would be lost, so
+/// that's refused unless `force`.
+pub fn rebuild_index(config: &Config, force: bool) -> Result<usize, Error> {
+  let repository = Repository::open(config.repository_directory())?;
+  let tree = find_last_commit(&repository)?.tree()?;
+
+  if !force {
+    let index = repository.index()?;
+    let staged = repository
+      .diff_tree_to_index(Some(&tree), Some(&index), None)?
+      .deltas()
+      .len();
+
+    if staged > 0 {
+      return Err(Error::Staged(staged));
+    }
+  }
+
+  let mut index = repository.index()?;
+  index.clear()?;
+  index.read_tree(&tree)?;
+  index.write()?;
+
+  Ok(index.len())
+}
+
+/// A unified diff, with the added and removed lines marked.
+pub fn render_diff(patch: &str) -> maud::Markup {
+  maud::html! {
+    pre .diff {
+      @for line in patch.lines() {
+        @if line.starts_with('+') {
+          ins { (line) } "\n"
+        } @else if line.starts_with('-') {
+          del { (line) } "\n"
+        } @else {
+          (line) "\n"
+        }
+      }
+    }
+  }
+}
+
+/// One commit of a `git format-patch` series. The changes are kept byte for byte, since a
+/// page doesn't have to be UTF-8.
+fn format_patch(
+  commit: &git2::Commit,
+  diff: &git2::Diff,
+  n: usize,
+  total: usize,
+) -> Result<Vec<u8>, Error> {
+  let author = commit.author();
+
+  let when = author.when();
+  let offset = time::UtcOffset::from_whole_seconds(when.offset_minutes() * 60).unwrap();
+  let date = time::OffsetDateTime::from_unix_timestamp(when.seconds())
+    .unwrap()
+    .to_offset(offset)
+    .format(&time::format_description::well_known::Rfc2822)
+    .unwrap();
+
+  let subject = commit.summary().unwrap_or("");
+  let body = commit.body().unwrap_or("");
+
+  let mut header = format!(
+    "From {} Mon Sep 17 00:00:00 2001\nFrom: {} <{}>\nDate: {}\nSubject: [PATCH {}/{}] {}\n\n",
+    commit.id(),
+    author.name().unwrap_or("Unknown"),
+    author.email().unwrap_or(""),
+    date,
+    n,
+    total,
+    subject,
+  );
+
+  if !body.is_empty() {
+    header.push_str(body.trim_end());
+    header.push('\n');
+  }
+
+  header.push_str("---\n");
+
+  let mut patch = header.into_bytes();
+
+  diff.print(git2::DiffFormat::Patch, |_, _, line| {
+    if let '+' | '-' | ' ' = line.origin() {
+      patch.push(line.origin() as u8);
+    }
+    patch.extend_from_slice(line.content());
+    true
+  })?;
+
+  patch.extend_from_slice(b"--\n\n");
+
+  Ok(patch)
+}
+
+/// The text of a patch. `headers` keeps the `diff --git` lines, which say which file each
+/// change is in.
+fn patch_text(diff: &git2::Diff, headers: bool) -> Result<String, Error> {
+  let mut patch = String::new();
+
+  diff.print(git2::DiffFormat::Patch, |_, _, line| {
+    if !headers && line.origin_value() == git2::DiffLineType::FileHeader {
+      return true;
+    }
+
+    if let '+' | '-' | ' ' = line.origin() {
+      patch.push(line.origin());
+    }
+    patch.push_str(&String::from_utf8_lossy(line.content()));
+    true
+  })?;
+
+  Ok(patch)
+}
+
+/// What's in the file at `path` as of `commit`, if it's there.
+fn file_at(
+  repository: &git2::Repository,
+  commit: &git2::Commit,
+  path: &Path,
+) -> Result<Option<Vec<u8>>, Error> {
+  match commit.tree()?.get_path(path) {
+    Ok(entry) => Ok(Some(repository.find_blob(entry.id())?.content().to_vec())),
+    Err(err) if err.code() == Code::NotFound => Ok(None),
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// A tree with nothing in it but `contents` at `path`, or nothing at all.
+fn only_file<'r>(
+  repository: &'r git2::Repository,
+  path: &Path,
+  contents: Option<Vec<u8>>,
+) -> Result<git2::Tree<'r>, Error> {
+  let mut index = git2::Index::new()?;
+
+  if let Some(contents) = contents {
+    index.add_frombuffer(&new_entry(path), &contents)?;
+  }
+
+  Ok(repository.find_tree(index.write_tree_to(repository)?)?)
+}
 
-    Ok(html)
+/// An index entry for a file that isn't in the repository yet.
+fn new_entry(path: &Path) -> git2::IndexEntry {
+  git2::IndexEntry {
+    ctime: git2::IndexTime::new(0, 0),
+    mtime: git2::IndexTime::new(0, 0),
+    dev: 0,
+    ino: 0,
+    mode: 0o100644,
+    uid: 0,
+    gid: 0,
+    file_size: 0,
+    id: Oid::zero(),
+    flags: 0,
+    flags_extended: 0,
+    path: path.to_string_lossy().as_bytes().to_vec(),
   }
 }
 
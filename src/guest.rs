@@ -0,0 +1,235 @@
+//! Edits from people who aren't logged in. When `guest_editing` is on, anyone can suggest a
+//! change to a page at `/meta/guest-edit/<page>`, which is committed to a draft branch for the
+//! page and waits at `/meta/drafts` until an administrator merges or rejects it.
+
+use std::{path::Path, sync::Arc};
+
+use axum::{
+  extract::{Extension, Form},
+  http::{HeaderMap, StatusCode},
+  response::{Html, IntoResponse, Redirect, Response},
+};
+use oauth2::url::Url;
+use serde::Deserialize;
+
+use crate::{
+  config::Config,
+  page::{Page, PageTab},
+  proxy::Forwarded,
+  quota::{Activity, Who},
+  spam::Submission,
+  template::Template,
+  user::User,
+  State,
+};
+
+/// Guests' emails are made up, at a domain that can't be delivered to.
+const GUEST_DOMAIN: &str = "guests.invalid";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  Acl(#[from] crate::acl::Error),
+  #[error(transparent)]
+  Spam(#[from] crate::spam::Error),
+  #[error("Guests can't edit this wiki - log in to make changes")]
+  Disabled,
+  #[error("This page is in a binary format, so it can't be edited here")]
+  Binary,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Page(err) => err.into_response(),
+      Self::Acl(err) => err.into_response(),
+      Self::Spam(err) => err.into_response(),
+      Self::Disabled => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+      Self::Binary => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+    }
+  }
+}
+
+/// Whether `user` is a guest, rather than someone who's logged in.
+pub fn is_guest(user: &User) -> bool {
+  user.email.ends_with(&format!("@{}", GUEST_DOMAIN))
+}
+
+/// Someone who isn't logged in, calling themselves `name`, editing the page at `path`. Guests'
+/// emails go by the page, so every guest edit to it goes to the same draft branch, rather than
+/// each making a new one.
+pub fn guest(name: &str, path: &Path, config: &Config) -> User {
+  let name = match name.trim() {
+    "" => String::from("Anonymous"),
+    name => name.to_string(),
+  };
+
+  let id = crate::assets::fingerprint(path.to_string_lossy().as_bytes());
+
+  User {
+    name: format!("{} (guest)", name),
+    email: format!("guest-{}@{}", id, GUEST_DOMAIN),
+    url: Url::parse(config.base_url()).unwrap_or_else(|_| Url::parse(&config.client_id).unwrap()),
+    approved: false,
+    roles: Vec::new(),
+    timezone: None,
+    locale: None,
+    edited_profile: false,
+    disabled: false,
+    notifications: Default::default(),
+  }
+}
+
+pub async fn form_handler(
+  page: Page,
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Response, Error> {
+  // People who are logged in get the real editor.
+  if user.is_some() {
+    return Ok(Redirect::to(&format!("/meta/edit/{}", page.path.display())).into_response());
+  }
+
+  let config = state.config();
+
+  if !config.guest_editing {
+    return Err(Error::Disabled);
+  }
+
+  if page.is_binary() {
+    return Err(Error::Binary);
+  }
+
+  let guest = guest("", &page.path, &config);
+  crate::acl::check_edit(&guest, &page.path, &config)?;
+  page.check_archived(None, &guest, &state).await?;
+
+  let file = page.raw(&state).await?;
+  let path = page.path.display().to_string();
+
+  let content = maud::html! {
+    p {
+      "You aren't logged in, so your changes will be reviewed before they're published. "
+      a href=(crate::auth::login_url(&format!("/meta/edit/{}", path))) { "Log in" }
+      " to edit the page straight away."
+    }
+
+    form #guest-edit method="post" action={ "/meta/guest-edit/" (path) } {
+      textarea name="body" rows="25" required { (file) }
+      input type="text" name="name" placeholder="Your name (optional)";
+      input type="text" name="summary" placeholder="Summary";
+      @if config.spam.honeypot {
+        // Hidden from people, but not from bots.
+        input .honeypot type="text" name="website" tabindex="-1" autocomplete="off"
+          aria-hidden="true" style="display: none";
+      }
+      button type="submit" { "Suggest changes" }
+    }
+  };
+
+  let html = Template::new()
+    .tabs(PageTab::Edit.render(&path))
+    .breadcrumbs(&path)
+    .title(maud::html! { (path) " - Suggest changes" })
+    .content(content)
+    .render(None, &state);
+
+  Ok(html.into_response())
+}
+
+#[derive(Deserialize)]
+pub struct GuestEdit {
+  body: String,
+  #[serde(default)]
+  name: String,
+  #[serde(default)]
+  summary: Option<String>,
+  /// The honeypot, which should always be empty.
+  #[serde(default)]
+  website: String,
+}
+
+pub async fn submit_handler(
+  page: Page,
+  user: Option<User>,
+  forwarded: Forwarded,
+  headers: HeaderMap,
+  Extension(state): Extension<Arc<State>>,
+  Form(edit): Form<GuestEdit>,
+) -> Result<Response, Error> {
+  if user.is_some() {
+    return Ok(Redirect::to(&format!("/meta/edit/{}", page.path.display())).into_response());
+  }
+
+  let config = state.config();
+
+  if !config.guest_editing {
+    return Err(Error::Disabled);
+  }
+
+  // The page could have been changed to a binary format since the form was shown.
+  if page.is_binary() {
+    return Err(Error::Binary);
+  }
+
+  let guest = guest(&edit.name, &page.path, &config);
+  crate::acl::check_edit(&guest, &page.path, &config)?;
+
+  let summary = edit.summary.filter(|summary| !summary.is_empty());
+
+  // Guests are counted by where they came from, as each of their edits is a different guest.
+  // Edits held as spam count too.
+  state
+    .usage
+    .take(
+      Who::guest(forwarded.client),
+      Activity::Edit,
+      config.quotas.quota(None),
+    )
+    .map_err(crate::page::Error::from)?;
+
+  // Only bots fill in the honeypot, so they're told it worked, and it's thrown away.
+  let is_bot = config.spam.honeypot && !edit.website.is_empty();
+
+  if !is_bot {
+    let submission = Submission {
+      path: page.path.clone(),
+      name: match edit.name.trim() {
+        "" => String::from("Anonymous"),
+        name => name.to_string(),
+      },
+      email: None,
+      summary: summary.clone(),
+      body: edit.body.clone(),
+      before: page.raw(&state).await?,
+      address: forwarded.client,
+      user_agent: crate::spam::user_agent(&headers),
+      base: page.revision(&state).await?,
+      created: None,
+    };
+
+    // Anything that looks like spam is held, rather than going to the drafts.
+    if !crate::spam::screen(submission, &state).await? {
+      page
+        .update(edit.body, summary.as_deref(), None, &guest, state.clone())
+        .await?;
+    }
+  }
+
+  let content = maud::html! {
+    p {
+      "Thanks! Your changes have been sent to the wiki's administrators, and will be published "
+      "once one of them has reviewed them."
+    }
+    p { a href=(page.url_path()) { "Back to the page" } }
+  };
+
+  let html = Template::new()
+    .title("Changes suggested")
+    .content(content)
+    .render(None, &state);
+
+  Ok(html.into_response())
+}
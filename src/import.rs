@@ -0,0 +1,784 @@
+//! Bringing pages in from other wikis - a MediaWiki XML dump, a gollum repository, or an
+//! Obsidian vault. Links are rewritten to go where the pages end up, and everything is committed
+//! a batch at a time, so a big wiki doesn't arrive as one enormous commit.
+
+use std::{
+  collections::HashMap,
+  ffi::OsStr,
+  io::Read,
+  path::{Component, Path, PathBuf},
+  sync::Arc,
+};
+
+use axum::{
+  extract::{multipart::MultipartError, ContentLengthLimit, Multipart},
+  http::StatusCode,
+  response::{Html, IntoResponse, Response},
+  Extension,
+};
+use serde::Deserialize;
+
+use crate::{
+  audit::Action,
+  config::Config,
+  format::Format,
+  front_matter::FrontMatter,
+  proxy::SameOrigin,
+  role::{Is, Role},
+  services::GitService,
+  template::Template,
+  user::User,
+  State,
+};
+
+/// How many files go in each commit.
+const BATCH_SIZE: usize = 100;
+
+/// The biggest dump or archive that can be uploaded - 100 MiB.
+const MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024;
+
+/// MediaWiki's main namespace - talk pages, templates, users' pages and the like aren't
+/// imported.
+const MAIN_NAMESPACE: i64 = 0;
+
+/// MediaWiki namespaces whose links are left as they are, rather than pointing at a page.
+const MEDIAWIKI_NAMESPACES: &[&str] = &[
+  "file", "image", "media", "template", "user", "help", "special", "talk", "project",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Walk(#[from] walkdir::Error),
+  #[error(transparent)]
+  Git(#[from] crate::git::Error),
+  #[error(transparent)]
+  FrontMatter(#[from] crate::front_matter::Error),
+  #[error("Couldn't read the MediaWiki dump: {0}")]
+  Dump(#[from] quick_xml::de::DeError),
+  #[error(transparent)]
+  Multipart(#[from] MultipartError),
+  #[error("There's no XML dump to import")]
+  NoDump,
+  #[error("'{0}' isn't a wiki that can be imported from")]
+  UnknownSource(String),
+  #[error("Nothing was uploaded")]
+  NoUpload,
+  #[error("'{0}' would be outside the pages directory, or hidden")]
+  UnsafePath(PathBuf),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    let code = match self {
+      Self::Dump(_)
+      | Self::Multipart(_)
+      | Self::NoDump
+      | Self::UnknownSource(_)
+      | Self::NoUpload
+      | Self::UnsafePath(_) => StatusCode::BAD_REQUEST,
+      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (code, self.to_string()).into_response()
+  }
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+  /// An XML dump, from `Special:Export` or `dumpBackup.php`
+  #[clap(name = "mediawiki")]
+  MediaWiki,
+  /// A gollum wiki's repository
+  Gollum,
+  /// An Obsidian vault
+  Obsidian,
+}
+
+impl Source {
+  const ALL: [Self; 3] = [Self::MediaWiki, Self::Gollum, Self::Obsidian];
+
+  /// The identifier used in forms.
+  fn id(&self) -> &'static str {
+    match self {
+      Self::MediaWiki => "mediawiki",
+      Self::Gollum => "gollum",
+      Self::Obsidian => "obsidian",
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match self {
+      Self::MediaWiki => "MediaWiki",
+      Self::Gollum => "gollum",
+      Self::Obsidian => "Obsidian",
+    }
+  }
+
+  fn from_id(id: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|source| source.id() == id)
+  }
+}
+
+/// A file to import, relative to the top of whatever it's imported from.
+pub struct File {
+  pub path: PathBuf,
+  pub contents: Vec<u8>,
+}
+
+/// Whether any part of `path` is hidden, like `.git` or `.obsidian`.
+fn is_hidden(path: &Path) -> bool {
+  path
+    .components()
+    .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+/// Reads `input` - a directory, a `.tar` archive of one, or a single file like a MediaWiki dump.
+pub fn read(input: &Path) -> Result<Vec<File>, Error> {
+  if input.is_dir() {
+    return read_directory(input);
+  }
+
+  let contents = std::fs::read(input)?;
+
+  match input.extension() == Some(OsStr::new("tar")) {
+    true => read_archive(&contents),
+    false => Ok(vec![File {
+      path: PathBuf::from(input.file_name().unwrap_or_default()),
+      contents,
+    }]),
+  }
+}
+
+fn read_directory(directory: &Path) -> Result<Vec<File>, Error> {
+  let mut files = Vec::new();
+
+  let entries = walkdir::WalkDir::new(directory)
+    .into_iter()
+    .filter_entry(|entry| entry.depth() == 0 || !is_hidden(Path::new(entry.file_name())));
+
+  for entry in entries {
+    let entry = entry?;
+
+    if !entry.file_type().is_file() {
+      continue;
+    }
+
+    files.push(File {
+      path: entry.path().strip_prefix(directory).unwrap().to_path_buf(),
+      contents: std::fs::read(entry.path())?,
+    });
+  }
+
+  Ok(files)
+}
+
+fn read_archive(archive: &[u8]) -> Result<Vec<File>, Error> {
+  let mut files = Vec::new();
+
+  for entry in tar::Archive::new(archive).entries()? {
+    let mut entry = entry?;
+
+    if !entry.header().entry_type().is_file() {
+      continue;
+    }
+
+    let path = entry.path()?.to_path_buf();
+
+    // Anything that could end up outside the pages directory is left out.
+    let is_safe = path
+      .components()
+      .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    if !is_safe || is_hidden(&path) {
+      continue;
+    }
+
+    let mut contents = Vec::new();
+    entry.read_to_end(&mut contents)?;
+
+    files.push(File {
+      path: path.components().collect(),
+      contents,
+    });
+  }
+
+  // A wiki is usually archived as its directory, which shouldn't end up in the pages' paths.
+  let top = files
+    .first()
+    .and_then(|file| file.path.components().next())
+    .map(|top| PathBuf::from(top.as_os_str()));
+
+  if let Some(top) = top {
+    let all_inside = files
+      .iter()
+      .all(|file| file.path.starts_with(&top) && file.path != top);
+
+    if all_inside {
+      for file in &mut files {
+        file.path = file.path.strip_prefix(&top).unwrap().to_path_buf();
+      }
+    }
+  }
+
+  Ok(files)
+}
+
+/// Turns what was read from another wiki into the files to add to this one.
+pub fn convert(source: Source, files: Vec<File>, config: &Config) -> Result<Vec<File>, Error> {
+  match source {
+    Source::MediaWiki => mediawiki(&files, config),
+    Source::Gollum => {
+      // gollum's `_Sidebar`, `_Header` and `_Footer` have nowhere to go here.
+      let files = files
+        .into_iter()
+        .filter(|file| {
+          let name = file.path.file_stem().unwrap_or_default().to_string_lossy();
+          !matches!(name.as_ref(), "_Sidebar" | "_Header" | "_Footer")
+        })
+        .collect();
+
+      Ok(linked(files, Links::Gollum))
+    },
+    Source::Obsidian => linked(files, Links::Obsidian)
+      .into_iter()
+      .map(|file| match is_markdown(&file.path) {
+        true => Ok(File {
+          contents: tags_to_categories(&String::from_utf8_lossy(&file.contents))?.into_bytes(),
+          ..file
+        }),
+        false => Ok(file),
+      })
+      .collect(),
+  }
+}
+
+#[derive(Deserialize)]
+struct Dump {
+  #[serde(rename = "page", default)]
+  pages: Vec<DumpPage>,
+}
+
+#[derive(Deserialize)]
+struct DumpPage {
+  title: String,
+  #[serde(default)]
+  ns: i64,
+  #[serde(rename = "revision", default)]
+  revisions: Vec<Revision>,
+}
+
+#[derive(Deserialize)]
+struct Revision {
+  text: Text,
+}
+
+#[derive(Deserialize)]
+struct Text {
+  #[serde(rename = "$value", default)]
+  value: String,
+}
+
+fn mediawiki(files: &[File], config: &Config) -> Result<Vec<File>, Error> {
+  let dumps: Vec<_> = files
+    .iter()
+    .filter(|file| file.path.extension() == Some(OsStr::new("xml")))
+    .collect();
+
+  if dumps.is_empty() {
+    return Err(Error::NoDump);
+  }
+
+  let mut pages = Vec::new();
+
+  for dump in dumps {
+    let dump: Dump = quick_xml::de::from_str(&String::from_utf8_lossy(&dump.contents))?;
+
+    for page in dump.pages {
+      // Only the latest revision is imported.
+      let revision = match page.revisions.last() {
+        Some(revision) if page.ns == MAIN_NAMESPACE => revision,
+        _ => continue,
+      };
+
+      let (text, categories, redirect) = mediawiki_links(&revision.text.value, config);
+
+      let front_matter = FrontMatter {
+        title: Some(page.title.clone()),
+        categories: match categories.is_empty() {
+          true => None,
+          false => Some(categories),
+        },
+        redirect_to: redirect.map(|path| format!("/{}", path)),
+        ..FrontMatter::default()
+      };
+
+      pages.push(File {
+        path: PathBuf::from(format!("{}.wiki", mediawiki_path(&page.title, config))),
+        contents: format!("{}{}", front_matter.render()?, text).into_bytes(),
+      });
+    }
+  }
+
+  Ok(pages)
+}
+
+/// Where the page called `title` goes - MediaWiki uses underscores for spaces in its URLs, and
+/// always capitalises the first letter.
+fn mediawiki_path(title: &str, config: &Config) -> String {
+  let title = title.trim().replace(' ', "_");
+  let mut chars = title.chars();
+
+  let title: String = match chars.next() {
+    Some(first) => first.to_uppercase().chain(chars).collect(),
+    None => String::new(),
+  };
+
+  config.slugs.apply(&title)
+}
+
+/// The page's text with its links pointing at where pages are imported to, along with the
+/// categories it was in and the page it redirects to, if it's a redirect.
+fn mediawiki_links(text: &str, config: &Config) -> (String, Vec<String>, Option<String>) {
+  let mut out = String::with_capacity(text.len());
+  let mut categories = Vec::new();
+  let mut targets = Vec::new();
+  let mut rest = text;
+
+  while let Some(start) = rest.find("[[") {
+    let after = &rest[start + 2..];
+
+    let end = match after.find("]]") {
+      Some(end) => end,
+      None => break,
+    };
+
+    out.push_str(&rest[..start]);
+
+    let inner = &after[..end];
+    let (target, label) = inner.split_once('|').unwrap_or((inner, inner));
+    let namespace = target
+      .split_once(':')
+      .map(|(namespace, _)| namespace.trim().to_lowercase());
+
+    match namespace.as_deref() {
+      Some("category") => {
+        let (_, category) = target.split_once(':').unwrap();
+        categories.push(category.trim().to_string());
+      },
+      // Links starting with `:` are shown as they are, even to categories.
+      _ if target.starts_with(':') => out.push_str(&rest[start..start + 2 + end + 2]),
+      Some(namespace) if MEDIAWIKI_NAMESPACES.contains(&namespace) => {
+        out.push_str(&rest[start..start + 2 + end + 2]);
+      },
+      _ => {
+        let (page, anchor) = target.split_once('#').unwrap_or((target, ""));
+        let path = mediawiki_path(page, config);
+
+        out.push_str("[[/");
+        out.push_str(&path);
+        if !anchor.is_empty() {
+          out.push('#');
+          out.push_str(anchor);
+        }
+        out.push('|');
+        out.push_str(label);
+        out.push_str("]]");
+
+        targets.push(path);
+      },
+    }
+
+    rest = &after[end + 2..];
+  }
+
+  out.push_str(rest);
+
+  let is_redirect = text
+    .trim_start()
+    .get(..9)
+    .map(|start| start.eq_ignore_ascii_case("#redirect"))
+    .unwrap_or(false);
+
+  let redirect = match is_redirect {
+    true => targets.into_iter().next(),
+    false => None,
+  };
+
+  (out, categories, redirect)
+}
+
+/// How a wiki writes `[[links]]`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Links {
+  /// `[[Text|Page]]`, where images are linked to the same way as pages.
+  Gollum,
+  /// `[[Page|Text]]`, and `![[image.png]]` for images.
+  Obsidian,
+}
+
+impl Links {
+  /// The page a link goes to, and what it says.
+  fn split(self, inner: &str) -> (&str, &str) {
+    match (self, inner.split_once('|')) {
+      (Self::Gollum, Some((text, target))) => (target, text),
+      (Self::Obsidian, Some((target, text))) => (target, text),
+      (_, None) => (inner, inner),
+    }
+  }
+}
+
+fn is_page(path: &Path) -> bool {
+  path
+    .extension()
+    .and_then(|ext| Format::from_extension(&ext.to_string_lossy()))
+    .is_some()
+}
+
+fn is_markdown(path: &Path) -> bool {
+  matches!(
+    path.extension().and_then(OsStr::to_str),
+    Some("md" | "markdown")
+  )
+}
+
+/// How a file is looked up from a link - neither wiki minds about case, and gollum writes
+/// spaces as `-` in file names.
+fn key(name: &str) -> String {
+  name.trim().to_lowercase().replace(' ', "-")
+}
+
+/// Rewrites the `[[links]]` in every Markdown file to go straight to the file they mean.
+/// Both wikis find a file from its name alone, so the one closest to the top wins.
+fn linked(mut files: Vec<File>, links: Links) -> Vec<File> {
+  files.sort_by(|a, b| {
+    let depth = |file: &File| file.path.components().count();
+    depth(a).cmp(&depth(b)).then_with(|| a.path.cmp(&b.path))
+  });
+
+  let mut index = HashMap::new();
+
+  for file in &files {
+    let path = file.path.to_string_lossy();
+    let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+
+    let mut keys = vec![key(&path), key(&name)];
+    if is_page(&file.path) {
+      keys.push(key(&file.path.with_extension("").to_string_lossy()));
+      keys.push(key(
+        &file.path.file_stem().unwrap_or_default().to_string_lossy(),
+      ));
+    }
+
+    for key in keys {
+      index.entry(key).or_insert_with(|| file.path.clone());
+    }
+  }
+
+  files
+    .into_iter()
+    .map(|file| match is_markdown(&file.path) {
+      true => File {
+        contents: wiki_links(&String::from_utf8_lossy(&file.contents), links, &index).into_bytes(),
+        ..file
+      },
+      false => file,
+    })
+    .collect()
+}
+
+fn wiki_links(doc: &str, links: Links, index: &HashMap<String, PathBuf>) -> String {
+  let mut out = String::with_capacity(doc.len());
+  let mut rest = doc;
+
+  while let Some(start) = rest.find("[[") {
+    let after = &rest[start + 2..];
+
+    let end = match after.find("]]") {
+      Some(end) => end,
+      None => break,
+    };
+
+    let embed = rest[..start].ends_with('!');
+    let from = if embed { start - 1 } else { start };
+
+    out.push_str(&rest[..from]);
+
+    let (target, text) = links.split(&after[..end]);
+    let (name, anchor) = target.split_once('#').unwrap_or((target, ""));
+
+    match index.get(&key(name)) {
+      Some(path) => out.push_str(&link(path, anchor, text, embed, links)),
+      // Left as it is, so nothing is lost.
+      None => out.push_str(&rest[from..start + 2 + end + 2]),
+    }
+
+    rest = &after[end + 2..];
+  }
+
+  out.push_str(rest);
+
+  out
+}
+
+fn link(path: &Path, anchor: &str, text: &str, embed: bool, links: Links) -> String {
+  if !is_page(path) {
+    let url = format!("/{}", path.display()).replace(' ', "%20");
+
+    return match embed || links == Links::Gollum {
+      true => format!("![{}]({})", text, url),
+      false => format!("[{}]({})", text, url),
+    };
+  }
+
+  let page = path.with_extension("");
+
+  // Shown inside this page, which is what `![[page]]` does here too.
+  if embed {
+    return format!("![[{}]]", page.display());
+  }
+
+  let mut url = format!("/{}", page.display()).replace(' ', "%20");
+  if !anchor.is_empty() {
+    url.push('#');
+    url.push_str(&anchor.trim().to_lowercase().replace(' ', "-"));
+  }
+
+  format!("[{}]({})", text, url)
+}
+
+/// Obsidian's `tags` are this wiki's `categories`.
+fn tags_to_categories(doc: &str) -> Result<String, Error> {
+  let (mut front_matter, data, err) = FrontMatter::split(doc);
+
+  let tags = match front_matter.extra.remove("tags") {
+    Some(tags) if err.is_none() => tags,
+    _ => return Ok(doc.to_string()),
+  };
+
+  let tags: Vec<String> = match tags {
+    serde_json::Value::String(tags) => tags.split([',', ' ']).map(str::to_string).collect(),
+    serde_json::Value::Array(tags) => tags
+      .iter()
+      .filter_map(|tag| tag.as_str().map(str::to_string))
+      .collect(),
+    _ => Vec::new(),
+  };
+
+  let tags = tags
+    .iter()
+    .map(|tag| tag.trim().trim_start_matches('#').to_string())
+    .filter(|tag| !tag.is_empty());
+
+  front_matter
+    .categories
+    .get_or_insert_with(Vec::new)
+    .extend(tags);
+
+  Ok(format!("{}{}", front_matter.render()?, data))
+}
+
+/// What an import did.
+pub struct Imported {
+  pub files: usize,
+  pub commits: usize,
+  /// Files that weren't imported, because there's already something there.
+  pub skipped: Vec<PathBuf>,
+}
+
+/// Writes `contents` next to `filepath` and then moves it there, so it's never half-written.
+async fn write_atomically(filepath: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+  let name = filepath.file_name().unwrap_or_default().to_string_lossy();
+  let temporary = filepath.with_file_name(format!(".{}.import", name));
+
+  tokio::fs::write(&temporary, contents).await?;
+  tokio::fs::rename(&temporary, filepath).await
+}
+
+/// Writes and stages each of `files`, keeping track of the ones that were written in `written`.
+async fn write_batch(
+  files: &[File],
+  config: &Config,
+  git: &dyn GitService,
+  written: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+  for file in files {
+    let filepath = config.pages_directory.join(&file.path);
+
+    if let Some(directory) = filepath.parent() {
+      tokio::fs::create_dir_all(directory).await?;
+    }
+    write_atomically(&filepath, &file.contents).await?;
+    written.push(file.path.clone());
+
+    git.add_file(&file.path)?;
+  }
+
+  Ok(())
+}
+
+/// Writes `files` to the pages directory and commits them as `user`, a batch at a time.
+/// Nothing that's already there is overwritten, and a batch that can't be committed is removed
+/// again.
+pub async fn commit(
+  source: Source,
+  files: Vec<File>,
+  config: &Config,
+  git: &dyn GitService,
+  user: &User,
+) -> Result<Imported, Error> {
+  // A dump's page titles can be anything, like `../../x` or `.git/hooks/post-commit`.
+  if let Some(file) = files
+    .iter()
+    .find(|file| !crate::page::is_safe_path(&file.path))
+  {
+    return Err(Error::UnsafePath(file.path.clone()));
+  }
+
+  let _writing = git.writing().lock().await;
+  let (files, skipped): (Vec<_>, Vec<_>) = files
+    .into_iter()
+    .partition(|file| !config.pages_directory.join(&file.path).exists());
+
+  let batches = (files.len() + BATCH_SIZE - 1) / BATCH_SIZE;
+
+  for (n, batch) in files.chunks(BATCH_SIZE).enumerate() {
+    let mut written = Vec::new();
+
+    let result = match write_batch(batch, config, git, &mut written).await {
+      Ok(()) => git
+        .commit(
+          &format!("Import from {} ({} of {})", source.name(), n + 1, batches),
+          user,
+        )
+        .map_err(Error::from),
+      Err(err) => Err(err),
+    };
+
+    if let Err(err) = result {
+      for path in &written {
+        if let Err(err) = tokio::fs::remove_file(config.pages_directory.join(path)).await {
+          log::warn!("Couldn't remove {:?} after a failed import: {}", path, err);
+        }
+        if let Err(err) = git.remove_file(path) {
+          log::warn!("Couldn't unstage {:?} after a failed import: {}", path, err);
+        }
+      }
+
+      return Err(err);
+    }
+  }
+
+  if batches > 0 {
+    git.push()?;
+  }
+
+  Ok(Imported {
+    files: files.len(),
+    commits: batches,
+    skipped: skipped.into_iter().map(|file| file.path).collect(),
+  })
+}
+
+pub async fn form_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Extension(state): Extension<Arc<State>>,
+) -> Html<String> {
+  let content = maud::html! {
+    p {
+      "Upload a MediaWiki XML dump, or a " code { ".tar" } " archive of a gollum repository or "
+      "an Obsidian vault. Links are rewritten to go to the imported pages, and nothing that's "
+      "already here is overwritten."
+    }
+
+    form #import method="post" action="/meta/admin/import" enctype="multipart/form-data" {
+      select name="source" {
+        @for source in Source::ALL {
+          option value=(source.id()) { (source.name()) }
+        }
+      }
+      input type="file" name="file" required;
+      button type="submit" { "Import" }
+    }
+  };
+
+  Template::new()
+    .title("Import")
+    .content(content)
+    .render(Some(admin), &state)
+}
+
+pub async fn upload_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+  ContentLengthLimit(mut multipart): ContentLengthLimit<Multipart, MAX_UPLOAD_SIZE>,
+) -> Result<Html<String>, Error> {
+  let config = state.config();
+
+  let mut source = None;
+  let mut upload = None;
+
+  while let Some(field) = multipart.next_field().await? {
+    let name = field.name().map(str::to_string);
+
+    match name.as_deref() {
+      Some("source") => {
+        let id = field.text().await?;
+        source = Some(Source::from_id(&id).ok_or(Error::UnknownSource(id))?);
+      },
+      Some("file") => {
+        let file_name = PathBuf::from(field.file_name().unwrap_or_default());
+        upload = Some((file_name, field.bytes().await?));
+      },
+      _ => (),
+    }
+  }
+
+  let source = source.ok_or(Error::NoUpload)?;
+  let (file_name, contents) = upload.ok_or(Error::NoUpload)?;
+
+  let files = match file_name.extension() == Some(OsStr::new("tar")) {
+    true => read_archive(&contents)?,
+    false => vec![File {
+      path: file_name,
+      contents: contents.to_vec(),
+    }],
+  };
+
+  let files = convert(source, files, &config)?;
+  let imported = commit(source, files, &config, state.git.as_ref(), &admin).await?;
+
+  let detail = format!("{} files from {}", imported.files, source.name());
+  state
+    .audit
+    .record(&admin.email, Action::ImportPages, "pages", Some(detail))
+    .await;
+
+  let content = maud::html! {
+    p {
+      "Imported " (imported.files) " files from " (source.name()) ", in " (imported.commits)
+      " commits."
+    }
+
+    @if !imported.skipped.is_empty() {
+      p { "These were left out, because there's already something there:" }
+      ul {
+        @for path in &imported.skipped {
+          li { a href={ "/" (path.with_extension("").display()) } { (path.display()) } }
+        }
+      }
+    }
+
+    p { a href="/meta/recent-changes" { "Recent changes" } }
+  };
+
+  let html = Template::new()
+    .title("Import")
+    .content(content)
+    .render(Some(admin), &state);
+
+  Ok(html)
+}
@@ -0,0 +1,84 @@
+//! Short links to other sites - `[Rust](wikipedia:Rust)` links to Wikipedia's page, using the
+//! prefixes set in the config.
+
+use std::collections::HashMap;
+
+use pandoc_ast::{Inline, MutVisitor};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Interwiki {
+  /// Where the links go, with `{}` replaced by whatever comes after the prefix.
+  pub url: String,
+  /// An image shown before the link, like the site's favicon. It has to be on the wiki, like
+  /// `/icons/wikipedia.png`, so readers aren't sent off to the other site just by looking.
+  #[serde(default)]
+  pub icon: Option<String>,
+}
+
+impl Interwiki {
+  /// The link to `target`, with each part of it percent-encoded - anything that's already
+  /// encoded is left as it was.
+  fn expand(&self, target: &str) -> String {
+    let segments: Vec<_> = target
+      .split('/')
+      .map(|segment| {
+        let decoded = urlencoding::decode(segment)
+          .map(|decoded| decoded.to_string())
+          .unwrap_or_else(|_| segment.to_string());
+
+        urlencoding::encode(&decoded).to_string()
+      })
+      .collect();
+
+    self.url.replace("{}", &segments.join("/"))
+  }
+
+  /// The icon, if it's on the wiki itself.
+  fn local_icon(&self) -> Option<&str> {
+    self
+      .icon
+      .as_deref()
+      .filter(|icon| icon.starts_with('/') && !icon.starts_with("//"))
+  }
+}
+
+/// Turns links like `wikipedia:Rust` into links to the other site.
+pub struct InterwikiFilter<'a> {
+  pub prefixes: &'a HashMap<String, Interwiki>,
+}
+
+impl MutVisitor for InterwikiFilter<'_> {
+  fn visit_inline(&mut self, inline: &mut Inline) {
+    if let Inline::Link((_, classes, _), content, (url, _)) = inline {
+      let expanded = url.split_once(':').and_then(|(prefix, target)| {
+        let interwiki = self.prefixes.get(prefix)?;
+
+        Some((prefix.to_string(), interwiki, target.to_string()))
+      });
+
+      if let Some((prefix, interwiki, target)) = expanded {
+        *url = interwiki.expand(&target);
+
+        classes.push(String::from("interwiki"));
+        classes.push(format!("interwiki-{}", prefix));
+
+        if let Some(icon) = interwiki.local_icon() {
+          let attr = (
+            String::new(),
+            vec![String::from("interwiki-icon")],
+            Vec::new(),
+          );
+          content.insert(
+            0,
+            Inline::Image(attr, Vec::new(), (icon.to_string(), String::new())),
+          );
+        }
+
+        return;
+      }
+    }
+
+    self.walk_inline(inline);
+  }
+}
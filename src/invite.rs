@@ -0,0 +1,276 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use axum::{
+  extract::{Extension, Form},
+  http::StatusCode,
+  response::{Html, IntoResponse, Redirect, Response},
+};
+use oauth2::CsrfToken;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+
+use crate::{
+  date::Dates,
+  proxy::{Forwarded, SameOrigin},
+  role::{Is, Role},
+  template::Template,
+  State,
+};
+
+/// Who can create an account by logging in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Registration {
+  /// Anyone can log in, and new accounts wait to be approved by an administrator.
+  Open,
+  /// New accounts need an invite from an administrator.
+  InviteOnly,
+}
+
+impl Default for Registration {
+  fn default() -> Self {
+    Self::Open
+  }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Ron(#[from] ron::Error),
+  #[error("This wiki is invite-only, and that invite isn't valid")]
+  Invalid,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    let code = match self {
+      Self::Invalid => StatusCode::FORBIDDEN,
+      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (code, self.to_string()).into_response()
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Invite {
+  pub token: String,
+  /// If this is set, only someone logging in with this email can use the invite.
+  pub email: Option<String>,
+  pub created_by: String,
+  /// Seconds since the Unix epoch.
+  created: i64,
+}
+
+impl Invite {
+  pub fn created(&self) -> OffsetDateTime {
+    Dates::from_timestamp(self.created)
+  }
+
+  pub fn url(&self, base_url: &str) -> String {
+    format!("{}/meta/login?invite={}", base_url, self.token)
+  }
+}
+
+/// The invites that haven't been used yet, which are saved to the `invites` file.
+#[derive(Clone)]
+pub struct Invites {
+  path: PathBuf,
+  invites: Arc<Mutex<HashMap<String, Invite>>>,
+}
+
+impl Invites {
+  pub async fn load(path: PathBuf) -> Result<Self, Error> {
+    let invites = if path.exists() {
+      let file = tokio::fs::read_to_string(&path).await?;
+      ron::from_str(&file)?
+    } else {
+      HashMap::new()
+    };
+
+    Ok(Self {
+      path,
+      invites: Arc::new(Mutex::new(invites)),
+    })
+  }
+
+  async fn save(&self, invites: &HashMap<String, Invite>) -> Result<(), Error> {
+    let value = ron::to_string(invites)?;
+    tokio::fs::write(&self.path, value).await?;
+
+    Ok(())
+  }
+
+  pub async fn list(&self) -> Vec<Invite> {
+    let mut invites: Vec<_> = self.invites.lock().await.values().cloned().collect();
+    invites.sort_by_key(|invite| std::cmp::Reverse(invite.created));
+
+    invites
+  }
+
+  pub async fn create(&self, email: Option<String>, created_by: &str) -> Result<Invite, Error> {
+    let invite = Invite {
+      token: CsrfToken::new_random().secret().clone(),
+      email,
+      created_by: created_by.to_string(),
+      created: OffsetDateTime::now_utc().unix_timestamp(),
+    };
+
+    let mut invites = self.invites.lock().await;
+    invites.insert(invite.token.clone(), invite.clone());
+    self.save(&invites).await?;
+
+    Ok(invite)
+  }
+
+  pub async fn revoke(&self, token: &str) -> Result<(), Error> {
+    let mut invites = self.invites.lock().await;
+
+    if invites.remove(token).is_some() {
+      self.save(&invites).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Uses up an invite for someone logging in with `email` for the first time.
+  pub async fn redeem(&self, token: &str, email: &str) -> Result<Invite, Error> {
+    let mut invites = self.invites.lock().await;
+
+    let valid = match invites.get(token) {
+      Some(Invite {
+        email: Some(invited),
+        ..
+      }) => invited.eq_ignore_ascii_case(email),
+      Some(_) => true,
+      None => false,
+    };
+
+    if !valid {
+      return Err(Error::Invalid);
+    }
+
+    let invite = invites.remove(token).unwrap();
+    self.save(&invites).await?;
+
+    Ok(invite)
+  }
+}
+
+/// Lets the admin send the invite from their own email client.
+fn mailto(invite: &Invite, base_url: &str) -> Option<String> {
+  let email = invite.email.as_ref()?;
+  let body = format!(
+    "You've been invited to the wiki! Log in with this link: {}",
+    invite.url(base_url)
+  );
+
+  Some(format!(
+    "mailto:{}?subject={}&body={}",
+    email,
+    urlencoding::encode("Your wiki invite"),
+    urlencoding::encode(&body),
+  ))
+}
+
+pub async fn invites_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  forwarded: Forwarded,
+  Extension(invites): Extension<Invites>,
+  Extension(state): Extension<Arc<State>>,
+) -> Html<String> {
+  let config = state.config();
+  let base_url = forwarded.base_url(&config);
+  let dates = Dates::new(Some(&admin), &config.dates);
+  let invites = invites.list().await;
+
+  let content = maud::html! {
+    @if config.registration == Registration::Open {
+      p { "Registration is open, so anyone can log in without an invite." }
+    }
+
+    form #new-invite method="post" action="/meta/admin/invites" {
+      input type="email" name="email" placeholder="Email (optional)";
+      button type="submit" { "Create invite" }
+    }
+
+    table #invites {
+      thead {
+        tr {
+          th { "Link" }
+          th { "For" }
+          th { "Created by" }
+          th { "Created" }
+          th {}
+        }
+      }
+      tbody {
+        @for invite in &invites {
+          tr {
+            td { input type="text" readonly value=(invite.url(&base_url)); }
+            td {
+              @if let Some(email) = &invite.email {
+                (email)
+                @if let Some(mailto) = mailto(invite, &base_url) {
+                  " (" a href=(mailto) { "send" } ")"
+                }
+              } @else {
+                "anyone"
+              }
+            }
+            td { (invite.created_by) }
+            td { (dates.render(invite.created())) }
+            td {
+              form method="post" action="/meta/admin/invites/revoke" {
+                input type="hidden" name="token" value=(invite.token);
+                button type="submit" { "Revoke" }
+              }
+            }
+          }
+        }
+      }
+    }
+  };
+
+  Template::new()
+    .title("Invites")
+    .content(content)
+    .render(Some(admin), &state)
+}
+
+#[derive(Deserialize)]
+pub struct CreateParams {
+  email: String,
+}
+
+pub async fn create_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Form(params): Form<CreateParams>,
+  Extension(invites): Extension<Invites>,
+) -> Result<Redirect, Error> {
+  let email = params.email.trim();
+  let email = (!email.is_empty()).then(|| email.to_string());
+
+  invites.create(email, &admin.email).await?;
+
+  Ok(Redirect::to("/meta/admin/invites"))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeParams {
+  token: String,
+}
+
+pub async fn revoke_handler(
+  Is(_): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Form(params): Form<RevokeParams>,
+  Extension(invites): Extension<Invites>,
+) -> Result<Redirect, Error> {
+  invites.revoke(&params.token).await?;
+
+  Ok(Redirect::to("/meta/admin/invites"))
+}
@@ -0,0 +1,470 @@
+#![feature(adt_const_params, error_reporter)]
+
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+use axum::{
+  body::HttpBody,
+  http::{header, Response},
+  routing::{get, post},
+  Extension,
+  Router,
+};
+use once_cell::sync::Lazy;
+use tower_http::compression::{predicate::Predicate, CompressionLayer};
+
+use crate::{
+  assets::Assets,
+  audit::{Action, AuditLog},
+  category::CategoryIndex,
+  collab::Collaboration,
+  config::Config,
+  git::Git,
+  lock::EditLocks,
+  lockout::Lockouts,
+  navigation::Navigation,
+  quota::Usage,
+  services::{Filesystem, GitService, PageService, Pandoc, RenderService, UserService},
+  shortcodes::Shortcodes,
+  spam::Quarantine,
+  suggest::Suggestions,
+  theme::Theme,
+  transclude::Transclusions,
+  trash::Trash,
+  user::UserDb,
+  views::ViewCounter,
+};
+
+mod acl;
+mod admin;
+mod api;
+mod assets;
+mod audit;
+pub mod auth;
+pub mod backup;
+mod boilerplate;
+mod cache;
+mod category;
+pub mod cli;
+mod collab;
+mod commit_index;
+mod commit_message;
+mod conditional;
+pub mod config;
+mod date;
+mod draft;
+mod error;
+mod file;
+pub mod format;
+mod front_matter;
+pub mod git;
+mod guest;
+pub mod import;
+mod interwiki;
+mod invite;
+mod line_map;
+pub mod listen;
+mod lock;
+mod lockout;
+mod media;
+mod micropub;
+mod namespace;
+mod navigation;
+mod notebook;
+mod notify;
+mod page;
+pub mod pandoc;
+mod preview;
+mod prose;
+mod proxy;
+mod quota;
+pub mod recent_changes;
+mod replace;
+mod report;
+mod role;
+mod route;
+mod sanitize;
+pub mod services;
+mod sessions;
+pub mod shortcodes;
+mod signing;
+mod site;
+mod slides;
+mod slug;
+mod spam;
+mod suggest;
+mod table;
+mod template;
+mod theme;
+mod thumbnail;
+mod transclude;
+mod trash;
+pub mod user;
+mod views;
+mod wikis;
+
+#[derive(Clone)]
+pub struct State {
+  /// Use [`State::config`] to read it - it's swapped out when the config is reloaded.
+  pub config: Arc<ArcSwap<Config>>,
+  pub auth: Arc<dyn auth::AuthProvider>,
+  pub git: Arc<dyn GitService>,
+  pub users: Arc<dyn UserService>,
+  pub pages: Arc<dyn PageService>,
+  pub render: Arc<dyn RenderService>,
+  pub theme: Arc<Theme>,
+  pub navigation: Arc<Navigation>,
+  pub assets: Arc<Assets>,
+  pub locks: Arc<EditLocks>,
+  pub collab: Arc<Collaboration>,
+  pub suggestions: Arc<Suggestions>,
+  pub categories: Arc<CategoryIndex>,
+  pub views: Arc<ViewCounter>,
+  pub trash: Arc<Trash>,
+  /// Add your own shortcodes with [`Shortcodes::register`].
+  pub shortcodes: Arc<Shortcodes>,
+  pub transclusions: Arc<Transclusions>,
+  pub audit: Arc<AuditLog>,
+  pub lockouts: Arc<Lockouts>,
+  pub quarantine: Arc<Quarantine>,
+  pub usage: Arc<Usage>,
+  /// What the installed pandoc can do.
+  pub pandoc: Arc<pandoc::Capabilities>,
+}
+
+impl State {
+  /// Sets up the default services for `config` - any of them can be replaced before the
+  /// state is passed to [`router`].
+  pub async fn new(config: Config) -> Result<Self, eyre::Report> {
+    Self::build(config, None).await
+  }
+
+  /// The state for one of the config's `wikis`. Its pages, history and everything about them
+  /// are its own, but it shares the users, login lockouts, quotas and audit log with this one.
+  pub async fn wiki(&self, wiki: &config::Wiki) -> Result<Self, eyre::Report> {
+    let config = self.config().for_wiki(wiki)?;
+
+    Self::build(config, Some(self)).await
+  }
+
+  async fn build(mut config: Config, main: Option<&State>) -> Result<Self, eyre::Report> {
+    // We make the directories, so we can canonicalize them!
+    tokio::fs::create_dir_all(&config.pages_directory).await?;
+    tokio::fs::create_dir_all(&config.cache_directory).await?;
+    tokio::fs::create_dir_all(&config.templates_directory).await?;
+    tokio::fs::create_dir_all(&config.static_directory).await?;
+
+    config.canonicalize()?;
+
+    let config = Arc::new(config);
+
+    let git = Git::new(config.clone())?;
+    let theme = Theme::load(&config.templates_directory)?;
+    let assets = Assets::load(&config.static_directory);
+    let views = ViewCounter::new(&config).await?;
+    let trash = Trash::load(&config.cache_directory)?;
+    let quarantine = Quarantine::load(&config.cache_directory)?;
+
+    let (auth, users, audit, lockouts, usage, pandoc) = match main {
+      Some(main) => (
+        main.auth.clone(),
+        main.users.clone(),
+        main.audit.clone(),
+        main.lockouts.clone(),
+        main.usage.clone(),
+        main.pandoc.clone(),
+      ),
+      None => {
+        let users: Arc<dyn UserService> = Arc::new(UserDb::new(config.clone()).await?);
+
+        (
+          Arc::from(config.auth.build()),
+          users,
+          Arc::new(AuditLog::new(&config).await?),
+          Arc::default(),
+          Arc::default(),
+          Arc::new(pandoc::Capabilities::probe()),
+        )
+      },
+    };
+
+    Ok(State {
+      config: Arc::new(ArcSwap::new(config)),
+      auth,
+      git: Arc::new(git),
+      users,
+      pages: Arc::new(Filesystem),
+      render: Arc::new(Pandoc),
+      theme: Arc::new(theme),
+      navigation: Arc::default(),
+      assets: Arc::new(assets),
+      locks: Arc::default(),
+      collab: Arc::default(),
+      suggestions: Arc::default(),
+      categories: Arc::default(),
+      views: Arc::new(views),
+      trash: Arc::new(trash),
+      shortcodes: Arc::default(),
+      transclusions: Arc::default(),
+      audit,
+      lockouts,
+      quarantine: Arc::new(quarantine),
+      usage,
+      pandoc,
+    })
+  }
+
+  /// The current config. Hold on to it for as long as it's needed, rather than calling this
+  /// again, so a reload part-way through doesn't mix old and new settings.
+  pub fn config(&self) -> Arc<Config> {
+    self.config.load_full()
+  }
+
+  /// Reads the config file again, and swaps in the settings that can change while the wiki
+  /// is running.
+  pub fn reload_config(&self) -> Result<(), config::Error> {
+    let config = self.config().reloaded()?;
+    self.config.store(Arc::new(config));
+
+    log::info!("Reloaded the config");
+
+    Ok(())
+  }
+}
+
+/// The whole wiki, and any others in its config's `wikis`, with the default services.
+pub async fn app(config: Config) -> Result<Router, eyre::Report> {
+  config.check()?;
+
+  let state = Arc::new(State::new(config).await?);
+  let config = state.config();
+  let logins = auth::Logins::new(&config).await?;
+
+  let main = routes(state.clone(), logins.clone()).await?;
+  let mut others = Vec::new();
+
+  for wiki in &config.wikis {
+    let state = Arc::new(state.wiki(wiki).await?);
+    others.push((wiki.hosts.clone(), routes(state, logins.clone()).await?));
+  }
+
+  Ok(wikis::router(main, others))
+}
+
+/// Reloads the config whenever the process gets a `SIGHUP`.
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<State>) {
+  use tokio::signal::unix::{signal, SignalKind};
+
+  let mut hangups = match signal(SignalKind::hangup()) {
+    Ok(hangups) => hangups,
+    Err(err) => {
+      log::warn!(
+        "Couldn't listen for SIGHUP, so it won't reload the config: {}",
+        err
+      );
+      return;
+    },
+  };
+
+  while hangups.recv().await.is_some() {
+    match state.reload_config() {
+      Ok(()) => {
+        // How the navigation pages render can depend on the config.
+        state.navigation.refresh(state.clone()).await;
+        state
+          .audit
+          .record("SIGHUP", Action::ReloadConfig, "config", None)
+          .await;
+      },
+      Err(err) => log::error!("Couldn't reload the config: {}", err),
+    }
+  }
+}
+
+/// Only text is worth compressing - and not the event stream, which has to reach the browser
+/// as it's written.
+#[derive(Clone, Copy)]
+struct Compressible;
+
+impl Predicate for Compressible {
+  fn should_compress<B>(&self, response: &Response<B>) -> bool
+  where
+    B: HttpBody,
+  {
+    // Compressing part of a file would make the range wrong.
+    if response.headers().contains_key(header::CONTENT_RANGE) {
+      return false;
+    }
+
+    let content_type = response
+      .headers()
+      .get(header::CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .unwrap_or_default();
+
+    [
+      "text/html",
+      "text/css",
+      "text/javascript",
+      "application/javascript",
+      "application/json",
+    ]
+    .iter()
+    .any(|compressible| content_type.starts_with(compressible))
+  }
+}
+
+/// The whole wiki, using the services in `state`.
+pub async fn router(state: Arc<State>) -> Result<Router, eyre::Report> {
+  let logins = auth::Logins::new(&state.config()).await?;
+
+  routes(state, logins).await
+}
+
+/// Every wiki that's being served, so [`stop`] can save what they haven't yet.
+static SERVING: Lazy<Mutex<Vec<Arc<State>>>> = Lazy::new(Default::default);
+
+/// Saves anything that's still waiting to be saved, once the server has stopped.
+pub async fn stop() {
+  let serving = std::mem::take(&mut *SERVING.lock().unwrap());
+
+  for state in serving {
+    if let Err(err) = state.users.flush().await {
+      log::error!("Couldn't save the users before stopping: {}", err);
+    }
+
+    if let Err(err) = state.views.flush().await {
+      log::error!("Couldn't save the page views before stopping: {}", err);
+    }
+  }
+}
+
+async fn routes(state: Arc<State>, logins: auth::Logins) -> Result<Router, eyre::Report> {
+  state.navigation.refresh(state.clone()).await;
+  SERVING.lock().unwrap().push(state.clone());
+
+  tokio::spawn(Suggestions::watch(state.clone()));
+  tokio::spawn(CategoryIndex::watch(state.clone()));
+  tokio::spawn(Transclusions::watch(state.clone()));
+  tokio::spawn(Navigation::watch(state.clone()));
+  tokio::spawn(ViewCounter::flush_periodically(state.clone()));
+
+  #[cfg(unix)]
+  tokio::spawn(reload_on_hangup(state.clone()));
+
+  let app = Router::new()
+    .route("/meta/error", get(error::handler))
+    .route("/meta/categories", get(category::categories_handler))
+    .route("/meta/category/:category", get(category::category_handler))
+    .route("/meta/admin/categories", post(category::rename_handler))
+    .route(
+      "/meta/drafts",
+      get(draft::drafts_handler).post(draft::action_handler),
+    )
+    .route("/meta/reports/doctor", get(report::doctor_handler))
+    .route("/meta/reports/stats", get(views::stats_handler))
+    .route("/meta/reports/prose", get(prose::handler))
+    .route("/meta/admin/users", get(admin::users_handler))
+    .route(
+      "/meta/admin/users/:user",
+      get(admin::user_handler).post(admin::user_action_handler),
+    )
+    .route(
+      "/meta/admin/invites",
+      get(invite::invites_handler).post(invite::create_handler),
+    )
+    .route("/meta/admin/invites/revoke", post(invite::revoke_handler))
+    .route("/meta/admin/reload", post(admin::reload_handler))
+    .route("/meta/admin/audit", get(audit::handler))
+    .route(
+      "/meta/admin/lockouts",
+      get(lockout::handler).post(lockout::unlock_handler),
+    )
+    .route(
+      "/meta/admin/quarantine",
+      get(spam::handler).post(spam::action_handler),
+    )
+    .route("/meta/admin/export-users", get(admin::export_users_handler))
+    .route("/meta/admin/import-users", post(admin::import_users_handler))
+    .route(
+      "/meta/admin/import",
+      get(import::form_handler).post(import::upload_handler),
+    )
+    .route(
+      "/meta/admin/replace",
+      get(replace::handler).post(replace::apply_handler),
+    )
+    .route(
+      "/meta/login",
+      get(auth::login_handler).post(auth::authenticate_handler),
+    )
+    .route("/meta/login-callback", get(auth::callback_handler))
+    .route("/meta/logout", get(auth::logout_handler))
+    .route("/meta/sessions/revoke", post(sessions::revoke_handler))
+    .route("/meta/sessions/revoke-all", post(sessions::revoke_all_handler))
+    .route("/meta/profile", post(user::edit_profile_handler))
+    .route("/meta/notifications", post(notify::preferences_handler))
+    .route("/meta/watch/*path", post(notify::watch_handler))
+    .route("/meta/profile/:user", get(user::profile_handler))
+    .route(
+      "/meta/profile/:user/contributions",
+      get(user::contributions_handler),
+    )
+    .route(
+      "/meta/new/*path",
+      get(page::new_handler::get).post(page::new_handler::post),
+    )
+    .route("/meta/history/*path", get(page::history_handler))
+    .route("/meta/compare/*path", get(page::compare_handler))
+    .route(
+      "/meta/export-history/*path",
+      get(page::export_history_handler),
+    )
+    .route(
+      "/meta/edit/*path",
+      get(page::edit_handler::get).post(page::edit_handler::post),
+    )
+    .route(
+      "/meta/guest-edit/*path",
+      get(guest::form_handler).post(guest::submit_handler),
+    )
+    .route("/meta/raw/*path", get(page::raw_handler))
+    .route("/meta/lock/*path", post(lock::handler))
+    .route(
+      "/meta/collab/*path",
+      get(collab::socket_handler).post(collab::save_handler),
+    )
+    .route("/meta/render", post(pandoc::render_handler))
+    .route("/meta/attachment/:name", get(notebook::attachment_handler))
+    .route("/meta/thumb/*path", get(thumbnail::handler))
+    .route(
+      "/meta/media",
+      get(media::handler).post(media::delete_handler),
+    )
+    .route("/meta/paste-image/*path", post(media::paste_handler))
+    .route("/meta/delete/*path", post(trash::delete_handler))
+    .route(
+      "/meta/trash",
+      get(trash::handler).post(trash::restore_handler),
+    )
+    .route("/meta/recent-changes", get(recent_changes::handler))
+    .route("/meta/events", get(recent_changes::events_handler))
+    .route("/api/v1/pages/*path", get(api::pages_handler))
+    .route("/api/v1/suggest", get(suggest::handler))
+    .route("/meta/quick-open", get(suggest::quick_open_handler))
+    .route(
+      "/micropub",
+      get(micropub::query_handler).post(micropub::handler),
+    )
+    .fallback(get(route::route))
+    // Inside the session layers, so the error page knows who's logged in.
+    .layer(axum::middleware::from_fn(error::negotiate));
+
+  let app = auth::setup(app, logins);
+  let app = app
+    .layer(CompressionLayer::new().compress_when(Compressible))
+    .layer(Extension(state));
+
+  Ok(app)
+}
@@ -0,0 +1,77 @@
+//! Where each line of a page's source ends up once site variables, shortcodes and includes
+//! have been filled in - they can add lines, so what the converter says about a line has to be
+//! traced back to the page.
+
+/// Which line of the original each line of some rebuilt text came from.
+#[derive(Clone, Debug, Default)]
+pub struct LineMap {
+  /// The original's line for each line of the text, counting from 0.
+  lines: Vec<usize>,
+}
+
+impl LineMap {
+  /// The original's line for `line` of the text, both counting from 1. Lines the map doesn't
+  /// know about are left as they are.
+  pub fn original(&self, line: usize) -> usize {
+    line
+      .checked_sub(1)
+      .and_then(|line| self.lines.get(line))
+      .map_or(line, |original| original + 1)
+  }
+
+  /// Follows each of `next`'s lines, which are of text rebuilt from this map's text, back to
+  /// this map's original.
+  pub fn then(&self, next: &LineMap) -> LineMap {
+    let lines = next
+      .lines
+      .iter()
+      .map(|line| self.lines.get(*line).copied().unwrap_or(*line))
+      .collect();
+
+    LineMap { lines }
+  }
+}
+
+/// Text that's being built from an original, with parts of it replaced.
+pub struct Rebuilt {
+  text: String,
+  map: LineMap,
+  /// The original's line that's being copied now.
+  line: usize,
+}
+
+impl Rebuilt {
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self {
+      text: String::with_capacity(capacity),
+      map: LineMap { lines: vec![0] },
+      line: 0,
+    }
+  }
+
+  /// Copies part of the original across as it is.
+  pub fn keep(&mut self, original: &str) {
+    self.text.push_str(original);
+
+    for _ in original.matches('\n') {
+      self.line += 1;
+      self.map.lines.push(self.line);
+    }
+  }
+
+  /// Puts `replacement` where `original` was. Every line of it is put down to the line
+  /// `original` starts on.
+  pub fn replace(&mut self, original: &str, replacement: &str) {
+    self.text.push_str(replacement);
+
+    for _ in replacement.matches('\n') {
+      self.map.lines.push(self.line);
+    }
+
+    self.line += original.matches('\n').count();
+  }
+
+  pub fn finish(self) -> (String, LineMap) {
+    (self.text, self.map)
+  }
+}
@@ -0,0 +1,182 @@
+//! Where the wiki listens for connections - a TCP port, or a Unix socket for a reverse proxy
+//! on the same machine - and serving HTTPS itself, when there isn't a proxy.
+
+use std::{
+  fmt,
+  net::SocketAddr,
+  os::unix::fs::FileTypeExt,
+  path::PathBuf,
+  time::{Duration, SystemTime},
+};
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+use tokio::{
+  net::UnixListener,
+  signal::unix::{signal, SignalKind},
+};
+use tokio_stream::wrappers::UnixListenerStream;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Listen {
+  Tcp(SocketAddr),
+  Unix {
+    unix: PathBuf,
+    /// The socket's permissions, like `0o660` - otherwise they come from the umask.
+    #[serde(default)]
+    mode: Option<u32>,
+  },
+}
+
+/// Serving HTTPS directly, with a certificate and key in PEM files - like the ones from Let's
+/// Encrypt.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tls {
+  /// The certificate chain.
+  pub certificate: PathBuf,
+  pub key: PathBuf,
+}
+
+/// How often the certificate files are checked for a renewal.
+const RENEWAL_CHECK: Duration = Duration::from_secs(60);
+
+impl Tls {
+  /// When either file last changed.
+  fn modified(&self) -> Option<SystemTime> {
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    modified(&self.certificate).max(modified(&self.key))
+  }
+
+  /// Reloads the certificate whenever the files change, so renewals are picked up without a
+  /// restart.
+  async fn reload_on_renewal(self, rustls: RustlsConfig) {
+    let mut last = self.modified();
+    let mut interval = tokio::time::interval(RENEWAL_CHECK);
+
+    loop {
+      interval.tick().await;
+
+      let modified = self.modified();
+      if modified == last {
+        continue;
+      }
+
+      match rustls
+        .reload_from_pem_file(&self.certificate, &self.key)
+        .await
+      {
+        Ok(()) => {
+          log::info!("reloaded the TLS certificate");
+          last = modified;
+        },
+        // It's probably still being written, so it's tried again next time.
+        Err(err) => log::warn!("Couldn't reload the TLS certificate: {}", err),
+      }
+    }
+  }
+}
+
+impl fmt::Display for Listen {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Listen::Tcp(address) => write!(f, "{}", address),
+      Listen::Unix { unix, .. } => write!(f, "unix:{}", unix.display()),
+    }
+  }
+}
+
+/// How long requests that are still going get to finish, once the wiki is asked to stop.
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Waits for the process to be asked to stop, with Ctrl-C or a `SIGTERM`.
+async fn stop_signal() {
+  let mut terminate = match signal(SignalKind::terminate()) {
+    Ok(terminate) => terminate,
+    Err(err) => {
+      log::warn!(
+        "Couldn't listen for SIGTERM, so only Ctrl-C stops it cleanly: {}",
+        err
+      );
+      let _ = tokio::signal::ctrl_c().await;
+      return;
+    },
+  };
+
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => (),
+    _ = terminate.recv() => (),
+  }
+
+  log::info!("stopping");
+}
+
+/// Serves `app` until it's asked to stop, over HTTPS if there's `tls`.
+pub async fn serve(app: Router, listen_on: &Listen, tls: Option<&Tls>) -> Result<(), eyre::Report> {
+  log::info!("listening on {}", listen_on);
+
+  match (listen_on, tls) {
+    (Listen::Tcp(address), Some(tls)) => {
+      let rustls = RustlsConfig::from_pem_file(&tls.certificate, &tls.key).await?;
+
+      tokio::spawn(tls.clone().reload_on_renewal(rustls.clone()));
+
+      let handle = axum_server::Handle::new();
+      tokio::spawn({
+        let handle = handle.clone();
+        async move {
+          stop_signal().await;
+          handle.graceful_shutdown(Some(GRACE_PERIOD));
+        }
+      });
+
+      axum_server::bind_rustls(*address, rustls)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    },
+    (Listen::Tcp(address), None) => {
+      axum::Server::bind(address)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(stop_signal())
+        .await?;
+    },
+    (Listen::Unix { .. }, Some(_)) => {
+      eyre::bail!("`tls` can't be used with a Unix socket - the proxy in front should do it");
+    },
+    (Listen::Unix { unix, mode }, None) => {
+      // A socket left over from last time would stop it binding, but anything else there
+      // isn't the wiki's to remove.
+      if let Ok(metadata) = std::fs::symlink_metadata(unix) {
+        if !metadata.file_type().is_socket() {
+          eyre::bail!("{} is already there, and isn't a socket", unix.display());
+        }
+
+        std::fs::remove_file(unix)?;
+      }
+
+      // The socket is made with its permissions, so there's no moment it has others.
+      let listener = match mode {
+        Some(mode) => {
+          let umask = unsafe { libc::umask(!*mode as libc::mode_t & 0o777) };
+          let listener = UnixListener::bind(unix);
+          unsafe { libc::umask(umask) };
+
+          listener?
+        },
+        None => UnixListener::bind(unix)?,
+      };
+
+      let incoming = hyper::server::accept::from_stream(UnixListenerStream::new(listener));
+
+      axum::Server::builder(incoming)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(stop_signal())
+        .await?;
+    },
+  }
+
+  Ok(())
+}
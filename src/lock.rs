@@ -0,0 +1,96 @@
+//! Soft edit locks - whoever opens the editor first holds a lease on the page, which the
+//! editor keeps renewing, so anyone else who opens it can see they might clash. Nothing stops
+//! them saving - conflicting saves are still merged or refused by git.
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+use axum::{
+  extract::Extension,
+  http::StatusCode,
+  response::{IntoResponse, Response},
+  Json,
+};
+use serde::Serialize;
+use time::{Duration, OffsetDateTime};
+
+use crate::{page::Page, user::User, State};
+
+/// How long a lease lasts without being renewed - the editor renews it every minute.
+const LEASE: Duration = Duration::minutes(5);
+
+#[derive(Clone, Serialize)]
+pub struct Lease {
+  pub email: String,
+  pub name: String,
+  #[serde(with = "time::serde::rfc3339")]
+  pub expires: OffsetDateTime,
+}
+
+impl Lease {
+  fn is_expired(&self) -> bool {
+    self.expires <= OffsetDateTime::now_utc()
+  }
+}
+
+#[derive(Default)]
+pub struct EditLocks {
+  leases: Mutex<HashMap<PathBuf, Lease>>,
+}
+
+impl EditLocks {
+  /// Takes out or renews `user`'s lease on the page at `path`. If someone else has a lease that
+  /// hasn't expired, that's returned instead - once it has, anyone can take over.
+  pub fn acquire(&self, path: &Path, user: &User) -> Result<Lease, Lease> {
+    let mut leases = self.leases.lock().unwrap();
+
+    if let Some(lease) = leases.get(path) {
+      if lease.email != user.email && !lease.is_expired() {
+        return Err(lease.clone());
+      }
+    }
+
+    let lease = Lease {
+      email: user.email.clone(),
+      name: user.name.clone(),
+      expires: OffsetDateTime::now_utc() + LEASE,
+    };
+    leases.insert(path.to_path_buf(), lease.clone());
+
+    Ok(lease)
+  }
+
+  /// Gives up `user`'s lease on `path`, once they've saved it.
+  pub fn release(&self, path: &Path, user: &User) {
+    let mut leases = self.leases.lock().unwrap();
+
+    if leases.get(path).map(|lease| lease.email == user.email) == Some(true) {
+      leases.remove(path);
+    }
+
+    // Nobody's going to renew these, so they might as well go now.
+    leases.retain(|_, lease| !lease.is_expired());
+  }
+}
+
+#[derive(Serialize)]
+pub struct LockStatus {
+  /// Whether the lease is the user's own.
+  held: bool,
+  lease: Lease,
+}
+
+/// Called by the editor to keep its lease, or to take over one that's expired.
+pub async fn handler(page: Page, user: User, Extension(state): Extension<Arc<State>>) -> Response {
+  match state.locks.acquire(&page.path, &user) {
+    Ok(lease) => Json(LockStatus { held: true, lease }).into_response(),
+    Err(lease) => (
+      StatusCode::CONFLICT,
+      Json(LockStatus { held: false, lease }),
+    )
+      .into_response(),
+  }
+}
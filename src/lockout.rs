@@ -0,0 +1,302 @@
+//! Slowing down, then locking out, whoever keeps failing to log in. Failures are counted for each
+//! IP address and for each account - once the login provider has said whose it is - so guessing at
+//! one account from many addresses, or at many accounts from one address, are both caught. After a
+//! few failures each try has to wait longer than the last, and after too many there are no more
+//! tries until the lockout is over. Failures are only kept in memory.
+
+use std::{
+  collections::HashMap,
+  fmt,
+  net::IpAddr,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use axum::{
+  extract::Form,
+  response::{Html, IntoResponse, Redirect, Response},
+  Extension,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+  date::Dates,
+  error::ErrorPage,
+  proxy::SameOrigin,
+  role::{Is, Role},
+  template::Template,
+  State,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("Too many failed logins - try again in {} minutes", .0.as_secs() / 60 + 1)]
+  LockedOut(Duration),
+  #[error("Too many failed logins - wait {} seconds before trying again", .0.as_secs() + 1)]
+  TooSoon(Duration),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    let (Self::LockedOut(wait) | Self::TooSoon(wait)) = &self;
+
+    ErrorPage::RateLimited {
+      seconds: wait.as_secs() + 1,
+    }
+    .into_response()
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Lockout {
+  /// How many failed logins are allowed before each try has to wait - one second after the
+  /// next failure, then two, then four, and so on.
+  pub free_attempts: u32,
+  /// How many failed logins lock the address or account out.
+  pub max_failures: u32,
+  /// How long failures are remembered for, in seconds.
+  pub window: u64,
+  /// How long a lockout lasts, in seconds.
+  pub duration: u64,
+}
+
+impl Default for Lockout {
+  fn default() -> Self {
+    Self {
+      free_attempts: 3,
+      max_failures: 10,
+      window: 900,
+      duration: 900,
+    }
+  }
+}
+
+/// What failures are counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+  Address(IpAddr),
+  /// The email of the account the login provider said someone is.
+  Account(String),
+}
+
+impl Key {
+  /// The keys for a login from `client` as `account`, whichever of them are known.
+  pub fn login(client: Option<IpAddr>, account: Option<&str>) -> Vec<Self> {
+    let client = client.map(Self::Address);
+    let account = account.map(|account| Self::Account(account.to_string()));
+
+    client.into_iter().chain(account).collect()
+  }
+
+  fn parse(key: &str) -> Option<Self> {
+    match key.split_once(':')? {
+      ("address", address) => address.parse().ok().map(Self::Address),
+      ("account", account) => Some(Self::Account(account.to_string())),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Key {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Address(address) => write!(f, "address:{}", address),
+      Self::Account(account) => write!(f, "account:{}", account),
+    }
+  }
+}
+
+#[derive(Clone)]
+struct Failures {
+  count: u32,
+  last: OffsetDateTime,
+  locked_until: Option<OffsetDateTime>,
+}
+
+impl Failures {
+  /// Whether these have been forgotten about.
+  fn is_expired(&self, now: OffsetDateTime, settings: &Lockout) -> bool {
+    let locked = self.locked_until.map(|until| until > now).unwrap_or(false);
+    let recent = now - self.last < time::Duration::seconds(settings.window as i64);
+
+    !locked && !recent
+  }
+
+  /// How much longer the next try has to wait.
+  fn wait(&self, now: OffsetDateTime, settings: &Lockout) -> Option<Error> {
+    if let Some(until) = self.locked_until.filter(|until| *until > now) {
+      return Some(Error::LockedOut(remaining(until - now)));
+    }
+
+    if self.count < settings.free_attempts {
+      return None;
+    }
+
+    let delay = 2u64
+      .saturating_pow(self.count - settings.free_attempts)
+      .min(settings.duration);
+    let ready = self.last + time::Duration::seconds(delay as i64);
+
+    (ready > now).then(|| Error::TooSoon(remaining(ready - now)))
+  }
+}
+
+fn remaining(duration: time::Duration) -> Duration {
+  Duration::try_from(duration).unwrap_or_default()
+}
+
+#[derive(Default)]
+pub struct Lockouts {
+  failures: Mutex<HashMap<Key, Failures>>,
+}
+
+impl Lockouts {
+  /// Whether a login with `keys` can be tried yet.
+  pub fn check(&self, keys: &[Key], settings: &Lockout) -> Result<(), Error> {
+    let now = OffsetDateTime::now_utc();
+    let mut failures = self.failures.lock().unwrap();
+
+    failures.retain(|_, failures| !failures.is_expired(now, settings));
+
+    let wait = keys
+      .iter()
+      .filter_map(|key| failures.get(key)?.wait(now, settings))
+      .next();
+
+    match wait {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+
+  /// Counts a failed login. Returns whichever of `keys` were locked out by it.
+  pub fn fail(&self, keys: &[Key], settings: &Lockout) -> Vec<Key> {
+    let now = OffsetDateTime::now_utc();
+    let mut failures = self.failures.lock().unwrap();
+    let mut locked = Vec::new();
+
+    for key in keys {
+      let entry = failures.entry(key.clone()).or_insert(Failures {
+        count: 0,
+        last: now,
+        locked_until: None,
+      });
+
+      entry.count += 1;
+      entry.last = now;
+
+      let is_locked = entry.locked_until.map(|until| until > now).unwrap_or(false);
+
+      if entry.count >= settings.max_failures && !is_locked {
+        entry.locked_until = Some(now + time::Duration::seconds(settings.duration as i64));
+        locked.push(key.clone());
+      }
+    }
+
+    locked
+  }
+
+  /// Forgets the failures of `keys`, after someone has logged in with them.
+  pub fn succeed(&self, keys: &[Key]) {
+    let mut failures = self.failures.lock().unwrap();
+
+    for key in keys {
+      failures.remove(key);
+    }
+  }
+}
+
+pub async fn handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Extension(state): Extension<Arc<State>>,
+) -> Html<String> {
+  let config = state.config();
+  let dates = Dates::new(Some(&admin), &config.dates);
+  let now = OffsetDateTime::now_utc();
+
+  let mut failures: Vec<(Key, Failures)> = state
+    .lockouts
+    .failures
+    .lock()
+    .unwrap()
+    .iter()
+    .filter(|(_, failures)| !failures.is_expired(now, &config.lockout))
+    .map(|(key, failures)| (key.clone(), failures.clone()))
+    .collect();
+
+  failures.sort_by(|(_, a), (_, b)| b.last.cmp(&a.last));
+
+  let content = maud::html! {
+    @if failures.is_empty() {
+      p { "Nobody has failed to log in recently." }
+    } @else {
+      table #lockouts {
+        thead {
+          tr {
+            th { "Address or account" }
+            th { "Failures" }
+            th { "Last failure" }
+            th { "Locked out until" }
+            th {}
+          }
+        }
+        tbody {
+          @for (key, failures) in &failures {
+            tr {
+              td {
+                @match key {
+                  Key::Address(address) => (address),
+                  Key::Account(account) => (account),
+                }
+              }
+              td { (failures.count) }
+              td { (dates.render(failures.last)) }
+              td {
+                @if let Some(until) = failures.locked_until.filter(|until| *until > now) {
+                  (dates.render(until))
+                }
+              }
+              td {
+                form method="post" action="/meta/admin/lockouts" {
+                  input type="hidden" name="key" value=(key);
+                  button type="submit" { "Unlock" }
+                }
+              }
+            }
+          }
+        }
+      }
+    }
+  };
+
+  Template::new()
+    .title("Failed logins")
+    .content(content)
+    .render(Some(admin), &state)
+}
+
+#[derive(Deserialize)]
+pub struct UnlockParams {
+  key: String,
+}
+
+/// Forgets an address's or account's failures, so they can try again straight away.
+pub async fn unlock_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+  Form(params): Form<UnlockParams>,
+) -> Redirect {
+  if let Some(key) = Key::parse(&params.key) {
+    state.lockouts.succeed(&[key]);
+
+    state
+      .audit
+      .record(&admin.email, crate::audit::Action::Unlock, params.key, None)
+      .await;
+  }
+
+  Redirect::to("/meta/admin/lockouts")
+}
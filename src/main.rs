@@ -1,37 +1,4 @@
-#![feature(adt_const_params, error_reporter)]
-
-use std::sync::{Arc, Mutex};
-
-use axum::{
-  routing::{get, post},
-  Extension,
-  Router,
-};
-
-use crate::{
-  config::{Args, Config},
-  git::Git,
-  user::UserDb,
-};
-
-mod auth;
-mod config;
-mod error;
-mod front_matter;
-mod git;
-mod page;
-mod pandoc;
-mod role;
-mod route;
-mod template;
-mod user;
-
-#[derive(Clone)]
-pub struct State {
-  config: Arc<Config>,
-  git: Arc<Git>,
-  users: Arc<Mutex<UserDb>>,
-}
+use gitalite::config::{Args, Command, Config};
 
 #[tokio::main]
 async fn main() -> Result<(), eyre::Report> {
@@ -40,57 +7,29 @@ async fn main() -> Result<(), eyre::Report> {
 
   let args = Args::parse();
 
-  let config = tokio::fs::read_to_string(args.config).await?;
-  let mut config: Config = ron::from_str(&config)?;
-
-  // We make the directory, so we can canonicalize it!
-  tokio::fs::create_dir_all(&config.pages_directory).await?;
-
-  config.canonicalize()?;
+  // There's no config to read yet - it comes from the archive.
+  if let Some(Command::Restore { input, key }) = &args.command {
+    let key = tokio::fs::read(key).await?;
+    gitalite::backup::restore(input, &args.config, &key).await?;
 
-  let config = Arc::new(config);
+    return Ok(());
+  }
 
-  let git = git::Git::new(config.clone())?;
-  let git = Arc::new(git);
+  let config = Config::load(&args.config)?;
 
-  let users = UserDb::new(config.clone()).await?;
-  let users = Arc::new(Mutex::new(users));
+  match args.command {
+    Some(Command::Serve) | None => (),
+    Some(command) => return gitalite::cli::run(command, config, &args.config).await,
+  }
 
-  let state = State { config, git, users };
-  let state = Arc::new(state);
-
-  pandoc::test_output()?;
+  let listen_on = config.listen_on.clone();
+  let tls = config.tls.clone();
 
   // build our application with a route
-  let app = Router::new()
-    .route("/meta/error", get(error::handler))
-    .route("/meta/categories", get(page::categories_handler))
-    .route(
-      "/meta/login",
-      get(auth::login_handler).post(auth::authenticate_handler),
-    )
-    .route("/meta/login-callback", get(auth::callback_handler))
-    .route("/meta/profile/:user", get(user::profile_handler))
-    .route(
-      "/meta/new/*path",
-      get(page::new_handler::get).post(page::new_handler::post),
-    )
-    .route("/meta/history/*path", get(page::history_handler))
-    .route(
-      "/meta/edit/*path",
-      get(page::edit_handler::get).post(page::edit_handler::post),
-    )
-    .route("/meta/raw/*path", get(page::raw_handler))
-    .route("/meta/render", post(pandoc::render_handler))
-    .fallback(get(route::route));
-
-  let app = auth::setup(app, state.clone()).await?;
-  let app = app.layer(Extension(state.clone()));
+  let app = gitalite::app(config).await?;
 
-  log::info!("listening on {}", state.config.listen_on);
-  axum::Server::bind(&state.config.listen_on)
-    .serve(app.into_make_service())
-    .await?;
+  gitalite::listen::serve(app, &listen_on, tls.as_ref()).await?;
+  gitalite::stop().await;
 
   Ok(())
 }
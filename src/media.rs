@@ -0,0 +1,518 @@
+//! Everything in the wiki that isn't a page - images, PDFs, videos and the like - listed at
+//! `/meta/media`.
+
+use std::{
+  collections::BTreeMap,
+  path::{Component, Path, PathBuf},
+  sync::Arc,
+};
+
+use axum::{
+  body::Bytes,
+  extract::{self, ContentLengthLimit, Extension, Form, Query},
+  http::{header, HeaderMap, StatusCode},
+  response::{Html, IntoResponse, Redirect, Response},
+  Json,
+};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+  audit::Action,
+  commit_message::{Kind, Vars},
+  config::Config,
+  date::Dates,
+  format::{Format, REGISTRY},
+  git::{Author, Commit},
+  page::Page,
+  proxy::SameOrigin,
+  quota::{Activity, Who},
+  role::Role,
+  template::Template,
+  user::User,
+  State,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Git(#[from] crate::git::Error),
+  #[error(transparent)]
+  Acl(#[from] crate::acl::Error),
+  #[error(transparent)]
+  CommitMessage(#[from] crate::commit_message::Error),
+  #[error(transparent)]
+  Quota(#[from] crate::quota::Error),
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error("'{0}' isn't a media file")]
+  NotMedia(String),
+  #[error("Files can't be added or deleted while your edits are reviewed")]
+  Drafted,
+  #[error("The archived page '{0}' uses this file, so only administrators can delete it")]
+  UsedByArchived(String),
+  #[error("Only PNG, JPEG, GIF and WebP images can be pasted, if they're in `allowed_mime_types`")]
+  NotAnImage,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Git(err) => err.into_response(),
+      Self::Acl(err) => err.into_response(),
+      Self::CommitMessage(err) => err.into_response(),
+      Self::Quota(err) => err.into_response(),
+      Self::Page(err) => err.into_response(),
+      Self::NotMedia(_) => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+      Self::Drafted | Self::UsedByArchived(_) => {
+        (StatusCode::FORBIDDEN, self.to_string()).into_response()
+      },
+      Self::NotAnImage => (StatusCode::UNSUPPORTED_MEDIA_TYPE, self.to_string()).into_response(),
+      Self::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
+    }
+  }
+}
+
+/// The biggest image that can be pasted, in bytes.
+const MAX_PASTE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The types of image that can be pasted, and the extension they're saved with. SVGs aren't
+/// included, as they can have scripts in them.
+const PASTE_TYPES: &[(&str, &str)] = &[
+  ("image/png", "png"),
+  ("image/jpeg", "jpg"),
+  ("image/gif", "gif"),
+  ("image/webp", "webp"),
+];
+
+struct MediaFile {
+  /// Relative to the pages directory.
+  path: PathBuf,
+  size: u64,
+  last_commit: Option<Commit>,
+}
+
+/// Whether the file at `path`, relative to the pages directory, is media rather than a page.
+fn is_media(path: &Path) -> bool {
+  let is_visible = path.components().all(|component| match component {
+    Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+    _ => false,
+  });
+
+  let is_page = path
+    .extension()
+    .and_then(|extension| Format::from_extension(&extension.to_string_lossy()))
+    .is_some();
+
+  let mime = mime_guess::from_path(path).first_or_text_plain();
+
+  is_visible && !is_page && mime.type_() != "text"
+}
+
+/// Every media file, relative to the pages directory.
+fn all(config: &Config) -> Vec<PathBuf> {
+  WalkDir::new(&config.pages_directory)
+    .into_iter()
+    // Skips `.git`, which is big and never has anything to show.
+    .filter_entry(|entry| {
+      entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+    })
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| entry.file_type().is_file())
+    .filter_map(|entry| {
+      let path = entry.path().strip_prefix(&config.pages_directory).ok()?;
+      is_media(path).then(|| path.to_path_buf())
+    })
+    .collect()
+}
+
+/// Whether `/meta/thumb` can make a thumbnail of `path`.
+fn has_thumbnail(path: &Path, config: &Config) -> bool {
+  let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+  image::ImageFormat::from_path(path).is_ok()
+    && config.allowed_mime_types.contains(mime.essence_str())
+}
+
+pub fn human_size(bytes: u64) -> String {
+  const UNITS: &[&str] = &["KB", "MB", "GB"];
+
+  if bytes < 1024 {
+    return format!("{} bytes", bytes);
+  }
+
+  let mut size = bytes as f64 / 1024.0;
+  let mut unit = UNITS[0];
+
+  for next in &UNITS[1..] {
+    if size < 1024.0 {
+      break;
+    }
+
+    size /= 1024.0;
+    unit = next;
+  }
+
+  format!("{:.1} {}", size, unit)
+}
+
+/// An archived page that links to or embeds the media at `path`, if there is one.
+async fn archived_page_using(path: &Path, state: &State) -> Result<Option<PathBuf>, Error> {
+  let config = state.config();
+  let path = path.to_string_lossy();
+  let url = path.replace(' ', "%20");
+
+  for page in Page::all(&config).filter(|page| !page.is_binary()) {
+    let raw = page.raw(state).await?;
+    if !raw.contains(path.as_ref()) && !raw.contains(&url) {
+      continue;
+    }
+
+    if page.split_front_matter(&raw).0.archived {
+      return Ok(Some(page.relative_path(&config)?));
+    }
+  }
+
+  Ok(None)
+}
+
+/// What to paste into a page to use `path`.
+fn embed_snippet(path: &Path, config: &Config) -> String {
+  let name = path
+    .file_name()
+    .map(|name| name.to_string_lossy().to_string())
+    .unwrap_or_default();
+  let url = format!("/{}", path.display()).replace(' ', "%20");
+  let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+  match mime.type_().as_str() {
+    "image" if has_thumbnail(path, config) => format!("![{}](/meta/thumb{}?w=800)", name, url),
+    "image" => format!("![{}]({})", name, url),
+    _ => format!("[{}]({})", name, url),
+  }
+}
+
+pub async fn handler(
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, Error> {
+  let config = state.config();
+
+  let files = tokio::task::spawn_blocking({
+    let state = state.clone();
+    let config = config.clone();
+    let user = user.clone();
+
+    move || -> Result<Vec<_>, Error> {
+      let paths: Vec<_> = all(&config)
+        .into_iter()
+        .filter(|path| crate::namespace::is_visible(path, user.as_ref(), &config))
+        .collect();
+      let last_commits = state.git.last_commits(&paths, &*state.users)?;
+
+      paths
+        .into_iter()
+        .zip(last_commits)
+        .map(|(path, last_commit)| {
+          let size = config.pages_directory.join(&path).metadata()?.len();
+
+          Ok(MediaFile {
+            path,
+            size,
+            last_commit,
+          })
+        })
+        .collect()
+    }
+  })
+  .await
+  .unwrap()?;
+
+  let mut directories: BTreeMap<PathBuf, Vec<MediaFile>> = BTreeMap::new();
+  for file in files {
+    let directory = file
+      .path
+      .parent()
+      .map(Path::to_path_buf)
+      .unwrap_or_default();
+    directories.entry(directory).or_default().push(file);
+  }
+
+  for files in directories.values_mut() {
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+  }
+
+  let dates = Dates::new(user.as_ref(), &config.dates);
+  let can_delete = |path: &Path| match &user {
+    Some(user) => {
+      crate::acl::can_edit(user, path, &config) && !crate::draft::is_drafted(user, &config)
+    },
+    None => false,
+  };
+
+  let content = maud::html! {
+    @if directories.is_empty() {
+      p { "There isn't any media in the wiki yet." }
+    }
+
+    @for (directory, files) in &directories {
+      section .media-directory {
+        h2 {
+          @if directory.as_os_str().is_empty() {
+            "/"
+          } @else {
+            (directory.display())
+          }
+        }
+        table .media {
+          @for file in files {
+            tr {
+              td .thumbnail {
+                @if has_thumbnail(&file.path, &config) {
+                  img src={ "/meta/thumb/" (file.path.display()) "?w=120&h=120" } alt="" loading="lazy";
+                }
+              }
+              td {
+                a href={ "/" (file.path.display()) } {
+                  (file.path.file_name().unwrap_or_default().to_string_lossy())
+                }
+              }
+              td { (human_size(file.size)) }
+              td {
+                @if let Some(commit) = &file.last_commit {
+                  (dates.render(commit.date))
+                  " by "
+                  @match &commit.author {
+                    Author::User(user) => {
+                      a href={ "/meta/profile/" (user.email) } { (user.name) }
+                    },
+                    Author::NonUser { name, .. } => (name),
+                  }
+                }
+              }
+              td .actions {
+                @if user.is_some() {
+                  button type="button" .copy-snippet data-snippet=(embed_snippet(&file.path, &config)) {
+                    "Copy embed snippet"
+                  }
+                }
+                @if can_delete(&file.path) {
+                  form method="post" action="/meta/media" {
+                    input type="hidden" name="path" value=(file.path.display());
+                    button type="submit" { "Delete" }
+                  }
+                }
+              }
+            }
+          }
+        }
+      }
+    }
+  };
+
+  let script = r#"
+    for (const button of document.querySelectorAll('.copy-snippet')) {
+      button.addEventListener('click', async () => {
+        await navigator.clipboard.writeText(button.dataset.snippet);
+        button.textContent = 'Copied!';
+      });
+    }
+  "#;
+
+  let html = Template::new()
+    .title("Media")
+    .content(content)
+    .script(script)
+    .render(user, &state);
+
+  Ok(html)
+}
+
+#[derive(Deserialize)]
+pub struct DeleteParams {
+  path: String,
+}
+
+pub async fn delete_handler(
+  user: User,
+  _: SameOrigin,
+  Form(params): Form<DeleteParams>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Redirect, Error> {
+  let config = state.config();
+  let path = PathBuf::from(&params.path);
+  let filepath = config.pages_directory.join(&path);
+
+  if !is_media(&path) || !filepath.is_file() {
+    return Err(Error::NotMedia(params.path));
+  }
+
+  crate::acl::check_edit(&user, &path, &config)?;
+
+  if crate::draft::is_drafted(&user, &config) {
+    return Err(Error::Drafted);
+  }
+
+  // Archived pages are kept as they are, down to what they show.
+  if !user.roles.contains(&Role::Administrator) {
+    if let Some(page) = archived_page_using(&path, &state).await? {
+      return Err(Error::UsedByArchived(page.display().to_string()));
+    }
+  }
+
+  let name = path.file_name().unwrap_or_default().to_string_lossy();
+  let message = config.commit_messages.render(
+    Kind::Delete,
+    Vars {
+      path: &params.path,
+      title: &name,
+      summary: None,
+    },
+  )?;
+
+  let writing = state.git.writing().lock().await;
+
+  // The file is only removed once the commit has been made, so it's still there if that fails.
+  state.git.remove_file(&path)?;
+  if let Err(err) = state.git.commit(&message, &user) {
+    state.git.add_file(&path)?;
+    return Err(err.into());
+  }
+  tokio::fs::remove_file(&filepath).await?;
+
+  state.git.push()?;
+  drop(writing);
+
+  state
+    .audit
+    .record(&user.email, Action::DeleteMedia, params.path.clone(), None)
+    .await;
+
+  crate::cache::purge(&config, &path);
+
+  Ok(Redirect::to("/meta/media"))
+}
+
+#[derive(Deserialize)]
+pub struct PasteParams {
+  /// The format the page is being written in, so the snippet suits it. If it's not given, or
+  /// is `auto`, the page's current format is used.
+  format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Pasted {
+  url: String,
+  /// What to insert into the page.
+  snippet: String,
+}
+
+/// Saves an image pasted into the editor for the page at `page`, next to it.
+pub async fn paste_handler(
+  user: User,
+  _: SameOrigin,
+  extract::Path(page): extract::Path<String>,
+  Query(params): Query<PasteParams>,
+  request_headers: HeaderMap,
+  Extension(state): Extension<Arc<State>>,
+  ContentLengthLimit(body): ContentLengthLimit<Bytes, MAX_PASTE_SIZE>,
+) -> Result<Json<Pasted>, Error> {
+  let config = state.config();
+
+  let content_type = request_headers
+    .get(header::CONTENT_TYPE)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.parse::<mime_guess::Mime>().ok());
+
+  let extension = content_type
+    .filter(|mime| config.allowed_mime_types.contains(mime.essence_str()))
+    .and_then(|mime| {
+      PASTE_TYPES
+        .iter()
+        .find(|(essence, _)| *essence == mime.essence_str())
+        .map(|(_, extension)| *extension)
+    })
+    .ok_or(Error::NotAnImage)?;
+
+  let page = PathBuf::from(page.trim_start_matches('/'));
+  let stem = match page.file_stem() {
+    Some(stem) if page.components().all(|c| matches!(c, Component::Normal(_))) => stem,
+    _ => return Err(Error::NotMedia(page.display().to_string())),
+  };
+
+  // Named after the page and what's in it, so pasting the same image twice doesn't copy it.
+  let fingerprint = crate::assets::fingerprint(&body);
+  let name = format!(
+    "{}-{}.{}",
+    stem.to_string_lossy(),
+    &fingerprint[..8],
+    extension
+  );
+  let path = page.with_file_name(&name);
+
+  crate::acl::check_edit(&user, &path, &config)?;
+
+  if crate::draft::is_drafted(&user, &config) {
+    return Err(Error::Drafted);
+  }
+
+  // The image is for putting in the page, which has to be one that can be changed.
+  if let Ok(existing) = Page::from_path(page.clone(), Some(user.clone()), &config) {
+    existing.check_archived(None, &user, &state).await?;
+  }
+
+  let filepath = config.pages_directory.join(&path);
+
+  if !filepath.is_file() {
+    state.usage.take(
+      Who::user(&user),
+      Activity::Upload(body.len() as u64),
+      config.quotas.quota(Some(&user)),
+    )?;
+
+    let message = config.commit_messages.render(
+      Kind::Create,
+      Vars {
+        path: &path.to_string_lossy(),
+        title: &name,
+        summary: None,
+      },
+    )?;
+
+    if let Some(directory) = filepath.parent() {
+      tokio::fs::create_dir_all(directory).await?;
+    }
+
+    let writing = state.git.writing().lock().await;
+    tokio::fs::write(&filepath, &body).await?;
+
+    state.git.add_file(&path)?;
+    state.git.commit(&message, &user)?;
+    state.git.push()?;
+    drop(writing);
+  }
+
+  let format = params
+    .format
+    .filter(|name| name != "auto")
+    .and_then(|name| REGISTRY.by_name(&name))
+    .or_else(|| {
+      let existing = crate::page::find_file(&page, &config).ok()?;
+      Format::from_extension(&existing.extension()?.to_string_lossy())
+    });
+
+  let is_markdown = format
+    .map(|format| format.name.contains("markdown") || format.name == "commonmark")
+    .unwrap_or(true);
+
+  let url = format!("/{}", path.display()).replace(' ', "%20");
+  let snippet = if is_markdown {
+    format!("![]({})", url)
+  } else {
+    format!("<img src=\"{}\" alt=\"\">", url)
+  };
+
+  Ok(Json(Pasted { url, snippet }))
+}
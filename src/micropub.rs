@@ -0,0 +1,578 @@
+//! A [Micropub](https://micropub.spec.indieweb.org/) endpoint, so IndieWeb posting clients
+//! can create and update pages.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use axum::{
+  body::Bytes,
+  extract::{Extension, Query},
+  http::{header, HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+  Json,
+};
+use oauth2::url::{form_urlencoded, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+  audit::Action,
+  config::Config,
+  format::Format,
+  front_matter::FrontMatter,
+  page::Page,
+  proxy::Forwarded,
+  quota::{Activity, Who},
+  spam::Submission,
+  user::User,
+  State,
+};
+
+/// Where Micropub posts go, and how their tokens are checked.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Micropub {
+  /// The IndieAuth token endpoint that access tokens are verified with.
+  pub token_endpoint: Url,
+  /// The directory new posts are created in.
+  #[serde(default = "default_directory")]
+  pub directory: PathBuf,
+}
+
+fn default_directory() -> PathBuf {
+  PathBuf::from("posts")
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("Micropub isn't enabled on this wiki")]
+  Disabled,
+  #[error("No access token was given")]
+  Unauthorized,
+  #[error("The access token isn't valid")]
+  InvalidToken,
+  #[error("The access token doesn't have the `{0}` scope")]
+  InsufficientScope(&'static str),
+  #[error("{0}")]
+  InvalidRequest(String),
+  #[error(transparent)]
+  Http(#[from] reqwest::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  FrontMatter(#[from] crate::front_matter::Error),
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  Spam(#[from] crate::spam::Error),
+  #[error(transparent)]
+  Quota(#[from] crate::quota::Error),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    let (code, error) = match self {
+      Self::Disabled => (StatusCode::NOT_FOUND, "not_found"),
+      Self::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+      Self::InvalidToken => (StatusCode::FORBIDDEN, "forbidden"),
+      Self::InsufficientScope(_) => (StatusCode::FORBIDDEN, "insufficient_scope"),
+      Self::Spam(crate::spam::Error::Blocked) => (StatusCode::FORBIDDEN, "forbidden"),
+      Self::Quota(_) => (StatusCode::TOO_MANY_REQUESTS, "forbidden"),
+      Self::InvalidRequest(_) | Self::Json(_) => (StatusCode::BAD_REQUEST, "invalid_request"),
+      _ => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+    };
+
+    let body = serde_json::json!({
+      "error": error,
+      "error_description": self.to_string(),
+    });
+
+    (code, Json(body)).into_response()
+  }
+}
+
+#[derive(Deserialize)]
+struct TokenInfo {
+  me: Url,
+  #[serde(default)]
+  scope: String,
+}
+
+impl TokenInfo {
+  fn check_scope(&self, scope: &'static str) -> Result<(), Error> {
+    let scopes: Vec<&str> = self.scope.split_whitespace().collect();
+
+    // `post` is what older clients asked for instead of `create`.
+    if scopes.contains(&scope) || (scope == "create" && scopes.contains(&"post")) {
+      Ok(())
+    } else {
+      Err(Error::InsufficientScope(scope))
+    }
+  }
+}
+
+/// Whether two profile URLs are the same site - scheme, host and port included.
+fn same_site(a: &Url, b: &Url) -> bool {
+  a.origin() == b.origin() && a.path().trim_end_matches('/') == b.path().trim_end_matches('/')
+}
+
+/// Asks the token endpoint who the token belongs to, and finds the matching user.
+async fn verify(
+  token: &str,
+  micropub: &Micropub,
+  state: &State,
+) -> Result<(User, TokenInfo), Error> {
+  let info: TokenInfo = reqwest::Client::new()
+    .get(micropub.token_endpoint.clone())
+    .bearer_auth(token)
+    .header(header::ACCEPT, "application/json")
+    .send()
+    .await?
+    .error_for_status()
+    .map_err(|_| Error::InvalidToken)?
+    .json()
+    .await?;
+
+  let mut users = state
+    .users
+    .all()
+    .await
+    .into_iter()
+    .filter(|user| user.approved && !user.disabled && same_site(&user.url, &info.me));
+
+  let user = users.next().ok_or(Error::InvalidToken)?;
+
+  // There's no telling which of two accounts for the same site the token is for.
+  if users.next().is_some() {
+    return Err(Error::InvalidToken);
+  }
+
+  Ok((user, info))
+}
+
+/// The properties of a post that the wiki knows what to do with.
+#[derive(Default)]
+struct Entry {
+  name: Option<String>,
+  content: Option<String>,
+  categories: Vec<String>,
+  summary: Option<String>,
+  slug: Option<String>,
+}
+
+impl Entry {
+  fn from_form(form: &HashMap<String, Vec<String>>) -> Self {
+    let first = |key: &str| form.get(key).and_then(|values| values.first()).cloned();
+
+    Self {
+      name: first("name"),
+      content: first("content"),
+      categories: form.get("category").cloned().unwrap_or_default(),
+      summary: first("summary"),
+      slug: first("mp-slug"),
+    }
+  }
+
+  fn from_properties(properties: &serde_json::Map<String, Value>) -> Self {
+    let mut entry = Self::default();
+    entry.set(properties);
+
+    entry
+  }
+
+  /// Replaces any of the properties that are in `properties`.
+  fn set(&mut self, properties: &serde_json::Map<String, Value>) {
+    for (key, values) in properties {
+      let values = json_values(values);
+
+      match key.as_str() {
+        "name" => self.name = values.into_iter().next(),
+        "content" => self.content = values.into_iter().next(),
+        "category" => self.categories = values,
+        "summary" => self.summary = values.into_iter().next(),
+        "mp-slug" => self.slug = values.into_iter().next(),
+        _ => {},
+      }
+    }
+  }
+}
+
+/// Property values are always arrays - `content` can also be `{ "html": "..." }`, which
+/// pandoc's Markdown reader is happy with.
+fn json_values(values: &Value) -> Vec<String> {
+  let values = match values {
+    Value::Array(values) => values.as_slice(),
+    value => std::slice::from_ref(value),
+  };
+
+  values
+    .iter()
+    .filter_map(|value| match value {
+      Value::String(value) => Some(value.clone()),
+      Value::Object(object) => object
+        .get("html")
+        .or_else(|| object.get("value"))
+        .and_then(Value::as_str)
+        .map(str::to_string),
+      _ => None,
+    })
+    .collect()
+}
+
+fn parse_form(body: &[u8]) -> HashMap<String, Vec<String>> {
+  let mut form: HashMap<String, Vec<String>> = HashMap::new();
+
+  for (key, value) in form_urlencoded::parse(body) {
+    let key = key.trim_end_matches("[]").to_string();
+    form.entry(key).or_default().push(value.into_owned());
+  }
+
+  form
+}
+
+fn slugify(text: &str) -> String {
+  let slug: String = text
+    .to_lowercase()
+    .chars()
+    .map(|c| if c.is_alphanumeric() { c } else { '-' })
+    .collect();
+
+  slug
+    .split('-')
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<_>>()
+    .join("-")
+}
+
+/// The page that a post's `url` is for, if it's one of the wiki's.
+fn page_path(url: &str, config: &Config) -> Option<PathBuf> {
+  let base = Url::parse(config.base_url()).ok()?;
+  let url = base.join(url).ok()?;
+
+  if url.origin() != base.origin() {
+    return None;
+  }
+
+  let path = url.path().strip_prefix(base.path().trim_end_matches('/'))?;
+
+  if !path.starts_with('/') {
+    return None;
+  }
+
+  let path = PathBuf::from(urlencoding::decode(&path[1..]).ok()?.to_string());
+
+  (!path.as_os_str().is_empty() && crate::page::is_safe_path(&path)).then_some(path)
+}
+
+/// Writes out the page, keeping anything else that was in `front_matter`, in the same
+/// dialect it was written in.
+fn page_contents(entry: &Entry, front_matter: FrontMatter) -> Result<String, Error> {
+  let front_matter = FrontMatter {
+    title: entry.name.clone(),
+    categories: (!entry.categories.is_empty()).then(|| entry.categories.clone()),
+    description: entry.summary.clone(),
+    ..front_matter
+  };
+
+  let content = entry.content.as_deref().unwrap_or("");
+
+  Ok(format!("{}\n{}\n", front_matter.render()?, content))
+}
+
+async fn create(
+  entry: Entry,
+  user: User,
+  micropub: &Micropub,
+  forwarded: &Forwarded,
+  headers: &HeaderMap,
+  state: Arc<State>,
+) -> Result<Response, Error> {
+  let slug = entry
+    .slug
+    .as_deref()
+    .or(entry.name.as_deref())
+    .map(slugify)
+    .filter(|slug| !slug.is_empty())
+    .unwrap_or_else(|| time::OffsetDateTime::now_utc().unix_timestamp().to_string());
+
+  let format = Format::from_extension("md").expect("Markdown is always supported");
+
+  // Don't overwrite an existing post with the same slug.
+  let mut path = micropub.directory.join(&slug);
+  let mut n = 1;
+  while crate::page::find_file(&path, &state.config()).is_ok() {
+    n += 1;
+    path = micropub.directory.join(format!("{}-{}", slug, n));
+  }
+
+  crate::acl::check_edit(&user, &path, &state.config()).map_err(crate::page::Error::from)?;
+
+  let config = state.config();
+  state.usage.take(
+    Who::user(&user),
+    Activity::NewPage,
+    config.quotas.quota(Some(&user)),
+  )?;
+
+  let page = Page {
+    filepath: state
+      .config()
+      .pages_directory
+      .join(&path)
+      .with_extension(format.extension()),
+    path,
+    format: Some(format),
+    user: Some(user.clone()),
+  };
+
+  let contents = page_contents(&entry, FrontMatter::default())?;
+
+  let submission = Submission {
+    path: page.path.clone(),
+    name: user.name.clone(),
+    email: Some(user.email.clone()),
+    summary: None,
+    body: contents.clone(),
+    before: String::new(),
+    address: forwarded.client,
+    user_agent: crate::spam::user_agent(headers),
+    base: None,
+    created: Some(page.relative_path(&state.config())?),
+  };
+
+  if crate::spam::screen_user(submission, &user, &state).await? {
+    return Ok((StatusCode::ACCEPTED, crate::spam::HELD_MESSAGE).into_response());
+  }
+
+  page.create(contents, None, &user, state.clone()).await?;
+
+  state
+    .audit
+    .record(
+      &user.email,
+      Action::MicropubCreate,
+      page.path.to_string_lossy(),
+      None,
+    )
+    .await;
+
+  let location = format!("{}{}", state.config().base_url(), page.url_path());
+
+  Ok((StatusCode::CREATED, [(header::LOCATION, location)]).into_response())
+}
+
+async fn update(
+  request: &Value,
+  user: User,
+  forwarded: &Forwarded,
+  headers: &HeaderMap,
+  state: Arc<State>,
+) -> Result<Response, Error> {
+  let url = request
+    .get("url")
+    .and_then(Value::as_str)
+    .ok_or_else(|| Error::InvalidRequest(String::from("`url` is missing")))?;
+
+  let no_page = || Error::InvalidRequest(format!("There's no page at {}", url));
+
+  let path = page_path(url, &state.config()).ok_or_else(no_page)?;
+  let page = Page::from_path(path, Some(user.clone()), &state.config()).map_err(|_| no_page())?;
+
+  // Posts are Markdown, and anything else would be broken by writing them over it.
+  if !page.format.map_or(false, |format| format.is_markdown()) {
+    return Err(Error::InvalidRequest(format!(
+      "Only Markdown pages can be updated, and {} isn't one",
+      url
+    )));
+  }
+
+  crate::acl::check_edit(&user, &page.path, &state.config()).map_err(crate::page::Error::from)?;
+
+  let config = state.config();
+  state.usage.take(
+    Who::user(&user),
+    Activity::Edit,
+    config.quotas.quota(Some(&user)),
+  )?;
+
+  let raw = page.raw(&state).await?;
+  let (front_matter, content, _) = page.split_front_matter(&raw);
+
+  let mut entry = Entry {
+    name: front_matter.title.clone(),
+    content: Some(content.trim().to_string()),
+    categories: front_matter.categories.clone().unwrap_or_default(),
+    summary: front_matter.description.clone(),
+    slug: None,
+  };
+
+  if let Some(replace) = request.get("replace").and_then(Value::as_object) {
+    entry.set(replace);
+  }
+
+  if let Some(add) = request.get("add").and_then(Value::as_object) {
+    if let Some(categories) = add.get("category") {
+      entry.categories.extend(json_values(categories));
+    }
+  }
+
+  match request.get("delete") {
+    Some(Value::Array(names)) => {
+      for name in names.iter().filter_map(Value::as_str) {
+        match name {
+          "name" => entry.name = None,
+          "content" => entry.content = None,
+          "category" => entry.categories.clear(),
+          "summary" => entry.summary = None,
+          _ => {},
+        }
+      }
+    },
+    Some(Value::Object(values)) => {
+      if let Some(categories) = values.get("category") {
+        let categories = json_values(categories);
+        entry
+          .categories
+          .retain(|category| !categories.contains(category));
+      }
+    },
+    _ => {},
+  }
+
+  let contents = page_contents(&entry, front_matter)?;
+  let base = page.revision(&state).await?;
+
+  let submission = Submission {
+    path: page.path.clone(),
+    name: user.name.clone(),
+    email: Some(user.email.clone()),
+    summary: None,
+    body: contents.clone(),
+    before: raw,
+    address: forwarded.client,
+    user_agent: crate::spam::user_agent(headers),
+    base,
+    created: None,
+  };
+
+  if crate::spam::screen_user(submission, &user, &state).await? {
+    return Ok((StatusCode::ACCEPTED, crate::spam::HELD_MESSAGE).into_response());
+  }
+
+  page
+    .update(contents, None, base, &user, state.clone())
+    .await?;
+
+  state
+    .audit
+    .record(
+      &user.email,
+      Action::MicropubUpdate,
+      page.path.to_string_lossy(),
+      None,
+    )
+    .await;
+
+  Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn handler(
+  headers: HeaderMap,
+  forwarded: Forwarded,
+  Extension(state): Extension<Arc<State>>,
+  body: Bytes,
+) -> Result<Response, Error> {
+  let micropub = state.config().micropub.clone().ok_or(Error::Disabled)?;
+
+  let is_json = headers
+    .get(header::CONTENT_TYPE)
+    .and_then(|value| value.to_str().ok())
+    .map(|value| value.starts_with("application/json"))
+    .unwrap_or(false);
+
+  let (json, form) = if is_json {
+    (Some(serde_json::from_slice::<Value>(&body)?), None)
+  } else {
+    (None, Some(parse_form(&body)))
+  };
+
+  // The token can be in the header, or in a form-encoded body.
+  let token = headers
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    .map(str::to_string)
+    .or_else(|| {
+      form
+        .as_ref()
+        .and_then(|form| form.get("access_token"))
+        .and_then(|values| values.first().cloned())
+    })
+    .ok_or(Error::Unauthorized)?;
+
+  let (user, info) = verify(&token, &micropub, &state).await?;
+
+  match (json, form) {
+    (Some(json), _) if json.get("action").and_then(Value::as_str) == Some("update") => {
+      info.check_scope("update")?;
+      update(&json, user, &forwarded, &headers, state).await
+    },
+    (Some(json), _) if json.get("action").is_some() => Err(Error::InvalidRequest(String::from(
+      "Only `update` actions are supported",
+    ))),
+    (Some(json), _) => {
+      info.check_scope("create")?;
+
+      let properties = json
+        .get("properties")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::InvalidRequest(String::from("`properties` is missing")))?;
+
+      create(
+        Entry::from_properties(properties),
+        user,
+        &micropub,
+        &forwarded,
+        &headers,
+        state,
+      )
+      .await
+    },
+    (None, Some(form)) if form.contains_key("action") => Err(Error::InvalidRequest(String::from(
+      "Updates have to be sent as JSON",
+    ))),
+    (None, Some(form)) => {
+      info.check_scope("create")?;
+      create(
+        Entry::from_form(&form),
+        user,
+        &micropub,
+        &forwarded,
+        &headers,
+        state,
+      )
+      .await
+    },
+    (None, None) => unreachable!(),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+  q: Option<String>,
+}
+
+/// Clients ask what the endpoint supports with `?q=config`.
+pub async fn query_handler(
+  Query(params): Query<QueryParams>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Response, Error> {
+  state.config().micropub.as_ref().ok_or(Error::Disabled)?;
+
+  match params.q.as_deref() {
+    Some("config") | Some("syndicate-to") => {
+      Ok(Json(serde_json::json!({ "syndicate-to": [] })).into_response())
+    },
+    _ => Err(Error::InvalidRequest(String::from(
+      "Only `q=config` and `q=syndicate-to` are supported",
+    ))),
+  }
+}
@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+
+use axum::response::Html;
+use globset::{Glob, GlobMatcher};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, page::Error, role::Role, template::Template, user::User, State};
+
+/// The page shown for a directory, instead of the generated index.
+pub const INDEX_PAGE: &str = "_index";
+
+/// Settings for the pages in a directory and the directories under it.
+pub const SETTINGS_FILE: &str = "_config.ron";
+
+/// Who can see a part of the wiki.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Visibility {
+  Everyone,
+  /// People who are logged in, and have been approved.
+  Members,
+  /// People with any of these roles.
+  Roles(Vec<Role>),
+}
+
+/// Settings that only apply to part of the wiki. Anything that isn't set is left as it is for
+/// the directory above.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Settings {
+  /// A template in `templates_directory` that's used instead of `base.html` to show the pages.
+  pub template: Option<String>,
+  /// The format picked for new pages.
+  pub format: Option<String>,
+  pub visibility: Option<Visibility>,
+  /// Who can edit, instead of the config's `editors`.
+  pub editors: Option<Vec<Role>>,
+}
+
+/// Settings for the pages whose paths match `glob`, like `private/**`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Namespace {
+  pub glob: String,
+  pub settings: Settings,
+  /// `glob`, compiled the first time it's needed - once each time the config is loaded.
+  #[serde(skip)]
+  matcher: OnceCell<Option<GlobMatcher>>,
+}
+
+impl Namespace {
+  fn matches(&self, path: &Path) -> bool {
+    self
+      .matcher
+      .get_or_init(|| {
+        Glob::new(&self.glob)
+          .ok()
+          .map(|glob| glob.compile_matcher())
+      })
+      .as_ref()
+      .map(|matcher| matcher.is_match(path))
+      .unwrap_or(false)
+  }
+}
+
+/// Whether `user` can see the page or file at `path`, relative to the pages directory.
+pub fn is_visible(path: &Path, user: Option<&User>, config: &Config) -> bool {
+  Settings::for_path(path, config).can_read(user)
+}
+
+impl Settings {
+  /// The settings for the page at `path`. The `_config.ron` in each directory from the top of
+  /// the wiki down to the page's come first, so the closest one wins, and then the config's
+  /// `namespaces`, in order - anyone who can change the pages can write a `_config.ron`, so
+  /// the config has the last word.
+  pub fn for_path(path: &Path, config: &Config) -> Self {
+    let mut settings = Self::default();
+
+    let mut directory = config.pages_directory.clone();
+    settings.merge(Self::load(&directory));
+
+    for component in path.parent().into_iter().flat_map(Path::components) {
+      directory.push(component);
+      settings.merge(Self::load(&directory));
+    }
+
+    for namespace in config.namespaces.iter().filter(|ns| ns.matches(path)) {
+      settings.merge(namespace.settings.clone());
+    }
+
+    settings
+  }
+
+  /// The `_config.ron` in `directory`, if it has one. One that can't be read is logged and
+  /// ignored, so a mistake in it doesn't take the pages down.
+  fn load(directory: &Path) -> Self {
+    let file: PathBuf = directory.join(SETTINGS_FILE);
+
+    let contents = match std::fs::read_to_string(&file) {
+      Ok(contents) => contents,
+      Err(_) => return Self::default(),
+    };
+
+    ron::from_str(&contents).unwrap_or_else(|err| {
+      log::warn!("Couldn't read {}: {}", file.display(), err);
+      Self::default()
+    })
+  }
+
+  fn merge(&mut self, other: Self) {
+    self.template = other.template.or(self.template.take());
+    self.format = other.format.or(self.format.take());
+    self.visibility = other.visibility.or(self.visibility.take());
+    self.editors = other.editors.or(self.editors.take());
+  }
+
+  pub fn can_read(&self, user: Option<&User>) -> bool {
+    if user.map(|user| user.roles.contains(&Role::Administrator)) == Some(true) {
+      return true;
+    }
+
+    match (&self.visibility, user) {
+      (None | Some(Visibility::Everyone), _) => true,
+      (Some(Visibility::Members), Some(user)) => user.approved,
+      (Some(Visibility::Roles(roles)), Some(user)) => {
+        user.roles.iter().any(|role| roles.contains(role))
+      },
+      (Some(_), None) => false,
+    }
+  }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Entry {
+  Directory(String),
+  Page(String),
+}
+
+/// Lists the pages and directories in a directory that doesn't have an `_index` page.
+pub async fn index_handler(
+  path: &Path,
+  user: Option<User>,
+  state: &State,
+) -> Result<Html<String>, Error> {
+  let config = state.config();
+  let mut entries = Vec::new();
+  let mut dir = tokio::fs::read_dir(config.pages_directory.join(path)).await?;
+
+  while let Some(entry) = dir.next_entry().await? {
+    let filepath = entry.path();
+
+    let name = match filepath.file_stem() {
+      Some(name) => name.to_string_lossy().to_string(),
+      None => continue,
+    };
+
+    // Hidden files, templates, and the like aren't pages.
+    if name.starts_with(['.', '_']) {
+      continue;
+    }
+
+    let is_dir = entry.file_type().await?.is_dir();
+    let settings = match is_dir {
+      true => Settings::for_path(&path.join(&name).join(INDEX_PAGE), &config),
+      false => Settings::for_path(&path.join(&name), &config),
+    };
+
+    // Whatever they can't see isn't listed.
+    if !settings.can_read(user.as_ref()) {
+      continue;
+    }
+
+    if is_dir {
+      entries.push(Entry::Directory(name));
+    } else if crate::page::format_of(&filepath).is_some() {
+      entries.push(Entry::Page(name));
+    }
+  }
+
+  entries.sort();
+  entries.dedup();
+
+  let path = path.to_string_lossy();
+  let url = |name: &str| {
+    if path.is_empty() {
+      format!("/{}", name)
+    } else {
+      format!("/{}/{}", path, name)
+    }
+  };
+
+  let content = maud::html! {
+    @if entries.is_empty() {
+      "There's nothing here yet."
+    } @else {
+      ul #namespace {
+        @for entry in &entries {
+          @match entry {
+            Entry::Directory(name) => li.directory { a href=(url(name)) { (name) "/" } },
+            Entry::Page(name) => li { a href=(url(name)) { (name) } },
+          }
+        }
+      }
+    }
+  };
+
+  let title = path
+    .rsplit('/')
+    .next()
+    .filter(|name| !name.is_empty())
+    .unwrap_or("All pages")
+    .to_string();
+
+  let html = Template::new()
+    .title(title)
+    .breadcrumbs(&path)
+    .content(content)
+    .render(user, &state);
+
+  Ok(html)
+}
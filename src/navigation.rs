@@ -0,0 +1,82 @@
+//! The sidebar's links, taken from wiki pages so they can be changed without touching the code.
+
+use std::{
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+};
+
+use maud::{Markup, PreEscaped};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{page::Page, State};
+
+/// Replaces the sidebar's "Site" links.
+pub const SIDEBAR: &str = "_sidebar";
+/// Shown in the sidebar, under the "Site" links.
+pub const NAVIGATION: &str = "_navigation";
+
+/// The rendered navigation pages, which are re-rendered whenever they're edited.
+#[derive(Default)]
+pub struct Navigation {
+  sidebar: RwLock<Option<Markup>>,
+  navigation: RwLock<Option<Markup>>,
+}
+
+impl Navigation {
+  pub fn sidebar(&self) -> Option<Markup> {
+    self.sidebar.read().unwrap().clone()
+  }
+
+  pub fn navigation(&self) -> Option<Markup> {
+    self.navigation.read().unwrap().clone()
+  }
+
+  /// Whether the page at `path` is one of the navigation pages.
+  pub fn is_navigation(path: &Path) -> bool {
+    path == Path::new(SIDEBAR) || path == Path::new(NAVIGATION)
+  }
+
+  pub async fn refresh(&self, state: Arc<State>) {
+    let sidebar = render(SIDEBAR, state.clone()).await;
+    let navigation = render(NAVIGATION, state).await;
+
+    *self.sidebar.write().unwrap() = sidebar;
+    *self.navigation.write().unwrap() = navigation;
+  }
+
+  /// Refreshes the navigation pages whenever they change, including when the change came from
+  /// somewhere else, like commits that were pulled in before pushing.
+  pub async fn watch(state: Arc<State>) {
+    let mut changes = state.git.subscribe();
+
+    loop {
+      match changes.recv().await {
+        Ok(change) => {
+          if change
+            .files
+            .iter()
+            .any(|file| Self::is_navigation(&file.with_extension("")))
+          {
+            state.navigation.refresh(state.clone()).await;
+          }
+        },
+        // Some changes were missed, so any of them could have been to the navigation.
+        Err(RecvError::Lagged(_)) => state.navigation.refresh(state.clone()).await,
+        Err(RecvError::Closed) => break,
+      }
+    }
+  }
+}
+
+async fn render(name: &str, state: Arc<State>) -> Option<Markup> {
+  let page = Page::from_path(PathBuf::from(name), None, &state.config()).ok()?;
+
+  match page.renderer(state).await {
+    // Anyone can edit these pages, and they're shown everywhere.
+    Ok(renderer) => Some(PreEscaped(crate::sanitize::clean(renderer.html()))),
+    Err(err) => {
+      log::warn!("Couldn't render the `{}` page: {}", name, err);
+      None
+    },
+  }
+}
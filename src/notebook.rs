@@ -0,0 +1,269 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use axum::{
+  extract::Path,
+  http::{header, StatusCode},
+  response::{IntoResponse, Response},
+  Extension,
+};
+use maud::{html, Markup, PreEscaped};
+use serde::Deserialize;
+
+use crate::{format::Format, State};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  Base64(#[from] base64::DecodeError),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Git(#[from] git2::Error),
+  #[error(transparent)]
+  Pandoc(#[from] crate::pandoc::Error),
+}
+
+/// Image types that are extracted from notebook outputs, in order of preference.
+const IMAGE_TYPES: [(&str, &str); 3] = [
+  ("image/png", "png"),
+  ("image/jpeg", "jpg"),
+  ("image/svg+xml", "svg"),
+];
+
+#[derive(Deserialize)]
+struct Notebook {
+  cells: Vec<Cell>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "cell_type", rename_all = "lowercase")]
+enum Cell {
+  Markdown {
+    source: Source,
+  },
+  Code {
+    source: Source,
+    execution_count: Option<u32>,
+    #[serde(default)]
+    outputs: Vec<Output>,
+  },
+  Raw {
+    source: Source,
+  },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "output_type", rename_all = "snake_case")]
+enum Output {
+  Stream {
+    name: String,
+    text: Source,
+  },
+  ExecuteResult {
+    data: HashMap<String, Source>,
+  },
+  DisplayData {
+    data: HashMap<String, Source>,
+  },
+  Error {
+    ename: String,
+    evalue: String,
+    traceback: Vec<String>,
+  },
+}
+
+/// Notebooks store text either as a single string or as a list of lines.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Source {
+  Lines(Vec<String>),
+  Text(String),
+}
+
+impl Source {
+  fn text(&self) -> String {
+    match self {
+      Source::Lines(lines) => lines.concat(),
+      Source::Text(text) => text.clone(),
+    }
+  }
+}
+
+fn attachments_directory(state: &State) -> PathBuf {
+  state.config().cache_directory.join("attachments")
+}
+
+/// Writes an image to the attachments directory, returning the URL it's served from.
+fn extract_image(data: &[u8], extension: &str, state: &State) -> Result<String, Error> {
+  let hash = git2::Oid::hash_object(git2::ObjectType::Blob, data)?;
+  let name = format!("{}.{}", hash, extension);
+
+  let directory = attachments_directory(state);
+  std::fs::create_dir_all(&directory)?;
+
+  let path = directory.join(&name);
+  if !path.exists() {
+    std::fs::write(&path, data)?;
+  }
+
+  Ok(format!("/meta/attachment/{}", name))
+}
+
+fn render_data(data: &HashMap<String, Source>, state: &State) -> Result<Markup, Error> {
+  for (mime, extension) in IMAGE_TYPES {
+    if let Some(image) = data.get(mime) {
+      let image = image.text();
+
+      let bytes = match mime {
+        "image/svg+xml" => image.into_bytes(),
+        _ => {
+          let image: String = image.split_whitespace().collect();
+          base64::decode(image)?
+        },
+      };
+
+      let src = extract_image(&bytes, extension, state)?;
+
+      return Ok(html! { img src=(src); });
+    }
+  }
+
+  if let Some(html) = data.get("text/html") {
+    return Ok(html! { (PreEscaped(html.text())) });
+  }
+
+  if let Some(text) = data.get("text/plain") {
+    return Ok(html! { pre { (text.text()) } });
+  }
+
+  Ok(html! {})
+}
+
+fn render_output(output: &Output, state: &State) -> Result<Markup, Error> {
+  let markup = match output {
+    Output::Stream { name, text } => html! {
+      pre class={ "stream " (name) } { (text.text()) }
+    },
+    Output::ExecuteResult { data } | Output::DisplayData { data } => render_data(data, state)?,
+    Output::Error {
+      ename,
+      evalue,
+      traceback,
+    } => html! {
+      pre .error {
+        (ename) ": " (evalue)
+        @for line in traceback {
+          "\n" (strip_ansi(line))
+        }
+      }
+    },
+  };
+
+  Ok(markup)
+}
+
+/// Tracebacks are coloured with ANSI escape codes, which we don't want in the HTML.
+fn strip_ansi(line: &str) -> String {
+  let mut out = String::with_capacity(line.len());
+  let mut chars = line.chars();
+
+  while let Some(c) = chars.next() {
+    if c == '\u{1b}' {
+      // Skip until the end of the escape sequence.
+      for c in chars.by_ref() {
+        if c.is_ascii_alphabetic() {
+          break;
+        }
+      }
+    } else {
+      out.push(c);
+    }
+  }
+
+  out
+}
+
+pub fn to_html(doc: &str, state: Arc<State>) -> Result<String, Error> {
+  let notebook: Notebook = serde_json::from_str(doc)?;
+
+  let mut cells = Vec::with_capacity(notebook.cells.len());
+
+  for cell in &notebook.cells {
+    let markup = match cell {
+      Cell::Markdown { source } => {
+        let rendered = state
+          .render
+          .to_html(source.text(), Format::from_extension("md"), state.clone())?;
+
+        html! { .cell.markdown { (PreEscaped(rendered)) } }
+      },
+      Cell::Code {
+        source,
+        execution_count,
+        outputs,
+      } => {
+        let mut rendered = Vec::with_capacity(outputs.len());
+        for output in outputs {
+          rendered.push(render_output(output, &state)?);
+        }
+
+        html! {
+          .cell.code {
+            .input {
+              .prompt {
+                "In [" @if let Some(count) = execution_count { (count) } @else { " " } "]:"
+              }
+              pre { code { (source.text()) } }
+            }
+            @if !rendered.is_empty() {
+              .outputs {
+                @for output in rendered {
+                  (output)
+                }
+              }
+            }
+          }
+        }
+      },
+      Cell::Raw { source } => html! { .cell.raw { pre { (source.text()) } } },
+    };
+
+    cells.push(markup);
+  }
+
+  let html = html! {
+    .notebook {
+      @for cell in cells {
+        (cell)
+      }
+    }
+  };
+
+  Ok(html.into_string())
+}
+
+pub async fn attachment_handler(
+  Path(name): Path<String>,
+  Extension(state): Extension<Arc<State>>,
+) -> Response {
+  if name.contains('/') || name.contains("..") {
+    return StatusCode::NOT_FOUND.into_response();
+  }
+
+  let path = attachments_directory(&state).join(&name);
+
+  match tokio::fs::read(&path).await {
+    Ok(file) => {
+      let mime = mime_guess::from_path(&path).first_or_octet_stream();
+
+      (
+        [(header::CONTENT_TYPE, mime.essence_str().to_string())],
+        file,
+      )
+        .into_response()
+    },
+    Err(_) => StatusCode::NOT_FOUND.into_response(),
+  }
+}
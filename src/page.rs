@@ -1,21 +1,40 @@
-use std::{collections::HashSet, path::PathBuf, string::FromUtf8Error, sync::Arc};
+use std::{
+  collections::{BTreeMap, HashMap},
+  ffi::OsStr,
+  path::PathBuf,
+  string::FromUtf8Error,
+  sync::{Arc, Mutex},
+  time::SystemTime,
+};
 
 use axum::{
   async_trait,
-  extract::{rejection::PathRejection, FromRequest, Path, RequestParts},
-  http::StatusCode,
+  extract::{rejection::PathRejection, FromRequest, Path, Query, RequestParts},
+  http::{header, HeaderMap, StatusCode},
   response::{Html, IntoResponse, Redirect, Response},
   Extension,
   Json,
 };
-use extract_frontmatter::{config::Splitter, Extractor};
+use git2::Oid;
+use once_cell::sync::Lazy;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use unicode_normalization::UnicodeNormalization;
 use walkdir::WalkDir;
 
 use crate::{
+  commit_message::{Kind, Vars},
+  conditional::Validators,
   config::Config,
-  error::ErrorPage,
+  date::Dates,
+  error::{ErrorPage, Problem},
+  format::{Choice, Format},
   front_matter::FrontMatter,
-  pandoc::Format,
+  git::{Author, CoAuthor, Commit},
+  navigation::Navigation,
+  proxy::Forwarded,
+  quota::{Activity, Who},
+  role::Role,
+  template::Metadata,
   user::User,
   State,
 };
@@ -27,7 +46,7 @@ pub enum Error {
   #[error(transparent)]
   Io(#[from] tokio::io::Error),
   #[error(transparent)]
-  FrontMatterError(#[from] toml::de::Error),
+  FrontMatterError(#[from] crate::front_matter::Error),
   #[error(transparent)]
   Git(#[from] crate::git::Error),
   #[error(transparent)]
@@ -38,15 +57,58 @@ pub enum Error {
   Utf8(#[from] FromUtf8Error),
   #[error(transparent)]
   Path(#[from] PagePathError),
+  #[error(transparent)]
+  Table(#[from] crate::table::Error),
+  #[error(transparent)]
+  Notebook(#[from] crate::notebook::Error),
+  #[error(transparent)]
+  Acl(#[from] crate::acl::Error),
+  #[error(transparent)]
+  CommitMessage(#[from] crate::commit_message::Error),
+  #[error(transparent)]
+  Sessions(#[from] crate::sessions::Error),
+  #[error(transparent)]
+  Quota(#[from] crate::quota::Error),
   #[error("This page is reserved")]
   ReservedPage { url: String },
+  #[error("Files of type '{mime}' aren't allowed")]
+  ForbiddenFileType { url: String, mime: String },
+  #[error("This page is archived, so only administrators can change it")]
+  Archived,
+  #[error("{source}")]
+  Render {
+    source: crate::pandoc::Error,
+    /// How many lines of front matter came before what was rendered.
+    line_offset: usize,
+  },
 }
 
 impl IntoResponse for Error {
   fn into_response(self) -> Response {
     match self {
       Self::ReservedPage { url } => ErrorPage::ReservedPage { url }.into_response(),
-      _ => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
+      Self::ForbiddenFileType { url, mime } => {
+        ErrorPage::ForbiddenFileType { url, mime }.into_response()
+      },
+      Self::Acl(err) => err.into_response(),
+      Self::CommitMessage(err) => err.into_response(),
+      Self::Git(err) => err.into_response(),
+      Self::Quota(err) => err.into_response(),
+      Self::Pandoc(err) => err.into_response(),
+      Self::Render {
+        source,
+        line_offset,
+      } => ErrorPage::RenderFailed {
+        stderr: source.stderr(),
+        diagnostics: source.diagnostics(line_offset),
+      }
+      .into_response(),
+      Self::Path(err) => err.into_response(),
+      Self::Io(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        ErrorPage::NotFound { url: None }.into_response()
+      },
+      Self::Archived => Problem::new(StatusCode::FORBIDDEN, "archived", self).into_response(),
+      _ => Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", self).into_response(),
     }
   }
 }
@@ -62,14 +124,98 @@ pub struct Page {
 pub struct PageContext {
   pub path: String,
   pub revision: Option<String>,
+  /// The page that redirected here, if there was one.
+  pub redirected_from: Option<String>,
   pub title: String,
+  pub description: Option<String>,
+  pub categories: Vec<String>,
+  pub author: Option<String>,
+  pub slug: Option<String>,
+  #[serde(with = "time::serde::rfc3339::option")]
+  pub date: Option<OffsetDateTime>,
+  pub weight: Option<i64>,
+  pub redirect_to: Option<String>,
+  /// Any front matter keys that gitalite doesn't use itself.
+  pub extra: BTreeMap<String, serde_json::Value>,
   pub user: Option<User>,
+  pub front_matter_error: Option<String>,
+  /// The page's full URL, for link previews.
+  pub url: Option<String>,
+  /// Who last changed the page, from git.
+  pub byline: Option<Byline>,
+  pub archived: bool,
+  pub extra_css: Vec<String>,
+  pub extra_js: Vec<String>,
+  /// Whether this is the printable version, without the sidebar, header and tabs.
+  pub print: bool,
+  pub layout: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct Byline {
+  pub name: String,
+  pub url: Option<String>,
+  #[serde(with = "time::serde::rfc3339")]
+  pub modified: OffsetDateTime,
+  /// The first line of the commit's message.
+  pub message: String,
+  pub hash: String,
+}
+
+impl From<Commit> for Byline {
+  fn from(commit: Commit) -> Self {
+    let (name, url) = match commit.author {
+      // It's shown on every page they edit, so it has to be safe to link to.
+      Author::User(user) if crate::user::is_web_url(&user.url) => {
+        (user.name, Some(user.url.to_string()))
+      },
+      Author::User(user) => (user.name, None),
+      Author::NonUser { name, .. } => (name, None),
+    };
+
+    Self {
+      name,
+      url,
+      modified: commit.date,
+      message: commit
+        .message
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string(),
+      hash: commit.hash,
+    }
+  }
 }
 
 impl Page {
+  pub fn from_path(
+    path: PathBuf,
+    user: Option<User>,
+    config: &Config,
+  ) -> Result<Self, std::io::Error> {
+    let filepath = find_file(&path, config)?;
+    let format = format_of(&filepath);
+
+    Ok(Page {
+      path,
+      filepath,
+      format,
+      user,
+    })
+  }
+
   pub fn all(config: &Config) -> impl Iterator<Item = Self> {
+    let templates = config.pages_directory.join(crate::boilerplate::DIRECTORY);
+
     WalkDir::new(&config.pages_directory)
       .into_iter()
+      // Templates are only starting points for new pages, not pages themselves, and hidden
+      // files and directories like `.git` aren't pages either.
+      .filter_entry(move |e| {
+        e.path() != templates
+          && (e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+      })
       .filter_map(|e| {
         let e = e.ok()?;
 
@@ -80,13 +226,7 @@ impl Page {
         let path = e.path().with_extension("");
         let filepath = e.path().to_path_buf();
 
-        let format = e
-          .path()
-          .extension()
-          .map(|ext| Format::from_extension(&ext.to_string_lossy()))
-          .flatten();
-
-        match format {
+        match format_of(&filepath) {
           Some(format) => Some(Self {
             path,
             filepath,
@@ -98,21 +238,6 @@ impl Page {
       })
   }
 
-  pub async fn categories(config: &Config) -> Result<HashSet<String>, Error> {
-    let mut categories = HashSet::new();
-
-    for page in Self::all(config) {
-      let file = page.raw().await?;
-      let (front_matter, _) = page.front_matter(&file)?;
-
-      if let Some(cat) = front_matter.categories {
-        categories.extend(cat);
-      }
-    }
-
-    Ok(categories)
-  }
-
   pub fn check_if_reserved(path: &str) -> Result<(), Error> {
     if path.starts_with("/meta") {
       return Err(Error::ReservedPage {
@@ -123,123 +248,397 @@ impl Page {
     Ok(())
   }
 
+  /// Only administrators can change archived pages, or archive them.
+  pub async fn check_archived(
+    &self,
+    contents: Option<&str>,
+    user: &User,
+    state: &State,
+  ) -> Result<(), Error> {
+    if user.roles.contains(&Role::Administrator) {
+      return Ok(());
+    }
+
+    // New pages aren't archived yet.
+    let archived = match self.filepath.is_file() {
+      true => self.split_front_matter(&self.raw(state).await?).0.archived,
+      false => false,
+    };
+    let archiving = contents
+      .map(|contents| self.split_front_matter(contents).0.archived)
+      .unwrap_or(false);
+
+    if archived || archiving {
+      return Err(Error::Archived);
+    }
+
+    Ok(())
+  }
+
+  /// Whether the page is a file like a `.docx` that's converted to be shown, rather than text.
+  pub fn is_binary(&self) -> bool {
+    self.format.map(|format| format.binary).unwrap_or(false)
+  }
+
   pub fn relative_path(&self, config: &Config) -> Result<PathBuf, Error> {
     let path = self.filepath.strip_prefix(&config.pages_directory)?;
 
     Ok(path.to_path_buf())
   }
 
+  /// The page's real path, if it was found with [`PathMatching`] under a path that's cased or
+  /// written differently.
+  pub fn canonical_path(&self, config: &Config) -> Option<PathBuf> {
+    let mut real = self.relative_path(config).ok()?.with_extension("");
+
+    if real.file_name() == Some(OsStr::new(crate::namespace::INDEX_PAGE))
+      && self.path.file_name() != real.file_name()
+    {
+      real.pop();
+    }
+
+    (real != self.path && config.path_matching.same_path(&real, &self.path)).then(|| real)
+  }
+
   pub fn url_path(&self) -> String {
     let path = self.path.with_extension("");
 
     format!("/{}", path.display())
   }
 
-  pub async fn raw(&self) -> Result<String, Error> {
-    let file = tokio::fs::read_to_string(&self.filepath).await?;
+  pub async fn raw(&self, state: &State) -> Result<String, Error> {
+    let file = state.pages.read(&self.filepath).await?;
 
     Ok(file)
   }
 
-  pub async fn context(&self) -> Result<(PageContext, String), Error> {
-    let file = self.raw().await?;
+  pub async fn context(&self, state: &State) -> Result<(PageContext, String), Error> {
+    let file = self.raw(state).await?;
 
     Ok(self.context_with(&file)?)
   }
 
-  pub fn front_matter(&self, file: &str) -> Result<(FrontMatter, String), Error> {
-    if file.starts_with(FrontMatter::DELIMITER) {
-      let (front_matter, data) =
-        Extractor::new(Splitter::EnclosingLines(FrontMatter::DELIMITER)).extract(file);
-      let data = data.to_string();
-      let front_matter = toml::from_str(&front_matter)?;
+  /// Whether the page is text that can start with front matter - a notebook, a table or a
+  /// Pandoc JSON document starting with `{` is just its own content.
+  pub fn can_have_front_matter(&self) -> bool {
+    match self.format {
+      Some(format) => format.has_front_matter(),
+      None => {
+        self.filepath.extension() != Some(OsStr::new("ipynb"))
+          && crate::table::delimiter(&self.filepath).is_none()
+      },
+    }
+  }
+
+  /// The last commit that changed the page, for changes to it to start from.
+  pub async fn revision(&self, state: &Arc<State>) -> Result<Option<Oid>, Error> {
+    let path = self.relative_path(&state.config())?;
+    let state = Arc::clone(state);
 
-      Ok((front_matter, data))
-    } else {
-      Ok((FrontMatter::default(), file.to_string()))
+    let commit = tokio::task::spawn_blocking(move || state.git.last_commit(&path, &*state.users))
+      .await
+      .unwrap()?;
+
+    Ok(commit.and_then(|commit| Oid::from_str(&commit.hash).ok()))
+  }
+
+  /// Splits the front matter from the rest of the page - see [`FrontMatter::split`].
+  pub fn split_front_matter(
+    &self,
+    file: &str,
+  ) -> (FrontMatter, String, Option<crate::front_matter::Error>) {
+    if !self.can_have_front_matter() {
+      return (FrontMatter::default(), file.to_string(), None);
     }
+
+    FrontMatter::split(file)
   }
 
   pub fn context_with(&self, file: &str) -> Result<(PageContext, String), Error> {
-    let (front_matter, data) = self.front_matter(file)?;
+    let (front_matter, data, error) = self.split_front_matter(file);
 
     Ok((
       PageContext {
         title: front_matter
           .title
           .unwrap_or_else(|| self.path.to_string_lossy().to_string()),
+        description: front_matter.description,
+        categories: front_matter.categories.unwrap_or_default(),
+        author: front_matter.author,
+        slug: front_matter.slug,
+        date: front_matter.date,
+        weight: front_matter.weight,
+        redirect_to: front_matter.redirect_to,
+        archived: front_matter.archived,
+        extra_css: front_matter.extra_css.unwrap_or_default(),
+        extra_js: front_matter.extra_js.unwrap_or_default(),
+        extra: front_matter.extra,
         user: self.user.clone(),
         path: self.path.to_string_lossy().to_string(),
         revision: None,
+        redirected_from: None,
+        front_matter_error: error.map(|err| err.to_string()),
+        url: None,
+        byline: None,
+        print: false,
+        layout: front_matter.layout,
       },
       data,
     ))
   }
 
+  fn commit_message(
+    &self,
+    kind: Kind,
+    title: &str,
+    summary: Option<&str>,
+    config: &Config,
+  ) -> Result<String, Error> {
+    let path = self.path.to_string_lossy();
+
+    let message = config.commit_messages.render(
+      kind,
+      Vars {
+        path: &path,
+        title,
+        summary,
+      },
+    )?;
+
+    Ok(message)
+  }
+
   pub async fn create(
     &self,
     contents: String,
+    summary: Option<&str>,
     user: &User,
     state: Arc<State>,
   ) -> Result<(), Error> {
+    self.check_archived(Some(&contents), user, &state).await?;
+
     // Make sure the page can render without errors
-    self
-      .renderer_with(&contents, state.clone())
-      .await?
-      .render()
-      .await?;
+    let renderer = self.renderer_with(&contents, state.clone()).await?;
+    let title = renderer.context.title.clone();
+    let message = self.commit_message(Kind::Create, &title, summary, &state.config())?;
+    renderer.render(&state).await?;
 
-    tokio::fs::write(&self.filepath, contents).await?;
+    if crate::draft::is_drafted(user, &state.config()) {
+      return self.save_draft(&contents, &message, user, &state);
+    }
 
-    state.git.add_file(&self.relative_path(&state.config)?)?;
-    state
-      .git
-      .commit(&format!("[create] {}", self.path.display()), user)?;
+    let writing = state.git.writing().lock().await;
+    state.pages.write(&self.filepath, &contents).await?;
+
+    let path = self.relative_path(&state.config())?;
+
+    state.git.add_file(&path)?;
+    state.git.commit(&message, user)?;
     state.git.push()?;
+    drop(writing);
+
+    crate::cache::purge(&state.config(), &path);
+    crate::notify::page_edited(&state, &self.path.to_string_lossy(), &title, summary, user).await;
+
+    if Navigation::is_navigation(&self.path) {
+      state.navigation.refresh(state.clone()).await;
+    }
 
     Ok(())
   }
 
+  /// Saves `contents` as the page's new contents. If `base` is the commit the edit started
+  /// from, anything that's been changed since is merged in, and whoever changed it is credited
+  /// as a co-author.
   pub async fn update(
     &self,
     contents: String,
+    summary: Option<&str>,
+    base: Option<Oid>,
     user: &User,
     state: Arc<State>,
   ) -> Result<(), Error> {
-    // Make sure the page can render without errors
     self
-      .renderer_with(&contents, state.clone())
-      .await?
-      .render()
-      .await?;
+      .update_with(contents, summary, base, Vec::new(), user, state)
+      .await
+  }
 
-    let raw = self.raw().await?;
+  /// Like [`Page::update`], crediting `co_authors` as well as whoever made any changes that
+  /// are merged in.
+  pub async fn update_with(
+    &self,
+    contents: String,
+    summary: Option<&str>,
+    base: Option<Oid>,
+    mut co_authors: Vec<CoAuthor>,
+    user: &User,
+    state: Arc<State>,
+  ) -> Result<(), Error> {
+    self.check_archived(Some(&contents), user, &state).await?;
+
+    let contents = match base {
+      Some(base) => {
+        let merged = state
+          .git
+          .merge(&self.relative_path(&state.config())?, base, &contents)?;
 
-    tokio::fs::write(&self.filepath, contents).await?;
+        for co_author in merged.co_authors {
+          if !co_authors.contains(&co_author) {
+            co_authors.push(co_author);
+          }
+        }
+
+        merged.contents
+      },
+      None => contents,
+    };
 
-    let git = || -> Result<(), Error> {
-      state.git.add_file(&self.relative_path(&state.config)?)?;
-      state
-        .git
-        .commit(&format!("[update] {}", self.path.display()), user)?;
-      state.git.push()?;
+    // Make sure the page can render without errors
+    let renderer = self.renderer_with(&contents, state.clone()).await?;
+    let title = renderer.context.title.clone();
+    let mut message = self.commit_message(Kind::Update, &title, summary, &state.config())?;
+    renderer.render(&state).await?;
+
+    let co_authors: Vec<_> = co_authors
+      .into_iter()
+      .filter(|co_author| co_author.email != user.email)
+      .collect();
+
+    if !co_authors.is_empty() {
+      message.push('\n');
+
+      for co_author in co_authors {
+        message.push_str(&format!(
+          "\nCo-authored-by: {} <{}>",
+          co_author.name, co_author.email
+        ));
+      }
+    }
+
+    if crate::draft::is_drafted(user, &state.config()) {
+      return self.save_draft(&contents, &message, user, &state);
+    }
+
+    let writing = state.git.writing().lock().await;
+    let raw = self.raw(&state).await?;
+
+    state.pages.write(&self.filepath, &contents).await?;
+
+    let path = self.relative_path(&state.config())?;
+
+    let commit = || -> Result<(), Error> {
+      state.git.add_file(&path)?;
+      state.git.commit(&message, user)?;
 
       Ok(())
     };
 
-    // If any of the `git` commands fail, revert the file on-disk to what it was before.
-    match git() {
-      Ok(_) => Ok(()),
-      Err(err) => {
-        tokio::fs::write(&self.filepath, raw).await?;
+    // If it can't be committed, revert the file on-disk to what it was before. Once it's
+    // committed the change has been made, even if it can't be pushed yet.
+    if let Err(err) = commit() {
+      state.pages.write(&self.filepath, &raw).await?;
+      state.git.add_file(&path)?;
 
-        Err(err)
-      },
+      return Err(err);
+    }
+    let pushed = state.git.push();
+    drop(writing);
+
+    crate::cache::purge(&state.config(), &path);
+    crate::notify::page_edited(&state, &self.path.to_string_lossy(), &title, summary, user).await;
+
+    if Navigation::is_navigation(&self.path) {
+      state.navigation.refresh(state.clone()).await;
+    }
+
+    pushed?;
+
+    Ok(())
+  }
+
+  /// Saves new contents for several pages, in a single commit. If anything goes wrong, every
+  /// page is put back how it was.
+  pub async fn update_all(
+    changes: &[(Page, String)],
+    message: &str,
+    user: &User,
+    state: Arc<State>,
+  ) -> Result<(), Error> {
+    let config = state.config();
+    let writing = state.git.writing().lock().await;
+
+    let mut originals = Vec::with_capacity(changes.len());
+    for (page, _) in changes {
+      originals.push(page.raw(&state).await?);
+    }
+
+    let written = async {
+      for (page, contents) in changes {
+        state.pages.write(&page.filepath, contents).await?;
+      }
+
+      Ok::<(), Error>(())
+    };
+
+    let commit = || -> Result<(), Error> {
+      for (page, _) in changes {
+        state.git.add_file(&page.relative_path(&config)?)?;
+      }
+
+      state.git.commit(message, user)?;
+
+      Ok(())
+    };
+
+    // Once it's committed the change has been made, even if it can't be pushed yet.
+    if let Err(err) = written.await.and_then(|_| commit()) {
+      for ((page, _), original) in changes.iter().zip(&originals) {
+        state.pages.write(&page.filepath, original).await?;
+        state.git.add_file(&page.relative_path(&config)?)?;
+      }
+
+      return Err(err);
+    }
+    let pushed = state.git.push();
+    drop(writing);
+
+    let mut navigation = false;
+    for (page, _) in changes {
+      let path = page.relative_path(&config)?;
+
+      crate::cache::purge(&config, &path);
+      navigation |= Navigation::is_navigation(&path.with_extension(""));
+    }
+
+    if navigation {
+      state.navigation.refresh(state.clone()).await;
     }
+
+    pushed?;
+
+    Ok(())
+  }
+
+  /// Commits `contents` to `user`'s draft branch, leaving the page itself as it is.
+  fn save_draft(
+    &self,
+    contents: &str,
+    message: &str,
+    user: &User,
+    state: &State,
+  ) -> Result<(), Error> {
+    let branch = crate::draft::branch(user);
+    let path = self.relative_path(&state.config())?;
+
+    state
+      .git
+      .commit_draft(&branch, &path, contents, message, user)?;
+
+    Ok(())
   }
 
   pub async fn renderer(&self, state: Arc<State>) -> Result<PageRender, Error> {
-    let file = self.raw().await?;
+    let file = self.raw(&state).await?;
 
     self.renderer_with(&file, state).await
   }
@@ -247,47 +646,166 @@ impl Page {
   pub async fn renderer_with(&self, file: &str, state: Arc<State>) -> Result<PageRender, Error> {
     let (context, data) = self.context_with(file)?;
 
+    if let Some(delimiter) = crate::table::delimiter(&self.filepath) {
+      let download = format!("/meta/raw/{}", self.path.display());
+      let html = crate::table::render(
+        &data,
+        delimiter,
+        state.config().csv_row_limit,
+        Some(&download),
+      )?;
+
+      return Ok(PageRender {
+        context,
+        html: html.into_string(),
+      });
+    }
+
+    if self.filepath.extension() == Some(OsStr::new("ipynb")) {
+      let html = tokio::task::spawn_blocking(move || crate::notebook::to_html(&data, state))
+        .await
+        .unwrap()?;
+
+      return Ok(PageRender { context, html });
+    }
+
+    // So the editor can point at the right line.
+    let line_offset = file
+      .strip_suffix(data.as_str())
+      .map(|front_matter| front_matter.lines().count())
+      .unwrap_or(0);
+
+    let format = self
+      .format
+      .unwrap_or_else(|| Format::detect(file, &state.config()));
+
     let html = tokio::task::spawn_blocking({
       let state = Arc::clone(&state);
-      let format = self.format.clone();
-      move || crate::pandoc::to_html(data, format, state)
+      let format = Some(format);
+      move || state.render.to_html(data, format, state.clone())
     })
     .await
-    .unwrap()?;
+    .unwrap()
+    .map_err(|source| Error::Render {
+      source,
+      line_offset,
+    })?;
 
     Ok(PageRender { context, html })
   }
 
-  pub async fn view_handler(self, state: Arc<State>) -> Result<Html<String>, Error> {
+  pub async fn view_handler(
+    self,
+    redirected_from: Option<String>,
+    print: bool,
+    state: Arc<State>,
+  ) -> Result<Html<String>, Error> {
     let mime = mime_guess::from_path(&self.path).first_or_text_plain();
 
     log::info!("{:?}: {:?}", self.path, mime.essence_str());
 
-    if mime.type_() != "text" && !state.config.allowed_mime_types.contains(mime.essence_str()) {
-      panic!()
+    if mime.type_() != "text"
+      && !state
+        .config()
+        .allowed_mime_types
+        .contains(mime.essence_str())
+    {
+      return Err(Error::ForbiddenFileType {
+        url: self.url_path(),
+        mime: mime.essence_str().to_string(),
+      });
+    }
+
+    let last_commit = {
+      let path = self.relative_path(&state.config())?;
+      let state = Arc::clone(&state);
+
+      tokio::task::spawn_blocking(move || state.git.last_commit(&path, &*state.users))
+        .await
+        .unwrap()?
+    };
+
+    let authors = {
+      let path = self.relative_path(&state.config())?;
+      let state = Arc::clone(&state);
+
+      tokio::task::spawn_blocking(move || state.git.author_emails(&path, None))
+        .await
+        .unwrap()?
+    };
+
+    let trusted = state
+      .config()
+      .sanitize_html
+      .is_trusted_authors(&authors, &state)
+      .await;
+
+    let file = self.raw(&state).await?;
+
+    let (context, data) = self.context_with(&file)?;
+    if context.layout.as_deref() == Some(crate::slides::LAYOUT) && !print {
+      return crate::slides::render(context, data, self.format, trusted, state).await;
+    }
+
+    let base_url = state.config().base_url().to_string();
+    let mut renderer = self.renderer_with(&file, state.clone()).await?;
+
+    if !trusted {
+      renderer.sanitize();
     }
 
-    let renderer = self.renderer(state).await?;
-    let html = renderer.render().await?;
+    let context = renderer.context_mut();
+    context.url = Some(format!("{}/{}", base_url, context.path));
+    context.redirected_from = redirected_from;
+    context.byline = last_commit.map(Byline::from);
+    context.print = print;
+
+    let html = renderer.render(&state).await?;
 
     Ok(html)
   }
 
-  pub async fn edit_handler(self) -> Result<Html<String>, Error> {
-    let file = self.raw().await?;
+  pub async fn edit_handler(self, state: Arc<State>) -> Result<Html<String>, Error> {
+    let file = self.raw(&state).await?;
 
     let (front_matter, _) = self.context_with(&file)?;
 
-    let tabs = PageTab::Edit.render(front_matter.path);
+    let tabs = PageTab::Edit.render(&front_matter.path);
+
+    // So changes made while this is being edited can be merged in when it's saved.
+    let revision = self.revision(&state).await?.map(|oid| oid.to_string());
+
+    let binary = self.is_binary();
+
+    // Someone else who's editing the page, if there is anyone.
+    let editing = self
+      .user
+      .as_ref()
+      .and_then(|user| state.locks.acquire(&self.path, user).err());
+
+    let config = state.config();
+    let dates = Dates::new(self.user.as_ref(), &config.dates);
 
     let content = maud::html! {
-      @if self.user.is_some() {
+      @if binary {
+        "This page is in a binary format, so it can't be edited here."
+      } @else if self.user.is_some() {
+        p #lock-notice .hidden[editing.is_none()] {
+          span #lock-holder {
+            @if let Some(lease) = &editing {
+              (lease.name) " is editing this page, so your changes might clash with theirs. \
+              Their lock expires " (dates.render(lease.expires)) "."
+            }
+          }
+          " "
+          button #take-over type="button" { "Take over" }
+        }
         #toolbar {
           div {
             select #format {
               option value="auto" selected { "Auto" }
-              @for format in crate::pandoc::VALID_FORMATS_WITH_NAME {
-                option value=(format.0) { (format.1) }
+              @for format in crate::format::REGISTRY.editor_formats(&state.config()) {
+                option value=(format.name) data-mode=(format.editor_mode) { (format.display_name) }
               }
             }
           }
@@ -300,56 +818,187 @@ impl Page {
           }
 
           div {
+            input #summary type="text" placeholder="Summary";
             button #save { "Save" }
           }
         }
 
-        #editor {}
+        form #delete-page method="post" action={ "/meta/delete/" (front_matter.path) }
+          onsubmit="return confirm('Delete this page? It can be restored from the trash.')" {
+          button type="submit" { "Delete page" }
+        }
+
+        #editor data-revision=[revision] data-collaborative[state.config().collaboration] {}
         #preview {}
       } @else {
         "You must be logged in to create new pages!"
       }
     };
 
-    let script = r#"
-      import { setup_editor } from '/bundle.js';
+    // It has to be the same URL as the template's, or the bundle is loaded twice.
+    let script = format!(
+      r#"
+      import {{ setup_editor }} from '{}';
       setup_editor();
-    "#;
+    "#,
+      state.assets.url("bundle.js")
+    );
 
     let template = crate::template::Template::new()
       .tabs(tabs)
+      .breadcrumbs(&front_matter.path)
       .title(maud::html! { (front_matter.title) " - Edit"})
       .content(content)
       .script(script)
-      .render(self.user);
+      .render(self.user, &state);
 
     Ok(template)
   }
 }
 
 pub async fn history_handler(page: Page, Extension(state): Extension<Arc<State>>) -> Response {
-  state
-    .git
-    .clone()
-    .history_listing_handler(&page, state)
+  crate::git::history_listing_handler(&page, state).await.into_response()
+}
+
+#[derive(serde::Deserialize)]
+pub struct CompareParams {
+  /// The older revision.
+  a: String,
+  /// The newer revision.
+  b: String,
+}
+
+pub async fn compare_handler(
+  page: Page,
+  Query(params): Query<CompareParams>,
+  Extension(state): Extension<Arc<State>>,
+) -> Response {
+  crate::git::compare_handler(&page, params.a, params.b, state)
     .await
     .into_response()
 }
 
+pub async fn export_history_handler(
+  page: Page,
+  Extension(state): Extension<Arc<State>>,
+) -> Response {
+  crate::git::export_history_handler(&page, state).await.into_response()
+}
+
 pub mod edit_handler {
   use super::*;
 
-  pub async fn get(page: Page, _: User) -> Response {
-    page.edit_handler().await.into_response()
+  pub async fn get(
+    page: Page,
+    user: Option<User>,
+    Extension(state): Extension<Arc<State>>,
+  ) -> Response {
+    let user = match user {
+      Some(user) => user,
+      // Guests can suggest changes instead, if they're allowed to.
+      None if state.config().guest_editing => {
+        let url = format!("/meta/guest-edit/{}", page.path.display());
+        return Redirect::to(&url).into_response();
+      },
+      None => {
+        let next = format!("/meta/edit/{}", page.path.display());
+        return Redirect::to(&crate::auth::login_url(&next)).into_response();
+      },
+    };
+
+    if let Err(err) = crate::acl::check_edit(&user, &page.path, &state.config()) {
+      return err.into_response();
+    }
+
+    if let Err(err) = page.check_archived(None, &user, &state).await {
+      return err.into_response();
+    }
+
+    page.edit_handler(state).await.into_response()
+  }
+
+  #[derive(serde::Deserialize)]
+  pub struct EditQuery {
+    summary: Option<String>,
+    /// The commit the page was at when editing started.
+    revision: Option<String>,
   }
 
   pub async fn post(
     page: Page,
+    Query(query): Query<EditQuery>,
+    forwarded: Forwarded,
+    headers: HeaderMap,
     body: String,
     user: User,
     Extension(state): Extension<Arc<State>>,
   ) -> Response {
-    match page.update(body, &user, state).await {
+    if let Err(err) = crate::acl::check_edit(&user, &page.path, &state.config()) {
+      return err.into_response();
+    }
+
+    let config = state.config();
+    let quota = config.quotas.quota(Some(&user));
+
+    if let Err(err) = state.usage.take(Who::user(&user), Activity::Edit, quota) {
+      return err.into_response();
+    }
+
+    let base = match query.revision.as_deref().map(Oid::from_str).transpose() {
+      Ok(base) => base,
+      Err(err) => return crate::git::Error::Git(err).into_response(),
+    };
+
+    // Anyone can sign up while registration is open, so edits from accounts that haven't been
+    // approved yet are checked for spam.
+    if !user.approved {
+      let before = match page.raw(&state).await {
+        Ok(before) => before,
+        Err(err) => return err.into_response(),
+      };
+
+      let base = match base {
+        Some(base) => Some(base),
+        None => match page.revision(&state).await {
+          Ok(base) => base,
+          Err(err) => return err.into_response(),
+        },
+      };
+
+      let submission = crate::spam::Submission {
+        path: page.path.clone(),
+        name: user.name.clone(),
+        email: Some(user.email.clone()),
+        summary: query.summary.clone(),
+        body: body.clone(),
+        before,
+        address: forwarded.client,
+        user_agent: crate::spam::user_agent(&headers),
+        base,
+        created: None,
+      };
+
+      match crate::spam::screen(submission, &state).await {
+        Ok(false) => (),
+        Ok(true) => return (StatusCode::ACCEPTED, crate::spam::HELD_MESSAGE).into_response(),
+        Err(err) => return err.into_response(),
+      }
+    }
+
+    let locks = state.locks.clone();
+
+    let drafted = crate::draft::is_drafted(&user, &state.config());
+
+    let result = page
+      .update(body, query.summary.as_deref(), base, &user, state)
+      .await;
+
+    if result.is_ok() {
+      locks.release(&page.path, &user);
+    }
+
+    match result {
+      Ok(_) if drafted => Redirect::to("/meta/drafts").into_response(),
       Ok(_) => Redirect::to(&page.url_path()).into_response(),
       Err(err) => err.into_response(),
     }
@@ -359,14 +1008,23 @@ pub mod edit_handler {
 pub mod new_handler {
   use super::*;
 
+  #[derive(serde::Deserialize)]
+  pub struct NewParams {
+    /// The name of a template in `_templates` to fill in, which is returned as JSON.
+    template: Option<String>,
+  }
+
   #[derive(serde::Deserialize)]
   pub struct NewPage {
     body: String,
-    format: Format,
+    format: Choice,
+    #[serde(default)]
+    summary: Option<String>,
   }
 
   pub async fn get(
     Path(path): Path<String>,
+    Query(params): Query<NewParams>,
     user: Option<User>,
     Extension(state): Extension<Arc<State>>,
   ) -> Result<Response, Error> {
@@ -374,31 +1032,84 @@ pub mod new_handler {
 
     let path = path.strip_prefix("/").unwrap();
 
-    match find_file(&path, &state.config) {
+    if let Some(name) = params.template {
+      return match crate::boilerplate::find(&name, &state.config()) {
+        Some(template) => {
+          let filled = template
+            .fill(std::path::Path::new(path), user.as_ref())
+            .await?;
+
+          Ok(Json(filled).into_response())
+        },
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+      };
+    }
+
+    let templates = crate::boilerplate::list(&state.config());
+    let settings =
+      crate::namespace::Settings::for_path(std::path::Path::new(path), &state.config());
+
+    match find_file(&path, &state.config()) {
       Ok(path) => {
-        let path = if path.starts_with(&state.config.pages_directory) {
+        let path = if path.starts_with(&state.config().pages_directory) {
           path
-            .strip_prefix(&state.config.pages_directory)
+            .strip_prefix(&state.config().pages_directory)
             .unwrap()
             .to_path_buf()
         } else {
           path
         };
 
-        return Ok(Redirect::to(&format!("/{}", path.display())).into_response());
+        return Ok(Redirect::to(&page_url(&path.to_string_lossy())).into_response());
       },
       Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
       Err(err) => return Err(Error::from(err)),
     };
 
+    let slug = state.config().slugs.apply(path);
+    if !slug.is_empty() && slug != path {
+      return Ok(Redirect::to(&format!("/meta/new{}", page_url(&slug))).into_response());
+    }
+
+    // Someone might be about to make a page that's already there under another name.
+    let similar = match &user {
+      Some(_) => {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        crate::slug::similar_pages(name, &state).await?
+      },
+      None => Vec::new(),
+    };
+
     let content = maud::html! {
       .warning { "The page at " (path) " doesn't exist." }
+      @if !similar.is_empty() {
+        .warning #similar-pages {
+          "Check this isn't the same as "
+          @for (i, page) in similar.iter().enumerate() {
+            @if i > 0 { ", " }
+            a href=(page.url) { (page.title) }
+          }
+          ", which already exist."
+        }
+      }
       @if user.is_some() {
         #toolbar {
           div {
             select #format {
-              @for format in crate::pandoc::VALID_FORMATS_WITH_NAME {
-                option value=(format.0) { (format.1) }
+              @for format in crate::format::REGISTRY.editor_formats(&state.config()) {
+                option value=(format.name) data-mode=(format.editor_mode)
+                  selected[settings.format.as_deref() == Some(format.name)] { (format.display_name) }
+              }
+            }
+          }
+
+          @if !templates.is_empty() {
+            div {
+              select #template {
+                option value="" { "Blank page" }
+                @for template in &templates {
+                  option value=(template.name) { (template.name) }
+                }
               }
             }
           }
@@ -411,6 +1122,7 @@ pub mod new_handler {
           }
 
           div {
+            input #summary type="text" placeholder="Summary";
             button #save { "Save" }
           }
         }
@@ -418,20 +1130,25 @@ pub mod new_handler {
         #editor {}
         #preview {}
       } @else {
-        "You must be logged in to create new pages!"
+        "You must be "
+        a href=(crate::auth::login_url(&format!("/meta/new/{}", path))) { "logged in" }
+        " to create new pages!"
       }
     };
 
-    let script = r#"
-      import { newpage_editor } from '/bundle.js';
+    let script = format!(
+      r#"
+      import {{ newpage_editor }} from '{}';
       newpage_editor();
-    "#;
+    "#,
+      state.assets.url("bundle.js")
+    );
 
     let template = crate::template::Template::new()
       .title("Create new page")
       .content(content)
       .script(script)
-      .render(user);
+      .render(user, &state);
 
     Ok(template.into_response())
   }
@@ -445,48 +1162,69 @@ pub mod new_handler {
     Page::check_if_reserved(&url_path)?;
 
     let path = url_path.strip_prefix("/").unwrap();
-    let path = PathBuf::from(path);
+    let path = PathBuf::from(state.config().slugs.apply(path));
+
+    crate::acl::check_edit(&user, &path, &state.config())?;
+
+    let config = state.config();
+    let quota = config.quotas.quota(Some(&user));
+    state
+      .usage
+      .take(Who::user(&user), Activity::NewPage, quota)?;
 
+    let format = new_page.format.resolve(&new_page.body, &config);
     let filepath =
-      dbg!(state.config.pages_directory.join(&path)).with_extension(new_page.format.extension());
+      dbg!(state.config().pages_directory.join(&path)).with_extension(format.extension());
 
     let page = Page {
       path,
       filepath,
-      format: Some(new_page.format),
+      format: Some(format),
       user: Some(user.clone()),
     };
 
-    page.create(new_page.body, &user, state).await?;
+    let drafted = crate::draft::is_drafted(&user, &state.config());
+
+    page
+      .create(new_page.body, new_page.summary.as_deref(), &user, state)
+      .await?;
+
+    if drafted {
+      return Ok(Redirect::to("/meta/drafts").into_response());
+    }
 
     Ok(Redirect::to(&page.url_path()).into_response())
   }
 }
 
-pub async fn raw_handler(page: Page) -> Response {
-  page.raw().await.into_response()
-}
-
-pub async fn categories_handler(
-  user: Option<User>,
+pub async fn raw_handler(
+  page: Page,
+  request_headers: HeaderMap,
   Extension(state): Extension<Arc<State>>,
-) -> Result<Html<String>, Error> {
-  let categories = Page::categories(&state.config).await?;
-
-  let content = maud::html! {
-    ul #categories {
-      @for category in &categories {
-        li { a href={"/meta/category/" (category)} { (category) } }
-      }
-    }
+) -> Response {
+  let mime = page
+    .format
+    .map(|format| format.mime)
+    .unwrap_or("text/plain");
+
+  let validators = match Validators::raw(&page, &state).await {
+    Ok(validators) => validators,
+    Err(err) => return err.into_response(),
   };
 
-  let template = crate::template::Template::new()
-    .title("Categories")
-    .content(content)
-    .render(user);
+  if validators.is_fresh(&request_headers) {
+    return validators.not_modified();
+  }
 
-  Ok(template)
+  match page.raw(&state).await {
+    Ok(raw) => (
+      [(header::CONTENT_TYPE, format!("{}; charset=utf-8", mime))],
+      validators.headers(),
+      raw,
+    )
+      .into_response(),
+    Err(err) => err.into_response(),
+  }
 }
 
 pub struct PageRender {
@@ -495,25 +1233,150 @@ pub struct PageRender {
 }
 
 impl PageRender {
+  pub fn html(&self) -> &str {
+    &self.html
+  }
+
   pub fn context_mut(&mut self) -> &mut PageContext {
     &mut self.context
   }
 
-  pub async fn render(self) -> Result<Html<String>, Error> {
-    let tabs = PageTab::View.render(self.context.path);
+  pub fn sanitize(&mut self) {
+    self.html = crate::sanitize::clean(&self.html);
+
+    // They could do anything the sanitizer stops, too.
+    self.context.extra_css.clear();
+    self.context.extra_js.clear();
+  }
+
+  pub async fn render(self, state: &State) -> Result<Html<String>, Error> {
+    let administrator = self
+      .context
+      .user
+      .as_ref()
+      .map(|user| user.roles.contains(&Role::Administrator))
+      .unwrap_or(false);
+
+    let tabs = match self.context.archived && !administrator {
+      true => PageTab::View.render_read_only(&self.context.path),
+      false => PageTab::View.render(&self.context.path),
+    };
+    let watching = self
+      .context
+      .user
+      .as_ref()
+      .map(|user| user.notifications.watching.contains(&self.context.path));
+
+    let tabs = maud::html! {
+      (tabs)
+      @if let Some(watching) = watching {
+        form .watch method="post" action={ "/meta/watch/" (self.context.path) } {
+          button type="submit" { @if watching { "unwatch" } @else { "watch" } }
+        }
+      }
+      a .print-link href={ "/" (self.context.path) "?print=1" } { "printable version" }
+    };
+
+    let config = state.config();
+    let dates = Dates::new(self.context.user.as_ref(), &config.dates);
 
     let content = maud::html! {
+      @if let Some(byline) = &self.context.byline {
+        p .byline {
+          "Last edited by "
+          @if let Some(url) = &byline.url {
+            a href=(url) { (byline.name) }
+          } @else {
+            (byline.name)
+          }
+          " on "
+          // "On 3 hours ago" wouldn't read right, so this is always the date itself.
+          a href={ "/meta/history/" (self.context.path) } {
+            time datetime=(byline.modified.format(&Rfc3339).unwrap_or_default())
+              title=(dates.relative(byline.modified)) {
+              (dates.absolute(byline.modified))
+            }
+          }
+          @if !byline.message.is_empty() {
+            " - " span .message { (byline.message) }
+          }
+        }
+      }
+      @if let Some(error) = &self.context.front_matter_error {
+        .warning {
+          "This page's front matter couldn't be read, so it's been ignored:"
+          pre { (error) }
+          a href={ "/meta/edit/" (self.context.path) } { "fix metadata" }
+        }
+      }
+      @if self.context.archived {
+        .notice.archived { "This page has been archived, so it's kept as it is." }
+      }
       @if let Some(revision) = self.context.revision {
         .warning { (revision) }
       }
-      (maud::PreEscaped(self.html))
+      @if let Some(from) = &self.context.redirected_from {
+        .notice {
+          "Redirected from "
+          a href={ "/" (from) "?redirect=no" } { (from) }
+        }
+      }
+      article .h-entry {
+        data .p-name value=(self.context.title) {}
+        @if let Some(description) = &self.context.description {
+          data .p-summary value=(description) {}
+        }
+        @if let Some(date) = self.context.date {
+          time .dt-published.hidden datetime=(date.format(&Rfc3339).unwrap_or_default()) {}
+        }
+        @for category in &self.context.categories {
+          data .p-category value=(category) {}
+        }
+        @if let Some(url) = &self.context.url {
+          a .u-url.hidden href=(url) {}
+        }
+        @if let Some(byline) = &self.context.byline {
+          @if let Some(url) = &byline.url {
+            a .p-author.h-card.hidden href=(url) { (byline.name) }
+          } @else {
+            span .p-author.h-card.hidden { (byline.name) }
+          }
+          time .dt-updated.hidden datetime=(byline.modified.format(&Rfc3339).unwrap_or_default()) {}
+        }
+        .e-content {
+          (maud::PreEscaped(self.html))
+        }
+      }
+    };
+
+    let metadata = Metadata {
+      title: self.context.title.clone(),
+      description: self.context.description.clone(),
+      url: self.context.url.clone(),
+      author: self
+        .context
+        .author
+        .clone()
+        .or_else(|| self.context.byline.as_ref().map(|byline| byline.name.clone())),
+      published: self.context.date,
+      modified: self.context.byline.as_ref().map(|byline| byline.modified),
     };
 
+    let settings = crate::namespace::Settings::for_path(
+      std::path::Path::new(&self.context.path),
+      &state.config(),
+    );
+
     let template = crate::template::Template::new()
+      .base(settings.template)
+      .metadata(metadata)
       .tabs(tabs)
+      .breadcrumbs(&self.context.path)
       .title(self.context.title)
       .content(content)
-      .render(self.context.user);
+      .extra_assets(self.context.extra_css, self.context.extra_js)
+      .print(self.context.print)
+      .render(self.context.user, state);
 
     Ok(template)
   }
@@ -534,6 +1397,14 @@ impl PageTab {
       a .active[self == PageTab::History] href={"/meta/history/" (path)} { "history" }
     }
   }
+
+  /// The tabs without "edit", for pages that can't be changed.
+  pub fn render_read_only(self, path: impl AsRef<str>) -> maud::Markup {
+    maud::html! {
+      a .active[self == PageTab::View] href={"/" (path)} { "view" }
+      a .active[self == PageTab::History] href={"/meta/history/" (path)} { "history" }
+    }
+  }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -542,24 +1413,37 @@ pub enum PagePathError {
   PathRejection(#[from] PathRejection),
   #[error(transparent)]
   Io(#[from] std::io::Error),
+  /// The page's namespace can't be seen by whoever asked for it.
+  #[error("You can't see this page")]
+  Hidden { logged_in: bool },
 }
 
 impl IntoResponse for PagePathError {
   fn into_response(self) -> Response {
-    let code = match self {
-      Self::PathRejection(_) => StatusCode::NOT_FOUND,
-      Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
-    };
-
-    (code, self.to_string()).into_response()
+    match self {
+      Self::PathRejection(_) => ErrorPage::NotFound { url: None }.into_response(),
+      Self::Hidden { logged_in: false } => ErrorPage::Unauthorized { role: None }.into_response(),
+      // Someone who's logged in isn't told it's there.
+      Self::Hidden { logged_in: true } => ErrorPage::NotFound { url: None }.into_response(),
+      Self::Io(_) => {
+        Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", self).into_response()
+      },
+    }
   }
 }
 
-const PATH_PREFIXES_TO_STRIP: [&'static str; 5] = [
+const PATH_PREFIXES_TO_STRIP: [&'static str; 12] = [
   "/meta/new/",
+  "/meta/watch/",
   "/meta/history/",
+  "/meta/compare/",
+  "/meta/export-history/",
   "/meta/edit/",
+  "/meta/guest-edit/",
   "/meta/raw/",
+  "/meta/lock/",
+  "/meta/collab/",
+  "/meta/delete/",
   "/",
 ];
 
@@ -588,40 +1472,204 @@ where
 
     let path = PathBuf::from(path);
 
-    let filepath = dbg!(find_file(&path, &state.config))?;
-
-    let format = filepath
-      .extension()
-      .map(|e| e.to_str())
-      .flatten()
-      .map(|ext| Format::from_extension(ext))
-      .flatten();
-
     // We're good to unwrap here because if there's an error, it'll just return `None`.
     let user = Option::<User>::from_request(req).await.unwrap();
 
-    let page = Page {
-      path,
-      filepath,
-      format,
-      user,
-    };
+    let config = state.config();
+    // Loose path matching could find a page in a namespace the path doesn't look like it's in.
+    let settings =
+      crate::namespace::Settings::for_path(&crate::acl::resolve(&path, &config), &config);
+
+    if !settings.can_read(user.as_ref()) {
+      return Err(PagePathError::Hidden {
+        logged_in: user.is_some(),
+      });
+    }
+
+    let page = Page::from_path(path, user, &config)?;
 
     Ok(page)
   }
 }
 
+/// How loosely page paths are matched against the files in the pages directory. Either way, a
+/// file whose name matches exactly is always preferred.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PathMatching {
+  /// `/home` finds `Home.md`.
+  pub case_insensitive: bool,
+  /// Paths are compared in Unicode's NFC form, so an "é" typed as "e" and an accent finds a file
+  /// named with a single "é" character.
+  pub normalize_unicode: bool,
+}
+
+impl PathMatching {
+  fn is_loose(&self) -> bool {
+    self.case_insensitive || self.normalize_unicode
+  }
+
+  fn normalize(&self, name: &OsStr) -> String {
+    let mut name = name.to_string_lossy().to_string();
+
+    if self.normalize_unicode {
+      name = name.nfc().collect();
+    }
+
+    if self.case_insensitive {
+      name = name.to_lowercase();
+    }
+
+    name
+  }
+
+  fn matches(&self, a: &OsStr, b: &OsStr) -> bool {
+    a == b || (self.is_loose() && self.normalize(a) == self.normalize(b))
+  }
+
+  /// Whether `a` and `b` are the same page path, once they're loosened up.
+  pub fn same_path(&self, a: &std::path::Path, b: &std::path::Path) -> bool {
+    let a: Vec<_> = a.iter().collect();
+    let b: Vec<_> = b.iter().collect();
+
+    a.len() == b.len() && a.iter().zip(&b).all(|(a, b)| self.matches(a, b))
+  }
+
+  /// `path` with each of its directories swapped for the one that's really in `parent`, if
+  /// there's one that matches.
+  pub fn resolve_directories(&self, parent: &std::path::Path, path: &std::path::Path) -> PathBuf {
+    let mut resolved = parent.to_path_buf();
+
+    for component in path.iter() {
+      let exact = resolved.join(component);
+      if exact.is_dir() || !self.is_loose() {
+        resolved = exact;
+        continue;
+      }
+
+      let found = std::fs::read_dir(&resolved).ok().and_then(|entries| {
+        entries
+          .filter_map(Result::ok)
+          .map(|entry| entry.path())
+          .filter(|path| path.is_dir())
+          .find(|path| {
+            path
+              .file_name()
+              .map(|name| self.matches(name, component))
+              .unwrap_or(false)
+          })
+      });
+
+      resolved = found.unwrap_or(exact);
+    }
+
+    resolved
+  }
+}
+
+/// A page's format - the `format` in its front matter if it has one, so pages can be kept in
+/// `.txt` files or without an extension, or else its extension's.
+/// The format named in each text file's front matter, as of when the file was last modified, so
+/// it's only read again once it's changed.
+static NAMED_FORMATS: Lazy<Mutex<HashMap<PathBuf, (SystemTime, Option<Format>)>>> =
+  Lazy::new(Default::default);
+
+pub fn format_of(filepath: &std::path::Path) -> Option<Format> {
+  let extension = filepath.extension().map(|ext| ext.to_string_lossy());
+  let from_extension = extension.as_deref().and_then(Format::from_extension);
+
+  // Only text files can have front matter, and there's no need to read anything else.
+  let is_text = match (&extension, from_extension) {
+    (_, Some(format)) => format.has_front_matter(),
+    (Some(extension), None) => extension == "txt",
+    (None, None) => true,
+  };
+
+  if !is_text {
+    return from_extension;
+  }
+
+  let modified = std::fs::metadata(filepath)
+    .and_then(|metadata| metadata.modified())
+    .ok();
+
+  if let Some(modified) = modified {
+    if let Some((when, named)) = NAMED_FORMATS.lock().unwrap().get(filepath) {
+      if *when == modified {
+        return named.or(from_extension);
+      }
+    }
+  }
+
+  let named = std::fs::read_to_string(filepath)
+    .ok()
+    .and_then(|file| FrontMatter::split(&file).0.format)
+    .and_then(|name| crate::format::REGISTRY.by_name(&name));
+
+  if let Some(modified) = modified {
+    NAMED_FORMATS
+      .lock()
+      .unwrap()
+      .insert(filepath.to_path_buf(), (modified, named));
+  }
+
+  named.or(from_extension)
+}
+
+/// Whether `path`, relative to the pages directory, stays inside it and isn't hidden, like
+/// `.git`.
+pub fn is_safe_path(path: &std::path::Path) -> bool {
+  path.components().all(|component| match component {
+    std::path::Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+    _ => false,
+  })
+}
+
+/// The URL of the page at `path`, with each part of it percent-encoded.
+pub fn page_url(path: &str) -> String {
+  let segments: Vec<_> = path.split('/').map(urlencoding::encode).collect();
+
+  format!("/{}", segments.join("/"))
+}
+
 pub fn find_file(
   path: impl AsRef<std::path::Path>,
   config: &Config,
 ) -> Result<PathBuf, std::io::Error> {
-  let mut path = config.pages_directory.join(&path);
+  let path = path.as_ref();
+  let relative = path.strip_prefix(&config.pages_directory).unwrap_or(path);
 
-  if path.is_dir() {
-    return Err(std::io::Error::new(
+  let not_found = || {
+    std::io::Error::new(
       std::io::ErrorKind::NotFound,
-      format!("{:?} is a directory", &path),
-    ));
+      format!("{:?} not found", relative),
+    )
+  };
+
+  if !is_safe_path(relative) {
+    return Err(not_found());
+  }
+
+  let found = find_in_pages(relative, config)?;
+
+  // A symlink could still lead out of the pages directory.
+  if !found.canonicalize()?.starts_with(&config.pages_directory) {
+    return Err(not_found());
+  }
+
+  Ok(found)
+}
+
+fn find_in_pages(relative: &std::path::Path, config: &Config) -> Result<PathBuf, std::io::Error> {
+  let matching = &config.path_matching;
+
+  let mut path = matching.resolve_directories(&config.pages_directory, relative);
+
+  // A directory's page is the `_index` inside it, or else a page next to it with the same name.
+  if path.is_dir() {
+    if let Ok(index) = find_file(path.join(crate::namespace::INDEX_PAGE), config) {
+      return Ok(index);
+    }
   }
 
   let name_to_match = path
@@ -632,31 +1680,36 @@ pub fn find_file(
     ))?
     .to_os_string();
 
-  dbg!(&path);
-
   path.pop();
 
-  dbg!(&name_to_match);
-  dbg!(&path);
+  let mut loose_match = None;
 
   for file in std::fs::read_dir(&path)? {
     let file = file?;
     let path = file.path();
 
+    if path.is_dir() {
+      continue;
+    }
+
     let name = match path.file_stem() {
       Some(name) => name,
       None => continue,
     };
 
-    dbg!(&name);
-
     if name_to_match == name {
       return Ok(file.path());
     }
+
+    if loose_match.is_none() && matching.matches(&name_to_match, name) {
+      loose_match = Some(file.path());
+    }
   }
 
-  return Err(std::io::Error::new(
-    std::io::ErrorKind::NotFound,
-    format!("{:?} not found", &path),
-  ));
+  loose_match.ok_or_else(|| {
+    std::io::Error::new(
+      std::io::ErrorKind::NotFound,
+      format!("{:?} not found", &path),
+    )
+  })
 }
@@ -1,218 +1,430 @@
-use std::sync::Arc;
+use std::{
+  collections::HashSet,
+  io::Write,
+  process::{Command, Stdio},
+  sync::Arc,
+};
 
 use axum::{
   extract::Query,
   response::{Html, IntoResponse, Response},
   Extension,
+  Json,
+};
+use pandoc::{
+  InputFormat,
+  InputKind,
+  MarkdownExtension,
+  OutputFormat,
+  OutputKind,
+  Pandoc,
+  PandocOption,
+  PandocOutput,
 };
-use pandoc::{InputKind, OutputFormat, OutputKind, Pandoc, PandocOption, PandocOutput};
 use pandoc_ast::MutVisitor;
-use serde::{Deserialize, Deserializer};
-
-use crate::State;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  config::Config,
+  error::ErrorPage,
+  format::{Choice, Format, Renderer},
+  interwiki::InterwikiFilter,
+  line_map::LineMap,
+  user::User,
+  State,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
   #[error(transparent)]
   PandocError(#[from] pandoc::PandocError),
-  #[error("Output from Pandoc is wrong\nExpected:\n{expected}\n\n\nActual:\n{actual}")]
-  PandocWrongOutput { expected: String, actual: String },
+  #[error(transparent)]
+  Shortcode(#[from] crate::shortcodes::Error),
+  #[error("AsciiDoc support isn't enabled")]
+  AsciiDocDisabled,
+  #[error("Couldn't run the AsciiDoc converter: {0}")]
+  AsciiDoc(std::io::Error),
+  #[error("The AsciiDoc converter failed:\n{stderr}")]
+  AsciiDocFailed { stderr: String },
+  #[error("Pandoc isn't installed, so only Markdown pages can be shown")]
+  Unavailable,
+  #[error("This version of Pandoc can't read {0}")]
+  Unsupported(String),
+  #[error("AsciiDoc pages can't be shown as slides")]
+  AsciiDocSlides,
+  /// The converter failed on the page once its shortcodes and the like were filled in, which
+  /// can add lines - `lines` says where each one came from.
+  #[error("{source}")]
+  Expanded { source: Box<Error>, lines: LineMap },
 }
 
-pub const VALID_FORMATS_WITH_NAME: [(&'static str, &'static str); 14] = [
-  ("markdown", "Markdown"),
-  ("rst", "reStructuredText"),
-  ("html", "HTML"),
-  ("latex", "LaTeX"),
-  ("mediawiki", "MediaWiki"),
-  ("textile", "Textile"),
-  ("org", "Emacs Org-Mode"),
-  ("opml", "OPML"),
-  ("docx", ".docx"),
-  ("haddock", "Haddock"),
-  ("epub", "EPUP"),
-  ("docbook", "DocBook"),
-  ("t2t", "txt2tags"),
-  ("twiki", "TWiki"),
-];
-
-const VALID_FORMATS: [&'static str; 14] = [
-  "markdown",
-  "rst",
-  "html",
-  "latex",
-  "mediawiki",
-  "textile",
-  "org",
-  "opml",
-  "docx",
-  "haddock",
-  "epub",
-  "docbook",
-  "t2t",
-  "twiki",
-];
-
-#[derive(serde::Deserialize)]
-pub struct QueryFormat {
-  format: Format,
+/// Something that went wrong rendering a page, and where, if the converter said.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Diagnostic {
+  pub line: Option<usize>,
+  pub column: Option<usize>,
+  pub message: String,
 }
 
-impl Into<Format> for QueryFormat {
-  fn into(self) -> Format {
-    self.format
+impl Error {
+  /// What the converter said went wrong, or the error itself if it didn't get that far.
+  pub fn stderr(&self) -> String {
+    match self {
+      Self::PandocError(pandoc::PandocError::Err(output)) => {
+        String::from_utf8_lossy(&output.stderr).to_string()
+      },
+      Self::AsciiDocFailed { stderr } => stderr.clone(),
+      Self::Expanded { source, .. } => source.stderr(),
+      err => err.to_string(),
+    }
   }
-}
 
-#[derive(Debug, Clone)]
-pub struct Format(::pandoc::InputFormat);
-
-impl Format {
-  pub fn from_extension(extension: &str) -> Option<Self> {
-    use ::pandoc::InputFormat;
-
-    match extension {
-      "lhs" => Some(Self(InputFormat::Native)),
-      "json" => Some(Self(InputFormat::Json)),
-      "md" => Some(Self(InputFormat::Markdown)),
-      "textile" => Some(Self(InputFormat::Textile)),
-      "rst" => Some(Self(InputFormat::Rst)),
-      "html" => Some(Self(InputFormat::Html)),
-      "dbk" => Some(Self(InputFormat::DocBook)),
-      "t2t" => Some(Self(InputFormat::T2t)),
-      "docx" => Some(Self(InputFormat::Docx)),
-      "epub" => Some(Self(InputFormat::Epub)),
-      "opml" => Some(Self(InputFormat::Opml)),
-      "org" => Some(Self(InputFormat::Org)),
-      "wiki" => Some(Self(InputFormat::MediaWiki)),
-      "twiki" => Some(Self(InputFormat::Twiki)),
-      "hs" => Some(Self(InputFormat::Haddock)),
-      "tex" => Some(Self(InputFormat::Latex)),
-      _ => None,
+  /// Each of the converter's messages, with the line and column it mentions, if it does. Lines
+  /// are counted from the page's first line, which is `line_offset` lines before the first line
+  /// the converter was given.
+  pub fn diagnostics(&self, line_offset: usize) -> Vec<Diagnostic> {
+    match self {
+      Self::Expanded { source, lines } => source
+        .diagnostics(0)
+        .into_iter()
+        .map(|diagnostic| Diagnostic {
+          line: diagnostic
+            .line
+            .map(|line| lines.original(line) + line_offset),
+          ..diagnostic
+        })
+        .collect(),
+      err => diagnostics(&err.stderr(), line_offset),
     }
   }
+}
 
-  pub fn extension(&self) -> &'static str {
-    use ::pandoc::InputFormat;
-
-    match self.clone().into() {
-      InputFormat::Native => "lhs",
-      InputFormat::Json => "json",
-      InputFormat::Markdown
-      | InputFormat::MarkdownStrict
-      | InputFormat::MarkdownPhpextra
-      | InputFormat::MarkdownGithub
-      | InputFormat::Commonmark => "md",
-      InputFormat::Textile => "textile",
-      InputFormat::Rst => "rst",
-      InputFormat::Html => "html",
-      InputFormat::DocBook => "dbk",
-      InputFormat::T2t => "t2t",
-      InputFormat::Docx => "docx",
-      InputFormat::Epub => "epub",
-      InputFormat::Opml => "opml",
-      InputFormat::Org => "org",
-      InputFormat::MediaWiki => "wiki",
-      InputFormat::Twiki => "twiki",
-      InputFormat::Haddock => "hs",
-      InputFormat::Latex => "tex",
-      other => panic!("Unsupported format: {}", other),
+/// Each of the messages in a converter's `stderr`, with the line and column it mentions.
+fn diagnostics(stderr: &str, line_offset: usize) -> Vec<Diagnostic> {
+  let position = Regex::new(r"(?i)line (\d+),? column (\d+)").unwrap();
+  let mut diagnostics: Vec<Diagnostic> = Vec::new();
+
+  let lines = stderr
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty());
+
+  for line in lines {
+    match (position.captures(line), diagnostics.last_mut()) {
+      (Some(captures), _) => diagnostics.push(Diagnostic {
+        line: captures[1]
+          .parse::<usize>()
+          .ok()
+          .map(|line| line + line_offset),
+        column: captures[2].parse().ok(),
+        message: line.to_string(),
+      }),
+      // The rest of the message before it.
+      (None, Some(last)) => {
+        last.message.push('\n');
+        last.message.push_str(line);
+      },
+      (None, None) => diagnostics.push(Diagnostic {
+        line: None,
+        column: None,
+        message: line.to_string(),
+      }),
     }
   }
+
+  diagnostics
 }
 
-impl Into<::pandoc::InputFormat> for Format {
-  fn into(self) -> ::pandoc::InputFormat {
-    self.0
+/// The warnings pandoc gives reading `doc`, like a reference to a link that isn't defined,
+/// for the editor's preview. The pandoc library only passes on what pandoc says when it fails,
+/// so this reads the page again on its own. Lines are the page's own, before any shortcodes or
+/// site variables are filled in.
+pub fn warnings(doc: &str, format: Option<Format>, state: &State) -> Vec<Diagnostic> {
+  let from = match format.as_ref().map(|format| &format.renderer) {
+    Some(Renderer::Pandoc(InputFormat::Markdown)) | None => {
+      let extensions: String = state
+        .config()
+        .markdown_extensions
+        .iter()
+        .map(|extension| format!("+{}", extension))
+        .collect();
+
+      format!("markdown{}", extensions)
+    },
+    Some(Renderer::Pandoc(format)) if state.pandoc.can_read(format) => format.to_string(),
+    _ => return Vec::new(),
+  };
+
+  if !state.pandoc.is_available() {
+    return Vec::new();
   }
+
+  let child = Command::new("pandoc")
+    .args(["--from", &from, "--to", "json", "--verbosity", "WARNING"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .spawn();
+
+  let mut child = match child {
+    Ok(child) => child,
+    Err(err) => {
+      log::warn!("Couldn't run pandoc to check for warnings: {}", err);
+      return Vec::new();
+    },
+  };
+
+  let mut stdin = child.stdin.take().unwrap();
+  let doc = doc.to_string();
+  let writer = std::thread::spawn(move || stdin.write_all(doc.as_bytes()));
+
+  let output = match child.wait_with_output() {
+    Ok(output) => output,
+    Err(err) => {
+      log::warn!("Couldn't run pandoc to check for warnings: {}", err);
+      return Vec::new();
+    },
+  };
+  let _ = writer.join();
+
+  diagnostics(&String::from_utf8_lossy(&output.stderr), 0)
 }
 
-impl<'de> Deserialize<'de> for Format {
-  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-  where
-    D: Deserializer<'de>,
-  {
-    deserializer.deserialize_str(InputFormatVisitor)
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    ErrorPage::RenderFailed {
+      stderr: self.stderr(),
+      diagnostics: self.diagnostics(0),
+    }
+    .into_response()
   }
 }
 
-struct InputFormatVisitor;
+#[derive(serde::Deserialize)]
+pub struct QueryFormat {
+  format: Choice,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+pub struct RenderOptions {
+  /// Send a [`crate::preview::Preview`] as JSON, rather than just the HTML.
+  json: bool,
+}
 
-impl<'de> serde::de::Visitor<'de> for InputFormatVisitor {
-  type Value = Format;
+/// What the installed pandoc can do, found out when the wiki starts.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+  /// Like `2.19.2`, or `None` if pandoc couldn't be run - then Markdown is rendered without
+  /// it, and nothing else can be.
+  pub version: Option<String>,
+  /// The formats it can read, by pandoc's names for them.
+  pub input_formats: HashSet<String>,
+}
 
-  fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-    formatter.write_str("an input format that `pandoc` recognises")
-  }
+impl Capabilities {
+  pub fn probe() -> Self {
+    let run = |arg: &str| {
+      let output = Command::new("pandoc")
+        .arg(arg)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+
+      Some(String::from_utf8_lossy(&output.stdout).to_string())
+    };
 
-  fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-  where
-    E: serde::de::Error,
-  {
-    use ::pandoc::InputFormat;
-    let format = match v {
-      "native" => InputFormat::Native,
-      "json" => InputFormat::Json,
-      "markdown" => InputFormat::Markdown,
-      "markdown_strict" => InputFormat::MarkdownStrict,
-      "markdown_phpextra" => InputFormat::MarkdownPhpextra,
-      "markdown_github" => InputFormat::MarkdownGithub,
-      "commonmark" => InputFormat::Commonmark,
-      "rst" => InputFormat::Rst,
-      "html" => InputFormat::Html,
-      "latex" => InputFormat::Latex,
-      "mediawiki" => InputFormat::MediaWiki,
-      "textile" => InputFormat::Textile,
-      "org" => InputFormat::Org,
-      "opml" => InputFormat::Opml,
-      "docx" => InputFormat::Docx,
-      "haddock" => InputFormat::Haddock,
-      "epub" => InputFormat::Epub,
-      "docbook" => InputFormat::DocBook,
-      "t2t" => InputFormat::T2t,
-      "twiki" => InputFormat::Twiki,
-      _ => return Err(serde::de::Error::unknown_variant(v, &VALID_FORMATS)),
+    let version = run("--version").and_then(|version| {
+      let first = version.lines().next()?;
+      first.strip_prefix("pandoc").map(|v| v.trim().to_string())
+    });
+
+    let version = match version {
+      Some(version) => version,
+      None => {
+        log::warn!("Couldn't run pandoc, so only Markdown pages will be rendered");
+        return Self::default();
+      },
     };
 
-    Ok(Format(format))
+    let input_formats: HashSet<String> = run("--list-input-formats")
+      .unwrap_or_default()
+      .lines()
+      .map(|format| format.trim().to_string())
+      .filter(|format| !format.is_empty())
+      .collect();
+
+    log::info!(
+      "Using pandoc {}, which can read {} formats",
+      version,
+      input_formats.len()
+    );
+
+    Self {
+      version: Some(version),
+      input_formats,
+    }
+  }
+
+  pub fn is_available(&self) -> bool {
+    self.version.is_some()
   }
+
+  /// Whether pandoc can read `format`. Older versions of pandoc can't list their formats, so
+  /// if there isn't a list, it's assumed they all can be.
+  pub fn can_read(&self, format: &InputFormat) -> bool {
+    self.is_available()
+      && (self.input_formats.is_empty() || self.input_formats.contains(&format.to_string()))
+  }
+}
+
+/// Renders Markdown without pandoc, for when it isn't installed.
+fn markdown_to_html(doc: &str) -> String {
+  use pulldown_cmark::{html, Options, Parser};
+
+  let options = Options::ENABLE_TABLES
+    | Options::ENABLE_FOOTNOTES
+    | Options::ENABLE_STRIKETHROUGH
+    | Options::ENABLE_TASKLISTS
+    | Options::ENABLE_HEADING_ATTRIBUTES;
+
+  let mut buffer = String::new();
+  html::push_html(&mut buffer, Parser::new_ext(doc, options));
+
+  buffer
 }
 
-pub fn test_output() -> Result<(), Error> {
+/// Converts rendered HTML to plain text, using pandoc's `plain` writer.
+pub fn html_to_plain(html: String) -> Result<String, Error> {
   let mut pandoc = Pandoc::new();
 
   pandoc
-    .set_input(InputKind::Pipe(String::from("# Hello, world!")))
+    .set_input_format(::pandoc::InputFormat::Html, Vec::new())
+    .set_input(InputKind::Pipe(html))
     .set_output(OutputKind::Pipe)
-    .set_output_format(OutputFormat::Html5, vec![]);
-
-  let out = pandoc.execute()?;
+    .set_output_format(OutputFormat::Plain, vec![]);
 
-  let actual = match out {
+  let buffer = match pandoc.execute()? {
     PandocOutput::ToBuffer(buffer) => buffer,
     _ => unreachable!(),
   };
 
-  let expected = String::from("<h1 id=\"hello-world\">Hello, world!</h1>\n");
+  Ok(buffer)
+}
+
+fn asciidoc_to_html(doc: String, config: &Config) -> Result<String, Error> {
+  let asciidoc = config.asciidoc.as_ref().ok_or(Error::AsciiDocDisabled)?;
+
+  let mut child = Command::new(&asciidoc.command)
+    .args(&asciidoc.args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(Error::AsciiDoc)?;
+
+  // Write from another thread, so a full stdout pipe can't deadlock us.
+  let mut stdin = child.stdin.take().unwrap();
+  let writer = std::thread::spawn(move || stdin.write_all(doc.as_bytes()));
 
-  if expected != actual {
-    Err(Error::PandocWrongOutput { expected, actual })?;
+  let output = child.wait_with_output().map_err(Error::AsciiDoc)?;
+  writer.join().unwrap().map_err(Error::AsciiDoc)?;
+
+  if !output.status.success() {
+    return Err(Error::AsciiDocFailed {
+      stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+    });
   }
 
-  Ok(())
+  Ok(String::from_utf8_lossy(&output.stdout).to_string())
 }
 
 pub fn to_html(doc: String, format: Option<Format>, state: Arc<State>) -> Result<String, Error> {
+  convert(doc, format, OutputFormat::Html5, state)
+}
+
+/// Renders the page as a slideshow's slides, one for each heading, for [`crate::slides`].
+pub fn to_slides(doc: String, format: Option<Format>, state: Arc<State>) -> Result<String, Error> {
+  convert(doc, format, OutputFormat::Slidy, state)
+}
+
+fn convert(
+  doc: String,
+  format: Option<Format>,
+  output: OutputFormat,
+  state: Arc<State>,
+) -> Result<String, Error> {
+  let (doc, substituted) = crate::site::substitute_with_lines(&doc, &state.config().site_vars);
+  crate::transclude::begin_page();
+  let (doc, shortcodes, expanded) = state.shortcodes.expand(&doc, &state)?;
+  let lines = substituted.then(&expanded);
+
+  // What the converter says about a line is about the page's line it came from.
+  let traced = |source: Error| match source {
+    Error::PandocError(_) | Error::AsciiDocFailed { .. } => Error::Expanded {
+      source: Box::new(source),
+      lines: lines.clone(),
+    },
+    err => err,
+  };
+
+  let is_markdown = match format.as_ref().map(|format| &format.renderer) {
+    Some(Renderer::Pandoc(format)) => {
+      matches!(format, InputFormat::Markdown | InputFormat::Commonmark)
+    },
+    Some(Renderer::AsciiDoc) => false,
+    // Pandoc reads Markdown unless it's told otherwise.
+    None => true,
+  };
+
+  if !state.pandoc.is_available() {
+    return match (is_markdown, output) {
+      (true, OutputFormat::Html5) => Ok(crate::shortcodes::restore(
+        markdown_to_html(&doc),
+        shortcodes,
+      )),
+      _ => Err(Error::Unavailable),
+    };
+  }
+
   let mut pandoc = Pandoc::new();
 
-  if let Some(format) = format {
-    pandoc.set_input_format(format.into(), Vec::new());
+  match format.as_ref().map(|format| &format.renderer) {
+    Some(Renderer::Pandoc(format)) if !state.pandoc.can_read(format) => {
+      return Err(Error::Unsupported(format.to_string()));
+    },
+    Some(Renderer::Pandoc(format)) => {
+      let extensions = match format {
+        InputFormat::Markdown => state
+          .config()
+          .markdown_extensions
+          .iter()
+          .map(|extension| MarkdownExtension::Other(extension.clone()))
+          .collect(),
+        _ => Vec::new(),
+      };
+
+      pandoc.set_input_format(format.clone(), extensions);
+    },
+    Some(Renderer::AsciiDoc) => {
+      if !matches!(output, OutputFormat::Html5) {
+        return Err(Error::AsciiDocSlides);
+      }
+
+      let html = asciidoc_to_html(doc, &state.config()).map_err(traced)?;
+
+      return Ok(crate::shortcodes::restore(html, shortcodes));
+    },
+    None => (),
   }
 
   pandoc
     .set_input(InputKind::Pipe(doc))
     .set_output(OutputKind::Pipe)
-    .set_output_format(OutputFormat::Html5, vec![]);
+    .set_output_format(output, vec![]);
 
   pandoc.add_options(&[PandocOption::Katex(None)]);
 
@@ -220,20 +432,47 @@ pub fn to_html(doc: String, format: Option<Format>, state: Arc<State>) -> Result
     pandoc_ast::filter(json, {
       let state = Arc::clone(&state);
       |mut pandoc| {
+        let config = state.config();
+        InterwikiFilter {
+          prefixes: &config.interwiki,
+        }
+        .walk_pandoc(&mut pandoc);
+
+        SemanticFilter.walk_pandoc(&mut pandoc);
         KatexFilter { state }.walk_pandoc(&mut pandoc);
         pandoc
       }
     })
   });
 
-  let out = pandoc.execute()?;
+  let out = pandoc.execute().map_err(|err| traced(err.into()))?;
 
   let buffer = match out {
     PandocOutput::ToBuffer(buffer) => buffer,
     _ => unreachable!(),
   };
 
-  Ok(buffer)
+  Ok(crate::shortcodes::restore(buffer, shortcodes))
+}
+
+/// Wraps blocks that pandoc doesn't give a class to in one that does, so themes can style them.
+struct SemanticFilter;
+
+impl pandoc_ast::MutVisitor for SemanticFilter {
+  fn visit_block(&mut self, block: &mut pandoc_ast::Block) {
+    self.walk_block(block);
+
+    if let pandoc_ast::Block::DefinitionList(_) = block {
+      let list = std::mem::replace(block, pandoc_ast::Block::Null);
+      let attr = (
+        String::new(),
+        vec![String::from("definition-list")],
+        Vec::new(),
+      );
+
+      *block = pandoc_ast::Block::Div(attr, vec![list]);
+    }
+  }
 }
 
 struct KatexFilter {
@@ -245,7 +484,7 @@ impl pandoc_ast::MutVisitor for KatexFilter {
     if let pandoc_ast::Inline::Math(ty, block) = inline {
       let mut opts = katex::Opts::builder();
       opts.display_mode(*ty == pandoc_ast::MathType::DisplayMath);
-      opts.macros(self.state.config.katex_macros.clone());
+      opts.macros(self.state.config().katex_macros.clone());
       opts.throw_on_error(false);
       let opts = opts.build().unwrap();
 
@@ -259,17 +498,40 @@ impl pandoc_ast::MutVisitor for KatexFilter {
 pub async fn render_handler(
   body: String,
   format: Option<Query<QueryFormat>>,
+  Query(options): Query<RenderOptions>,
+  user: Option<User>,
   Extension(state): Extension<Arc<State>>,
 ) -> Result<Response, crate::page::Error> {
-  let format = format.map(|query| query.0);
+  let config = state.config();
+  let trusted = config.sanitize_html.is_trusted(user.as_ref());
+
+  let format = match format {
+    Some(Query(query)) => query.format.resolve(&body, &config),
+    None => Format::detect(&body, &config),
+  };
+
+  let response = tokio::task::spawn_blocking(move || {
+    let warnings = match options.json {
+      true => warnings(&body, Some(format), &state),
+      false => Vec::new(),
+    };
+
+    let mut rendered = state.render.to_html(body, Some(format), state.clone())?;
+
+    if !trusted {
+      rendered = crate::sanitize::clean(&rendered);
+    }
+
+    if !options.json {
+      return Ok::<_, crate::page::Error>(Html(rendered).into_response());
+    }
 
-  let html = tokio::task::spawn_blocking(move || {
-    let rendered = to_html(body, format.map(|f| f.into()), state)?;
+    let preview = crate::preview::Preview::new(rendered, warnings, user.as_ref(), &state.config());
 
-    Ok::<_, crate::page::Error>(Html(rendered))
+    Ok(Json(preview).into_response())
   })
   .await
   .unwrap()?;
 
-  Ok(html.into_response())
+  Ok(response)
 }
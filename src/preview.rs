@@ -0,0 +1,158 @@
+//! What the editor's preview shows besides the page itself - an outline, the pages it links to,
+//! how long it is, and anything that looks wrong - from `/meta/render?json=true`.
+
+use std::path::Path;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{config::Config, pandoc::Diagnostic, user::User};
+
+#[derive(Serialize, Debug)]
+pub struct Heading {
+  pub level: u8,
+  /// The heading's `id`, to link to it with `#`, if it has one.
+  pub id: Option<String>,
+  pub text: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Preview {
+  pub html: String,
+  pub headings: Vec<Heading>,
+  /// The wiki's own pages that are linked to, in the order they're first linked.
+  pub links: Vec<String>,
+  pub words: usize,
+  /// Things that rendered, but probably aren't what was meant.
+  pub warnings: Vec<Diagnostic>,
+}
+
+/// `html` without its tags, with the common entities turned back into characters.
+fn text_of(html: &str) -> String {
+  let tags = Regex::new(r"<[^>]*>").unwrap();
+
+  tags
+    .replace_all(html, " ")
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&quot;", "\"")
+    .replace("&#39;", "'")
+    .replace("&amp;", "&")
+}
+
+fn headings(html: &str) -> Vec<Heading> {
+  let heading = Regex::new(r"(?is)<h([1-6])([^>]*)>(.*?)</h[1-6]>").unwrap();
+  let id = Regex::new(r#"\bid="([^"]*)""#).unwrap();
+
+  heading
+    .captures_iter(html)
+    .map(|captures| Heading {
+      level: captures[1].parse().unwrap(),
+      id: id.captures(&captures[2]).map(|id| id[1].to_string()),
+      text: text_of(&captures[3])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" "),
+    })
+    .collect()
+}
+
+/// The page path a link points at, if it's to one of the wiki's pages. Links with `..` or the
+/// like in them, even encoded, aren't looked up.
+fn page_link(href: &str) -> Option<String> {
+  if !href.starts_with('/') || href.starts_with("//") || href.starts_with("/meta/") {
+    return None;
+  }
+
+  let path = href.split(['?', '#']).next().unwrap_or(href);
+  let path = path.trim_matches('/');
+
+  // The front page is always there.
+  if path.is_empty() {
+    return None;
+  }
+
+  let path = urlencoding::decode(path).ok()?.to_string();
+
+  crate::page::is_safe_path(Path::new(&path)).then_some(path)
+}
+
+fn links(html: &str) -> Vec<String> {
+  let href = Regex::new(r#"(?i)<a\s[^>]*\bhref="([^"]*)""#).unwrap();
+  let mut links: Vec<String> = Vec::new();
+
+  for link in href
+    .captures_iter(html)
+    .filter_map(|captures| page_link(&captures[1]))
+  {
+    if !links.contains(&link) {
+      links.push(link);
+    }
+  }
+
+  links
+}
+
+fn warning(message: String) -> Diagnostic {
+  Diagnostic {
+    line: None,
+    column: None,
+    message,
+  }
+}
+
+impl Preview {
+  /// The preview of `html`, with any `warnings` from rendering it first.
+  pub fn new(
+    html: String,
+    mut warnings: Vec<Diagnostic>,
+    user: Option<&User>,
+    config: &Config,
+  ) -> Self {
+    let headings = headings(&html);
+    let links = links(&html);
+    let words = text_of(&html).split_whitespace().count();
+
+    let mut previous = 0;
+    for heading in &headings {
+      if previous > 0 && heading.level > previous + 1 {
+        warnings.push(warning(format!(
+          "The heading \"{}\" skips from level {} to level {}",
+          heading.text, previous, heading.level
+        )));
+      }
+      previous = heading.level;
+    }
+
+    let mut ids: Vec<&str> = Vec::new();
+    for id in headings.iter().filter_map(|heading| heading.id.as_deref()) {
+      if ids.contains(&id) {
+        warnings.push(warning(format!(
+          "More than one heading has the id \"{}\", so links to it go to the first",
+          id
+        )));
+      }
+      ids.push(id);
+    }
+
+    // Pages the user can't see look missing too, so the warnings don't give them away. It's
+    // the page the link finds that's checked, which might not be spelled the same.
+    for link in &links {
+      let path = Path::new(link);
+      let exists = crate::page::find_file(path, config).is_ok()
+        && crate::namespace::is_visible(&crate::acl::resolve(path, config), user, config);
+
+      if !exists {
+        warnings.push(warning(format!("The page \"/{}\" doesn't exist", link)));
+      }
+    }
+
+    Self {
+      html,
+      headings,
+      links,
+      words,
+      warnings,
+    }
+  }
+}
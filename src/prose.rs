@@ -0,0 +1,312 @@
+//! A report of spelling mistakes and style problems in every page, at `/meta/reports/prose`.
+//! Spelling is checked by `hunspell`, with the dictionaries in the config, and style by regular
+//! expressions, like Vale's `existence` rules.
+
+use std::{
+  collections::{HashMap, HashSet},
+  process::Stdio,
+  sync::{Arc, Mutex},
+};
+
+use axum::{
+  http::StatusCode,
+  response::{Html, IntoResponse, Response},
+  Extension,
+};
+use git2::{ObjectType, Oid};
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+  front_matter::FrontMatter,
+  page::Page,
+  role::{Is, Role},
+  template::Template,
+  State,
+};
+
+/// What was found in each version of a page, by its blob's ID - so only pages that have
+/// changed are checked again - along with a fingerprint of the settings they were checked with.
+static CHECKED: Lazy<Mutex<(String, HashMap<Oid, Vec<Issue>>)>> = Lazy::new(Mutex::default);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  Git(#[from] crate::git::Error),
+  #[error("Couldn't run hunspell: {0}")]
+  Hunspell(std::io::Error),
+  #[error("The rule '{0}' isn't a valid regular expression: {1}")]
+  Rule(String, regex::Error),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Page(err) => err.into_response(),
+      Self::Git(err) => err.into_response(),
+      Self::Hunspell(_) | Self::Rule(..) => {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+      },
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Prose {
+  /// Hunspell dictionaries to check spelling with, like `en_US`. Spelling isn't checked if there
+  /// aren't any.
+  pub dictionaries: Vec<String>,
+  /// Words that are spelled right, even though they aren't in the dictionaries.
+  pub words: Vec<String>,
+  pub rules: Vec<Rule>,
+}
+
+/// Text that shouldn't be in pages, and why.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+  /// A regular expression, matched case-insensitively against each line.
+  pub pattern: String,
+  pub message: String,
+}
+
+#[derive(Clone)]
+struct Issue {
+  line: Option<usize>,
+  message: String,
+}
+
+/// The lines of a page's source that are prose - without front matter, code, or links' URLs -
+/// with their line numbers.
+fn prose_lines(file: &str) -> Vec<(usize, String)> {
+  let (_, data, _) = FrontMatter::split(file);
+  // The front matter's lines are still counted, so line numbers match the editor.
+  let offset = file.lines().count().saturating_sub(data.lines().count());
+
+  let mut lines = Vec::new();
+  let mut in_code = false;
+
+  for (number, line) in data.lines().enumerate() {
+    if line.trim_start().starts_with("```") || line.trim_start().starts_with("~~~") {
+      in_code = !in_code;
+      continue;
+    }
+
+    if in_code || line.starts_with("    ") || line.starts_with('\t') {
+      continue;
+    }
+
+    let text: String = line
+      .split('`')
+      .step_by(2)
+      .collect::<Vec<_>>()
+      .join(" ")
+      .split_whitespace()
+      .filter(|word| !word.contains("://") && !word.contains("](") && !word.starts_with('<'))
+      .collect::<Vec<_>>()
+      .join(" ");
+
+    lines.push((offset + number + 1, text));
+  }
+
+  lines
+}
+
+/// The words hunspell doesn't know, in the order they're first used.
+async fn misspelled(text: &str, prose: &Prose) -> Result<Vec<String>, Error> {
+  let mut child = tokio::process::Command::new("hunspell")
+    .arg("-l")
+    .arg("-d")
+    .arg(prose.dictionaries.join(","))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .map_err(Error::Hunspell)?;
+
+  let mut stdin = child.stdin.take().unwrap();
+  let text = text.to_string();
+  // Written from another task, so a full stdout pipe can't deadlock us.
+  let writer = tokio::spawn(async move { stdin.write_all(text.as_bytes()).await });
+
+  let output = child.wait_with_output().await.map_err(Error::Hunspell)?;
+  writer.await.unwrap().map_err(Error::Hunspell)?;
+
+  let mut words: Vec<String> = Vec::new();
+  for word in String::from_utf8_lossy(&output.stdout).lines() {
+    let known = prose
+      .words
+      .iter()
+      .any(|known| known.eq_ignore_ascii_case(word));
+
+    if !known && !words.iter().any(|seen| seen == word) {
+      words.push(word.to_string());
+    }
+  }
+
+  Ok(words)
+}
+
+async fn check(file: &str, rules: &[(Regex, &str)], prose: &Prose) -> Result<Vec<Issue>, Error> {
+  let lines = prose_lines(file);
+  let mut issues = Vec::new();
+
+  for (number, line) in &lines {
+    for (pattern, message) in rules {
+      if let Some(found) = pattern.find(line) {
+        issues.push(Issue {
+          line: Some(*number),
+          message: format!("\"{}\" - {}", found.as_str(), message),
+        });
+      }
+    }
+  }
+
+  if !prose.dictionaries.is_empty() {
+    let text = lines
+      .iter()
+      .map(|(_, line)| line.as_str())
+      .collect::<Vec<_>>()
+      .join("\n");
+
+    let words = misspelled(&text, prose).await?;
+    if !words.is_empty() {
+      issues.push(Issue {
+        line: None,
+        message: format!("Possibly misspelled: {}", words.join(", ")),
+      });
+    }
+  }
+
+  Ok(issues)
+}
+
+/// The issues in `file`, from the last time this version of it was checked if it has been.
+async fn check_cached(
+  oid: Oid,
+  file: &str,
+  rules: &[(Regex, &str)],
+  prose: &Prose,
+  settings: &str,
+) -> Result<Vec<Issue>, Error> {
+  {
+    let mut checked = CHECKED.lock().unwrap();
+
+    // The rules or dictionaries have changed, so everything has to be checked again.
+    if checked.0 != settings {
+      *checked = (settings.to_string(), HashMap::new());
+    }
+
+    if let Some(issues) = checked.1.get(&oid) {
+      return Ok(issues.clone());
+    }
+  }
+
+  let issues = check(file, rules, prose).await?;
+  CHECKED.lock().unwrap().1.insert(oid, issues.clone());
+
+  Ok(issues)
+}
+
+pub async fn handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, Error> {
+  let config = state.config();
+
+  let content = match &config.prose {
+    None => maud::html! {
+      p { "Prose isn't being checked - set " code { "prose" } " in the config to start." }
+    },
+    Some(prose) => {
+      let rules = prose
+        .rules
+        .iter()
+        .map(|rule| {
+          let pattern = RegexBuilder::new(&rule.pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|err| Error::Rule(rule.pattern.clone(), err))?;
+
+          Ok((pattern, rule.message.as_str()))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+      let settings = crate::assets::fingerprint(&serde_json::to_vec(prose).unwrap_or_default());
+      let mut pages = Vec::new();
+      let mut seen = HashSet::new();
+
+      for page in Page::all(&config) {
+        // Tables, notebooks and binary files aren't prose.
+        let is_notebook = page.filepath.extension() == Some(std::ffi::OsStr::new("ipynb"));
+        if crate::table::delimiter(&page.filepath).is_some() || is_notebook || page.is_binary() {
+          continue;
+        }
+
+        if !crate::namespace::is_visible(&page.path, Some(&admin), &config) {
+          continue;
+        }
+
+        let path = page
+          .relative_path(&config)?
+          .with_extension("")
+          .to_string_lossy()
+          .to_string();
+
+        let file = page.raw(&state).await?;
+        let oid =
+          Oid::hash_object(ObjectType::Blob, file.as_bytes()).map_err(crate::git::Error::Git)?;
+        let issues = check_cached(oid, &file, &rules, prose, &settings).await?;
+        seen.insert(oid);
+
+        if !issues.is_empty() {
+          pages.push((path, issues));
+        }
+      }
+
+      pages.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+      // Old versions of pages won't be asked about again.
+      CHECKED
+        .lock()
+        .unwrap()
+        .1
+        .retain(|oid, _| seen.contains(oid));
+
+      maud::html! {
+        @if pages.is_empty() {
+          "No problems found!"
+        } @else {
+          @for (path, issues) in &pages {
+            h3 {
+              a href={ "/" (path) } { (path) }
+              " "
+              a href={ "/meta/edit/" (path) } { "(edit)" }
+            }
+            ul .prose-issues {
+              @for issue in issues {
+                li {
+                  @if let Some(line) = issue.line {
+                    "Line " (line) ": "
+                  }
+                  (issue.message)
+                }
+              }
+            }
+          }
+        }
+      }
+    },
+  };
+
+  let html = Template::new()
+    .title("Prose")
+    .content(content)
+    .render(Some(admin), &state);
+
+  Ok(html)
+}
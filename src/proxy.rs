@@ -0,0 +1,184 @@
+//! Working out how the wiki was reached when it's behind a reverse proxy, like nginx or
+//! Traefik, from the `X-Forwarded-*` headers the proxy adds.
+
+use std::{
+  convert::Infallible,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+};
+
+use axum::{
+  async_trait,
+  extract::{ConnectInfo, FromRequest, RequestParts},
+  http::{header, HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+  Extension,
+};
+use oauth2::url::Url;
+
+use crate::{config::Config, error::Problem, State};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("The request didn't come from one of the wiki's own pages")]
+  CrossOrigin,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    Problem::new(StatusCode::FORBIDDEN, "cross-origin", self).into_response()
+  }
+}
+
+/// What the proxy said about the request. Everything is `None` unless
+/// `trust_forwarded_headers` is on, as anyone can send these headers - except `client`, which
+/// falls back to the address the connection came from.
+pub struct Forwarded {
+  /// `http` or `https`.
+  pub proto: Option<String>,
+  pub host: Option<String>,
+  /// Who made the request.
+  pub client: Option<IpAddr>,
+}
+
+impl Forwarded {
+  pub fn from_headers(headers: &HeaderMap, config: &Config) -> Self {
+    if !config.trust_forwarded_headers {
+      return Self {
+        proto: None,
+        host: None,
+        client: None,
+      };
+    }
+
+    // With several proxies, the first value is the one closest to the client.
+    let first = |name: &str| {
+      headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+    };
+
+    // The client can send its own `X-Forwarded-For`, which each proxy adds to the end of, so
+    // the client is the last address that isn't one of the proxies.
+    let client = headers
+      .get_all("x-forwarded-for")
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .map(|value| value.trim().parse::<IpAddr>())
+      .rev()
+      .find(|address| match address {
+        Ok(address) => !config.trusted_proxies.contains(address),
+        Err(_) => true,
+      })
+      .and_then(Result::ok);
+
+    let host = first("x-forwarded-host").or_else(|| {
+      headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+    });
+
+    Self {
+      proto: first("x-forwarded-proto").map(|proto| proto.to_lowercase()),
+      host,
+      client,
+    }
+  }
+
+  /// Whether the browser reached the wiki over HTTPS, so cookies should be `Secure`.
+  pub fn is_secure(&self, config: &Config) -> bool {
+    match &self.proto {
+      Some(proto) => proto == "https",
+      None => config.base_url().starts_with("https://"),
+    }
+  }
+
+  /// Where the wiki was reached, without a trailing slash. `base_url` always wins, and
+  /// otherwise it comes from the proxy's headers, if there are any.
+  pub fn base_url(&self, config: &Config) -> String {
+    match (&config.base_url, &self.proto, &self.host) {
+      (None, Some(proto), Some(host)) => format!("{}://{}", proto, host),
+      _ => config.base_url().to_string(),
+    }
+  }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Forwarded
+where
+  B: Send,
+{
+  type Rejection = Infallible;
+
+  async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+    let Extension(state) = Extension::<Arc<State>>::from_request(req)
+      .await
+      .expect("`State` extension missing");
+
+    let config = state.config();
+    let peer = req
+      .extensions()
+      .get::<ConnectInfo<SocketAddr>>()
+      .map(|ConnectInfo(address)| address.ip());
+
+    // Anyone else could have sent the headers themselves.
+    let from_proxy = match peer {
+      Some(peer) if !config.trusted_proxies.is_empty() => config.trusted_proxies.contains(&peer),
+      _ => true,
+    };
+
+    let mut forwarded = match from_proxy {
+      true => Self::from_headers(req.headers(), &config),
+      false => Self {
+        proto: None,
+        host: None,
+        client: None,
+      },
+    };
+
+    // Without a proxy, the client is whoever's on the other end of the connection.
+    if forwarded.client.is_none() {
+      forwarded.client = peer;
+    }
+
+    Ok(forwarded)
+  }
+}
+
+/// A request that came from one of the wiki's own pages, going by its `Origin`, or its
+/// `Referer` if the browser didn't send one. Forms that change something important ask for
+/// this, so another site can't get someone's browser to send them.
+pub struct SameOrigin;
+
+#[async_trait]
+impl<B> FromRequest<B> for SameOrigin
+where
+  B: Send,
+{
+  type Rejection = Error;
+
+  async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+    let Extension(state) = Extension::<Arc<State>>::from_request(req)
+      .await
+      .expect("`State` extension missing");
+
+    let forwarded = Forwarded::from_request(req).await.unwrap();
+    let base_url = Url::parse(&forwarded.base_url(&state.config())).ok();
+
+    let from = [header::ORIGIN, header::REFERER]
+      .iter()
+      .find_map(|name| req.headers().get(name))
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| Url::parse(value).ok());
+
+    match (from, base_url) {
+      (Some(from), Some(base_url)) if from.origin() == base_url.origin() => Ok(Self),
+      _ => Err(Error::CrossOrigin),
+    }
+  }
+}
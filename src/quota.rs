@@ -0,0 +1,208 @@
+//! Limits on how much people can change, so a wiki that's open to newcomers can't be flooded.
+//! Each role has its own limits on edits an hour, new pages a day and uploaded bytes a day, and
+//! so do guests, accounts that haven't been approved, and everyone else. What people have done
+//! is only kept in memory.
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Duration};
+
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{error::ErrorPage, role::Role, user::User};
+
+const HOUR: time::Duration = time::Duration::HOUR;
+const DAY: time::Duration = time::Duration::DAY;
+
+#[derive(Debug, thiserror::Error)]
+#[error("You've reached your limit of {limit} - try again in {} minutes", .wait.as_secs() / 60 + 1)]
+pub struct Error {
+  limit: String,
+  wait: Duration,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    ErrorPage::QuotaExceeded {
+      limit: self.limit,
+      minutes: self.wait.as_secs() / 60 + 1,
+    }
+    .into_response()
+  }
+}
+
+/// How much someone can do. Anything that's `None` has no limit.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Quota {
+  /// Edits and new pages in the last hour.
+  pub edits_per_hour: Option<usize>,
+  /// New pages in the last day.
+  pub new_pages_per_day: Option<usize>,
+  /// Bytes of files uploaded in the last day.
+  pub upload_bytes_per_day: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Quotas {
+  /// People who aren't logged in, counted by their address.
+  pub guests: Quota,
+  /// Accounts that haven't been approved yet.
+  pub unapproved: Quota,
+  /// Approved accounts without a role.
+  pub members: Quota,
+  /// By role, like `Administrator`. Anyone with a role that isn't here has no limits.
+  pub roles: HashMap<Role, Quota>,
+}
+
+impl Quotas {
+  /// The limits for `user`, or for a guest if there isn't one.
+  pub fn quota(&self, user: Option<&User>) -> Option<&Quota> {
+    let user = match user {
+      Some(user) => user,
+      None => return Some(&self.guests),
+    };
+
+    if !user.approved {
+      return Some(&self.unapproved);
+    }
+
+    if user.roles.is_empty() {
+      return Some(&self.members);
+    }
+
+    user.roles.iter().find_map(|role| self.roles.get(role))
+  }
+}
+
+/// Who's counted - an account, or for guests, where they came from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Who {
+  Account(String),
+  Address(IpAddr),
+  /// Guests whose address isn't known, like over a Unix socket without a proxy's headers, who
+  /// all share one count.
+  Unknown,
+}
+
+impl Who {
+  pub fn user(user: &User) -> Self {
+    Self::Account(user.email.clone())
+  }
+
+  /// A guest, by where they came from.
+  pub fn guest(address: Option<IpAddr>) -> Self {
+    address.map(Self::Address).unwrap_or(Self::Unknown)
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Activity {
+  Edit,
+  /// Counts as an edit, too.
+  NewPage,
+  /// This many bytes.
+  Upload(u64),
+}
+
+#[derive(Default)]
+pub struct Usage {
+  done: Mutex<HashMap<Who, Vec<(OffsetDateTime, Activity)>>>,
+}
+
+impl Usage {
+  /// Counts `activity` against `who`, unless it would go over `quota`. It's counted as soon as
+  /// it's allowed, so requests made at the same time can't all squeeze in under the limit.
+  pub fn take(&self, who: Who, activity: Activity, quota: Option<&Quota>) -> Result<(), Error> {
+    let now = OffsetDateTime::now_utc();
+    let mut done = self.done.lock().unwrap();
+
+    done.retain(|_, activities| {
+      activities.retain(|(date, _)| now - *date < DAY);
+      !activities.is_empty()
+    });
+
+    if let Some(quota) = quota {
+      Self::check(&done, &who, activity, quota, now)?;
+    }
+
+    done.entry(who).or_default().push((now, activity));
+
+    Ok(())
+  }
+
+  fn check(
+    done: &HashMap<Who, Vec<(OffsetDateTime, Activity)>>,
+    who: &Who,
+    activity: Activity,
+    quota: &Quota,
+    now: OffsetDateTime,
+  ) -> Result<(), Error> {
+    let activities = match done.get(who) {
+      Some(activities) => activities.as_slice(),
+      None => &[],
+    };
+
+    // How long until the first of `matching` is forgotten about, once it's `period` old.
+    let wait = |period: time::Duration, matching: &dyn Fn(&Activity) -> bool| {
+      let oldest = activities
+        .iter()
+        .filter(|(date, activity)| now - *date < period && matching(activity))
+        .map(|(date, _)| *date)
+        .min()
+        .unwrap_or(now);
+
+      Duration::try_from(oldest + period - now).unwrap_or_default()
+    };
+
+    let is_edit = |activity: &Activity| matches!(activity, Activity::Edit | Activity::NewPage);
+    let is_new_page = |activity: &Activity| matches!(activity, Activity::NewPage);
+
+    let count = |period: time::Duration, matching: &dyn Fn(&Activity) -> bool| {
+      activities
+        .iter()
+        .filter(|(date, activity)| now - *date < period && matching(activity))
+        .count()
+    };
+
+    if let (true, Some(limit)) = (is_edit(&activity), quota.edits_per_hour) {
+      if count(HOUR, &is_edit) >= limit {
+        return Err(Error {
+          limit: format!("{} edits an hour", limit),
+          wait: wait(HOUR, &is_edit),
+        });
+      }
+    }
+
+    if let (true, Some(limit)) = (is_new_page(&activity), quota.new_pages_per_day) {
+      if count(DAY, &is_new_page) >= limit {
+        return Err(Error {
+          limit: format!("{} new pages a day", limit),
+          wait: wait(DAY, &is_new_page),
+        });
+      }
+    }
+
+    if let (Activity::Upload(bytes), Some(limit)) = (activity, quota.upload_bytes_per_day) {
+      let uploaded: u64 = activities
+        .iter()
+        .filter_map(|(_, activity)| match activity {
+          Activity::Upload(bytes) => Some(*bytes),
+          _ => None,
+        })
+        .sum();
+
+      if uploaded + bytes > limit {
+        return Err(Error {
+          limit: format!("{} uploaded a day", crate::media::human_size(limit)),
+          wait: wait(DAY, &|activity: &Activity| {
+            matches!(activity, Activity::Upload(_))
+          }),
+        });
+      }
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,103 @@
+use std::{
+  convert::Infallible,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use axum::{
+  response::sse::{Event, KeepAlive, Sse},
+  Extension,
+  Json,
+};
+use git2::Repository;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::{config::Config, git::Error, user::User, State};
+
+/// How many changes are kept around for the sidebar widget.
+pub const RECENT_CHANGES: usize = 5;
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct RecentChange {
+  pub hash: String,
+  pub author: String,
+  pub message: String,
+  /// Seconds since the Unix epoch.
+  pub timestamp: i64,
+  /// Relative to the pages, and only the ones in `subdir`.
+  pub files: Vec<PathBuf>,
+}
+
+impl RecentChange {
+  pub fn from_commit(
+    commit: &git2::Commit,
+    repository: &Repository,
+    subdir: &Path,
+  ) -> Result<Self, Error> {
+    let tree = commit.tree()?;
+    let parent_tree = match commit.parent_count() {
+      0 => None,
+      _ => Some(commit.parent(0)?.tree()?),
+    };
+
+    let diff = repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+
+    let files = diff
+      .deltas()
+      .filter_map(|delta| delta.new_file().path()?.strip_prefix(subdir).ok())
+      .map(Path::to_path_buf)
+      .collect();
+
+    Ok(Self {
+      hash: commit.id().to_string(),
+      author: commit.author().name().unwrap_or("Unknown").to_string(),
+      message: commit.summary().unwrap_or("").to_string(),
+      timestamp: commit.time().seconds(),
+      files,
+    })
+  }
+
+  /// Whether `user` can see every file the change touched - its message could give any of them
+  /// away.
+  pub fn is_visible_to(&self, user: Option<&User>, config: &Config) -> bool {
+    self
+      .files
+      .iter()
+      .all(|file| crate::namespace::is_visible(file, user, config))
+  }
+}
+
+pub async fn handler(
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Json<Vec<RecentChange>> {
+  let config = state.config();
+
+  let changes = state
+    .git
+    .recent_changes()
+    .into_iter()
+    .filter(|change| change.is_visible_to(user.as_ref(), &config))
+    .collect();
+
+  Json(changes)
+}
+
+pub async fn events_handler(
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+  let stream = BroadcastStream::new(state.git.subscribe()).filter_map(move |change| {
+    // Lagging behind just means we've missed some changes, which is fine for a widget.
+    let change = change.ok()?;
+    if !change.is_visible_to(user.as_ref(), &state.config()) {
+      return None;
+    }
+
+    let event = Event::default().event("commit").json_data(change).ok()?;
+
+    Some(Ok(event))
+  });
+
+  Sse::new(stream).keep_alive(KeepAlive::default())
+}
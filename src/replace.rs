@@ -0,0 +1,344 @@
+//! Search and replace across every page's source, for administrators - matches are previewed
+//! first, then every changed page is saved in a single commit.
+
+use std::sync::Arc;
+
+use axum::{
+  extract::{Form, Query},
+  http::StatusCode,
+  response::{Html, IntoResponse, Redirect, Response},
+  Extension,
+};
+use maud::{html, Markup};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use crate::{
+  audit::Action,
+  commit_message::{Kind, Vars},
+  page::Page,
+  proxy::SameOrigin,
+  role::{Is, Role},
+  template::Template,
+  State,
+};
+
+/// How many matching lines the preview shows, so a very common term doesn't make a huge page.
+const PREVIEW_LINES: usize = 500;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  CommitMessage(#[from] crate::commit_message::Error),
+  #[error("The search isn't a valid regular expression: {0}")]
+  Regex(#[from] regex::Error),
+  #[error("There's nothing to search for")]
+  Empty,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Page(err) => err.into_response(),
+      Self::CommitMessage(err) => err.into_response(),
+      Self::Regex(_) | Self::Empty => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+    }
+  }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Search {
+  find: String,
+  replace: String,
+  /// Whether `find` is a regular expression, rather than literal text. `$1` and the like in
+  /// `replace` are only expanded when it is.
+  regex: bool,
+  case_sensitive: bool,
+  /// The commit's summary - what's being replaced, if it's left empty.
+  summary: String,
+}
+
+impl Search {
+  fn pattern(&self) -> Result<Regex, Error> {
+    if self.find.is_empty() {
+      return Err(Error::Empty);
+    }
+
+    let pattern = match self.regex {
+      true => self.find.clone(),
+      false => regex::escape(&self.find),
+    };
+
+    let regex = RegexBuilder::new(&pattern)
+      .case_insensitive(!self.case_sensitive)
+      .multi_line(true)
+      .build()?;
+
+    Ok(regex)
+  }
+
+  fn replace(&self, regex: &Regex, text: &str) -> String {
+    match self.regex {
+      true => regex.replace_all(text, self.replace.as_str()).into_owned(),
+      false => regex
+        .replace_all(text, regex::NoExpand(&self.replace))
+        .into_owned(),
+    }
+  }
+}
+
+/// A page with matches, and what it'll be once they're replaced.
+struct Changed {
+  page: Page,
+  path: String,
+  before: String,
+  after: String,
+}
+
+async fn changed_pages(
+  search: &Search,
+  regex: &Regex,
+  state: &State,
+) -> Result<Vec<Changed>, Error> {
+  let config = state.config();
+  let mut changed = Vec::new();
+
+  for page in Page::all(&config) {
+    // Binary pages, like spreadsheets, don't have source to search.
+    if page.is_binary() {
+      continue;
+    }
+
+    let before = page.raw(state).await?;
+
+    if !regex.is_match(&before) {
+      continue;
+    }
+
+    let after = search.replace(regex, &before);
+    let path = page
+      .relative_path(&config)?
+      .with_extension("")
+      .to_string_lossy()
+      .to_string();
+
+    changed.push(Changed {
+      page,
+      path,
+      before,
+      after,
+    });
+  }
+
+  changed.sort_by(|a, b| a.path.cmp(&b.path));
+
+  Ok(changed)
+}
+
+/// The lines in `text` with matches on them, and the number of the first of each - a match
+/// that spans several lines has them all together, as do matches next to each other.
+fn matching_lines<'a>(text: &'a str, regex: &Regex) -> Vec<(usize, &'a str)> {
+  let mut spans: Vec<(usize, usize)> = Vec::new();
+
+  for found in regex.find_iter(text) {
+    let start = text[..found.start()].rfind('\n').map_or(0, |i| i + 1);
+    let end = text[found.end()..]
+      .find('\n')
+      .map_or(text.len(), |i| found.end() + i);
+
+    match spans.last_mut() {
+      Some((_, last)) if start <= *last => *last = end.max(*last),
+      _ => spans.push((start, end)),
+    }
+  }
+
+  let mut number = 0;
+  let mut counted = 0;
+
+  spans
+    .into_iter()
+    .map(|(start, end)| {
+      number += text[counted..start].matches('\n').count();
+      counted = start;
+
+      (number, &text[start..end])
+    })
+    .collect()
+}
+
+/// `line` with each match marked.
+fn highlight(line: &str, regex: &Regex) -> Markup {
+  let mut parts = Vec::new();
+  let mut last = 0;
+
+  for found in regex.find_iter(line) {
+    parts.push((&line[last..found.start()], false));
+    parts.push((found.as_str(), true));
+    last = found.end();
+  }
+
+  parts.push((&line[last..], false));
+
+  html! {
+    @for (text, found) in parts {
+      @if found { mark { (text) } } @else { (text) }
+    }
+  }
+}
+
+fn preview(search: &Search, regex: &Regex, changed: &[Changed]) -> Markup {
+  let mut remaining = PREVIEW_LINES;
+
+  // The matching lines in each page, with their line numbers.
+  let lines: Vec<Vec<(usize, &str)>> = changed
+    .iter()
+    .map(|page| {
+      let lines: Vec<_> = matching_lines(&page.before, regex)
+        .into_iter()
+        .take(remaining)
+        .collect();
+
+      remaining -= lines.len();
+
+      lines
+    })
+    .collect();
+
+  html! {
+    @if changed.is_empty() {
+      p { "No pages match." }
+    } @else {
+      p { (changed.len()) " pages match." }
+
+      form method="post" action="/meta/admin/replace" {
+        input type="hidden" name="find" value=(search.find);
+        input type="hidden" name="replace" value=(search.replace);
+        @if search.regex {
+          input type="hidden" name="regex" value="true";
+        }
+        @if search.case_sensitive {
+          input type="hidden" name="case_sensitive" value="true";
+        }
+        input type="text" name="summary" placeholder="Summary";
+        button type="submit" { "Replace in " (changed.len()) " pages" }
+      }
+
+      @for (page, lines) in changed.iter().zip(&lines) {
+        h3 { a href={ "/" (page.path) } { (page.path) } }
+        @if !lines.is_empty() {
+          table .replace-preview {
+            @for (number, line) in lines {
+              tr {
+                td .line-number { (number + 1) }
+                td { del { (highlight(line, regex)) } br; ins { (search.replace(regex, line)) } }
+              }
+            }
+          }
+        }
+      }
+
+      @if remaining == 0 {
+        p { "Only the first " (PREVIEW_LINES) " matching lines are shown." }
+      }
+    }
+  }
+}
+
+pub async fn handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Query(search): Query<Search>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, Error> {
+  let results = match search.find.is_empty() {
+    true => None,
+    false => {
+      let regex = search.pattern()?;
+      let changed = changed_pages(&search, &regex, &state).await?;
+
+      Some(preview(&search, &regex, &changed))
+    },
+  };
+
+  let content = html! {
+    form #replace method="get" action="/meta/admin/replace" {
+      label { "Find " input type="text" name="find" value=(search.find) required; }
+      label { "Replace with " input type="text" name="replace" value=(search.replace); }
+      label { input type="checkbox" name="regex" value="true" checked[search.regex]; " Regular expression" }
+      label {
+        input type="checkbox" name="case_sensitive" value="true" checked[search.case_sensitive];
+        " Match case"
+      }
+      button type="submit" { "Preview" }
+    }
+
+    @if let Some(results) = results {
+      (results)
+    }
+  };
+
+  let html = Template::new()
+    .title("Search and replace")
+    .content(content)
+    .render(Some(admin), &state);
+
+  Ok(html)
+}
+
+/// Replaces every match, committing every changed page at once.
+pub async fn apply_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+  Form(search): Form<Search>,
+) -> Result<Redirect, Error> {
+  let regex = search.pattern()?;
+  let changed = changed_pages(&search, &regex, &state).await?;
+
+  if changed.is_empty() {
+    return Ok(Redirect::to("/meta/admin/replace"));
+  }
+
+  let description = format!(
+    "Replace \"{}\" with \"{}\" in {} pages",
+    search.find,
+    search.replace,
+    changed.len()
+  );
+
+  let paths: Vec<_> = changed
+    .iter()
+    .map(|changed| changed.path.as_str())
+    .collect();
+  let paths = paths.join(", ");
+
+  let summary = match search.summary.trim() {
+    "" => description.as_str(),
+    summary => summary,
+  };
+
+  let message = state.config().commit_messages.render(
+    Kind::Update,
+    Vars {
+      path: &paths,
+      title: &paths,
+      summary: Some(summary),
+    },
+  )?;
+
+  let changes: Vec<_> = changed
+    .into_iter()
+    .map(|changed| (changed.page, changed.after))
+    .collect();
+
+  Page::update_all(&changes, &message, &admin, state.clone()).await?;
+
+  state
+    .audit
+    .record(&admin.email, Action::Replace, "pages", Some(description))
+    .await;
+
+  Ok(Redirect::to("/meta/admin/replace"))
+}
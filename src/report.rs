@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{response::Html, Extension};
+
+use crate::{page::Page, template::Template, user::User, State};
+
+/// Something wrong with a page that an editor should fix.
+pub enum Issue {
+  BrokenFrontMatter {
+    error: String,
+  },
+  /// The page redirects to another redirect, which is one hop more than it needs.
+  RedirectChain {
+    chain: Vec<String>,
+  },
+  /// Following the page's redirects ends up back where they started.
+  RedirectLoop {
+    chain: Vec<String>,
+  },
+}
+
+/// The page path that a local `redirect_to` points at, in the same form as the doctor's paths.
+fn redirect_target(target: &str) -> Option<String> {
+  if !target.starts_with('/') || target.starts_with("//") {
+    return None;
+  }
+
+  let target = target.split(['?', '#']).next().unwrap_or(target);
+  let target = std::path::Path::new(target.trim_start_matches('/')).with_extension("");
+
+  Some(target.to_string_lossy().to_string())
+}
+
+fn redirect_issues(redirects: &BTreeMap<String, String>) -> Vec<PageIssue> {
+  let mut issues = Vec::new();
+
+  for (path, target) in redirects {
+    let mut chain = vec![path.clone(), target.clone()];
+    let mut looped = false;
+
+    while let Some(next) = redirects.get(chain.last().unwrap()) {
+      looped = chain.contains(next);
+      chain.push(next.clone());
+
+      if looped {
+        break;
+      }
+    }
+
+    let issue = if looped {
+      Issue::RedirectLoop { chain }
+    } else if chain.len() > 2 {
+      Issue::RedirectChain { chain }
+    } else {
+      continue;
+    };
+
+    issues.push(PageIssue {
+      path: path.clone(),
+      issue,
+    });
+  }
+
+  issues
+}
+
+pub struct PageIssue {
+  pub path: String,
+  pub issue: Issue,
+}
+
+pub async fn doctor(state: &State) -> Result<Vec<PageIssue>, crate::page::Error> {
+  let mut issues = Vec::new();
+  let mut redirects = BTreeMap::new();
+
+  for page in Page::all(&state.config()) {
+    let path = page
+      .relative_path(&state.config())?
+      .with_extension("")
+      .to_string_lossy()
+      .to_string();
+
+    let file = page.raw(state).await?;
+    let (front_matter, _, error) = page.split_front_matter(&file);
+
+    if let Some(target) = front_matter
+      .redirect_to
+      .as_deref()
+      .and_then(redirect_target)
+    {
+      redirects.insert(path.clone(), target);
+    }
+
+    if let Some(error) = error {
+      issues.push(PageIssue {
+        path,
+        issue: Issue::BrokenFrontMatter {
+          error: error.to_string(),
+        },
+      });
+    }
+  }
+
+  issues.extend(redirect_issues(&redirects));
+
+  Ok(issues)
+}
+
+pub async fn doctor_handler(
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, crate::page::Error> {
+  let issues = doctor(&state).await?;
+
+  let content = maud::html! {
+    @if issues.is_empty() {
+      "No problems found!"
+    } @else {
+      ul #issues {
+        @for PageIssue { path, issue } in &issues {
+          li {
+            a href={ "/" (path) } { (path) }
+            @match issue {
+              Issue::BrokenFrontMatter { error } => {
+                " has front matter that can't be read "
+                a href={ "/meta/edit/" (path) } { "(fix)" }
+                pre { (error) }
+              },
+              Issue::RedirectChain { chain } => {
+                " redirects through another redirect: "
+                (chain.join(" → "))
+                " "
+                a href={ "/meta/edit/" (path) } { "(fix)" }
+              },
+              Issue::RedirectLoop { chain } => {
+                " redirects in a loop: "
+                (chain.join(" → "))
+                " "
+                a href={ "/meta/edit/" (path) } { "(fix)" }
+              },
+            }
+          }
+        }
+      }
+    }
+  };
+
+  let html = Template::new()
+    .title("Doctor")
+    .content(content)
+    .render(user, &state);
+
+  Ok(html)
+}
@@ -1,18 +1,31 @@
 use axum::{
   async_trait,
   extract::{FromRequest, RequestParts},
-  http::StatusCode,
   response::IntoResponse,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{auth::UserExtractError, user::User};
+use crate::{auth::UserExtractError, error::ErrorPage, user::User};
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub enum Role {
   Administrator,
 }
 
+impl Role {
+  pub const ALL: [Role; 1] = [Role::Administrator];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Role::Administrator => "Administrator",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|role| role.name() == name)
+  }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
   #[error(transparent)]
@@ -23,16 +36,17 @@ pub enum Error {
 
 impl IntoResponse for Error {
   fn into_response(self) -> axum::response::Response {
-    let code = match self {
-      Self::Unauthorised(_) => StatusCode::UNAUTHORIZED,
-      _ => StatusCode::INTERNAL_SERVER_ERROR,
-    };
-
-    (code, self.to_string()).into_response()
+    match self {
+      Self::UserExtract(err) => err.into_response(),
+      Self::Unauthorised(role) => ErrorPage::Unauthorized {
+        role: Some(format!("{:?}", role)),
+      }
+      .into_response(),
+    }
   }
 }
 
-pub struct Is<const ROLE: Role>(User);
+pub struct Is<const ROLE: Role>(pub User);
 
 #[async_trait]
 impl<const ROLE: Role, B> FromRequest<B> for Is<ROLE>
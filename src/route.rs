@@ -1,19 +1,55 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
 
 use axum::{
   extract::{FromRequest, RequestParts},
-  http::{header, Request},
+  http::{HeaderMap, Request, StatusCode},
   response::{IntoResponse, Redirect, Response},
 };
 
 use crate::{
+  conditional::Validators,
   page::{Page, PagePathError},
+  template::Template,
+  user::User,
   State,
 };
 
+/// A page that's shown instead of the usual message when a page doesn't exist.
+const NOT_FOUND_PAGE: &str = "_404";
+
 #[derive(serde::Deserialize)]
 struct RouteQuery {
   revision: Option<String>,
+  /// Set to `no` to show a redirect page itself, rather than following it.
+  redirect: Option<String>,
+  redirected_from: Option<String>,
+  /// Set to show the page without the sidebar, header and tabs, for printing.
+  print: Option<String>,
+  /// The fingerprint of a static file, which means the file at this URL never changes.
+  v: Option<String>,
+}
+
+/// Adds where the redirect came from to the target, so the page can say so. Only redirects to
+/// the wiki's own pages are followed.
+fn redirect_url(target: &str, from: &str) -> Option<String> {
+  let target = crate::auth::safe_redirect(target)?;
+  let (target, fragment) = match target.split_once('#') {
+    Some((target, fragment)) => (target, format!("#{}", fragment)),
+    None => (target, String::new()),
+  };
+
+  let separator = if target.contains('?') { '&' } else { '?' };
+
+  Some(format!(
+    "{}{}redirected_from={}{}",
+    target,
+    separator,
+    urlencoding::encode(from),
+    fragment
+  ))
 }
 
 pub async fn route<T: Send>(request: Request<T>) -> Result<Response, crate::page::Error> {
@@ -25,6 +61,7 @@ pub async fn route<T: Send>(request: Request<T>) -> Result<Response, crate::page
   let path = urlencoding::decode(path)?;
   let path = PathBuf::from(path.to_string());
 
+  let raw_query = request.uri().query().map(str::to_string);
   let query = request.uri().query().unwrap_or("");
   let query = serde_qs::from_str::<RouteQuery>(query).unwrap();
 
@@ -34,46 +71,175 @@ pub async fn route<T: Send>(request: Request<T>) -> Result<Response, crate::page
     .expect("`State` extension missing")
     .clone();
 
-  let static_path = state.config.static_directory.join(&path);
+  let fingerprinted = state.assets.is_current(&path, query.v.as_deref());
+
+  let static_path = state.config().static_directory.join(&path);
   if static_path.is_file() {
-    return static_handler(&static_path).await;
+    return static_handler(&static_path, request.headers(), fingerprinted).await;
+  }
+
+  if let Some(contents) = crate::assets::embedded(&path) {
+    let cache_control = cache_control(fingerprinted);
+    return Ok(crate::file::serve_embedded(
+      &path,
+      contents,
+      request.headers(),
+      cache_control,
+    ));
   }
 
   let mut parts = RequestParts::new(request);
+  let request_headers = parts.headers().clone();
 
   let page = match Page::from_request(&mut parts).await {
     Ok(page) => page,
     Err(PagePathError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
-      return Ok(Redirect::to(&format!("/meta/new/{}", path.display())).into_response());
+      // We're good to unwrap here because if there's an error, it'll just return `None`.
+      let user = Option::<User>::from_request(&mut parts).await.unwrap();
+
+      // Hidden directories like `.git` aren't listed.
+      if crate::page::is_safe_path(&path) && state.config().pages_directory.join(&path).is_dir() {
+        let index = path.join(crate::namespace::INDEX_PAGE);
+        let settings = crate::namespace::Settings::for_path(&index, &state.config());
+
+        if !settings.can_read(user.as_ref()) {
+          let hidden = PagePathError::Hidden {
+            logged_in: user.is_some(),
+          };
+          return Err(crate::page::Error::Path(hidden));
+        }
+
+        let html = crate::namespace::index_handler(&path, user, &state).await?;
+
+        return Ok(html.into_response());
+      }
+
+      return not_found(&path, user, state).await;
     },
     Err(err) => return Err(crate::page::Error::Path(err)),
   };
 
+  if let Some(canonical) = page.canonical_path(&state.config()) {
+    let url = match raw_query {
+      Some(query) => format!("/{}?{}", canonical.display(), query),
+      None => format!("/{}", canonical.display()),
+    };
+
+    return Ok(Redirect::permanent(&url).into_response());
+  }
+
   if let Some(revision) = query.revision {
-    let html = state
-      .git
-      .clone()
-      .history_handler(&page, revision, state)
-      .await?;
+    let html = crate::git::history_handler(&page, revision, state).await?;
 
     return Ok(html.into_response());
   }
 
-  let html = page.view_handler(state.clone()).await?;
+  // Images, videos and the like are sent as they are, rather than rendered.
+  let mime = mime_guess::from_path(&page.filepath).first_or_text_plain();
+  let is_media = matches!(mime.type_().as_str(), "image" | "video" | "audio");
+  if is_media
+    && state
+      .config()
+      .allowed_mime_types
+      .contains(mime.essence_str())
+  {
+    return static_handler(&page.filepath, &request_headers, false).await;
+  }
 
-  Ok(html.into_response())
+  if query.redirect.as_deref() != Some("no") {
+    let (context, _) = page.context(&state).await?;
+
+    if let Some(url) = context
+      .redirect_to
+      .as_deref()
+      .and_then(|target| redirect_url(target, &context.path))
+    {
+      return Ok(Redirect::to(&url).into_response());
+    }
+  }
+
+  let path = page.relative_path(&state.config())?;
+  state
+    .views
+    .record(&path.with_extension("").to_string_lossy());
+
+  let validators = Validators::rendered(&page, &state).await?;
+  if let Some(validators) = validators
+    .as_ref()
+    .filter(|validators| validators.is_fresh(&request_headers))
+  {
+    return Ok(validators.not_modified());
+  }
+
+  let logged_in = page.user.is_some();
+
+  let html = page
+    .view_handler(query.redirected_from, query.print.is_some(), state.clone())
+    .await?;
+  let headers = crate::cache::headers(&state.config(), &path, logged_in);
+
+  let validators = validators
+    .map(|validators| validators.headers())
+    .unwrap_or_default();
+
+  Ok((headers, validators, html).into_response())
 }
 
-async fn static_handler(path: &std::path::Path) -> Result<Response, crate::page::Error> {
-  let mime = mime_guess::from_path(path).first_or_text_plain();
+/// A real `404`, so crawlers don't think missing pages exist. If there's a `_404` page, it's
+/// shown instead of the usual message, and people who are logged in can make the page.
+async fn not_found(
+  path: &Path,
+  user: Option<User>,
+  state: Arc<State>,
+) -> Result<Response, crate::page::Error> {
+  let config = state.config();
+
+  let message = match Page::from_path(PathBuf::from(NOT_FOUND_PAGE), None, &config) {
+    Ok(page) => {
+      let mut renderer = page.renderer(state.clone()).await?;
+      // Anyone who can edit it could put anything in it.
+      renderer.sanitize();
+
+      maud::PreEscaped(renderer.html().to_string())
+    },
+    Err(_) => maud::html! {
+      p { "There's no page at " code { "/" (path.display()) } " yet." }
+    },
+  };
+
+  let content = maud::html! {
+    (message)
 
-  let file = tokio::fs::read(path).await?;
+    @if user.is_some() {
+      form method="get" action={ "/meta/new/" (path.display()) } {
+        button type="submit" { "Create this page" }
+      }
+    }
+  };
 
-  let response = (
-    [(header::CONTENT_TYPE, mime.essence_str().to_string())],
-    file,
-  )
-    .into_response();
+  let html = Template::new()
+    .breadcrumbs(&path.display().to_string())
+    .title("Page not found")
+    .content(content)
+    .render(user, &state);
 
-  Ok(response)
+  Ok((StatusCode::NOT_FOUND, html).into_response())
+}
+
+/// Fingerprinted files are cached for a year, and everything else is checked with its ETag
+/// each time it's used.
+async fn static_handler(
+  path: &std::path::Path,
+  request_headers: &HeaderMap,
+  fingerprinted: bool,
+) -> Result<Response, crate::page::Error> {
+  Ok(crate::file::serve(path, request_headers, cache_control(fingerprinted)).await?)
+}
+
+fn cache_control(fingerprinted: bool) -> &'static str {
+  if fingerprinted {
+    "public, max-age=31536000, immutable"
+  } else {
+    "public, no-cache"
+  }
 }
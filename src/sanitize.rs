@@ -0,0 +1,139 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  role::Role,
+  user::{User, UserKey},
+  State,
+};
+
+/// Tags used by KaTeX's MathML output, on top of `ammonia`'s defaults.
+const MATHML_TAGS: [&str; 14] = [
+  "math",
+  "semantics",
+  "annotation",
+  "mrow",
+  "mi",
+  "mn",
+  "mo",
+  "ms",
+  "mtext",
+  "msub",
+  "msup",
+  "msubsup",
+  "mfrac",
+  "msqrt",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SanitizeHtml {
+  pub enabled: bool,
+  /// Pages only ever edited by users with any of these roles aren't sanitized.
+  pub trusted_roles: Vec<Role>,
+}
+
+impl Default for SanitizeHtml {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      trusted_roles: vec![Role::Administrator],
+    }
+  }
+}
+
+impl SanitizeHtml {
+  pub fn is_trusted(&self, user: Option<&User>) -> bool {
+    if !self.enabled {
+      return true;
+    }
+
+    match user {
+      Some(user) => user
+        .roles
+        .iter()
+        .any(|role| self.trusted_roles.contains(role)),
+      None => false,
+    }
+  }
+
+  /// Whether content written by the users with these emails should be trusted. Everyone who's
+  /// ever written to a page has to be, or a small trusted edit would vouch for an untrusted one
+  /// before it.
+  pub async fn is_trusted_authors(&self, emails: &[Option<String>], state: &State) -> bool {
+    if !self.enabled {
+      return true;
+    }
+
+    if emails.is_empty() {
+      return false;
+    }
+
+    for email in emails {
+      let user = match email {
+        Some(email) => state.users.get(&UserKey::from(email.clone())).await,
+        None => None,
+      };
+
+      if !self.is_trusted(user.as_ref()) {
+        return false;
+      }
+    }
+
+    true
+  }
+}
+
+/// Put in front of the `id`s in sanitized pages, so they can't clobber the wiki's own.
+const ID_PREFIX: &str = "user-content-";
+
+pub fn clean(html: &str) -> String {
+  let mut builder = ammonia::Builder::default();
+
+  builder
+    .add_generic_attributes(["class", "id", "aria-hidden"])
+    .id_prefix(Some(ID_PREFIX))
+    .add_tag_attributes("td", ["style"])
+    .add_tag_attributes("th", ["style"])
+    .add_tags(MATHML_TAGS)
+    .add_tag_attributes("math", ["xmlns", "display"])
+    .add_tag_attributes("annotation", ["encoding"])
+    .add_tags(["iframe", "input"])
+    .add_tag_attributes("iframe", ["src", "title", "loading", "allowfullscreen"])
+    .add_tag_attributes("input", ["type", "checked", "disabled"])
+    .attribute_filter(|element, attribute, value| match (element, attribute) {
+      // The only embeds are from the `youtube` shortcode.
+      ("iframe", "src") if !value.starts_with(crate::shortcodes::YOUTUBE_EMBED) => None,
+      // And the only inputs are task lists' checkboxes - the rest are dropped below.
+      ("input", "type") if value != "checkbox" => None,
+      // Links within the page go to the prefixed `id`s.
+      ("a", "href") if value.starts_with('#') => {
+        Some(format!("#{}{}", ID_PREFIX, &value[1..]).into())
+      },
+      // Tables' alignment is the only styling that's kept.
+      ("td" | "th", "style") => {
+        let align = value.trim().trim_end_matches(';').trim();
+
+        matches!(
+          align,
+          "text-align: left" | "text-align: right" | "text-align: center"
+        )
+        .then(|| value.into())
+      },
+      _ => Some(value.into()),
+    });
+
+  let cleaned = builder.clean(html).to_string();
+
+  // An `input` without a `type` is a text box, and ammonia can only drop the attribute, so
+  // any that aren't checkboxes are taken out afterwards. Its output always quotes attributes.
+  let input = Regex::new(r"<input\b[^>]*>").unwrap();
+  input
+    .replace_all(&cleaned, |found: &regex::Captures| {
+      match found[0].contains(r#"type="checkbox""#) {
+        true => found[0].to_string(),
+        false => String::new(),
+      }
+    })
+    .into_owned()
+}
@@ -0,0 +1,191 @@
+//! The pieces of [`State`](crate::State) that can be swapped out when embedding the wiki.
+
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use axum::async_trait;
+use git2::Oid;
+use tokio::sync::broadcast;
+
+use crate::{
+  format::Format,
+  git::{self, Commit, Draft, Merged},
+  recent_changes::RecentChange,
+  user::{self, User, UserDb, UserKey},
+  State,
+};
+
+/// Reading and writing the page files themselves.
+#[async_trait]
+pub trait PageService: Send + Sync {
+  async fn read(&self, path: &Path) -> std::io::Result<String>;
+  /// Writes a page, creating any directories it needs.
+  async fn write(&self, path: &Path, contents: &str) -> std::io::Result<()>;
+}
+
+/// Pages stored directly on disk, in the pages directory.
+pub struct Filesystem;
+
+#[async_trait]
+impl PageService for Filesystem {
+  async fn read(&self, path: &Path) -> std::io::Result<String> {
+    tokio::fs::read_to_string(path).await
+  }
+
+  async fn write(&self, path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+
+    tokio::fs::write(path, contents).await
+  }
+}
+
+/// The history of the pages, and recording changes to them.
+pub trait GitService: Send + Sync {
+  fn recent_changes(&self) -> Vec<RecentChange>;
+  fn subscribe(&self) -> broadcast::Receiver<RecentChange>;
+  /// Held from writing to the pages until the change is committed and pushed, so pulling in
+  /// changes from elsewhere can't overwrite it in between.
+  fn writing(&self) -> &tokio::sync::Mutex<()>;
+  fn add_file(&self, path: &Path) -> Result<(), git::Error>;
+  /// Stages the deletion of `path`, which has already been removed from the working tree.
+  fn remove_file(&self, path: &Path) -> Result<(), git::Error>;
+  fn commit(&self, subject: &str, user: &User) -> Result<(), git::Error>;
+  fn push(&self) -> Result<(), git::Error>;
+  /// The contents of the page at `path` as of `commit`.
+  fn get_file(&self, path: &Path, commit: Oid) -> Result<String, git::Error>;
+  fn file_history(&self, path: &Path, users: &dyn UserService) -> Result<Vec<Commit>, git::Error>;
+  /// The most recent commit that touched `path`.
+  fn last_commit(&self, path: &Path, users: &dyn UserService)
+    -> Result<Option<Commit>, git::Error>;
+  /// Like [`GitService::last_commit`], for each of `paths` at once.
+  fn last_commits(
+    &self,
+    paths: &[PathBuf],
+    users: &dyn UserService,
+  ) -> Result<Vec<Option<Commit>>, git::Error>;
+  /// The email of the author of the most recent commit that touched `path`.
+  fn last_author_email(&self, path: &Path) -> Result<Option<String>, git::Error>;
+  fn author_email(&self, commit: Oid) -> Result<Option<String>, git::Error>;
+  /// The emails of everyone who's written to `path`, as of `commit` or else `HEAD`.
+  fn author_emails(
+    &self,
+    path: &Path,
+    commit: Option<Oid>,
+  ) -> Result<Vec<Option<String>>, git::Error>;
+  /// The first parent of `commit`, unless it's the first commit.
+  fn parent(&self, commit: Oid) -> Result<Option<Oid>, git::Error>;
+  /// Exports every commit that touched `path` as a `git format-patch` style series,
+  /// oldest first, which can be applied to another repository with `git am`.
+  fn export_history(&self, path: &Path) -> Result<Vec<u8>, git::Error>;
+  /// Merges `contents`, which was written starting from the page at `path` as of `base`, with
+  /// anything that's been committed to it since.
+  fn merge(&self, path: &Path, base: Oid, contents: &str) -> Result<Merged, git::Error>;
+  /// A unified diff of the page at `path`, from how it was in `from` to how it was in `to`.
+  fn compare(&self, path: &Path, from: Oid, to: Oid) -> Result<String, git::Error>;
+  /// Commits `contents` as the page at `path` on the draft `branch`, which is started from
+  /// `HEAD` if it doesn't exist yet. The working tree isn't touched.
+  fn commit_draft(
+    &self,
+    branch: &str,
+    path: &Path,
+    contents: &str,
+    subject: &str,
+    user: &User,
+  ) -> Result<(), git::Error>;
+  /// Every draft branch with changes that haven't been merged.
+  fn drafts(&self, users: &dyn UserService) -> Result<Vec<Draft>, git::Error>;
+  /// Merges the draft `branch` into `HEAD`, as `user`, and deletes it. Returns the paths
+  /// that changed.
+  fn merge_draft(&self, branch: &str, user: &User) -> Result<Vec<PathBuf>, git::Error>;
+  fn delete_draft(&self, branch: &str) -> Result<(), git::Error>;
+  /// The commits made by `user`, newest first, skipping the first `skip`.
+  fn user_history(
+    &self,
+    user: &UserKey,
+    skip: usize,
+    limit: Option<usize>,
+    users: &dyn UserService,
+  ) -> Result<Vec<Commit>, git::Error>;
+  /// How many commits `user` has made, and up to `limit` of them after the first `skip`, from
+  /// the same look at the history.
+  fn user_contributions(
+    &self,
+    user: &UserKey,
+    skip: usize,
+    limit: usize,
+    users: &dyn UserService,
+  ) -> Result<(usize, Vec<Commit>), git::Error>;
+}
+
+/// Looking up and saving the users that can log in.
+#[async_trait]
+pub trait UserService: Send + Sync {
+  /// A user that's already known about.
+  async fn get(&self, key: &UserKey) -> Option<User>;
+  async fn all(&self) -> Vec<User>;
+  /// Like [`UserService::get`], for blocking tasks that can't `await`.
+  fn blocking_get(&self, key: &UserKey) -> Option<User>;
+  /// Like [`UserService::get`], but also finds users that have been added elsewhere.
+  async fn fetch(&self, key: &UserKey) -> Result<Option<User>, user::Error>;
+  /// Saves `user`, and only returns once they've been saved.
+  async fn set(&self, user: User) -> Result<(), user::Error>;
+  /// Waits for every change so far to be saved.
+  async fn flush(&self) -> Result<(), user::Error>;
+}
+
+#[async_trait]
+impl UserService for UserDb {
+  async fn get(&self, key: &UserKey) -> Option<User> {
+    UserDb::get(self, key).await
+  }
+
+  async fn all(&self) -> Vec<User> {
+    UserDb::all(self).await
+  }
+
+  fn blocking_get(&self, key: &UserKey) -> Option<User> {
+    UserDb::blocking_get(self, key)
+  }
+
+  async fn fetch(&self, key: &UserKey) -> Result<Option<User>, user::Error> {
+    UserDb::fetch(self, key).await
+  }
+
+  async fn set(&self, user: User) -> Result<(), user::Error> {
+    UserDb::set(self, user).await
+  }
+
+  async fn flush(&self) -> Result<(), user::Error> {
+    UserDb::flush(self).await
+  }
+}
+
+/// Turning a page's source into HTML.
+///
+/// This is called from a blocking task, so it's fine for it to block.
+pub trait RenderService: Send + Sync {
+  fn to_html(
+    &self,
+    doc: String,
+    format: Option<Format>,
+    state: Arc<State>,
+  ) -> Result<String, crate::pandoc::Error>;
+}
+
+/// Renders pages with pandoc, and the other renderers in the format registry.
+pub struct Pandoc;
+
+impl RenderService for Pandoc {
+  fn to_html(
+    &self,
+    doc: String,
+    format: Option<Format>,
+    state: Arc<State>,
+  ) -> Result<String, crate::pandoc::Error> {
+    crate::pandoc::to_html(doc, format, state)
+  }
+}
@@ -0,0 +1,482 @@
+use std::{
+  collections::HashMap,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use async_session::{async_trait, MemoryStore, Session, SessionStore};
+use async_sqlx_session::{PostgresSessionStore, SqliteSessionStore};
+use axum::{
+  extract::Extension,
+  http::StatusCode,
+  response::{IntoResponse, Redirect},
+  Form,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+  any::{AnyKind, AnyPool},
+  Row,
+};
+use time::OffsetDateTime;
+
+use crate::{
+  date::Dates,
+  proxy::SameOrigin,
+  user::{User, UserKey},
+};
+
+/// The table `async-sqlx-session` keeps the sessions themselves in.
+const SESSION_TABLE: &str = "async_sessions";
+
+/// How often sessions that have expired are cleared out.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Sqlx(#[from] sqlx::Error),
+  #[error(transparent)]
+  Session(#[from] async_session::Error),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> axum::response::Response {
+    (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+  }
+}
+
+/// Where login sessions are kept.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Backend {
+  /// The URL of a Postgres database.
+  Postgres(String),
+  /// The path to an SQLite database, which is created if it doesn't exist.
+  Sqlite(PathBuf),
+  /// Sessions are lost whenever the server restarts.
+  Memory,
+}
+
+impl Backend {
+  fn sqlite_url(path: &PathBuf) -> String {
+    format!("sqlite://{}?mode=rwc", path.display())
+  }
+
+  pub async fn build(&self) -> Result<(Store, SessionIndex), Error> {
+    let store = match self {
+      Backend::Postgres(url) => {
+        let store = PostgresSessionStore::new(url).await?;
+        store.migrate().await?;
+
+        Store::Postgres(store)
+      },
+      Backend::Sqlite(path) => {
+        let store = SqliteSessionStore::new(&Self::sqlite_url(path)).await?;
+        store.migrate().await?;
+
+        Store::Sqlite(store)
+      },
+      Backend::Memory => Store::Memory(MemoryStore::new()),
+    };
+
+    let index = match self {
+      Backend::Postgres(url) => SessionIndex::sql(url).await?,
+      Backend::Sqlite(path) => SessionIndex::sql(&Self::sqlite_url(path)).await?,
+      Backend::Memory => SessionIndex::Memory(Default::default()),
+    };
+
+    Ok((store, index))
+  }
+}
+
+/// The session store picked in the config.
+#[derive(Clone, Debug)]
+pub enum Store {
+  Postgres(PostgresSessionStore),
+  Sqlite(SqliteSessionStore),
+  Memory(MemoryStore),
+}
+
+#[async_trait]
+impl SessionStore for Store {
+  async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+    match self {
+      Store::Postgres(store) => store.load_session(cookie_value).await,
+      Store::Sqlite(store) => store.load_session(cookie_value).await,
+      Store::Memory(store) => store.load_session(cookie_value).await,
+    }
+  }
+
+  async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+    match self {
+      Store::Postgres(store) => store.store_session(session).await,
+      Store::Sqlite(store) => store.store_session(session).await,
+      Store::Memory(store) => store.store_session(session).await,
+    }
+  }
+
+  async fn destroy_session(&self, session: Session) -> async_session::Result {
+    match self {
+      Store::Postgres(store) => store.destroy_session(session).await,
+      Store::Sqlite(store) => store.destroy_session(session).await,
+      Store::Memory(store) => store.destroy_session(session).await,
+    }
+  }
+
+  async fn clear_store(&self) -> async_session::Result {
+    match self {
+      Store::Postgres(store) => store.clear_store().await,
+      Store::Sqlite(store) => store.clear_store().await,
+      Store::Memory(store) => store.clear_store().await,
+    }
+  }
+}
+
+impl Store {
+  /// Removes the sessions that have expired.
+  async fn cleanup(&self) -> Result<(), Error> {
+    match self {
+      Store::Postgres(store) => store.cleanup().await?,
+      Store::Sqlite(store) => store.cleanup().await?,
+      Store::Memory(store) => store.cleanup().await?,
+    }
+
+    Ok(())
+  }
+}
+
+/// Clears out the sessions that have expired, and the index's record of them, every
+/// [`PRUNE_INTERVAL`] - starting straight away.
+pub async fn prune_periodically(store: Store, index: SessionIndex) {
+  let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+  loop {
+    interval.tick().await;
+
+    let pruned = match store.cleanup().await {
+      Ok(()) => index.prune().await,
+      Err(err) => Err(err),
+    };
+
+    if let Err(err) = pruned {
+      log::warn!("Couldn't clear out expired sessions: {}", err);
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct SessionInfo {
+  pub id: String,
+  email: String,
+  /// Seconds since the Unix epoch.
+  created: i64,
+  last_seen: i64,
+  pub user_agent: Option<String>,
+}
+
+impl SessionInfo {
+  pub fn created(&self) -> OffsetDateTime {
+    Dates::from_timestamp(self.created)
+  }
+
+  pub fn last_seen(&self) -> OffsetDateTime {
+    Dates::from_timestamp(self.last_seen)
+  }
+}
+
+#[derive(Default)]
+pub struct MemoryIndex {
+  sessions: HashMap<String, SessionInfo>,
+}
+
+/// Keeps track of which sessions belong to which user, so they can be listed and revoked.
+///
+/// A user session is only valid while it's in the index, so revoking one doesn't depend on
+/// being able to remove it from the session store. Revoked sessions are still removed from
+/// SQL stores, so any session there that isn't in the index is from before it was kept.
+#[derive(Clone)]
+pub enum SessionIndex {
+  Sql(AnyPool),
+  Memory(Arc<Mutex<MemoryIndex>>),
+}
+
+fn now() -> i64 {
+  OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Whether the session store's row `a` hasn't expired - Postgres keeps when it expires as a
+/// timestamp, and SQLite as seconds since the Unix epoch.
+fn unexpired(pool: &AnyPool) -> &'static str {
+  match pool.any_kind() {
+    AnyKind::Postgres => "(a.expires IS NULL OR a.expires > NOW())",
+    _ => "(a.expires IS NULL OR a.expires > CAST(strftime('%s', 'now') AS INTEGER))",
+  }
+}
+
+impl SessionIndex {
+  async fn sql(url: &str) -> Result<Self, Error> {
+    let pool = AnyPool::connect(url).await?;
+
+    sqlx::query(
+      "CREATE TABLE IF NOT EXISTS gitalite_sessions (
+        id TEXT PRIMARY KEY,
+        email TEXT NOT NULL,
+        created BIGINT NOT NULL,
+        last_seen BIGINT NOT NULL,
+        user_agent TEXT
+      )",
+    )
+    .execute(&pool)
+    .await?;
+
+    let index = Self::Sql(pool);
+    index.migrate().await?;
+
+    Ok(index)
+  }
+
+  /// Adds the people logged in from before the index was kept, so they stay logged in.
+  async fn migrate(&self) -> Result<(), Error> {
+    let pool = match self {
+      Self::Sql(pool) => pool,
+      Self::Memory(_) => return Ok(()),
+    };
+
+    let rows = sqlx::query(&format!(
+      "SELECT a.id, a.session FROM {} a
+      WHERE {} AND a.id NOT IN (SELECT id FROM gitalite_sessions)",
+      SESSION_TABLE,
+      unexpired(pool)
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    for row in rows {
+      let id: String = row.get(0);
+      let session: String = row.get(1);
+
+      // Sessions part of the way through logging in aren't anyone's yet.
+      let key = serde_json::from_str::<Session>(&session)
+        .ok()
+        .and_then(|session| UserKey::from_session(&session).ok());
+
+      if let Some(key) = key {
+        self.record(&id, key.email(), None).await?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Forgets the sessions that aren't in the store any more.
+  async fn prune(&self) -> Result<(), Error> {
+    if let Self::Sql(pool) = self {
+      sqlx::query(&format!(
+        "DELETE FROM gitalite_sessions WHERE id NOT IN (SELECT id FROM {})",
+        SESSION_TABLE
+      ))
+      .execute(pool)
+      .await?;
+    }
+
+    Ok(())
+  }
+
+  pub async fn record(
+    &self,
+    id: &str,
+    email: &str,
+    user_agent: Option<&str>,
+  ) -> Result<(), Error> {
+    let now = now();
+
+    match self {
+      Self::Sql(pool) => {
+        sqlx::query(
+          "INSERT INTO gitalite_sessions (id, email, created, last_seen, user_agent)
+          VALUES ($1, $2, $3, $3, $4)
+          ON CONFLICT (id) DO UPDATE SET email = $2, last_seen = $3, user_agent = $4",
+        )
+        .bind(id)
+        .bind(email)
+        .bind(now)
+        .bind(user_agent)
+        .execute(pool)
+        .await?;
+      },
+      Self::Memory(index) => {
+        index.lock().unwrap().sessions.insert(
+          id.to_string(),
+          SessionInfo {
+            id: id.to_string(),
+            email: email.to_string(),
+            created: now,
+            last_seen: now,
+            user_agent: user_agent.map(|agent| agent.to_string()),
+          },
+        );
+      },
+    }
+
+    Ok(())
+  }
+
+  /// Marks a session as just used, returning `false` if it isn't in the index.
+  pub async fn touch(&self, id: &str) -> Result<bool, Error> {
+    let found = match self {
+      Self::Sql(pool) => {
+        let updated = sqlx::query("UPDATE gitalite_sessions SET last_seen = $2 WHERE id = $1")
+          .bind(id)
+          .bind(now())
+          .execute(pool)
+          .await?;
+
+        updated.rows_affected() > 0
+      },
+      Self::Memory(index) => match index.lock().unwrap().sessions.get_mut(id) {
+        Some(session) => {
+          session.last_seen = now();
+          true
+        },
+        None => false,
+      },
+    };
+
+    Ok(found)
+  }
+
+  /// Logs the user with this email out everywhere, after they've been given or lost
+  /// privileges - so nobody holding one of their session IDs gets the new privileges, and they
+  /// get a new session when they log in again.
+  pub async fn privileges_changed(&self, email: &str) -> Result<(), Error> {
+    self.revoke_all(email).await
+  }
+
+  pub async fn list(&self, email: &str) -> Result<Vec<SessionInfo>, Error> {
+    let sessions = match self {
+      Self::Sql(pool) => {
+        // Sessions that have expired in the store are gone, even if they haven't been pruned.
+        let rows = sqlx::query(&format!(
+          "SELECT s.id, s.email, s.created, s.last_seen, s.user_agent
+          FROM gitalite_sessions s
+          JOIN {} a ON a.id = s.id
+          WHERE s.email = $1 AND {}
+          ORDER BY s.last_seen DESC",
+          SESSION_TABLE,
+          unexpired(pool)
+        ))
+        .bind(email)
+        .fetch_all(pool)
+        .await?;
+
+        rows
+          .into_iter()
+          .map(|row| SessionInfo {
+            id: row.get(0),
+            email: row.get(1),
+            created: row.get(2),
+            last_seen: row.get(3),
+            user_agent: row.get(4),
+          })
+          .collect()
+      },
+      Self::Memory(index) => {
+        let mut sessions: Vec<_> = index
+          .lock()
+          .unwrap()
+          .sessions
+          .values()
+          .filter(|session| session.email == email)
+          .cloned()
+          .collect();
+
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+        sessions
+      },
+    };
+
+    Ok(sessions)
+  }
+
+  /// Removes a session, as long as it belongs to the user with this email.
+  pub async fn revoke(&self, id: &str, email: &str) -> Result<(), Error> {
+    match self {
+      Self::Sql(pool) => {
+        let removed = sqlx::query("DELETE FROM gitalite_sessions WHERE id = $1 AND email = $2")
+          .bind(id)
+          .bind(email)
+          .execute(pool)
+          .await?;
+
+        if removed.rows_affected() > 0 {
+          sqlx::query(&format!("DELETE FROM {} WHERE id = $1", SESSION_TABLE))
+            .bind(id)
+            .execute(pool)
+            .await?;
+        }
+      },
+      Self::Memory(index) => {
+        let sessions = &mut index.lock().unwrap().sessions;
+        if sessions.get(id).map(|session| session.email == email) == Some(true) {
+          sessions.remove(id);
+        }
+      },
+    }
+
+    Ok(())
+  }
+
+  pub async fn revoke_all(&self, email: &str) -> Result<(), Error> {
+    match self {
+      Self::Sql(pool) => {
+        sqlx::query(&format!(
+          "DELETE FROM {} WHERE id IN (SELECT id FROM gitalite_sessions WHERE email = $1)",
+          SESSION_TABLE
+        ))
+        .bind(email)
+        .execute(pool)
+        .await?;
+
+        sqlx::query("DELETE FROM gitalite_sessions WHERE email = $1")
+          .bind(email)
+          .execute(pool)
+          .await?;
+      },
+      Self::Memory(index) => {
+        index
+          .lock()
+          .unwrap()
+          .sessions
+          .retain(|_, session| session.email != email);
+      },
+    }
+
+    Ok(())
+  }
+}
+
+#[derive(serde::Deserialize)]
+pub struct RevokeParams {
+  id: String,
+}
+
+pub async fn revoke_handler(
+  user: User,
+  _: SameOrigin,
+  Form(params): Form<RevokeParams>,
+  Extension(index): Extension<SessionIndex>,
+) -> Result<Redirect, Error> {
+  index.revoke(&params.id, &user.email).await?;
+
+  Ok(Redirect::to(&format!("/meta/profile/{}", user.email)))
+}
+
+pub async fn revoke_all_handler(
+  user: User,
+  _: SameOrigin,
+  Extension(index): Extension<SessionIndex>,
+) -> Result<Redirect, Error> {
+  index.revoke_all(&user.email).await?;
+
+  Ok(Redirect::to("/"))
+}
@@ -0,0 +1,356 @@
+//! Shortcodes like `{{< recent-changes limit=5 >}}`, which put something that's worked out
+//! when the page is rendered into it. Each one either adds to the page's source before pandoc
+//! sees it, or adds HTML to what pandoc makes. More can be added to [`Shortcodes`] before it's
+//! put in the [`State`].
+
+use std::{collections::HashMap, sync::Arc};
+
+use maud::{html, Markup};
+
+use crate::{
+  line_map::{LineMap, Rebuilt},
+  State,
+};
+
+const START: &str = "{{<";
+const END: &str = ">}}";
+
+/// How many shortcodes can put in source with more shortcodes, one inside another.
+const MAX_DEPTH: usize = 5;
+
+/// How many changes `recent-changes` shows if it's not given a `limit`.
+const RECENT_CHANGES: usize = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Table(#[from] crate::table::Error),
+  #[error("The `{0}` shortcode needs a `{1}`")]
+  MissingArgument(&'static str, &'static str),
+  #[error("`{0}` isn't a number")]
+  NotANumber(String),
+  #[error("Shortcodes are nested more than {} deep", MAX_DEPTH)]
+  TooDeep,
+}
+
+/// What a shortcode was given - `{{< name "first" second key=value >}}`.
+#[derive(Debug, Default)]
+pub struct Args {
+  pub positional: Vec<String>,
+  pub named: HashMap<String, String>,
+}
+
+impl Args {
+  fn parse(args: &str) -> Self {
+    let mut parsed = Self::default();
+    let mut chars = args.chars().peekable();
+
+    loop {
+      while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+      if chars.peek().is_none() {
+        break;
+      }
+
+      let mut key = None;
+      let mut value = String::new();
+
+      while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+        match c {
+          '"' => {
+            while let Some(c) = chars.next_if(|c| *c != '"') {
+              value.push(c);
+            }
+            chars.next();
+          },
+          '=' if key.is_none() => key = Some(std::mem::take(&mut value)),
+          c => value.push(c),
+        }
+      }
+
+      match key {
+        Some(key) => {
+          parsed.named.insert(key, value);
+        },
+        None => parsed.positional.push(value),
+      }
+    }
+
+    parsed
+  }
+
+  /// The argument called `name`, or else the one at `position`.
+  pub fn get(&self, name: &str, position: usize) -> Option<&str> {
+    self
+      .named
+      .get(name)
+      .or_else(|| self.positional.get(position))
+      .map(String::as_str)
+  }
+}
+
+pub enum Output {
+  /// Added to the page's source, so it's rendered like the rest of the page.
+  Source(String),
+  /// Added to the rendered page as it is.
+  Html(Markup),
+}
+
+pub trait Shortcode: Send + Sync {
+  fn render(&self, args: &Args, state: &Arc<State>) -> Result<Output, Error>;
+}
+
+pub struct Shortcodes {
+  shortcodes: HashMap<String, Arc<dyn Shortcode>>,
+}
+
+impl Default for Shortcodes {
+  fn default() -> Self {
+    let mut shortcodes = Self {
+      shortcodes: HashMap::new(),
+    };
+
+    shortcodes.register("csv", Csv);
+    shortcodes.register("include", crate::transclude::Include);
+    shortcodes.register("recent-changes", RecentChanges);
+    shortcodes.register("youtube", YouTube);
+
+    shortcodes
+  }
+}
+
+impl Shortcodes {
+  /// Adds a shortcode, replacing any with the same name.
+  pub fn register(&mut self, name: impl Into<String>, shortcode: impl Shortcode + 'static) {
+    self.shortcodes.insert(name.into(), Arc::new(shortcode));
+  }
+
+  /// Runs every shortcode in `doc`. HTML is swapped for a placeholder that survives being run
+  /// through pandoc, and is returned to put back with [`restore`], along with which line of
+  /// `doc` each line of the source came from.
+  pub fn expand(
+    &self,
+    doc: &str,
+    state: &Arc<State>,
+  ) -> Result<(String, Vec<Markup>, LineMap), Error> {
+    let mut html = Vec::new();
+    let (doc, included) = wiki_includes(doc);
+    let (source, expanded) = self.expand_into(&doc, state, &mut html, 0)?;
+
+    Ok((source, html, included.then(&expanded)))
+  }
+
+  fn expand_into(
+    &self,
+    doc: &str,
+    state: &Arc<State>,
+    html: &mut Vec<Markup>,
+    depth: usize,
+  ) -> Result<(String, LineMap), Error> {
+    if depth > MAX_DEPTH {
+      return Err(Error::TooDeep);
+    }
+
+    let mut out = Rebuilt::with_capacity(doc.len());
+    let mut rest = doc;
+
+    while let Some(found) = Found::next(rest) {
+      let original = &rest[found.start..found.end];
+      out.keep(&rest[..found.start]);
+
+      match self.shortcodes.get(found.name) {
+        Some(shortcode) => match shortcode.render(&found.args, state)? {
+          Output::Source(source) => {
+            let (source, _) = self.expand_into(&source, state, html, depth + 1)?;
+            out.replace(original, &source);
+          },
+          Output::Html(markup) => {
+            out.replace(original, &placeholder(html.len()));
+            html.push(markup);
+          },
+        },
+        // It's left as it is, so pages can write about other sites' shortcodes.
+        None => out.keep(original),
+      }
+
+      rest = &rest[found.end..];
+    }
+
+    out.keep(rest);
+
+    Ok(out.finish())
+  }
+}
+
+/// A shortcode in a page's source.
+struct Found<'a> {
+  /// Where it starts and ends, including the brackets.
+  start: usize,
+  end: usize,
+  name: &'a str,
+  args: Args,
+}
+
+impl<'a> Found<'a> {
+  fn next(doc: &'a str) -> Option<Self> {
+    let start = doc.find(START)?;
+    let after = &doc[start + START.len()..];
+    let end = after.find(END)?;
+
+    let inside = after[..end].trim();
+    let (name, args) = inside
+      .split_once(char::is_whitespace)
+      .unwrap_or((inside, ""));
+
+    Some(Self {
+      start,
+      end: start + START.len() + end + END.len(),
+      name,
+      args: Args::parse(args),
+    })
+  }
+}
+
+/// The arguments of every `name` shortcode in `doc`, without running them.
+pub fn calls(doc: &str, name: &str) -> Vec<Args> {
+  let (doc, _) = wiki_includes(doc);
+  let mut calls = Vec::new();
+  let mut rest = doc.as_str();
+
+  while let Some(found) = Found::next(rest) {
+    if found.name == name {
+      calls.push(found.args);
+    }
+
+    rest = &rest[found.end..];
+  }
+
+  calls
+}
+
+/// Turns `![[other/page]]` into `{{< include "other/page" >}}`.
+fn wiki_includes(doc: &str) -> (String, LineMap) {
+  let mut out = Rebuilt::with_capacity(doc.len());
+  let mut rest = doc;
+
+  while let Some(start) = rest.find("![[") {
+    let after = &rest[start + 3..];
+
+    let end = match after.find("]]") {
+      Some(end) => end,
+      None => break,
+    };
+
+    out.keep(&rest[..start]);
+    out.replace(
+      &rest[start..start + 3 + end + 2],
+      &format!("{} include \"{}\" {}", START, after[..end].trim(), END),
+    );
+
+    rest = &after[end + 2..];
+  }
+
+  out.keep(rest);
+
+  out.finish()
+}
+
+fn placeholder(n: usize) -> String {
+  format!("GITALITE-SHORTCODE-{}", n)
+}
+
+/// Puts the HTML from [`Shortcodes::expand`] back into the rendered page.
+pub fn restore(mut html: String, rendered: Vec<Markup>) -> String {
+  // Backwards, so `-1` isn't replaced inside `-10`.
+  for (n, markup) in rendered.into_iter().enumerate().rev() {
+    let placeholder = placeholder(n);
+    let markup = markup.into_string();
+
+    // Pandoc will usually wrap the placeholder in its own paragraph.
+    let paragraph = format!("<p>{}</p>", placeholder);
+
+    html = if html.contains(&paragraph) {
+      html.replace(&paragraph, &markup)
+    } else {
+      html.replace(&placeholder, &markup)
+    };
+  }
+
+  html
+}
+
+/// `{{< csv "path/to/file.csv" >}}` - a table of the file's data.
+struct Csv;
+
+impl Shortcode for Csv {
+  fn render(&self, args: &Args, state: &Arc<State>) -> Result<Output, Error> {
+    let path = args
+      .get("path", 0)
+      .ok_or(Error::MissingArgument("csv", "path"))?;
+
+    Ok(Output::Html(crate::table::embed(path, &state.config())?))
+  }
+}
+
+/// `{{< recent-changes limit=5 >}}` - the latest commits, and the pages they changed.
+struct RecentChanges;
+
+impl Shortcode for RecentChanges {
+  fn render(&self, args: &Args, state: &Arc<State>) -> Result<Output, Error> {
+    let limit = match args.get("limit", 0) {
+      Some(limit) => limit
+        .parse()
+        .map_err(|_| Error::NotANumber(limit.to_string()))?,
+      None => RECENT_CHANGES,
+    };
+
+    // The page is shown to everyone the same, so it only lists what everyone can see.
+    let config = state.config();
+    let changes: Vec<_> = state
+      .git
+      .recent_changes()
+      .into_iter()
+      .filter(|change| change.is_visible_to(None, &config))
+      .collect();
+
+    Ok(Output::Html(html! {
+      ul .recent-changes {
+        @for change in changes.iter().take(limit) {
+          li {
+            (change.message) " · " (change.author)
+            @for file in &change.files {
+              @let path = file.with_extension("");
+              " · " a href={ "/" (path.display()) } { (path.display()) }
+            }
+          }
+        }
+      }
+    }))
+  }
+}
+
+/// Where YouTube videos are embedded from - the only place the sanitizer allows embeds from.
+pub const YOUTUBE_EMBED: &str = "https://www.youtube-nocookie.com/embed/";
+
+/// `{{< youtube id >}}` - an embedded YouTube video.
+struct YouTube;
+
+impl Shortcode for YouTube {
+  fn render(&self, args: &Args, _: &Arc<State>) -> Result<Output, Error> {
+    let id = args
+      .get("id", 0)
+      .ok_or(Error::MissingArgument("youtube", "id"))?;
+
+    // Anything else could point the embed somewhere other than a video.
+    let id: String = id
+      .chars()
+      .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+      .collect();
+
+    Ok(Output::Html(html! {
+      .video {
+        iframe src={ (YOUTUBE_EMBED) (id) } title="YouTube video" loading="lazy" allowfullscreen {}
+      }
+    }))
+  }
+}
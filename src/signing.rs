@@ -0,0 +1,156 @@
+use std::{
+  io::Write,
+  path::PathBuf,
+  process::{Command, Output, Stdio},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("Couldn't run the signing program: {0}")]
+  Io(#[from] std::io::Error),
+  #[error("Signing the commit failed: {stderr}")]
+  Failed { stderr: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Format {
+  Gpg,
+  Ssh,
+}
+
+/// Signing the commits the wiki makes.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Signing {
+  pub format: Format,
+  /// The GPG key ID or fingerprint, or the path to the SSH private key. Only GPG signatures made
+  /// with this key are shown as verified.
+  pub key: String,
+  /// For SSH, a file in `ssh-keygen`'s "allowed signers" format, which signatures are
+  /// checked against. SSH signatures are never shown as verified without one.
+  #[serde(default)]
+  pub allowed_signers: Option<PathBuf>,
+  /// Defaults to `gpg` or `ssh-keygen`.
+  #[serde(default)]
+  pub program: Option<String>,
+}
+
+impl Signing {
+  fn program(&self) -> &str {
+    match (&self.program, self.format) {
+      (Some(program), _) => program,
+      (None, Format::Gpg) => "gpg",
+      (None, Format::Ssh) => "ssh-keygen",
+    }
+  }
+
+  /// A detached, armored signature of `data`.
+  pub fn sign(&self, data: &str) -> Result<String, Error> {
+    let args = match self.format {
+      Format::Gpg => vec!["--status-fd=2", "-bsau", &self.key],
+      Format::Ssh => vec!["-Y", "sign", "-n", "git", "-f", &self.key],
+    };
+
+    let output = run(self.program(), &args, data)?;
+
+    if !output.status.success() {
+      return Err(Error::Failed {
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+      });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+  }
+
+  /// Whether `signature` is a good signature of `data`, from the configured GPG key or, for
+  /// SSH, from a key allowed to sign as `email`, the commit's author.
+  pub fn verify(&self, signature: &str, data: &str, email: Option<&str>) -> Result<bool, Error> {
+    // Both programs need the signature in a file, as the data goes on stdin.
+    let mut signature_file = tempfile::NamedTempFile::new()?;
+    signature_file.write_all(signature.as_bytes())?;
+    let signature_path = signature_file.path().to_string_lossy();
+
+    let verified = match (self.format, &self.allowed_signers, email) {
+      (Format::Gpg, _, _) => {
+        let output = run(
+          self.program(),
+          &["--status-fd=1", "--verify", &signature_path, "-"],
+          data,
+        )?;
+
+        let status = String::from_utf8_lossy(&output.stdout);
+
+        output.status.success()
+          && status
+            .lines()
+            .any(|line| line.starts_with("[GNUPG:] GOODSIG "))
+          && status
+            .lines()
+            .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+            .any(|fields| self.is_key(fields))
+      },
+      (Format::Ssh, Some(allowed_signers), Some(email)) => {
+        let allowed_signers = allowed_signers.to_string_lossy();
+        let args = [
+          "-Y",
+          "verify",
+          "-f",
+          &allowed_signers,
+          "-I",
+          email,
+          "-n",
+          "git",
+          "-s",
+          &signature_path,
+        ];
+
+        run(self.program(), &args, data)?.status.success()
+      },
+      (Format::Ssh, _, _) => false,
+    };
+
+    Ok(verified)
+  }
+
+  /// Whether a GPG `VALIDSIG` status line's `fields` are for the configured key. They start with
+  /// the fingerprint of the key that signed, and end with its primary key's, and a key ID is the
+  /// end of a fingerprint.
+  fn is_key(&self, fields: &str) -> bool {
+    let key = self
+      .key
+      .trim_start_matches("0x")
+      .replace(' ', "")
+      .to_uppercase();
+
+    if key.is_empty() {
+      return false;
+    }
+
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+
+    [fields.first(), fields.last()]
+      .into_iter()
+      .flatten()
+      .any(|fingerprint| fingerprint.to_uppercase().ends_with(&key))
+  }
+}
+
+fn run(program: &str, args: &[&str], input: &str) -> Result<Output, std::io::Error> {
+  let mut child = Command::new(program)
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+
+  // Write from another thread, so a full stdout pipe can't deadlock us.
+  let mut stdin = child.stdin.take().unwrap();
+  let input = input.to_string();
+  let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+  let output = child.wait_with_output()?;
+  writer.join().unwrap()?;
+
+  Ok(output)
+}
@@ -0,0 +1,140 @@
+//! Site variables, like `{{ site.name }}`, which are set in the config and can be used in any
+//! page or theme template.
+
+use std::collections::HashMap;
+
+use crate::line_map::{LineMap, Rebuilt};
+
+/// `doc` with every `{{ site.<name> }}` replaced by its value. Any that aren't set are left as
+/// they are, and so is code - fenced code blocks, and inline code - so pages can show them.
+pub fn substitute(doc: &str, vars: &HashMap<String, String>) -> String {
+  substitute_with_lines(doc, vars).0
+}
+
+/// Like [`substitute`], along with which line of `doc` each line came from.
+pub fn substitute_with_lines(doc: &str, vars: &HashMap<String, String>) -> (String, LineMap) {
+  let mut out = Rebuilt::with_capacity(doc.len());
+
+  if vars.is_empty() {
+    out.keep(doc);
+    return out.finish();
+  }
+
+  // The fence that opened the code block that's being skipped, if there is one.
+  let mut fence: Option<&str> = None;
+
+  for line in doc.split_inclusive('\n') {
+    match (fence, fence_of(line)) {
+      (Some(open), Some(marker)) if closes(open, marker, line) => {
+        fence = None;
+        out.keep(line);
+      },
+      (Some(_), _) => out.keep(line),
+      (None, Some(marker)) => {
+        fence = Some(marker);
+        out.keep(line);
+      },
+      (None, None) => substitute_line(&mut out, line, vars),
+    }
+  }
+
+  out.finish()
+}
+
+/// The run of backticks or tildes that `line` starts with, if it's a code fence.
+fn fence_of(line: &str) -> Option<&str> {
+  let line = line.trim_start();
+  let marker = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+  let length = line.len() - line.trim_start_matches(marker).len();
+
+  (length >= 3).then(|| &line[..length])
+}
+
+/// Whether `line`, which starts with `marker`, ends the code block that `open` started - it has
+/// to be at least as long, and have nothing after it.
+fn closes(open: &str, marker: &str, line: &str) -> bool {
+  marker.starts_with(&open[..1]) && marker.len() >= open.len() && line.trim() == marker
+}
+
+/// Adds `line` to `out` with its variables replaced, apart from in its inline code.
+fn substitute_line(out: &mut Rebuilt, line: &str, vars: &HashMap<String, String>) {
+  let mut rest = line;
+
+  while let Some(start) = rest.find('`') {
+    let ticks = backticks_at(rest, start);
+    let after = &rest[start + ticks..];
+
+    substitute_text(out, &rest[..start], vars);
+
+    // The code ends at the next run of just as many backticks - without one, they're only
+    // backticks.
+    match closing_backticks(after, ticks) {
+      Some(end) => {
+        out.keep(&rest[start..start + ticks + end + ticks]);
+        rest = &after[end + ticks..];
+      },
+      None => {
+        out.keep(&rest[start..start + ticks]);
+        rest = after;
+      },
+    }
+  }
+
+  substitute_text(out, rest, vars);
+}
+
+/// How many backticks there are in a row from `start`.
+fn backticks_at(text: &str, start: usize) -> usize {
+  let from = &text[start..];
+
+  from.len() - from.trim_start_matches('`').len()
+}
+
+/// Where the next run of exactly `ticks` backticks in `text` starts.
+fn closing_backticks(text: &str, ticks: usize) -> Option<usize> {
+  let mut offset = 0;
+
+  while let Some(start) = text[offset..].find('`') {
+    let start = offset + start;
+    let run = backticks_at(text, start);
+
+    if run == ticks {
+      return Some(start);
+    }
+
+    offset = start + run;
+  }
+
+  None
+}
+
+/// Adds `text` to `out` with every variable replaced, without looking for code.
+fn substitute_text(out: &mut Rebuilt, text: &str, vars: &HashMap<String, String>) {
+  let mut rest = text;
+
+  while let Some(start) = rest.find("{{") {
+    let after = &rest[start + 2..];
+
+    let end = match after.find("}}") {
+      Some(end) => end,
+      None => break,
+    };
+
+    let value = after[..end]
+      .trim()
+      .strip_prefix("site.")
+      .and_then(|name| vars.get(name));
+
+    let variable = &rest[start..start + 2 + end + 2];
+
+    out.keep(&rest[..start]);
+    match value {
+      Some(value) => out.replace(variable, value),
+      None => out.keep(variable),
+    }
+
+    rest = &after[end + 2..];
+  }
+
+  out.keep(rest);
+}
@@ -0,0 +1,57 @@
+//! Pages with `layout = "slides"` in their front matter are shown as a slideshow - each heading
+//! starts a new slide. Pandoc lays them out for [Slidy](https://www.w3.org/Talks/Tools/Slidy2/),
+//! and the wiki's own `slides.js` shows them, so nothing is loaded from anywhere else.
+
+use std::sync::Arc;
+
+use axum::response::Html;
+use maud::{html, PreEscaped, DOCTYPE};
+
+use crate::{format::Format, page::PageContext, State};
+
+/// The front matter `layout` for a slideshow.
+pub const LAYOUT: &str = "slides";
+
+/// The page's slideshow, on its own, without the wiki around it.
+pub async fn render(
+  context: PageContext,
+  data: String,
+  format: Option<Format>,
+  trusted: bool,
+  state: Arc<State>,
+) -> Result<Html<String>, crate::page::Error> {
+  let mut slides = tokio::task::spawn_blocking({
+    let state = Arc::clone(&state);
+    move || crate::pandoc::to_slides(data, format, state)
+  })
+  .await
+  .unwrap()?;
+
+  if !trusted {
+    slides = crate::sanitize::clean(&slides);
+  }
+
+  let html = html! {
+    (DOCTYPE)
+    html {
+      head {
+        meta charset="utf-8";
+        meta name="viewport" content="width=device-width, initial-scale=1";
+        title { (context.title) }
+        link rel="stylesheet" type="text/css" href=(state.assets.url("slides.css"));
+        script type="module" src=(state.assets.url("slides.js")) {}
+      }
+      body {
+        .slide.cover {
+          h1 .title { (context.title) }
+          @if let Some(author) = &context.author {
+            p .author { (author) }
+          }
+        }
+        (PreEscaped(slides))
+      }
+    }
+  };
+
+  Ok(Html(html.into_string()))
+}
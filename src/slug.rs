@@ -0,0 +1,117 @@
+//! Tidying up the paths of new pages, and spotting when a new page looks like one that's
+//! already there.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{suggest::Suggestion, State};
+
+/// How many similar pages are shown when making a new one.
+const MAX_SIMILAR: usize = 5;
+/// Names shorter than this, once simplified, are a typo or two away from too much to be worth
+/// comparing.
+const MIN_SIMILAR_LENGTH: usize = 4;
+
+/// What's done to the path of every new page. Everything is off by default, so paths are used
+/// as they're typed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SlugRules {
+  pub lowercase: bool,
+  /// Replaces runs of spaces - and anything else that isn't a letter, number, `-` or `_`.
+  pub separator: Option<String>,
+  /// Turns accented and non-Latin letters into their closest ASCII, like "Café" into "Cafe".
+  pub transliterate: bool,
+}
+
+impl SlugRules {
+  /// `path` with the rules applied to each of its segments.
+  pub fn apply(&self, path: &str) -> String {
+    path
+      .split('/')
+      .map(|segment| self.apply_segment(segment))
+      .filter(|segment| !segment.is_empty())
+      .collect::<Vec<_>>()
+      .join("/")
+  }
+
+  fn apply_segment(&self, segment: &str) -> String {
+    let mut segment = match self.transliterate {
+      true => deunicode::deunicode(segment),
+      false => segment.to_string(),
+    };
+
+    if self.lowercase {
+      segment = segment.to_lowercase();
+    }
+
+    if let Some(separator) = &self.separator {
+      segment = segment
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == '.'))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(separator);
+    }
+
+    segment
+  }
+}
+
+/// A title boiled down to its letters and numbers, so "Release Notes" and "release-notes"
+/// are the same.
+fn simplify(title: &str) -> String {
+  deunicode::deunicode(title)
+    .to_lowercase()
+    .chars()
+    .filter(|c| c.is_alphanumeric())
+    .collect()
+}
+
+/// How many characters have to be added, removed or changed to turn `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+  for (i, a) in a.chars().enumerate() {
+    let mut current = vec![i + 1];
+
+    for (j, b) in b.iter().enumerate() {
+      let substitution = previous[j] + usize::from(a != *b);
+      current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+    }
+
+    previous = current;
+  }
+
+  previous[b.len()]
+}
+
+/// Pages whose titles or names are the same as, or a typo or two away from, `title`.
+pub async fn similar_pages(
+  title: &str,
+  state: &State,
+) -> Result<Vec<Suggestion>, crate::page::Error> {
+  let title = simplify(title);
+  if title.chars().count() < MIN_SIMILAR_LENGTH {
+    return Ok(Vec::new());
+  }
+
+  // Longer titles can be a little further apart and still be confusing.
+  let allowed = (title.chars().count() / 8).max(1);
+
+  let pages = state.suggestions.pages(state).await?;
+
+  let similar = pages
+    .iter()
+    .filter(|page| {
+      let name = page.path.rsplit('/').next().unwrap_or(&page.path);
+
+      [page.title.as_str(), name]
+        .iter()
+        .any(|candidate| distance(&title, &simplify(candidate)) <= allowed)
+    })
+    .take(MAX_SIMILAR)
+    .cloned()
+    .collect();
+
+  Ok(similar)
+}
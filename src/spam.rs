@@ -0,0 +1,542 @@
+//! Keeping spam out when the wiki is open to the public. Guests' suggestions, and edits from
+//! accounts that haven't been approved yet, are checked before they're saved - for too many new
+//! links, blocked words, and optionally with Akismet. Anything that looks like spam is held at
+//! `/meta/admin/quarantine`, where an administrator can let it through or throw it away.
+
+use std::{
+  net::IpAddr,
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use axum::{
+  extract::{Extension, Form},
+  http::{header, HeaderMap, StatusCode},
+  response::{Html, IntoResponse, Redirect, Response},
+};
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+
+use crate::{
+  audit::Action,
+  date::Dates,
+  page::Page,
+  proxy::SameOrigin,
+  role::{Is, Role},
+  template::Template,
+  user::{User, UserKey},
+  State,
+};
+
+/// The quarantine's file, in `cache_directory`.
+const FILE: &str = "quarantine.json";
+
+/// The most edits that are held at once - the oldest are dropped to make room for more.
+const MAX_HELD: usize = 500;
+
+/// The most edits held from any one address, so one spammer can't push everyone else's out.
+const MAX_HELD_PER_ADDRESS: usize = 10;
+
+/// How long to wait for more edits to be held before saving the quarantine.
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// What someone whose edit has been held is told.
+pub const HELD_MESSAGE: &str = "Your edit looks like it might be spam, so it's being held until \
+  an administrator has checked it.";
+
+/// Akismet's own spam check.
+const AKISMET_URL: &str = "https://rest.akismet.com/1.1/comment-check";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  User(#[from] crate::user::Error),
+  #[error("Edits can't be made from this address")]
+  Blocked,
+  #[error("'{0}' isn't in the quarantine")]
+  NotHeld(String),
+  #[error("The account that made this edit doesn't exist any more")]
+  NoUser,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Page(err) => err.into_response(),
+      Self::User(err) => err.into_response(),
+      Self::Blocked => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+      Self::NotHeld(_) | Self::NoUser => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+      Self::Io(_) | Self::Json(_) => {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+      },
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Spam {
+  /// Adds a field to the guest editor that people can't see, but bots fill in. Anything that
+  /// comes back with it filled in is thrown away.
+  pub honeypot: bool,
+  /// The most links an edit can add before it's held.
+  pub max_links: usize,
+  /// Words and phrases that get an edit held if it adds them, matched case-insensitively.
+  pub blocked_words: Vec<String>,
+  /// Addresses whose edits are turned away, without being held.
+  pub blocked_addresses: Vec<IpAddr>,
+  pub akismet: Option<Akismet>,
+}
+
+impl Default for Spam {
+  fn default() -> Self {
+    Self {
+      honeypot: true,
+      max_links: 5,
+      blocked_words: Vec::new(),
+      blocked_addresses: Vec::new(),
+      akismet: None,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Akismet {
+  pub key: String,
+  /// The `comment-check` URL - Akismet's, unless it's another service that speaks its API.
+  #[serde(default = "default_akismet_url")]
+  pub url: String,
+}
+
+fn default_akismet_url() -> String {
+  String::from(AKISMET_URL)
+}
+
+/// An edit to check.
+pub struct Submission {
+  /// The page's path.
+  pub path: PathBuf,
+  /// A guest's name, or the name of the account that made it.
+  pub name: String,
+  /// The account that made it - guests don't have one.
+  pub email: Option<String>,
+  pub summary: Option<String>,
+  pub body: String,
+  /// The page as it was before, so only what's been added is checked.
+  pub before: String,
+  pub address: Option<IpAddr>,
+  pub user_agent: Option<String>,
+  /// The commit the edit started from, so it's merged with anything since when it's let
+  /// through.
+  pub base: Option<Oid>,
+  /// For a new page, the file it's created as, relative to the pages directory.
+  pub created: Option<PathBuf>,
+}
+
+/// The browser or client an edit was sent from, for Akismet.
+pub fn user_agent(headers: &HeaderMap) -> Option<String> {
+  headers
+    .get(header::USER_AGENT)
+    .and_then(|agent| agent.to_str().ok())
+    .map(str::to_string)
+}
+
+/// How many links are in `text`.
+fn links(text: &str) -> usize {
+  text.matches("http://").count() + text.matches("https://").count()
+}
+
+impl Submission {
+  /// Why this looks like spam, if it does.
+  async fn check(&self, spam: &Spam, base_url: &str) -> Option<String> {
+    let added = links(&self.body).saturating_sub(links(&self.before));
+    if added > spam.max_links {
+      return Some(format!("It adds {} links", added));
+    }
+
+    let body = self.body.to_lowercase();
+    let before = self.before.to_lowercase();
+
+    let word = spam.blocked_words.iter().find(|word| {
+      let word = word.to_lowercase();
+      body.contains(&word) && !before.contains(&word)
+    });
+
+    if let Some(word) = word {
+      return Some(format!("It adds \"{}\"", word));
+    }
+
+    match &spam.akismet {
+      Some(akismet) => self.akismet(akismet, base_url).await,
+      None => None,
+    }
+  }
+
+  async fn akismet(&self, akismet: &Akismet, base_url: &str) -> Option<String> {
+    let address = self.address.map(|address| address.to_string());
+
+    let params = [
+      ("api_key", Some(akismet.key.as_str())),
+      ("blog", Some(base_url)),
+      ("user_ip", address.as_deref()),
+      ("user_agent", self.user_agent.as_deref()),
+      // The closest of Akismet's types to a wiki edit.
+      ("comment_type", Some("blog-post")),
+      ("comment_author", Some(self.name.as_str())),
+      ("comment_author_email", self.email.as_deref()),
+      ("comment_content", Some(self.body.as_str())),
+    ];
+
+    let params: Vec<(&str, &str)> = params
+      .into_iter()
+      .filter_map(|(name, value)| Some((name, value?)))
+      .collect();
+
+    let response = async {
+      reqwest::Client::new()
+        .post(&akismet.url)
+        .header(reqwest::header::USER_AGENT, "gitalite")
+        .form(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+    };
+
+    match response.await {
+      Ok(verdict) if verdict.trim() == "true" => Some(String::from("Akismet thinks it's spam")),
+      Ok(_) => None,
+      // It's held rather than let through, so nothing slips past while Akismet is down.
+      Err(err) => {
+        log::warn!("Couldn't check an edit with Akismet: {}", err);
+        Some(format!("Akismet couldn't be reached: {}", err))
+      },
+    }
+  }
+}
+
+/// Checks `submission`, and holds it in the quarantine if it looks like spam. Returns whether
+/// it was held, in which case it shouldn't be saved.
+pub async fn screen(submission: Submission, state: &State) -> Result<bool, Error> {
+  let config = state.config();
+
+  if let Some(address) = submission.address {
+    if config.spam.blocked_addresses.contains(&address) {
+      return Err(Error::Blocked);
+    }
+  }
+
+  let reason = match submission.check(&config.spam, config.base_url()).await {
+    Some(reason) => reason,
+    None => return Ok(false),
+  };
+
+  log::info!(
+    "Holding an edit to '{}' by {}: {}",
+    submission.path.display(),
+    submission.name,
+    reason
+  );
+
+  let date = OffsetDateTime::now_utc();
+
+  state.quarantine.add(Held {
+    id: date.unix_timestamp_nanos().to_string(),
+    date,
+    path: submission.path,
+    name: submission.name,
+    email: submission.email,
+    summary: submission.summary,
+    body: submission.body,
+    address: submission.address,
+    reason,
+    base: submission.base.map(|base| base.to_string()),
+    created: submission.created,
+  });
+
+  Ok(true)
+}
+
+/// Like [`screen`], for an edit by `user` - only accounts that haven't been approved yet are
+/// checked, as anyone can sign up while registration is open.
+pub async fn screen_user(
+  submission: Submission,
+  user: &User,
+  state: &State,
+) -> Result<bool, Error> {
+  match user.approved {
+    true => Ok(false),
+    false => screen(submission, state).await,
+  }
+}
+
+/// An edit that looked like spam.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Held {
+  pub id: String,
+  #[serde(with = "time::serde::rfc3339")]
+  pub date: OffsetDateTime,
+  pub path: PathBuf,
+  pub name: String,
+  pub email: Option<String>,
+  pub summary: Option<String>,
+  pub body: String,
+  pub address: Option<IpAddr>,
+  /// Why it was held.
+  pub reason: String,
+  /// The commit the edit started from.
+  #[serde(default)]
+  pub base: Option<String>,
+  /// For a new page, the file it's created as, relative to the pages directory.
+  #[serde(default)]
+  pub created: Option<PathBuf>,
+}
+
+pub struct Quarantine {
+  held: Arc<Mutex<Vec<Held>>>,
+  /// Tells the saving task there's something new to save.
+  changed: Arc<Notify>,
+}
+
+impl Quarantine {
+  /// Loads the quarantine, and starts saving changes to it in the background.
+  pub fn load(cache_directory: &Path) -> Result<Self, Error> {
+    let file = cache_directory.join(FILE);
+
+    let held = match std::fs::read(&file) {
+      Ok(contents) => serde_json::from_slice(&contents)?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+      Err(err) => return Err(err.into()),
+    };
+
+    let held = Arc::new(Mutex::new(held));
+    let changed = Arc::new(Notify::new());
+
+    tokio::spawn(save_changes(file, held.clone(), changed.clone()));
+
+    Ok(Self { held, changed })
+  }
+
+  /// Everything that's held, newest first.
+  pub fn all(&self) -> Vec<Held> {
+    let mut held = self.held.lock().unwrap().clone();
+    held.sort_by(|a, b| b.date.cmp(&a.date));
+
+    held
+  }
+
+  fn add(&self, held: Held) {
+    let mut all = self.held.lock().unwrap();
+
+    if let Some(address) = held.address {
+      let from_address = all
+        .iter()
+        .filter(|other| other.address == Some(address))
+        .count();
+
+      if from_address >= MAX_HELD_PER_ADDRESS {
+        log::info!(
+          "Dropping an edit from {}, which has enough held already",
+          address
+        );
+        return;
+      }
+    }
+
+    if all.len() >= MAX_HELD {
+      let excess = all.len() + 1 - MAX_HELD;
+      all.drain(..excess);
+    }
+
+    all.push(held);
+    self.changed.notify_one();
+  }
+
+  fn get(&self, id: &str) -> Result<Held, Error> {
+    self
+      .held
+      .lock()
+      .unwrap()
+      .iter()
+      .find(|held| held.id == id)
+      .cloned()
+      .ok_or_else(|| Error::NotHeld(id.to_string()))
+  }
+
+  fn remove(&self, id: &str) {
+    self.held.lock().unwrap().retain(|held| held.id != id);
+    self.changed.notify_one();
+  }
+}
+
+/// Saves the quarantine whenever it changes, waiting for [`SAVE_DELAY`] first so a burst of
+/// changes only writes it once.
+async fn save_changes(file: PathBuf, held: Arc<Mutex<Vec<Held>>>, changed: Arc<Notify>) {
+  loop {
+    changed.notified().await;
+    tokio::time::sleep(SAVE_DELAY).await;
+
+    let contents = match serde_json::to_vec(&*held.lock().unwrap()) {
+      Ok(contents) => contents,
+      Err(err) => {
+        log::error!("Couldn't save the quarantine: {}", err);
+        continue;
+      },
+    };
+
+    if let Err(err) = tokio::fs::write(&file, contents).await {
+      log::error!("Couldn't save the quarantine: {}", err);
+    }
+  }
+}
+
+pub async fn handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  Extension(state): Extension<Arc<State>>,
+) -> Html<String> {
+  let config = state.config();
+  let dates = Dates::new(Some(&admin), &config.dates);
+  let held = state.quarantine.all();
+
+  let content = maud::html! {
+    @if held.is_empty() {
+      p { "Nothing has been held." }
+    }
+
+    @for held in &held {
+      section .held {
+        h2 { a href={ "/" (held.path.display()) } { (held.path.display()) } }
+        p {
+          (dates.render(held.date)) " by "
+          @match &held.email {
+            Some(email) => { a href={ "/meta/profile/" (email) } { (held.name) } },
+            None => { (held.name) " (guest)" },
+          }
+          @if let Some(address) = held.address {
+            " from " (address)
+          }
+        }
+        p { strong { "Held because: " } (held.reason) }
+        @if let Some(summary) = &held.summary {
+          p { "Summary: " (summary) }
+        }
+        details {
+          summary { "Contents" }
+          pre { (held.body) }
+        }
+        form method="post" action="/meta/admin/quarantine" {
+          input type="hidden" name="id" value=(held.id);
+          button type="submit" name="action" value="release" { "Not spam" }
+          button type="submit" name="action" value="discard" { "Discard" }
+        }
+      }
+    }
+  };
+
+  Template::new()
+    .title("Quarantine")
+    .content(content)
+    .render(Some(admin), &state)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuarantineAction {
+  Release,
+  Discard,
+}
+
+#[derive(Deserialize)]
+pub struct QuarantineParams {
+  id: String,
+  action: QuarantineAction,
+}
+
+/// Lets a held edit through, as if it had never been held - so a guest's still goes to the
+/// drafts - or throws it away.
+pub async fn action_handler(
+  Is(admin): Is<{ Role::Administrator }>,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+  Form(params): Form<QuarantineParams>,
+) -> Result<Redirect, Error> {
+  let held = state.quarantine.get(&params.id)?;
+  let target = held.path.to_string_lossy().to_string();
+
+  match params.action {
+    QuarantineAction::Release => {
+      let config = state.config();
+
+      let user = match &held.email {
+        Some(email) => state
+          .users
+          .fetch(&UserKey::from(email.clone()))
+          .await?
+          .ok_or(Error::NoUser)?,
+        None => crate::guest::guest(&held.name, &held.path, &config),
+      };
+
+      let existing = Page::from_path(held.path.clone(), None, &config);
+
+      match (existing, &held.created) {
+        // It was a new page, and nobody's made it since.
+        (Err(_), Some(created)) => {
+          let filepath = config.pages_directory.join(created);
+          let page = Page {
+            path: held.path.clone(),
+            format: crate::page::format_of(&filepath),
+            filepath,
+            user: None,
+          };
+
+          page
+            .create(held.body, held.summary.as_deref(), &user, state.clone())
+            .await?;
+        },
+        (page, _) => {
+          // Anything that's changed since it was held is merged in, rather than overwritten.
+          let base = held
+            .base
+            .as_deref()
+            .and_then(|base| Oid::from_str(base).ok());
+
+          page?
+            .update(
+              held.body,
+              held.summary.as_deref(),
+              base,
+              &user,
+              state.clone(),
+            )
+            .await?;
+        },
+      }
+
+      state.quarantine.remove(&params.id);
+      state
+        .audit
+        .record(&admin.email, Action::Release, target, None)
+        .await;
+    },
+    QuarantineAction::Discard => {
+      state.quarantine.remove(&params.id);
+      state
+        .audit
+        .record(&admin.email, Action::Discard, target, Some(held.reason))
+        .await;
+    },
+  }
+
+  Ok(Redirect::to("/meta/admin/quarantine"))
+}
@@ -0,0 +1,206 @@
+//! Quick-open suggestions - every page's title and path are kept in memory, so they can be
+//! fuzzy-matched as someone types.
+
+use std::{
+  path::Path,
+  sync::{Arc, RwLock},
+};
+
+use axum::{
+  extract::{Extension, Query},
+  response::Redirect,
+  Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{page::Page, user::User, State};
+
+/// How many suggestions are given if the request doesn't say.
+const DEFAULT_LIMIT: usize = 10;
+/// The most suggestions that can be asked for at once.
+const MAX_LIMIT: usize = 50;
+
+#[derive(Clone, Serialize)]
+pub struct Suggestion {
+  pub title: String,
+  /// The page's path, without its extension.
+  pub path: String,
+  pub url: String,
+}
+
+/// Every page's title, which is read again after anything is committed.
+#[derive(Default)]
+pub struct Suggestions {
+  pages: RwLock<Option<Arc<Vec<Suggestion>>>>,
+}
+
+impl Suggestions {
+  /// Forgets the titles, so they're read again the next time they're needed.
+  pub fn invalidate(&self) {
+    *self.pages.write().unwrap() = None;
+  }
+
+  /// Forgets the titles whenever anything is committed.
+  pub async fn watch(state: Arc<State>) {
+    let mut changes = state.git.subscribe();
+
+    loop {
+      match changes.recv().await {
+        Ok(_) | Err(RecvError::Lagged(_)) => state.suggestions.invalidate(),
+        Err(RecvError::Closed) => break,
+      }
+    }
+  }
+
+  pub async fn pages(&self, state: &State) -> Result<Arc<Vec<Suggestion>>, crate::page::Error> {
+    let cached = self.pages.read().unwrap().clone();
+    if let Some(pages) = cached {
+      return Ok(pages);
+    }
+
+    let config = state.config();
+    let mut pages = Vec::new();
+
+    for page in Page::all(&config) {
+      let path = page.relative_path(&config)?.with_extension("");
+      let path = path.to_string_lossy().to_string();
+
+      // Binary pages don't have front matter, so they go by their path.
+      let title = match page.is_binary() {
+        true => None,
+        false => page.split_front_matter(&page.raw(state).await?).0.title,
+      };
+
+      pages.push(Suggestion {
+        title: title.unwrap_or_else(|| path.clone()),
+        url: crate::page::page_url(&path),
+        path,
+      });
+    }
+
+    let pages = Arc::new(pages);
+    *self.pages.write().unwrap() = Some(pages.clone());
+
+    Ok(pages)
+  }
+}
+
+/// How well `query` matches `candidate`, if it does at all - every character of the query has
+/// to be in the candidate, in order. Runs of characters and the starts of words count for more.
+fn score(query: &[char], candidate: &str) -> Option<i64> {
+  let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+
+  let mut score = 0;
+  let mut position = 0;
+  let mut previous: Option<usize> = None;
+
+  for c in query {
+    let found = position + candidate[position..].iter().position(|d| d == c)?;
+
+    score += 1;
+
+    if previous.map(|previous| previous + 1) == Some(found) {
+      score += 5;
+    }
+
+    if found == 0 || !candidate[found - 1].is_alphanumeric() {
+      score += 3;
+    }
+
+    previous = Some(found);
+    position = found + 1;
+  }
+
+  // Shorter candidates are closer to what was typed.
+  Some(score * 100 - candidate.len() as i64)
+}
+
+#[derive(Deserialize)]
+pub struct SuggestQuery {
+  q: String,
+  limit: Option<usize>,
+}
+
+/// The pages `user` can see whose titles or paths best match `query`, best first.
+async fn suggest(
+  query: &str,
+  limit: usize,
+  user: Option<&User>,
+  state: &State,
+) -> Result<Vec<Suggestion>, crate::page::Error> {
+  let search: Vec<char> = query
+    .to_lowercase()
+    .chars()
+    .filter(|c| !c.is_whitespace())
+    .collect();
+
+  if search.is_empty() {
+    return Ok(Vec::new());
+  }
+
+  let config = state.config();
+  let pages = state.suggestions.pages(state).await?;
+
+  let mut matches: Vec<(i64, &Suggestion)> = pages
+    .iter()
+    .filter(|page| crate::namespace::is_visible(Path::new(&page.path), user, &config))
+    .filter_map(|page| {
+      let title = score(&search, &page.title);
+      // A match in the title is better than one in the path.
+      let path = score(&search, &page.path).map(|score| score - 50);
+
+      Some((title.max(path)?, page))
+    })
+    .collect();
+
+  matches.sort_by(|(a, a_page), (b, b_page)| b.cmp(a).then_with(|| a_page.path.cmp(&b_page.path)));
+
+  let suggestions = matches
+    .into_iter()
+    .take(limit)
+    .map(|(_, page)| page.clone())
+    .collect();
+
+  Ok(suggestions)
+}
+
+/// `/api/v1/suggest?q=` - the pages whose titles or paths best match `q`.
+pub async fn handler(
+  Query(query): Query<SuggestQuery>,
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Vec<Suggestion>>, crate::page::Error> {
+  let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+  Ok(Json(suggest(&query.q, limit, user.as_ref(), &state).await?))
+}
+
+/// The sidebar's quick-open box, without JavaScript - goes straight to the best match, or to
+/// the page named `q` so it can be made if there isn't one.
+pub async fn quick_open_handler(
+  Query(query): Query<SuggestQuery>,
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Redirect, crate::page::Error> {
+  let best = suggest(&query.q, 1, user.as_ref(), &state)
+    .await?
+    .into_iter()
+    .next();
+
+  let url = match best {
+    Some(page) => page.url,
+    None => {
+      let path = query.q.trim().trim_matches('/');
+
+      // Anything that couldn't be a page, like `..`, isn't gone to.
+      if !crate::page::is_safe_path(Path::new(path)) {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+      }
+
+      crate::page::page_url(path)
+    },
+  };
+
+  Ok(Redirect::to(&url))
+}
@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use maud::{html, Markup};
+
+use crate::config::Config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Csv(#[from] csv::Error),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error("'{0}' isn't a CSV/TSV file inside the pages directory")]
+  InvalidEmbed(String),
+}
+
+/// Returns the field delimiter for CSV/TSV files, or `None` if `path` is neither.
+pub fn delimiter(path: &Path) -> Option<u8> {
+  match path.extension()?.to_str()? {
+    "csv" => Some(b','),
+    "tsv" => Some(b'\t'),
+    _ => None,
+  }
+}
+
+/// Renders delimited data as a sortable table, showing at most `row_limit` rows.
+pub fn render(
+  data: &str,
+  delimiter: u8,
+  row_limit: usize,
+  download: Option<&str>,
+) -> Result<Markup, Error> {
+  let mut reader = csv::ReaderBuilder::new()
+    .delimiter(delimiter)
+    .flexible(true)
+    .from_reader(data.as_bytes());
+
+  let headers = reader.headers()?.clone();
+
+  let mut rows = Vec::new();
+  let mut total = 0;
+
+  for record in reader.records() {
+    let record = record?;
+
+    if rows.len() < row_limit {
+      rows.push(record);
+    }
+
+    total += 1;
+  }
+
+  Ok(html! {
+    table .sortable {
+      thead {
+        tr {
+          @for header in &headers {
+            th { (header) }
+          }
+        }
+      }
+      tbody {
+        @for row in &rows {
+          tr {
+            @for field in row {
+              td { (field) }
+            }
+          }
+        }
+      }
+    }
+    @if total > rows.len() {
+      .warning { "Showing " (rows.len()) " of " (total) " rows." }
+    }
+    @if let Some(download) = download {
+      a href=(download) download { "Download" }
+    }
+  })
+}
+
+/// Renders the CSV/TSV file at `path`, inside the pages directory, with a link to download it.
+pub fn embed(path: &str, config: &Config) -> Result<Markup, Error> {
+  let relative = path.trim_start_matches('/');
+  let filepath = config.pages_directory.join(relative).canonicalize()?;
+
+  let file = match filepath.strip_prefix(&config.pages_directory) {
+    Ok(file) => file,
+    Err(_) => return Err(Error::InvalidEmbed(path.to_string())),
+  };
+
+  // It's shown to everyone the same, like an included page, so hidden files aren't shown at all.
+  if !crate::namespace::is_visible(file, None, config) {
+    return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+  }
+
+  let delimiter = delimiter(&filepath).ok_or_else(|| Error::InvalidEmbed(path.to_string()))?;
+  let data = std::fs::read_to_string(&filepath)?;
+
+  let download = format!("/meta/raw/{}", relative);
+
+  render(&data, delimiter, config.csv_row_limit, Some(&download))
+}
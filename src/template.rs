@@ -1,17 +1,126 @@
-use std::{fmt, fmt::Write as _};
+use std::{collections::HashMap, fmt, fmt::Write as _};
 
 use axum::response::Html;
 use maud::{html, Escaper, Markup, PreEscaped, Render, DOCTYPE};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-use crate::{role::Role, user::User};
+use crate::{role::Role, user::User, State};
+
+/// The wiki's name, logo and footer. The name falls back to `name` in `site_vars`, and the
+/// footer to `organization` and `contact`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Branding {
+  /// Shown after every page's title.
+  pub title: Option<String>,
+  /// Where the logo at the top of the sidebar is - `None` to not have one.
+  pub logo: Option<String>,
+  /// HTML for the footer of every page, with `{{ site.<name> }}` filled in.
+  pub footer: Option<String>,
+}
+
+impl Default for Branding {
+  fn default() -> Self {
+    Self {
+      title: None,
+      logo: Some(String::from("/logo.png")),
+      footer: None,
+    }
+  }
+}
+
+impl Branding {
+  pub fn title<'a>(&'a self, site: &'a HashMap<String, String>) -> Option<&'a str> {
+    self
+      .title
+      .as_deref()
+      .or_else(|| site.get("name").map(String::as_str))
+  }
+
+  pub fn footer(&self, site: &HashMap<String, String>) -> Option<Markup> {
+    if let Some(footer) = &self.footer {
+      return Some(PreEscaped(crate::site::substitute(footer, site)));
+    }
+
+    let organization = site.get("organization")?;
+
+    Some(html! {
+      "© "
+      @if let Some(contact) = site.get("contact") {
+        a href=(contact) { (organization) }
+      } @else {
+        (organization)
+      }
+    })
+  }
+}
+
+/// Extra markup added to every page, like analytics, fonts or site verification tags.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Inject {
+  /// HTML added to the end of every page's `<head>`.
+  pub head: Option<String>,
+  pub stylesheets: Vec<String>,
+  pub scripts: Vec<String>,
+}
+
+/// What link previews (OpenGraph and Twitter cards) show for a page.
+#[derive(Clone, Default)]
+pub struct Metadata {
+  pub title: String,
+  pub description: Option<String>,
+  pub url: Option<String>,
+  pub author: Option<String>,
+  pub published: Option<OffsetDateTime>,
+  pub modified: Option<OffsetDateTime>,
+}
+
+impl Render for Metadata {
+  fn render(&self) -> Markup {
+    html! {
+      meta property="og:type" content="article";
+      meta property="og:title" content=(self.title);
+      meta name="twitter:card" content="summary";
+      meta name="twitter:title" content=(self.title);
+      @if let Some(description) = &self.description {
+        meta name="description" content=(description);
+        meta property="og:description" content=(description);
+        meta name="twitter:description" content=(description);
+      }
+      @if let Some(url) = &self.url {
+        meta property="og:url" content=(url);
+        link rel="canonical" href=(url);
+      }
+      @if let Some(author) = &self.author {
+        meta name="author" content=(author);
+        meta property="article:author" content=(author);
+      }
+      @if let Some(published) = self.published {
+        meta property="article:published_time" content=(published.format(&Rfc3339).unwrap_or_default());
+      }
+      @if let Some(modified) = self.modified {
+        meta property="article:modified_time" content=(modified.format(&Rfc3339).unwrap_or_default());
+      }
+    }
+  }
+}
 
 #[derive(Clone, Default)]
 pub struct Template {
   head: Option<Markup>,
+  metadata: Option<Metadata>,
   title: Option<Markup>,
   script: Option<String>,
   tabs: Option<Markup>,
+  breadcrumbs: Option<Markup>,
   content: Option<Markup>,
+  extra_css: Vec<String>,
+  extra_js: Vec<String>,
+  print: bool,
+  /// The theme's template to use instead of `base.html`.
+  base: Option<String>,
 }
 
 impl Template {
@@ -19,11 +128,22 @@ impl Template {
     Self::default()
   }
 
+  /// Uses the theme's template called `base`, if there is one, instead of `base.html`.
+  pub fn base(mut self, base: Option<String>) -> Self {
+    self.base = base;
+    self
+  }
+
   pub fn head(mut self, head: Markup) -> Self {
     self.head = Some(head);
     self
   }
 
+  pub fn metadata(mut self, metadata: Metadata) -> Self {
+    self.metadata = Some(metadata);
+    self
+  }
+
   pub fn title(mut self, title: impl Render) -> Self {
     self.title = Some(html! { (title) });
     self
@@ -39,25 +159,164 @@ impl Template {
     self
   }
 
+  /// Links to each directory above the page at `path`.
+  pub fn breadcrumbs(mut self, path: &str) -> Self {
+    let segments: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    self.breadcrumbs = Some(html! {
+      ol {
+        li { a href="/" { "Home" } }
+        @for (i, segment) in segments.iter().enumerate() {
+          li {
+            @if i + 1 == segments.len() {
+              (segment)
+            } @else {
+              a href={ "/" (segments[..=i].join("/")) } { (segment) }
+            }
+          }
+        }
+      }
+    });
+    self
+  }
+
   pub fn content(mut self, content: Markup) -> Self {
     self.content = Some(content);
     self
   }
 
-  pub fn render(self, user: Option<User>) -> Html<String> {
+  /// Stylesheets and scripts for just this page, from its front matter.
+  pub fn extra_assets(mut self, css: Vec<String>, js: Vec<String>) -> Self {
+    self.extra_css = css;
+    self.extra_js = js;
+    self
+  }
+
+  /// Leaves out the sidebar, header and tabs, for printing.
+  pub fn print(mut self, print: bool) -> Self {
+    self.print = print;
+    self
+  }
+
+  /// The config's `inject`, then this page's own stylesheets and scripts.
+  fn extra_head(&self, inject: &Inject) -> Markup {
+    html! {
+      @for stylesheet in inject.stylesheets.iter().chain(&self.extra_css) {
+        link rel="stylesheet" type="text/css" href=(stylesheet);
+      }
+      @for script in inject.scripts.iter().chain(&self.extra_js) {
+        script src=(script) defer {}
+      }
+      @if let Some(head) = &inject.head {
+        (PreEscaped(head))
+      }
+    }
+  }
+
+  /// Renders the page with `base.html` - the theme's, or else the built-in one - falling back to
+  /// the built-in markup if it can't be rendered.
+  pub fn render(self, user: Option<User>, state: &State) -> Html<String> {
+    let lang = user
+      .as_ref()
+      .and_then(|user| user.locale.clone())
+      .unwrap_or_else(|| String::from("en"));
+
+    let markup = |markup: &Option<Markup>| markup.as_ref().map(|markup| markup.0.as_str());
+    let metadata = self.metadata.as_ref().map(|metadata| metadata.render().0);
+    let sidebar = state.navigation.sidebar();
+    let navigation = state.navigation.navigation();
+    let popular = crate::views::popular_list(user.as_ref(), state);
+    let stylesheet = state.assets.url("bundle.css");
+    let bundle = state.assets.url("bundle.js");
+    let config = state.config();
+    let footer = config.branding.footer(&config.site_vars);
+    let extra_head = self.extra_head(&config.inject);
+    let timezone = user
+      .as_ref()
+      .and_then(|user| user.timezone.as_deref())
+      .or(config.dates.timezone.as_deref());
+
+    let chrome = Chrome {
+      lang: &lang,
+      title: markup(&self.title),
+      metadata: metadata.as_deref(),
+      head: markup(&self.head),
+      extra_head: &extra_head.0,
+      script: self.script.as_deref(),
+      tabs: markup(&self.tabs),
+      breadcrumbs: markup(&self.breadcrumbs),
+      content: markup(&self.content),
+      sidebar: markup(&sidebar),
+      navigation: markup(&navigation),
+      popular: markup(&popular),
+      stylesheet: &stylesheet,
+      bundle: &bundle,
+      site: &config.site_vars,
+      site_title: config.branding.title(&config.site_vars),
+      logo: config.branding.logo.as_deref(),
+      footer: footer.as_ref().map(|footer| footer.0.as_str()),
+      print: self.print,
+      user: user.as_ref(),
+      administrator: user
+        .as_ref()
+        .map(|user| user.roles.contains(&Role::Administrator))
+        .unwrap_or(false),
+      drafts: config.drafts,
+      timezone,
+      relative_dates: config.dates.relative,
+    };
+
+    let base = self.base.as_deref().unwrap_or(crate::theme::BASE);
+
+    if let Some(html) = state.theme.render(base, &chrome) {
+      return Html(html);
+    }
+
+    self.builtin(state, lang, user, sidebar, navigation, popular)
+  }
+
+  fn builtin(
+    self,
+    state: &State,
+    lang: String,
+    user: Option<User>,
+    sidebar: Option<Markup>,
+    navigation: Option<Markup>,
+    popular: Option<Markup>,
+  ) -> Html<String> {
+    let config = state.config();
+    let site = &config.site_vars;
+    let site_title = config.branding.title(site);
+    let footer = config.branding.footer(site);
+    let extra_head = self.extra_head(&config.inject);
+    let print = self.print;
+    let dates = &config.dates;
+    let timezone = user
+      .as_ref()
+      .and_then(|user| user.timezone.as_deref())
+      .or(dates.timezone.as_deref());
+
     let PreEscaped(html) = html! {
       (DOCTYPE)
       meta charset="utf-8";
-      html lang="en" {
+      html lang=(lang) {
         head {
           title {
             @if let Some(title) = self.title {
-              (title) " - "
+              (title)
+              @if site_title.is_some() {
+                " - "
+              }
+            }
+            @if let Some(site_title) = site_title {
+              (site_title)
             }
-            "Title"
           }
-          link rel="stylesheet" type="text/css" href="/bundle.css";
-          script type="module" src="/bundle.js" {}
+          @if let Some(metadata) = &self.metadata {
+            (metadata)
+          }
+          link rel="stylesheet" type="text/css" href=(state.assets.url("bundle.css"));
+          script type="module" src=(state.assets.url("bundle.js")) {}
           @if let Some(head) = self.head {
             (head)
           }
@@ -66,71 +325,120 @@ impl Template {
               (PreEscaped(script))
             }
           }
+          (extra_head)
         }
 
-        body {
-          #sidebar {
-            a href="/" {
-              img src="/logo.png";
-            }
+        body .print[print] {
+          @if !print {
+            #sidebar {
+              @if let Some(logo) = &config.branding.logo {
+                a href="/" {
+                  img src=(logo) alt=[site_title];
+                }
+              }
 
-            fieldset {
-              legend { "Site" }
-              ul {
-                li { a href="/" { "Front page "} }
-                li { "All pages" }
-                li { a href="/meta/categories" { "Categories" } }
-                li { "Random page" }
-                li { "Recent activity" }
-                @if let Some(user) = &user {
-                  @if user.roles.contains(&Role::Administrator) {
-                    li { "Admin" }
-                  } @else {
-                    li { "Regular user" }
-                  }
+              form #quick-open action="/meta/quick-open" {
+                input type="search" name="q" placeholder="Go to page" list="quick-open-pages"
+                  autocomplete="off" aria-label="Go to page";
+                datalist #quick-open-pages {}
+              }
+
+              fieldset {
+                legend { "Site" }
+                @if let Some(sidebar) = sidebar {
+                  (sidebar)
                 } @else {
-                  li { "Not logged in" }
+                  ul {
+                    li { a href="/" { "Front page "} }
+                    li { "All pages" }
+                    li { a href="/meta/categories" { "Categories" } }
+                    li { a href="/meta/media" { "Media" } }
+                    li { a href="/meta/trash" { "Trash" } }
+                    li { "Random page" }
+                    li { "Recent activity" }
+                    @if let Some(user) = &user {
+                      @if user.roles.contains(&Role::Administrator) {
+                        li { "Admin" }
+                      } @else {
+                        li { "Regular user" }
+                      }
+                    } @else {
+                      li { "Not logged in" }
+                    }
+                  }
                 }
               }
-            }
 
-            fieldset {
-              legend { "Settings" }
-              label {
-                span { "Theme:" }
-                select #color-scheme {
-                  option value="light" { "Light" }
-                  option value="system" { "System" }
-                  option value="dark" { "Dark" }
+              @if let Some(navigation) = navigation {
+                fieldset #navigation {
+                  legend { "Navigation" }
+                  (navigation)
                 }
               }
-            }
-          }
 
-          #header {
-            #account {
-              @if let Some(user) = &user {
-                a href={ "/meta/profile/" (user.email) } {
-                  (user.name) "⟨" (user.email) "⟩"
+              @if let Some(popular) = popular {
+                fieldset #popular {
+                  legend { "Popular pages" }
+                  (popular)
+                }
+              }
+
+              fieldset #recent-changes data-timezone=[timezone] data-relative=(dates.relative) {
+                legend { "Latest changes" }
+                ol {}
+              }
+
+              fieldset {
+                legend { "Settings" }
+                label {
+                  span { "Theme:" }
+                  select #color-scheme {
+                    option value="light" { "Light" }
+                    option value="system" { "System" }
+                    option value="dark" { "Dark" }
+                  }
                 }
-                "·"
-                a href="/meta/logout" { "log out" }
-              } @else {
-                a href="/meta/login" { "log in" }
               }
             }
 
-            @if let Some(tabs) = self.tabs {
-              #tabs { (tabs) }
+            #header {
+              #account {
+                @if let Some(user) = &user {
+                  a href={ "/meta/profile/" (user.email) } {
+                    (user.name) "⟨" (user.email) "⟩"
+                  }
+                  "·"
+                  @if user.roles.contains(&Role::Administrator) {
+                    a href="/meta/admin/users" { "users" }
+                    "·"
+                  }
+                  @if config.drafts {
+                    a href="/meta/drafts" { "drafts" }
+                    "·"
+                  }
+                  a href="/meta/logout" { "log out" }
+                } @else {
+                  a href="/meta/login" { "log in" }
+                }
+              }
+
+              @if let Some(tabs) = self.tabs {
+                #tabs { (tabs) }
+              }
             }
           }
 
           @if let Some(content) = self.content {
-            #content { (content) }
+            #content {
+              @if let Some(breadcrumbs) = self.breadcrumbs.filter(|_| !print) {
+                nav #breadcrumbs aria-label="Breadcrumbs" { (breadcrumbs) }
+              }
+              (content)
+            }
           }
 
-          #footer {
-            "© Copyright 2008 by " a href="http://domain.invalid" { "you" }
+          @if let Some(footer) = footer {
+            #footer { (footer) }
           }
         }
       }
@@ -140,6 +448,45 @@ impl Template {
   }
 }
 
+/// Everything a theme's `base.html` can use. The markup is already escaped, so it should be
+/// used with Tera's `safe` filter.
+#[derive(Serialize)]
+struct Chrome<'a> {
+  lang: &'a str,
+  title: Option<&'a str>,
+  metadata: Option<&'a str>,
+  head: Option<&'a str>,
+  /// The config's `inject`, and the page's `extra_css` and `extra_js`.
+  extra_head: &'a str,
+  script: Option<&'a str>,
+  tabs: Option<&'a str>,
+  breadcrumbs: Option<&'a str>,
+  content: Option<&'a str>,
+  /// The rendered `_sidebar` and `_navigation` pages.
+  sidebar: Option<&'a str>,
+  navigation: Option<&'a str>,
+  /// The most viewed pages, when `views` is set.
+  popular: Option<&'a str>,
+  /// Where to link to `bundle.css` and `bundle.js`, with their fingerprints.
+  stylesheet: &'a str,
+  bundle: &'a str,
+  /// The config's `site_vars`.
+  site: &'a HashMap<String, String>,
+  /// From the config's `branding`.
+  site_title: Option<&'a str>,
+  logo: Option<&'a str>,
+  footer: Option<&'a str>,
+  /// Whether this is a page's printable version, which shouldn't have the sidebar or header.
+  print: bool,
+  user: Option<&'a User>,
+  administrator: bool,
+  /// Whether `drafts` is on, so there's a drafts page to link to.
+  drafts: bool,
+  /// The timezone and style that dates in the sidebar are shown in.
+  timezone: Option<&'a str>,
+  relative_dates: bool,
+}
+
 pub struct PrettyPrint<T: fmt::Debug>(pub T);
 
 impl<T: fmt::Debug> Render for PrettyPrint<T> {
@@ -0,0 +1,66 @@
+//! Reskinning the wiki with [Tera](https://tera.netlify.app) templates from the
+//! `templates_directory`, which override the built-in ones with the same names.
+
+use std::path::Path;
+
+use include_dir::{include_dir, Dir};
+use serde::Serialize;
+use tera::{Context, Tera};
+
+/// The template that wraps every page, with the sidebar, header, and footer.
+pub const BASE: &str = "base.html";
+
+/// The templates in `templates` when the wiki was compiled.
+static BUILT_IN: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/templates");
+
+#[derive(Default)]
+pub struct Theme {
+  /// `None` until the templates are loaded, so the built-in markup is used.
+  tera: Option<Tera>,
+}
+
+impl Theme {
+  pub fn load(templates_directory: &Path) -> Result<Self, tera::Error> {
+    let mut tera = if templates_directory.is_dir() {
+      let glob = templates_directory.join("**").join("*.html");
+      Tera::new(&glob.to_string_lossy())?
+    } else {
+      Tera::default()
+    };
+
+    let overridden: Vec<String> = tera.get_template_names().map(str::to_string).collect();
+
+    if !overridden.is_empty() {
+      log::info!("Using the templates in {}", templates_directory.display());
+    }
+
+    let built_in = BUILT_IN
+      .files()
+      .filter_map(|file| Some((file.path().to_str()?, file.contents_utf8()?)))
+      .filter(|(name, _)| !overridden.iter().any(|overridden| overridden == name));
+
+    tera.add_raw_templates(built_in)?;
+
+    Ok(Self { tera: Some(tera) })
+  }
+
+  /// Renders the template called `name`, if the theme has one. Errors are logged, so the
+  /// built-in markup can be used instead of showing a broken page.
+  pub fn render(&self, name: &str, context: &impl Serialize) -> Option<String> {
+    let tera = self.tera.as_ref()?;
+
+    if !tera.get_template_names().any(|template| template == name) {
+      return None;
+    }
+
+    let rendered = Context::from_serialize(context).and_then(|context| tera.render(name, &context));
+
+    match rendered {
+      Ok(html) => Some(html),
+      Err(err) => {
+        log::warn!("Couldn't render the `{}` template: {:?}", name, err);
+        None
+      },
+    }
+  }
+}
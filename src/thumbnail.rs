@@ -0,0 +1,175 @@
+//! Smaller versions of the images in the wiki, at `/meta/thumb/<path>?w=&h=`, so pages can use
+//! responsive images without anyone having to upload every size.
+
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use axum::{
+  extract::{self, Extension, Query},
+  http::{HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+};
+use image::ImageFormat;
+use serde::Deserialize;
+
+use crate::{user::User, State};
+
+/// The widths and heights thumbnails are made at - anything else is rounded up to the next
+/// one, so nobody can fill the cache with every size in between.
+const SIZES: [u32; 10] = [64, 128, 256, 320, 480, 640, 800, 1024, 1600, 2048];
+
+/// The biggest width or height that can be asked for.
+const MAX_SIZE: u32 = SIZES[SIZES.len() - 1];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Image(#[from] image::ImageError),
+  #[error("'{0}' isn't an image that can be resized")]
+  NotAnImage(String),
+  #[error("Give a width (`w`) or a height (`h`) of at most {} pixels", MAX_SIZE)]
+  Size,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Io(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        StatusCode::NOT_FOUND.into_response()
+      },
+      Self::NotAnImage(_) => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+      Self::Size => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+      Self::Io(_) | Self::Image(_) => {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+      },
+    }
+  }
+}
+
+#[derive(Deserialize)]
+pub struct Size {
+  w: Option<u32>,
+  h: Option<u32>,
+}
+
+pub async fn handler(
+  extract::Path(path): extract::Path<String>,
+  Query(size): Query<Size>,
+  user: Option<User>,
+  request_headers: HeaderMap,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Response, Error> {
+  let config = state.config();
+
+  let relative = PathBuf::from(path.trim_start_matches('/'));
+  let is_inside = crate::page::is_safe_path(&relative)
+    && crate::namespace::is_visible(&relative, user.as_ref(), &config);
+
+  let source = config.pages_directory.join(&relative);
+  let mime = mime_guess::from_path(&source).first_or_octet_stream();
+
+  let format = match ImageFormat::from_path(&source) {
+    Ok(format)
+      if is_inside
+        && mime.type_() == "image"
+        && config.allowed_mime_types.contains(mime.essence_str()) =>
+    {
+      format
+    },
+    _ => return Err(Error::NotAnImage(path)),
+  };
+
+  // The image is scaled to fit inside the box, so a missing side doesn't limit it.
+  let (width, height) = match (size.w, size.h) {
+    (None, None) => return Err(Error::Size),
+    (width, height) => (width.unwrap_or(MAX_SIZE), height.unwrap_or(MAX_SIZE)),
+  };
+
+  if width == 0 || height == 0 {
+    return Err(Error::Size);
+  }
+
+  let round_up = |size: u32| SIZES.iter().copied().find(|allowed| size <= *allowed);
+  let (width, height) = match (round_up(width), round_up(height)) {
+    (Some(width), Some(height)) => (width, height),
+    _ => return Err(Error::Size),
+  };
+
+  // Each image's thumbnails are kept together, and a new version of it gets new ones.
+  let modified = tokio::fs::metadata(&source).await?.modified()?;
+  let directory = config
+    .cache_directory
+    .join("thumbnails")
+    .join(crate::assets::fingerprint(
+      relative.to_string_lossy().as_bytes(),
+    ));
+  let version = crate::assets::fingerprint(format!("{:?}", modified).as_bytes());
+  let extension = format.extensions_str().first().copied().unwrap_or("img");
+
+  let thumbnail = directory.join(format!("{}-{}x{}.{}", version, width, height, extension));
+
+  if !thumbnail.is_file() {
+    tokio::task::spawn_blocking({
+      let thumbnail = thumbnail.clone();
+      move || {
+        resize(&source, &thumbnail, width, height, format)?;
+        prune(&directory, &version)
+      }
+    })
+    .await
+    .unwrap()?;
+  }
+
+  // Shared caches mustn't keep images that not everyone can see.
+  let cache_control = match crate::namespace::is_visible(&relative, None, &config) {
+    true => "public, no-cache",
+    false => "private, no-cache",
+  };
+
+  Ok(crate::file::serve(&thumbnail, &request_headers, cache_control).await?)
+}
+
+/// Removes the thumbnails of older versions of an image.
+fn prune(directory: &Path, version: &str) -> Result<(), Error> {
+  for entry in std::fs::read_dir(directory)? {
+    let entry = entry?;
+
+    if !entry.file_name().to_string_lossy().starts_with(version) {
+      std::fs::remove_file(entry.path())?;
+    }
+  }
+
+  Ok(())
+}
+
+fn resize(
+  source: &Path,
+  thumbnail: &Path,
+  width: u32,
+  height: u32,
+  format: ImageFormat,
+) -> Result<(), Error> {
+  let image = image::open(source)?;
+
+  // Images are only ever made smaller.
+  let image = if image.width() <= width && image.height() <= height {
+    image
+  } else {
+    image.thumbnail(width, height)
+  };
+
+  if let Some(directory) = thumbnail.parent() {
+    std::fs::create_dir_all(directory)?;
+  }
+
+  // It's written somewhere else first, so a half-written thumbnail is never served.
+  let partial = thumbnail.with_extension("partial");
+  image.save_with_format(&partial, format)?;
+  std::fs::rename(&partial, thumbnail)?;
+
+  Ok(())
+}
@@ -0,0 +1,342 @@
+//! Showing one page inside another, with `{{< include "other/page" >}}` or `![[other/page]]`.
+//! Which pages include which is indexed, so that when a page changes, every page showing it is
+//! purged from caches, and gets a new `ETag`.
+
+use std::{
+  cell::{Cell, RefCell},
+  collections::{HashMap, HashSet},
+  ffi::OsStr,
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+};
+
+use maud::{html, PreEscaped};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+  format::Format,
+  front_matter::FrontMatter,
+  namespace::Settings,
+  page::Page,
+  shortcodes::{Args, Output, Shortcode},
+  State,
+};
+
+/// How many pages deep pages can be included in each other.
+const MAX_DEPTH: usize = 5;
+/// How many pages can be included in one page, counting the pages included in those.
+const MAX_INCLUDES: usize = 50;
+
+thread_local! {
+  /// The pages being included right now - each one is rendered inside the one before it, on the
+  /// same thread.
+  static INCLUDING: RefCell<Vec<String>> = RefCell::new(Vec::new());
+  /// How many pages have been included in the page being rendered on this thread.
+  static INCLUDED: Cell<usize> = Cell::new(0);
+}
+
+/// Starts counting includes again, unless this is a page being included in another.
+pub fn begin_page() {
+  let nested = INCLUDING.with(|including| !including.borrow().is_empty());
+
+  if !nested {
+    INCLUDED.with(|included| included.set(0));
+  }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Pandoc(#[from] crate::pandoc::Error),
+  #[error(transparent)]
+  Table(#[from] crate::table::Error),
+  #[error(transparent)]
+  Notebook(#[from] crate::notebook::Error),
+  #[error("'{0}' includes itself")]
+  Cycle(String),
+  #[error("Pages can only be included {} deep", MAX_DEPTH)]
+  TooDeep,
+  #[error("Only {} pages can be included in one page", MAX_INCLUDES)]
+  TooMany,
+}
+
+/// `{{< include "other/page" >}}` - another page, rendered.
+pub struct Include;
+
+impl Shortcode for Include {
+  fn render(&self, args: &Args, state: &Arc<State>) -> Result<Output, crate::shortcodes::Error> {
+    let path = args
+      .get("page", 0)
+      .ok_or(crate::shortcodes::Error::MissingArgument("include", "page"))?;
+    let path = path.trim_matches('/').to_string();
+
+    let included = INCLUDING.with(|including| {
+      let mut including = including.borrow_mut();
+
+      if including.contains(&path) {
+        return Err(Error::Cycle(path.clone()));
+      }
+
+      if including.len() >= MAX_DEPTH {
+        return Err(Error::TooDeep);
+      }
+
+      let count = INCLUDED.with(|included| {
+        included.set(included.get() + 1);
+        included.get()
+      });
+      if count > MAX_INCLUDES {
+        return Err(Error::TooMany);
+      }
+
+      including.push(path.clone());
+
+      Ok(())
+    });
+
+    let html = included.and_then(|_| {
+      let html = render(&path, state);
+      INCLUDING.with(|including| including.borrow_mut().pop());
+
+      html
+    });
+
+    // A broken include shouldn't break the rest of the page.
+    let markup = match html {
+      Ok(html) => html! { .included { (PreEscaped(html)) } },
+      Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => html! {
+        .notice.included-error {
+          "There's no page at " a href={ "/meta/new/" (path) } { (path) } " to include."
+        }
+      },
+      Err(err) => html! {
+        .notice.included-error { "Couldn't include " (path) ": " (err) }
+      },
+    };
+
+    Ok(Output::Html(markup))
+  }
+}
+
+/// The page at `path`, rendered like it would be on its own.
+fn render(path: &str, state: &Arc<State>) -> Result<String, Error> {
+  let config = state.config();
+
+  // Whoever's reading might not be allowed to see it, so only pages everyone can see are shown.
+  // It's the file that's found that matters - with loose path matching, `Private/x` is
+  // `private/x`.
+  let resolved = crate::acl::resolve(Path::new(path), &config);
+  if !Settings::for_path(&resolved, &config).can_read(None) {
+    return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+  }
+
+  let filepath = crate::page::find_file(path, &config)?;
+
+  let file = std::fs::read_to_string(&filepath)?;
+  let (_, data, _) = FrontMatter::split(&file);
+
+  let html = if let Some(delimiter) = crate::table::delimiter(&filepath) {
+    crate::table::render(&data, delimiter, config.csv_row_limit, None)?.into_string()
+  } else if filepath.extension() == Some(OsStr::new("ipynb")) {
+    crate::notebook::to_html(&data, state.clone())?
+  } else {
+    let format = crate::page::format_of(&filepath);
+
+    state.render.to_html(data, format, state.clone())?
+  };
+
+  // Whoever wrote the included page might not be trusted, even if whoever wrote this one is.
+  match config.sanitize_html.enabled {
+    true => Ok(crate::sanitize::clean(&html)),
+    false => Ok(html),
+  }
+}
+
+#[derive(Clone)]
+struct Entry {
+  /// The page's file, relative to the pages directory.
+  file: PathBuf,
+  /// The paths of the pages it includes.
+  includes: Vec<String>,
+}
+
+/// Which pages each page includes, by its path without an extension.
+#[derive(Default)]
+pub struct Transclusions {
+  index: RwLock<Option<Arc<HashMap<String, Entry>>>>,
+}
+
+impl Transclusions {
+  /// The files of every page shown in the page at `path`, including pages shown inside those.
+  pub async fn included_in(
+    &self,
+    path: &Path,
+    state: &State,
+  ) -> Result<Vec<PathBuf>, crate::page::Error> {
+    let index = self.load(state).await?;
+
+    let mut found = HashSet::new();
+    let mut next = vec![key(path)];
+
+    while let Some(page) = next.pop() {
+      for included in index
+        .get(&page)
+        .map(|entry| &entry.includes)
+        .into_iter()
+        .flatten()
+      {
+        if found.insert(included.clone()) {
+          next.push(included.clone());
+        }
+      }
+    }
+
+    Ok(
+      found
+        .iter()
+        .filter_map(|page| index.get(page))
+        .map(|entry| entry.file.clone())
+        .collect(),
+    )
+  }
+
+  /// The files of every page that shows the page at `path`, including pages that show those.
+  async fn including(
+    &self,
+    path: &Path,
+    state: &State,
+  ) -> Result<Vec<PathBuf>, crate::page::Error> {
+    let index = self.load(state).await?;
+
+    let mut found = HashSet::new();
+    let mut next = vec![key(path)];
+
+    while let Some(page) = next.pop() {
+      for (including, entry) in index.iter() {
+        if entry.includes.contains(&page) && found.insert(including.clone()) {
+          next.push(including.clone());
+        }
+      }
+    }
+
+    Ok(
+      found
+        .iter()
+        .filter_map(|page| index.get(page))
+        .map(|entry| entry.file.clone())
+        .collect(),
+    )
+  }
+
+  /// Purges the pages that include each page changed by a commit, and keeps the index up to date.
+  pub async fn watch(state: Arc<State>) {
+    let mut changes = state.git.subscribe();
+
+    loop {
+      match changes.recv().await {
+        Ok(change) => {
+          if let Err(err) = state.transclusions.update(&change.files, &state).await {
+            log::warn!("Couldn't update the included pages: {}", err);
+          }
+        },
+        // Some commits were missed, so it's read from scratch next time.
+        Err(RecvError::Lagged(_)) => *state.transclusions.index.write().unwrap() = None,
+        Err(RecvError::Closed) => break,
+      }
+    }
+  }
+
+  async fn load(&self, state: &State) -> Result<Arc<HashMap<String, Entry>>, crate::page::Error> {
+    let cached = self.index.read().unwrap().clone();
+    if let Some(index) = cached {
+      return Ok(index);
+    }
+
+    let config = state.config();
+    let mut index = HashMap::new();
+
+    // Binary pages can't include anything, and reading them as text would fail.
+    for page in Page::all(&config).filter(|page| !page.is_binary()) {
+      let file = page.relative_path(&config)?;
+      let contents = page.raw(state).await?;
+
+      index.insert(
+        key(&file),
+        Entry {
+          includes: includes(&contents),
+          file,
+        },
+      );
+    }
+
+    let index = Arc::new(index);
+    *self.index.write().unwrap() = Some(index.clone());
+
+    Ok(index)
+  }
+
+  async fn update(&self, files: &[PathBuf], state: &State) -> Result<(), crate::page::Error> {
+    let config = state.config();
+
+    // Pages that included the old version need purging too, so this is before the index changes.
+    for file in files {
+      for including in self.including(file, state).await? {
+        crate::cache::purge(&config, &including);
+      }
+    }
+
+    let cached = self.index.read().unwrap().clone();
+    let mut index = match cached {
+      Some(index) => HashMap::clone(&index),
+      // It'll be read from scratch when it's needed.
+      None => return Ok(()),
+    };
+
+    for file in files {
+      let is_text_page = file
+        .extension()
+        .and_then(|ext| Format::from_extension(&ext.to_string_lossy()))
+        .map(|format| !format.binary)
+        .unwrap_or(false);
+
+      if !is_text_page || file.starts_with(crate::boilerplate::DIRECTORY) {
+        continue;
+      }
+
+      match state.pages.read(&config.pages_directory.join(file)).await {
+        Ok(contents) => {
+          index.insert(
+            key(file),
+            Entry {
+              file: file.clone(),
+              includes: includes(&contents),
+            },
+          );
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+          index.remove(&key(file));
+        },
+        Err(err) => return Err(err.into()),
+      }
+    }
+
+    *self.index.write().unwrap() = Some(Arc::new(index));
+
+    Ok(())
+  }
+}
+
+fn key(path: &Path) -> String {
+  path.with_extension("").to_string_lossy().to_string()
+}
+
+/// The paths of the pages included in a page's source.
+fn includes(file: &str) -> Vec<String> {
+  crate::shortcodes::calls(file, "include")
+    .iter()
+    .filter_map(|args| args.get("page", 0))
+    .map(|path| path.trim_matches('/').to_string())
+    .collect()
+}
@@ -0,0 +1,351 @@
+//! Deleting pages, and getting them back - every deleted page is listed at `/meta/trash`, and
+//! restoring one brings back the page as it was just before the commit that deleted it.
+
+use std::{
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::{Arc, Mutex},
+};
+
+use axum::{
+  extract::{Extension, Form},
+  http::StatusCode,
+  response::{Html, IntoResponse, Redirect, Response},
+};
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+  audit::Action,
+  commit_message::{Kind, Vars},
+  date::Dates,
+  error::ErrorPage,
+  page::Page,
+  proxy::SameOrigin,
+  template::Template,
+  user::User,
+  State,
+};
+
+/// The trash's file, in `cache_directory`.
+const FILE: &str = "trash.json";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Page(#[from] crate::page::Error),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Git(#[from] crate::git::Error),
+  #[error(transparent)]
+  Acl(#[from] crate::acl::Error),
+  #[error(transparent)]
+  CommitMessage(#[from] crate::commit_message::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error("'{0}' isn't in the trash")]
+  NotInTrash(String),
+  #[error("There's already a page at '{0}', so it can't be restored")]
+  Exists(String),
+  #[error("Pages can't be deleted or restored while your edits are reviewed")]
+  Drafted,
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Page(err) => err.into_response(),
+      Self::Git(err) => err.into_response(),
+      Self::Acl(err) => err.into_response(),
+      Self::CommitMessage(err) => err.into_response(),
+      Self::NotInTrash(path) => ErrorPage::NotFound { url: Some(path) }.into_response(),
+      Self::Exists(_) => ErrorPage::Conflict {
+        message: self.to_string(),
+      }
+      .into_response(),
+      Self::Drafted => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+      Self::Io(_) | Self::Json(_) => {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+      },
+    }
+  }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Deleted {
+  /// The page's file, relative to the pages directory.
+  pub path: PathBuf,
+  pub title: String,
+  pub deleted_by: String,
+  #[serde(with = "time::serde::rfc3339")]
+  pub date: OffsetDateTime,
+  /// The commit that deleted it.
+  pub commit: String,
+}
+
+pub struct Trash {
+  file: PathBuf,
+  deleted: Mutex<Vec<Deleted>>,
+}
+
+impl Trash {
+  pub fn load(cache_directory: &Path) -> Result<Self, Error> {
+    let file = cache_directory.join(FILE);
+
+    let deleted = match std::fs::read(&file) {
+      Ok(contents) => serde_json::from_slice(&contents)?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+      Err(err) => return Err(err.into()),
+    };
+
+    Ok(Self {
+      file,
+      deleted: Mutex::new(deleted),
+    })
+  }
+
+  /// Everything in the trash, most recently deleted first.
+  pub fn all(&self) -> Vec<Deleted> {
+    let mut deleted = self.deleted.lock().unwrap().clone();
+    deleted.sort_by(|a, b| b.date.cmp(&a.date));
+
+    deleted
+  }
+
+  fn add(&self, deleted: Deleted) -> Result<(), Error> {
+    let mut all = self.deleted.lock().unwrap();
+    all.push(deleted);
+
+    self.save(&all)
+  }
+
+  /// Takes the page deleted by `commit` out of the trash.
+  fn take(&self, path: &Path, commit: &str) -> Result<Deleted, Error> {
+    let mut all = self.deleted.lock().unwrap();
+
+    let position = all
+      .iter()
+      .position(|deleted| deleted.path == path && deleted.commit == commit)
+      .ok_or_else(|| Error::NotInTrash(path.display().to_string()))?;
+
+    let deleted = all.remove(position);
+    self.save(&all)?;
+
+    Ok(deleted)
+  }
+
+  fn save(&self, all: &[Deleted]) -> Result<(), Error> {
+    std::fs::write(&self.file, serde_json::to_vec(all)?)?;
+
+    Ok(())
+  }
+}
+
+/// Deletes the page, and puts it in the trash.
+pub async fn delete_handler(
+  page: Page,
+  user: User,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Redirect, Error> {
+  let config = state.config();
+
+  crate::acl::check_edit(&user, &page.path, &config)?;
+  page.check_archived(None, &user, &state).await?;
+
+  if crate::draft::is_drafted(&user, &config) {
+    return Err(Error::Drafted);
+  }
+
+  let path = page.relative_path(&config)?;
+  let (context, _) = page.context(&state).await?;
+
+  let message = config.commit_messages.render(
+    Kind::Delete,
+    Vars {
+      path: &page.path.to_string_lossy(),
+      title: &context.title,
+      summary: None,
+    },
+  )?;
+
+  let writing = state.git.writing().lock().await;
+  let original = tokio::fs::read(&page.filepath).await?;
+  tokio::fs::remove_file(&page.filepath).await?;
+
+  let committed = (|| {
+    state.git.remove_file(&path)?;
+    state.git.commit(&message, &user)
+  })();
+
+  // If it can't be committed, the page is put back. Once it's committed it's been deleted,
+  // even if it can't be pushed yet.
+  if let Err(err) = committed {
+    tokio::fs::write(&page.filepath, &original).await?;
+    state.git.add_file(&path)?;
+
+    return Err(err.into());
+  }
+  let pushed = state.git.push();
+  drop(writing);
+
+  crate::cache::purge(&config, &path);
+
+  state
+    .audit
+    .record(&user.email, Action::Delete, path.to_string_lossy(), None)
+    .await;
+
+  let commit = state
+    .git
+    .recent_changes()
+    .into_iter()
+    .find(|change| change.files.contains(&path))
+    .map(|change| change.hash);
+
+  if let Some(commit) = commit {
+    state.trash.add(Deleted {
+      path,
+      title: context.title,
+      deleted_by: user.name,
+      date: OffsetDateTime::now_utc(),
+      commit,
+    })?;
+  }
+
+  pushed?;
+
+  Ok(Redirect::to("/meta/trash"))
+}
+
+/// The deleted pages that `user` could restore - nobody else needs to know what was deleted.
+pub async fn handler(user: User, Extension(state): Extension<Arc<State>>) -> Html<String> {
+  let config = state.config();
+  let dates = Dates::new(Some(&user), &config.dates);
+  let deleted: Vec<_> = state
+    .trash
+    .all()
+    .into_iter()
+    .filter(|page| crate::acl::can_edit(&user, &page.path.with_extension(""), &config))
+    .collect();
+
+  let content = maud::html! {
+    @if deleted.is_empty() {
+      p { "The trash is empty." }
+    } @else {
+      table #trash {
+        thead {
+          tr {
+            th { "Page" }
+            th { "Deleted by" }
+            th { "When" }
+            th {}
+          }
+        }
+        tbody {
+          @for page in &deleted {
+            @let path = page.path.with_extension("");
+            tr {
+              td { (page.title) " " small { (path.display()) } }
+              td { (page.deleted_by) }
+              td { (dates.render(page.date)) }
+              td {
+                form method="post" action="/meta/trash" {
+                  input type="hidden" name="path" value=(page.path.display());
+                  input type="hidden" name="commit" value=(page.commit);
+                  button type="submit" { "Restore" }
+                }
+              }
+            }
+          }
+        }
+      }
+    }
+  };
+
+  Template::new()
+    .title("Trash")
+    .content(content)
+    .render(Some(user), &state)
+}
+
+#[derive(Deserialize)]
+pub struct RestoreParams {
+  path: PathBuf,
+  commit: String,
+}
+
+/// Brings a page back as it was just before it was deleted.
+pub async fn restore_handler(
+  user: User,
+  _: SameOrigin,
+  Extension(state): Extension<Arc<State>>,
+  Form(params): Form<RestoreParams>,
+) -> Result<Redirect, Error> {
+  let config = state.config();
+  let url_path = params.path.with_extension("");
+
+  crate::acl::check_edit(&user, &url_path, &config)?;
+
+  if crate::draft::is_drafted(&user, &config) {
+    return Err(Error::Drafted);
+  }
+
+  let filepath = config.pages_directory.join(&params.path);
+  if filepath.exists() {
+    return Err(Error::Exists(url_path.display().to_string()));
+  }
+
+  let commit = Oid::from_str(&params.commit).map_err(crate::git::Error::from)?;
+  let parent = state
+    .git
+    .parent(commit)?
+    .ok_or_else(|| Error::NotInTrash(params.path.display().to_string()))?;
+  let contents = state.git.get_file(&filepath, parent)?;
+
+  let deleted = state.trash.take(&params.path, &params.commit)?;
+
+  let message = config.commit_messages.render(
+    Kind::Create,
+    Vars {
+      path: &url_path.to_string_lossy(),
+      title: &deleted.title,
+      summary: Some("Restored from the trash"),
+    },
+  )?;
+
+  let writing = state.git.writing().lock().await;
+  state.pages.write(&filepath, &contents).await?;
+
+  let committed = (|| {
+    state.git.add_file(&params.path)?;
+    state.git.commit(&message, &user)
+  })();
+
+  if let Err(err) = committed {
+    // It's put back in the trash, so it can be tried again.
+    tokio::fs::remove_file(&filepath).await?;
+    state.git.remove_file(&params.path)?;
+    state.trash.add(deleted)?;
+
+    return Err(err.into());
+  }
+  let pushed = state.git.push();
+  drop(writing);
+
+  state
+    .audit
+    .record(
+      &user.email,
+      Action::Restore,
+      params.path.to_string_lossy(),
+      None,
+    )
+    .await;
+
+  pushed?;
+
+  Ok(Redirect::to(&format!("/{}", url_path.display())))
+}
@@ -1,23 +1,44 @@
 use std::{
   collections::HashMap,
   fmt::Debug,
-  path::{Path, PathBuf},
   sync::Arc,
+  time::Duration,
 };
 
 use async_session::Session;
-use axum::{extract::Extension, response::Html};
-use cocoon::Cocoon;
+use axum::{
+  extract::{Extension, Form, Query},
+  http::StatusCode,
+  response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::CookieJar;
+use maud::PreEscaped;
 use oauth2::url::Url;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, RwLock};
 
 use crate::{
   config::Config,
+  date::Dates,
+  notify::Preferences,
+  page::Page,
   role::Role,
+  sessions::SessionIndex,
   template::{PrettyPrint, Template},
   State,
 };
 
+mod store;
+mod transfer;
+
+pub use store::{migrate, Backend, DatabaseLock, UserStore};
+pub use transfer::{export, parse, ExportFormat, Plan};
+
+/// How many of someone's commits are shown on their profile.
+const RECENT_COMMITS: usize = 10;
+/// How many commits are shown on each page of someone's contributions.
+const CONTRIBUTIONS_PER_PAGE: usize = 50;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
   #[error(transparent)]
@@ -26,6 +47,40 @@ pub enum Error {
   Ron(#[from] ron::Error),
   #[error("Cocoon error: {0:#?}")]
   Cocoon(cocoon::Error),
+  #[error(transparent)]
+  Sqlx(#[from] sqlx::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error("'{0}' isn't a timezone")]
+  UnknownTimezone(String),
+  #[error("'{0}' isn't a language tag")]
+  InvalidLocale(String),
+  #[error("'{0}' isn't an http or https URL")]
+  InvalidUrl(String),
+  #[error("Your name can't be empty")]
+  EmptyName,
+  #[error("'{0}' can't be used as a name - it names your page at user/<name>")]
+  InvalidName(String),
+  #[error("Someone else is already called '{0}'")]
+  NameTaken(String),
+  #[error("Couldn't save the users: {0}")]
+  Save(String),
+  #[error("{0} is in use - stop the wiki first, or make the change from /meta/admin/users")]
+  Locked(std::path::PathBuf),
+}
+
+impl IntoResponse for Error {
+  fn into_response(self) -> Response {
+    match self {
+      Self::UnknownTimezone(_)
+      | Self::InvalidLocale(_)
+      | Self::InvalidUrl(_)
+      | Self::EmptyName
+      | Self::InvalidName(_) => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+      Self::NameTaken(_) => (StatusCode::CONFLICT, self.to_string()).into_response(),
+      _ => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
+    }
+  }
 }
 
 impl From<cocoon::Error> for Error {
@@ -53,6 +108,21 @@ pub struct User {
   pub url: Url,
   pub approved: bool,
   pub roles: Vec<Role>,
+  /// An IANA timezone name, used when showing dates.
+  #[serde(default)]
+  pub timezone: Option<String>,
+  /// A BCP 47 language tag, like `en-GB`.
+  #[serde(default)]
+  pub locale: Option<String>,
+  /// Whether the user has changed their name or URL themselves, in which case they're
+  /// no longer updated from their login profile.
+  #[serde(default)]
+  pub edited_profile: bool,
+  /// Disabled users can't log in or make changes.
+  #[serde(default)]
+  pub disabled: bool,
+  #[serde(default)]
+  pub notifications: Preferences,
 }
 
 impl User {
@@ -89,109 +159,229 @@ impl UserKey {
   }
 }
 
-#[derive(Serialize, Deserialize)]
+/// How long to wait for more changes before saving users to the [`UserStore`].
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// A changed user to save - or `None`, to wait for everything before it - and who to tell once
+/// it's been saved.
+type Change = (Option<User>, oneshot::Sender<Result<(), String>>);
+
+/// Everyone who's logged in, kept in memory in front of the [`UserStore`].
+///
+/// Changes are saved to the store in batches, and [`UserDb::set`] waits for its batch, so the
+/// caller finds out if it couldn't be saved.
 pub struct UserDb {
-  path: PathBuf,
-  password: Vec<u8>,
-  map: HashMap<UserKey, User>,
+  store: Arc<dyn UserStore>,
+  map: Arc<RwLock<HashMap<UserKey, User>>>,
+  changes: mpsc::UnboundedSender<Change>,
+  _lock: DatabaseLock,
 }
 
 impl UserDb {
   pub async fn new(config: impl AsRef<Config>) -> Result<Self, Error> {
     let config = config.as_ref();
 
-    let password = tokio::fs::read(&config.users.password).await?;
+    let lock = DatabaseLock::acquire(&config.users)?;
+    let store: Arc<dyn UserStore> = Arc::from(config.users.store.build(&config.users).await?);
+    let mut map = store.load().await?;
 
-    if config.users.database.exists() {
-      Self::from_path(&config.users.database, &password)
-    } else {
+    if map.is_empty() {
       log::info!("Creating new user database");
 
-      let mut db = Self {
-        path: config.users.database.clone(),
-        password,
-        map: HashMap::new(),
-      };
-
       let user = User {
         name: config.users.initial.name.clone(),
         email: config.users.initial.email.clone(),
         url: config.users.initial.url.clone(),
         approved: true,
         roles: vec![Role::Administrator],
+        timezone: None,
+        locale: None,
+        edited_profile: false,
+        disabled: false,
+        notifications: Preferences::default(),
       };
 
-      db.set(user)?;
-
-      Ok(db)
+      map.insert(user.key(), user.clone());
+      store.save(&[user], &map).await?;
     }
-  }
-
-  pub fn from_path(path: impl AsRef<Path>, password: &[u8]) -> Result<Self, Error> {
-    log::info!("Loading user database from {}", path.as_ref().display());
 
-    let mut file = std::fs::File::open(path.as_ref())?;
-    let cocoon = Cocoon::new(password).parse(&mut file)?;
-    let map = ron::de::from_bytes(&cocoon)?;
+    let map = Arc::new(RwLock::new(map));
+    let (changes, receiver) = mpsc::unbounded_channel();
 
-    for (k, v) in &map {
-      log::info!("{:?}, {:?}", k, v);
-    }
+    tokio::spawn(save_changes(receiver, store.clone(), map.clone()));
 
     Ok(Self {
+      store,
       map,
-      path: path.as_ref().to_path_buf(),
-      password: password.to_vec(),
+      changes,
+      _lock: lock,
     })
   }
 
-  pub fn save(&self) -> Result<(), Error> {
-    log::info!("Saving user database");
+  pub async fn get(&self, key: &UserKey) -> Option<User> {
+    self.map.read().await.get(key).cloned()
+  }
 
-    let mut file = std::fs::File::create(&self.path)?;
-    let value = ron::to_string(&self.map)?;
+  pub async fn all(&self) -> Vec<User> {
+    self.map.read().await.values().cloned().collect()
+  }
 
-    Cocoon::new(&self.password).dump(value.as_bytes().to_vec(), &mut file)?;
+  /// For blocking tasks, which can't `await` [`UserDb::get`].
+  pub fn blocking_get(&self, key: &UserKey) -> Option<User> {
+    self.map.blocking_read().get(key).cloned()
+  }
+
+  /// Checks the store if the user isn't known yet - they might have been added by another
+  /// instance sharing the same store.
+  pub async fn fetch(&self, key: &UserKey) -> Result<Option<User>, Error> {
+    if let Some(user) = self.get(key).await {
+      return Ok(Some(user));
+    }
+
+    let user = self.store.get(key).await?;
+
+    if let Some(user) = &user {
+      self.map.write().await.insert(key.clone(), user.clone());
+    }
 
-    Ok(())
+    Ok(user)
   }
 
-  pub fn get(&self, key: &UserKey) -> Option<&User> {
-    self.map.get(key)
+  pub async fn set(&self, user: User) -> Result<(), Error> {
+    self.map.write().await.insert(user.key(), user.clone());
+
+    self.saved(Some(user)).await
   }
 
-  pub fn set(&mut self, user: User) -> Result<(), Error> {
-    self.map.insert(UserKey(user.email.clone()), user.into());
-    self.save()
+  /// Waits for every change so far to be saved, for when the wiki is stopping.
+  pub async fn flush(&self) -> Result<(), Error> {
+    self.saved(None).await
+  }
+
+  async fn saved(&self, user: Option<User>) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    let stopped = || Error::Save(String::from("the saving task has stopped"));
+
+    self.changes.send((user, sender)).map_err(|_| stopped())?;
+
+    receiver.await.map_err(|_| stopped())?.map_err(Error::Save)
+  }
+}
+
+/// Saves changed users, waiting for [`SAVE_DELAY`] after each change so a burst of them
+/// only touches the store once.
+async fn save_changes(
+  mut changes: mpsc::UnboundedReceiver<Change>,
+  store: Arc<dyn UserStore>,
+  map: Arc<RwLock<HashMap<UserKey, User>>>,
+) {
+  while let Some(change) = changes.recv().await {
+    tokio::time::sleep(SAVE_DELAY).await;
+
+    let mut changed = HashMap::new();
+    let mut waiting = Vec::new();
+
+    let batch = std::iter::once(change).chain(std::iter::from_fn(|| changes.try_recv().ok()));
+    for (user, saved) in batch {
+      if let Some(user) = user {
+        changed.insert(user.key(), user);
+      }
+
+      waiting.push(saved);
+    }
+
+    let changed: Vec<User> = changed.into_values().collect();
+    let result = if changed.is_empty() {
+      Ok(())
+    } else {
+      let users = map.read().await.clone();
+
+      store.save(&changed, &users).await.map_err(|err| {
+        log::error!("Couldn't save users: {}", err);
+        err.to_string()
+      })
+    };
+
+    for saved in waiting {
+      let _ = saved.send(result.clone());
+    }
   }
 }
 
 pub async fn profile_handler(
   axum::extract::Path(user_key): axum::extract::Path<UserKey>,
   user: Option<User>,
+  jar: CookieJar,
+  Extension(index): Extension<SessionIndex>,
   Extension(state): Extension<Arc<State>>,
 ) -> Result<Html<String>, crate::page::Error> {
-  let profile = {
-    let users = state.users.lock().unwrap();
-    users.get(&user_key).unwrap().clone()
+  let profile = state.users.get(&user_key).await.unwrap();
+
+  // You can only see your own sessions.
+  let sessions = match &user {
+    Some(user) if user.email == profile.email => Some(index.list(&user.email).await?),
+    _ => None,
+  };
+  let current_session = crate::auth::current_session_id(&jar);
+  let config = state.config();
+  let email_enabled = config.email.is_some();
+  let dates = Dates::new(user.as_ref(), &config.dates);
+
+  let user_page = crate::acl::user_page(&profile);
+  let can_edit = user
+    .as_ref()
+    .map(|user| crate::acl::can_edit(user, &user_page, &config))
+    .unwrap_or(false);
+
+  let user_page_html = match Page::from_path(user_page.clone(), user.clone(), &config) {
+    Ok(page) => Some(page.renderer(state.clone()).await?.html().to_string()),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+    Err(err) => return Err(err.into()),
   };
 
   let recent_commits = tokio::task::spawn_blocking({
     let profile = profile.clone();
-    move || state.git.user_history(&profile.key(), Some(10), &state)
+    let state = state.clone();
+    move || {
+      state
+        .git
+        .user_history(&profile.key(), 0, Some(RECENT_COMMITS), &*state.users)
+    }
   })
   .await
   .unwrap()?;
+  let more_commits = recent_commits.len() == RECENT_COMMITS;
+  let recent_commits = visible_commits(recent_commits, user.as_ref(), &config);
 
   let content = maud::html! {
     @if let Some(user) = &user {
       pre { (PrettyPrint(user)) }
 
-      h1 { (profile.name) }
+      h1 {
+        (profile.name)
+        @if profile.disabled {
+          " " span .badge.banned { "banned" }
+        }
+      }
+
+      @if let Some(html) = user_page_html {
+        #user-page {
+          (PreEscaped(html))
+        }
+        @if can_edit {
+          a href={ "/meta/edit/" (user_page.display()) } { "edit" }
+        }
+      } @else if can_edit {
+        a href={ "/meta/new/" (user_page.display()) } { "create your page" }
+      }
 
       ul {
         li {
-          a href=(profile.url) { (profile.url) }
+          @if is_web_url(&profile.url) {
+            a href=(profile.url) { (profile.url) }
+          } @else {
+            (profile.url)
+          }
         }
         li {
           a href={"mailto:" (profile.email)} { (profile.email) }
@@ -199,9 +389,9 @@ pub async fn profile_handler(
       }
 
       ol #commits {
-        @for commit in recent_commits.iter().take(10) {
+        @for commit in &recent_commits {
           li {
-            .date { (commit.date) }
+            .date { (dates.render(commit.date)) }
             .message { (commit.message) }
             ul .files {
               @for file in &commit.files {
@@ -213,13 +403,331 @@ pub async fn profile_handler(
           }
         }
       }
+      @if more_commits {
+        a href={ "/meta/profile/" (profile.key().email()) "/contributions" } { "all contributions" }
+      }
+
+      @if let Some(sessions) = sessions {
+        h2 { "Edit profile" }
+        form #edit-profile method="post" action="/meta/profile" {
+          label {
+            "Name"
+            input type="text" name="name" required value=(user.name);
+          }
+          label {
+            "URL"
+            input type="url" name="url" required value=(user.url);
+          }
+          label {
+            "Timezone"
+            input #timezone type="text" name="timezone" placeholder="UTC"
+              value=(user.timezone.as_deref().unwrap_or(""));
+          }
+          label {
+            "Language"
+            input #locale type="text" name="locale" placeholder="en"
+              value=(user.locale.as_deref().unwrap_or(""));
+          }
+          button type="submit" { "save" }
+        }
+
+        h2 { "Notifications" }
+        @if !email_enabled {
+          p { "This wiki isn't set up to send emails, so these won't be sent yet." }
+        }
+        form #notifications method="post" action="/meta/notifications" {
+          @if user.roles.contains(&Role::Administrator) {
+            label {
+              input type="checkbox" name="new_users" checked[user.notifications.new_users];
+              "Email me when someone new logs in"
+            }
+          }
+          label {
+            input type="checkbox" name="page_edits" checked[user.notifications.page_edits];
+            "Email me when pages I'm watching are edited"
+          }
+          button type="submit" { "save" }
+        }
+        @if !user.notifications.watching.is_empty() {
+          h3 { "Watching" }
+          ul #watching {
+            @for path in &user.notifications.watching {
+              li {
+                a href={ "/" (path) } { (path) }
+                form .watch method="post" action={ "/meta/watch/" (path) } {
+                  button type="submit" { "unwatch" }
+                }
+              }
+            }
+          }
+        }
+
+        h2 { "Sessions" }
+        table #sessions {
+          thead {
+            tr {
+              th { "Signed in" }
+              th { "Last seen" }
+              th { "Browser" }
+              th {}
+            }
+          }
+          tbody {
+            @for session in &sessions {
+              tr {
+                td { (dates.render(session.created())) }
+                td { (dates.render(session.last_seen())) }
+                td { (session.user_agent.as_deref().unwrap_or("unknown")) }
+                td {
+                  @if current_session.as_deref() == Some(session.id.as_str()) {
+                    "this session"
+                  } @else {
+                    form method="post" action="/meta/sessions/revoke" {
+                      input type="hidden" name="id" value=(session.id);
+                      button type="submit" { "revoke" }
+                    }
+                  }
+                }
+              }
+            }
+          }
+        }
+        form method="post" action="/meta/sessions/revoke-all" {
+          button type="submit" { "log out everywhere" }
+        }
+      }
     }
   };
 
   let html = Template::new()
     .title(profile.url)
     .content(content)
-    .render(user);
+    .render(user, &state);
 
   Ok(html)
 }
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ContributionsQuery {
+  page: usize,
+}
+
+pub async fn contributions_handler(
+  axum::extract::Path(user_key): axum::extract::Path<UserKey>,
+  Query(query): Query<ContributionsQuery>,
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Html<String>, crate::page::Error> {
+  let profile = match state.users.get(&user_key).await {
+    Some(profile) => profile,
+    None => return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+  };
+
+  let config = state.config();
+  let dates = Dates::new(user.as_ref(), &config.dates);
+  let page = query.page.max(1);
+
+  let (count, commits) = tokio::task::spawn_blocking({
+    let key = profile.key();
+    let state = state.clone();
+    move || {
+      state.git.user_contributions(
+        &key,
+        (page - 1).saturating_mul(CONTRIBUTIONS_PER_PAGE),
+        CONTRIBUTIONS_PER_PAGE,
+        &*state.users,
+      )
+    }
+  })
+  .await
+  .unwrap()?;
+  let commits = visible_commits(commits, user.as_ref(), &config);
+
+  let pages = ((count + CONTRIBUTIONS_PER_PAGE - 1) / CONTRIBUTIONS_PER_PAGE).max(1);
+  let url = format!("/meta/profile/{}/contributions", profile.key().email());
+
+  // The commits are newest first, so each day's commits are next to each other.
+  let mut days: Vec<(String, Vec<&crate::git::Commit>)> = Vec::new();
+  for commit in &commits {
+    let day = dates.day(commit.date);
+    match days.last_mut() {
+      Some((last, commits)) if *last == day => commits.push(commit),
+      _ => days.push((day, vec![commit])),
+    }
+  }
+
+  let content = maud::html! {
+    h1 {
+      a href={ "/meta/profile/" (profile.key().email()) } { (profile.name) }
+      " - contributions"
+    }
+    p { (count) " commits" }
+
+    @for (day, commits) in &days {
+      h2 .day { (day) }
+      ol .commits {
+        @for commit in commits {
+          li {
+            .date { (dates.render(commit.date)) }
+            .message { (commit.message) }
+            ul .files {
+              @for file in &commit.files {
+                li {
+                  a href={"/" (file.to_string_lossy())} { (file.to_string_lossy()) }
+                  " "
+                  a href={"/" (file.to_string_lossy()) "?revision=" (commit.hash)} { "this revision" }
+                }
+              }
+            }
+          }
+        }
+      }
+    }
+
+    nav .pagination {
+      @if page > 1 {
+        a href={ (url) "?page=" (page - 1) } { "previous" }
+        " "
+      }
+      @for number in page_numbers(page, pages) {
+        @if let Some(number) = number {
+          @if number == page {
+            strong { (number) }
+          } @else {
+            a href={ (url) "?page=" (number) } { (number) }
+          }
+        } @else {
+          "…"
+        }
+        " "
+      }
+      @if page < pages {
+        a href={ (url) "?page=" (page + 1) } { "next" }
+      }
+    }
+  };
+
+  let html = Template::new()
+    .title(format!("{}'s contributions", profile.name))
+    .content(content)
+    .render(user, &state);
+
+  Ok(html)
+}
+
+#[derive(Deserialize)]
+pub struct ProfileParams {
+  name: String,
+  url: String,
+  timezone: String,
+  locale: String,
+}
+
+/// The pages of contributions to link to - the first and last, and the ones either side of
+/// `page` - with `None` where the ones between are left out.
+fn page_numbers(page: usize, pages: usize) -> Vec<Option<usize>> {
+  let mut numbers = Vec::new();
+
+  for number in 1..=pages {
+    if number == 1 || number == pages || number.abs_diff(page) <= 2 {
+      numbers.push(Some(number));
+    } else if numbers.last() != Some(&None) {
+      numbers.push(None);
+    }
+  }
+
+  numbers
+}
+
+/// `commits` with the files `user` can't see left out, and without the commits that only
+/// touched files like that.
+fn visible_commits(
+  commits: Vec<crate::git::Commit>,
+  user: Option<&User>,
+  config: &Config,
+) -> Vec<crate::git::Commit> {
+  commits
+    .into_iter()
+    .filter_map(|mut commit| {
+      let touched = !commit.files.is_empty();
+      commit.files.retain(|file| crate::namespace::is_visible(file, user, config));
+
+      if touched && commit.files.is_empty() {
+        None
+      } else {
+        Some(commit)
+      }
+    })
+    .collect()
+}
+
+/// Whether `url` is a web page, so it's safe to link to - not `javascript:` or the like.
+pub fn is_web_url(url: &Url) -> bool {
+  matches!(url.scheme(), "http" | "https")
+}
+
+/// A rough check that `locale` looks like a BCP 47 language tag, like `en` or `pt-BR`.
+fn is_locale(locale: &str) -> bool {
+  locale.len() <= 35
+    && locale.split('-').all(|part| {
+      (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+    })
+}
+
+pub async fn edit_profile_handler(
+  user: User,
+  Form(params): Form<ProfileParams>,
+  Extension(state): Extension<Arc<State>>,
+) -> Result<Redirect, Error> {
+  let name = match params.name.trim() {
+    "" => return Err(Error::EmptyName),
+    name => name.to_string(),
+  };
+
+  // The name is who owns `user/<name>`, so it can't lead anywhere else, or be someone else's.
+  if name.contains(['/', '\\']) || name.contains("..") {
+    return Err(Error::InvalidName(name));
+  }
+
+  if name != user.name {
+    let taken = state.users.all().await.iter().any(|other| {
+      other.email != user.email && other.name.to_lowercase() == name.to_lowercase()
+    });
+
+    if taken {
+      return Err(Error::NameTaken(name));
+    }
+  }
+
+  let url = Url::parse(params.url.trim())
+    .ok()
+    .filter(is_web_url)
+    .ok_or_else(|| Error::InvalidUrl(params.url.clone()))?;
+
+  let timezone = match params.timezone.trim() {
+    "" => None,
+    name if crate::date::timezone(name).is_some() => Some(name.to_string()),
+    name => return Err(Error::UnknownTimezone(name.to_string())),
+  };
+
+  let locale = match params.locale.trim() {
+    "" => None,
+    locale if is_locale(locale) => Some(locale.to_string()),
+    locale => return Err(Error::InvalidLocale(locale.to_string())),
+  };
+
+  let edited_profile = user.edited_profile || name != user.name || url != user.url;
+
+  let user = User {
+    name,
+    url,
+    timezone,
+    locale,
+    edited_profile,
+    ..user
+  };
+  state.users.set(user.clone()).await?;
+
+  Ok(Redirect::to(&format!("/meta/profile/{}", user.email)))
+}
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use axum::async_trait;
+use cocoon::Cocoon;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPool, Row};
+
+use super::{Error, User, UserKey};
+use crate::config::Users;
+
+/// Where users are kept.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Backend {
+  /// The encrypted `database` file, using `password` as the key.
+  File,
+  /// The URL of a Postgres database, so several instances can share their users.
+  Postgres(String),
+}
+
+impl Default for Backend {
+  fn default() -> Self {
+    Self::File
+  }
+}
+
+impl Backend {
+  pub async fn build(&self, config: &Users) -> Result<Box<dyn UserStore>, Error> {
+    let store: Box<dyn UserStore> = match self {
+      Backend::File => Box::new(File::new(config).await?),
+      Backend::Postgres(url) => Box::new(Postgres::new(url).await?),
+    };
+
+    Ok(store)
+  }
+}
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+  async fn load(&self) -> Result<HashMap<UserKey, User>, Error>;
+  /// Looks up a single user, in case they were added since everyone was loaded.
+  async fn get(&self, key: &UserKey) -> Result<Option<User>, Error>;
+  /// Saves the `changed` users, where `users` is everyone, including them.
+  async fn save(&self, changed: &[User], users: &HashMap<UserKey, User>) -> Result<(), Error>;
+}
+
+pub struct File {
+  path: PathBuf,
+  password: Vec<u8>,
+}
+
+/// Keeps anyone else from writing the `database` file while it's held. The wiki holds it for as
+/// long as it's running, as it would write over changes made underneath it.
+pub struct DatabaseLock(Option<std::fs::File>);
+
+impl DatabaseLock {
+  pub fn acquire(config: &Users) -> Result<Self, Error> {
+    // Postgres saves each user on their own, so nothing is written over.
+    if !matches!(config.store, Backend::File) {
+      return Ok(Self(None));
+    }
+
+    let file = std::fs::OpenOptions::new()
+      .create(true)
+      .write(true)
+      .open(config.database.with_extension("lock"))?;
+
+    file
+      .try_lock_exclusive()
+      .map_err(|_| Error::Locked(config.database.clone()))?;
+
+    Ok(Self(Some(file)))
+  }
+}
+
+impl File {
+  pub async fn new(config: &Users) -> Result<Self, Error> {
+    let password = tokio::fs::read(&config.password).await?;
+
+    Ok(Self {
+      path: config.database.clone(),
+      password,
+    })
+  }
+}
+
+#[async_trait]
+impl UserStore for File {
+  async fn load(&self) -> Result<HashMap<UserKey, User>, Error> {
+    if !self.path.exists() {
+      return Ok(HashMap::new());
+    }
+
+    log::info!("Loading user database from {}", self.path.display());
+
+    let mut file = std::fs::File::open(&self.path)?;
+    let cocoon = Cocoon::new(&self.password).parse(&mut file)?;
+    let map: HashMap<UserKey, User> = ron::de::from_bytes(&cocoon)?;
+
+    for (k, v) in &map {
+      log::info!("{:?}, {:?}", k, v);
+    }
+
+    Ok(map)
+  }
+
+  async fn get(&self, _: &UserKey) -> Result<Option<User>, Error> {
+    // Nobody else writes to the file, so everyone has already been loaded.
+    Ok(None)
+  }
+
+  async fn save(&self, _: &[User], users: &HashMap<UserKey, User>) -> Result<(), Error> {
+    log::info!("Saving user database");
+
+    let mut file = std::fs::File::create(&self.path)?;
+    let value = ron::to_string(users)?;
+
+    Cocoon::new(&self.password).dump(value.as_bytes().to_vec(), &mut file)?;
+
+    Ok(())
+  }
+}
+
+pub struct Postgres {
+  pool: PgPool,
+}
+
+impl Postgres {
+  pub async fn new(url: &str) -> Result<Self, Error> {
+    let pool = PgPool::connect(url).await?;
+
+    sqlx::query(
+      "CREATE TABLE IF NOT EXISTS gitalite_users (
+        email TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+      )",
+    )
+    .execute(&pool)
+    .await?;
+
+    Ok(Self { pool })
+  }
+}
+
+#[async_trait]
+impl UserStore for Postgres {
+  async fn load(&self) -> Result<HashMap<UserKey, User>, Error> {
+    let rows = sqlx::query("SELECT data FROM gitalite_users")
+      .fetch_all(&self.pool)
+      .await?;
+
+    let mut map = HashMap::with_capacity(rows.len());
+
+    for row in rows {
+      let user: User = serde_json::from_str(row.get(0))?;
+      map.insert(user.key(), user);
+    }
+
+    Ok(map)
+  }
+
+  async fn get(&self, key: &UserKey) -> Result<Option<User>, Error> {
+    let row = sqlx::query("SELECT data FROM gitalite_users WHERE email = $1")
+      .bind(key.email())
+      .fetch_optional(&self.pool)
+      .await?;
+
+    match row {
+      Some(row) => Ok(Some(serde_json::from_str(row.get(0))?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn save(&self, changed: &[User], _: &HashMap<UserKey, User>) -> Result<(), Error> {
+    let mut transaction = self.pool.begin().await?;
+
+    for user in changed {
+      sqlx::query(
+        "INSERT INTO gitalite_users (email, data) VALUES ($1, $2)
+        ON CONFLICT (email) DO UPDATE SET data = $2",
+      )
+      .bind(&user.email)
+      .bind(serde_json::to_string(user)?)
+      .execute(&mut transaction)
+      .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+  }
+}
+
+/// Copies every user from the encrypted file into the configured store.
+pub async fn migrate(config: &Users) -> Result<usize, Error> {
+  let from = File::new(config).await?;
+  let to = config.store.build(config).await?;
+
+  let users = from.load().await?;
+  let changed: Vec<User> = users.values().cloned().collect();
+
+  to.save(&changed, &users).await?;
+
+  Ok(users.len())
+}
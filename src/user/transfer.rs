@@ -0,0 +1,163 @@
+//! Every user as plain RON or JSON, for auditing them or moving them to another wiki, and
+//! bringing them back in bulk.
+
+use std::{
+  collections::{HashMap, HashSet},
+  path::Path,
+};
+
+use serde::Deserialize;
+
+use super::{Error, User, UserKey};
+use crate::role::Role;
+
+#[derive(clap::ArgEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+  Ron,
+  Json,
+}
+
+impl Default for ExportFormat {
+  fn default() -> Self {
+    Self::Ron
+  }
+}
+
+impl ExportFormat {
+  /// Guessed from a file's name - it's RON unless it ends in `.json`.
+  pub fn from_path(path: &Path) -> Self {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("json") => Self::Json,
+      _ => Self::Ron,
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Self::Ron => "ron",
+      Self::Json => "json",
+    }
+  }
+
+  pub fn mime(&self) -> &'static str {
+    match self {
+      Self::Ron => "text/plain; charset=utf-8",
+      Self::Json => "application/json",
+    }
+  }
+}
+
+/// `users`, sorted by email.
+pub fn export(users: &[User], format: ExportFormat) -> Result<String, Error> {
+  let mut users: Vec<&User> = users.iter().collect();
+  users.sort_by(|a, b| a.email.cmp(&b.email));
+
+  let exported = match format {
+    ExportFormat::Ron => ron::ser::to_string_pretty(&users, ron::ser::PrettyConfig::default())?,
+    ExportFormat::Json => serde_json::to_string_pretty(&users)?,
+  };
+
+  Ok(exported)
+}
+
+/// Users written by [`export`].
+pub fn parse(text: &str, format: ExportFormat) -> Result<Vec<User>, Error> {
+  let users = match format {
+    ExportFormat::Ron => ron::de::from_str(text)?,
+    ExportFormat::Json => serde_json::from_str(text)?,
+  };
+
+  Ok(users)
+}
+
+/// What importing some users would do. Users that are already there are replaced by the
+/// imported ones, and anyone who isn't in the import is left alone.
+#[derive(Default)]
+pub struct Plan {
+  pub added: Vec<User>,
+  pub updated: Vec<User>,
+  pub unchanged: usize,
+  /// Why the users can't be imported - if there are any, none of them are.
+  pub problems: Vec<String>,
+}
+
+impl Plan {
+  pub fn new(imported: Vec<User>, existing: &HashMap<UserKey, User>) -> Self {
+    let mut plan = Self::default();
+    let mut seen = HashSet::new();
+
+    for user in imported {
+      for problem in problems(&user) {
+        plan.problems.push(format!("{}: {}", user.email, problem));
+      }
+
+      if !seen.insert(user.key()) {
+        plan.problems.push(format!(
+          "{}: they're in the import more than once",
+          user.email
+        ));
+        continue;
+      }
+
+      match existing.get(&user.key()) {
+        None => plan.added.push(user),
+        Some(current) if *current == user => plan.unchanged += 1,
+        Some(_) => plan.updated.push(user),
+      }
+    }
+
+    let mut after = existing.clone();
+    for user in plan.changed() {
+      after.insert(user.key(), user.clone());
+    }
+
+    // Otherwise there'd be nobody who could put it right.
+    let has_administrator = after
+      .values()
+      .any(|user| user.approved && !user.disabled && user.roles.contains(&Role::Administrator));
+
+    if !has_administrator {
+      plan.problems.push(String::from(
+        "There wouldn't be an approved administrator left",
+      ));
+    }
+
+    plan
+  }
+
+  /// The users that would be added or updated.
+  pub fn changed(&self) -> impl Iterator<Item = &User> {
+    self.added.iter().chain(&self.updated)
+  }
+}
+
+fn problems(user: &User) -> Vec<String> {
+  let mut problems = Vec::new();
+
+  if user.name.trim().is_empty() {
+    problems.push(String::from("their name is empty"));
+  }
+
+  if !user.email.contains('@') {
+    problems.push(String::from("that isn't an email address"));
+  }
+
+  if !super::is_web_url(&user.url) {
+    problems.push(format!("'{}' isn't an http or https URL", user.url));
+  }
+
+  if let Some(timezone) = &user.timezone {
+    if crate::date::timezone(timezone).is_none() {
+      problems.push(format!("'{}' isn't a timezone", timezone));
+    }
+  }
+
+  if let Some(locale) = &user.locale {
+    if !super::is_locale(locale) {
+      problems.push(format!("'{}' isn't a language tag", locale));
+    }
+  }
+
+  problems
+}
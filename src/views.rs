@@ -0,0 +1,321 @@
+//! Counting how many times each page is viewed, for the popular pages list. Only the page and
+//! a running total are kept - nothing about who viewed it. Views are counted in memory, and
+//! added to the store every so often, and when the wiki stops.
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use axum::{response::Html, Extension};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPool, Row};
+
+use crate::{config::Config, user::User, State};
+
+/// How many pages the stats page lists.
+const STATS_PAGES: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  Sqlx(#[from] sqlx::Error),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Views {
+  pub store: Store,
+  /// How often the counts are saved, in seconds.
+  pub flush_interval: u64,
+  /// How many pages are in the popular pages list.
+  pub popular: usize,
+}
+
+impl Default for Views {
+  fn default() -> Self {
+    Self {
+      store: Store::default(),
+      flush_interval: 300,
+      popular: 5,
+    }
+  }
+}
+
+/// Where the counts are kept between restarts.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Store {
+  /// `views.json` in `cache_directory`.
+  File,
+  /// The URL of a Postgres database, so several instances can share their counts.
+  Postgres(String),
+}
+
+impl Default for Store {
+  fn default() -> Self {
+    Self::File
+  }
+}
+
+enum Backend {
+  File(PathBuf),
+  Postgres(PgPool),
+}
+
+impl Backend {
+  async fn load(&self) -> Result<HashMap<String, u64>, Error> {
+    match self {
+      Self::File(path) => match tokio::fs::read(path).await {
+        Ok(file) => Ok(serde_json::from_slice(&file)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err.into()),
+      },
+      Self::Postgres(pool) => {
+        let rows = sqlx::query("SELECT path, views FROM gitalite_views")
+          .fetch_all(pool)
+          .await?;
+
+        Ok(
+          rows
+            .into_iter()
+            .map(|row| (row.get(0), row.get::<i64, _>(1) as u64))
+            .collect(),
+        )
+      },
+    }
+  }
+
+  /// Saves the counts - `totals` for a file, which is only written by this wiki, and `new` is
+  /// added to what's there for a database, which others might be adding to too.
+  async fn save(
+    &self,
+    new: &HashMap<String, u64>,
+    totals: &HashMap<String, u64>,
+  ) -> Result<(), Error> {
+    match self {
+      Self::File(path) => tokio::fs::write(path, serde_json::to_vec(totals)?).await?,
+      Self::Postgres(pool) => {
+        let mut transaction = pool.begin().await?;
+
+        for (path, views) in new {
+          sqlx::query(
+            "INSERT INTO gitalite_views (path, views) VALUES ($1, $2)
+            ON CONFLICT (path) DO UPDATE SET views = gitalite_views.views + $2",
+          )
+          .bind(path)
+          .bind(*views as i64)
+          .execute(&mut transaction)
+          .await?;
+        }
+
+        transaction.commit().await?;
+      },
+    }
+
+    Ok(())
+  }
+}
+
+#[derive(Default)]
+pub struct ViewCounter {
+  /// `None` when counting views is turned off.
+  backend: Option<Backend>,
+  totals: Mutex<HashMap<String, u64>>,
+  /// Views since the counts were last saved.
+  new: Mutex<HashMap<String, u64>>,
+}
+
+impl ViewCounter {
+  pub async fn new(config: &Config) -> Result<Self, Error> {
+    let backend = match config.views.as_ref().map(|views| &views.store) {
+      None => return Ok(Self::default()),
+      Some(Store::File) => Backend::File(config.cache_directory.join("views.json")),
+      Some(Store::Postgres(url)) => {
+        let pool = PgPool::connect(url).await?;
+
+        sqlx::query(
+          "CREATE TABLE IF NOT EXISTS gitalite_views (
+            path TEXT PRIMARY KEY,
+            views BIGINT NOT NULL
+          )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Backend::Postgres(pool)
+      },
+    };
+
+    let totals = backend.load().await?;
+
+    Ok(Self {
+      backend: Some(backend),
+      totals: Mutex::new(totals),
+      new: Mutex::default(),
+    })
+  }
+
+  /// Counts a view of the page at `path`.
+  pub fn record(&self, path: &str) {
+    if self.backend.is_none() {
+      return;
+    }
+
+    *self
+      .totals
+      .lock()
+      .unwrap()
+      .entry(path.to_string())
+      .or_insert(0) += 1;
+    *self
+      .new
+      .lock()
+      .unwrap()
+      .entry(path.to_string())
+      .or_insert(0) += 1;
+  }
+
+  /// The `count` most viewed pages, and how many times they've been viewed.
+  pub fn popular(&self, count: usize, user: Option<&User>, config: &Config) -> Vec<(String, u64)> {
+    let mut pages: Vec<_> = self
+      .totals
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|(path, _)| crate::namespace::is_visible(Path::new(path), user, config))
+      .map(|(path, views)| (path.clone(), *views))
+      .collect();
+
+    pages.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+    pages.truncate(count);
+
+    pages
+  }
+
+  /// Saves the views counted since last time. With a database, the totals are read back too,
+  /// to pick up the views other instances have added.
+  pub async fn flush(&self) -> Result<(), Error> {
+    let backend = match &self.backend {
+      Some(backend) => backend,
+      None => return Ok(()),
+    };
+
+    let new = std::mem::take(&mut *self.new.lock().unwrap());
+
+    if !new.is_empty() {
+      let totals = self.totals.lock().unwrap().clone();
+
+      if let Err(err) = backend.save(&new, &totals).await {
+        // They're kept, to try again next time.
+        let mut pending = self.new.lock().unwrap();
+        for (path, views) in new {
+          *pending.entry(path).or_insert(0) += views;
+        }
+
+        return Err(err);
+      }
+    }
+
+    if let Backend::Postgres(_) = backend {
+      let mut totals = backend.load().await?;
+
+      // Anything counted while saving hasn't been added to the database yet.
+      let pending = self.new.lock().unwrap();
+      for (path, views) in pending.iter() {
+        *totals.entry(path.clone()).or_insert(0) += views;
+      }
+
+      *self.totals.lock().unwrap() = totals;
+    }
+
+    Ok(())
+  }
+
+  /// Saves the counts every `flush_interval` seconds.
+  pub async fn flush_periodically(state: Arc<State>) {
+    let interval = match &state.config().views {
+      Some(views) => Duration::from_secs(views.flush_interval.max(1)),
+      None => return,
+    };
+
+    let mut interval = tokio::time::interval(interval);
+    // The first tick is straight away, when there's nothing to save.
+    interval.tick().await;
+
+    loop {
+      interval.tick().await;
+
+      if let Err(err) = state.views.flush().await {
+        log::warn!("Couldn't save the page views: {}", err);
+      }
+    }
+  }
+}
+
+/// The most viewed pages that `user` can see, linked to, for the sidebar.
+pub fn popular_list(user: Option<&User>, state: &State) -> Option<maud::Markup> {
+  let config = state.config();
+  let count = config.views.as_ref()?.popular;
+  let pages = state.views.popular(count, user, &config);
+
+  if pages.is_empty() {
+    return None;
+  }
+
+  Some(maud::html! {
+    ol {
+      @for (path, views) in &pages {
+        li {
+          a href={ "/" (path) } { (path) }
+          " · " (views) " views"
+        }
+      }
+    }
+  })
+}
+
+pub async fn stats_handler(
+  user: Option<User>,
+  Extension(state): Extension<Arc<State>>,
+) -> Html<String> {
+  let config = state.config();
+  let counting = config.views.is_some();
+  let pages = state.views.popular(STATS_PAGES, user.as_ref(), &config);
+
+  let content = maud::html! {
+    h2 { "Popular pages" }
+    @if !counting {
+      p { "Page views aren't being counted - set " code { "views" } " in the config to start." }
+    } @else if pages.is_empty() {
+      p { "Nobody has viewed any pages yet." }
+    } @else {
+      table {
+        thead {
+          tr {
+            th { "Page" }
+            th { "Views" }
+          }
+        }
+        tbody {
+          @for (path, views) in &pages {
+            tr {
+              td { a href={ "/" (path) } { (path) } }
+              td { (views) }
+            }
+          }
+        }
+      }
+    }
+  };
+
+  crate::template::Template::new()
+    .title("Stats")
+    .content(content)
+    .render(user, &state)
+}
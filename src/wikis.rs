@@ -0,0 +1,48 @@
+//! Serving several wikis from one process. Each of the config's `wikis` is picked by the host
+//! a request is for, and anything else goes to the main wiki.
+
+use std::sync::Arc;
+
+use axum::{
+  body::Body,
+  http::{header, Request},
+  Router,
+};
+use tower::ServiceExt;
+
+/// The main wiki's routes, with requests for any of `others`' hosts sent to them instead.
+pub fn router(main: Router, others: Vec<(Vec<String>, Router)>) -> Router {
+  if others.is_empty() {
+    return main;
+  }
+
+  let others = Arc::new(others);
+
+  Router::new().fallback(tower::service_fn(move |request: Request<Body>| {
+    let wiki = host(&request)
+      .and_then(|host| {
+        others
+          .iter()
+          .find(|(hosts, _)| hosts.iter().any(|name| name.eq_ignore_ascii_case(host)))
+      })
+      .map(|(_, router)| router)
+      .unwrap_or(&main)
+      .clone();
+
+    async move { wiki.oneshot(request).await }
+  }))
+}
+
+/// The host the request is for, without the port.
+fn host(request: &Request<Body>) -> Option<&str> {
+  let host = request
+    .headers()
+    .get(header::HOST)
+    .and_then(|host| host.to_str().ok())
+    .or_else(|| request.uri().host())?;
+
+  match host.rsplit_once(':') {
+    Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => Some(name),
+    _ => Some(host),
+  }
+}
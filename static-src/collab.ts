@@ -0,0 +1,69 @@
+import type { CodeJar } from 'codejar';
+import * as Y from 'yjs';
+
+// Connects the editor to everyone else editing the page, so their changes show up as they
+// type, and ours show up for them.
+export function collaborate(editor: HTMLDivElement, jar: CodeJar): void {
+  const doc = new Y.Doc();
+  const text = doc.getText('content');
+
+  const scheme = location.protocol === 'https:' ? 'wss:' : 'ws:';
+  const path = location.pathname.replace('/meta/edit', '/meta/collab');
+  const socket = new WebSocket(`${scheme}//${location.host}${path}`);
+  socket.binaryType = 'arraybuffer';
+
+  socket.addEventListener('message', event => {
+    Y.applyUpdate(doc, new Uint8Array(event.data), 'remote');
+  });
+
+  socket.addEventListener('close', () => {
+    alert(
+      "You've been disconnected from the other editors - copy your changes and reload the page.",
+    );
+  });
+
+  doc.on('update', (update: Uint8Array, origin: unknown) => {
+    if (origin !== 'remote' && socket.readyState === WebSocket.OPEN) {
+      socket.send(update);
+    }
+  });
+
+  text.observe(event => {
+    if (event.transaction.origin === 'local') {
+      return;
+    }
+
+    // Keeps the cursor where it was, as long as the change was after it.
+    const position = jar.save();
+    jar.updateCode(text.toString());
+    jar.restore(position);
+  });
+
+  jar.onUpdate(code => {
+    const current = text.toString();
+
+    if (code === current) {
+      return;
+    }
+
+    // Only the part that changed is sent, so it merges with what everyone else is doing.
+    let start = 0;
+    while (start < code.length && start < current.length && code[start] === current[start]) {
+      start += 1;
+    }
+
+    let end = 0;
+    while (
+      end < code.length - start &&
+      end < current.length - start &&
+      code[code.length - 1 - end] === current[current.length - 1 - end]
+    ) {
+      end += 1;
+    }
+
+    doc.transact(() => {
+      text.delete(start, current.length - start - end);
+      text.insert(start, code.slice(start, code.length - end));
+    }, 'local');
+  });
+}
@@ -1,20 +1,119 @@
 import { CodeJar } from 'codejar';
+import { collaborate } from './collab';
 import { get_id } from './dom';
 
+interface Diagnostic {
+  line: number | null;
+  column: number | null;
+  message: string;
+}
+
+interface Heading {
+  level: number;
+  id: string | null;
+  text: string;
+}
+
+interface Preview {
+  html: string;
+  headings: Heading[];
+  links: string[];
+  words: number;
+  warnings: Diagnostic[];
+}
+
+// The lines the page couldn't be rendered at, the last time it was saved.
+let error_lines = new Set<number>();
+
+const escape = (text: string): string =>
+  text.replace(/&/g, '&amp;').replace(/</g, '&lt;').replace(/>/g, '&gt;');
+
 const highlight = (editor: HTMLElement): void => {
   const code = editor.textContent ?? '';
-  // Do something with code and set html.
-  editor.innerHTML = code;
+
+  if (error_lines.size === 0) {
+    // Do something with code and set html.
+    editor.innerHTML = code;
+    return;
+  }
+
+  editor.innerHTML = code
+    .split('\n')
+    .map((line, i) =>
+      error_lines.has(i + 1)
+        ? `<mark class="render-error">${escape(line)}</mark>`
+        : escape(line),
+    )
+    .join('\n');
 };
 
-async function save(editor: HTMLDivElement): Promise<void> {
-  const res = await fetch(location.pathname, {
+// Shows why the save failed - and when the page couldn't be rendered, marks the lines that
+// pandoc complained about.
+async function show_error(res: Response, jar: CodeJar): Promise<void> {
+  const type = res.headers.get('content-type') ?? '';
+
+  if (!type.startsWith('application/json')) {
+    alert(await res.text());
+    return;
+  }
+
+  const { code, message, details } = await res.json();
+  const diagnostics: Diagnostic[] =
+    code === 'render-failed' ? details?.diagnostics ?? [] : [];
+
+  error_lines = new Set(
+    diagnostics.flatMap(diagnostic => (diagnostic.line != null ? [diagnostic.line] : [])),
+  );
+  jar.updateCode(jar.toString());
+
+  if (diagnostics.length === 0) {
+    alert(message);
+    return;
+  }
+
+  const lines = diagnostics.map(diagnostic =>
+    diagnostic.line != null ? `Line ${diagnostic.line}: ${diagnostic.message}` : diagnostic.message,
+  );
+
+  alert(`${message as string}:\n\n${lines.join('\n\n')}`);
+}
+
+function summary(): string {
+  return get_id<HTMLInputElement>('summary').value;
+}
+
+async function save(editor: HTMLDivElement, jar: CodeJar): Promise<void> {
+  const query = new URLSearchParams({ summary: summary() });
+
+  // Everyone's changes are already on the server, so it commits those.
+  if (editor.dataset.collaborative != null) {
+    const path = location.pathname.replace('/meta/edit', '/meta/collab');
+    const res = await fetch(`${path}?${query.toString()}`, { method: 'POST' });
+
+    if (res.redirected) {
+      location.assign(res.url);
+    } else if (!res.ok) {
+      alert(await res.text());
+    }
+
+    return;
+  }
+
+  // Lets the server merge in anything that's changed since we started editing.
+  if (editor.dataset.revision != null) {
+    query.set('revision', editor.dataset.revision);
+  }
+
+  const res = await fetch(`${location.pathname}?${query.toString()}`, {
     method: 'POST',
+    headers: { Accept: 'application/json' },
     body: editor.innerText,
   });
 
   if (res.redirected) {
     location.assign(res.url);
+  } else if (!res.ok) {
+    await show_error(res, jar);
   }
 }
 
@@ -31,6 +130,7 @@ async function create(editor: HTMLDivElement): Promise<void> {
     body: JSON.stringify({
       format,
       body: editor.innerText,
+      summary: summary(),
     }),
   });
 
@@ -49,21 +149,45 @@ async function preview(
   const format_select = get_id<HTMLSelectElement>('format');
   const format = format_select.options[format_select.selectedIndex].value;
 
-  let req = '/meta/render';
+  let req = '/meta/render?json=true';
 
   if (format != null) {
-    req += `?format=${format}`;
+    req += `&format=${format}`;
   }
 
   const res = await fetch(req, {
     method: 'POST',
     body: editor.innerText,
   });
-  const html = await res.text();
 
   editor.classList.add('hidden');
 
-  preview.innerHTML = html;
+  if (!res.ok) {
+    preview.innerHTML = await res.text();
+    return;
+  }
+
+  const { html, headings, words, warnings }: Preview = await res.json();
+
+  const outline = headings
+    .map(heading => {
+      const text = escape(heading.text);
+      const link = heading.id != null ? `<a href="#${escape(heading.id)}">${text}</a>` : text;
+
+      return `<li class="level-${heading.level}">${link}</li>`;
+    })
+    .join('');
+
+  const problems = warnings.map(warning => `<li>${escape(warning.message)}</li>`).join('');
+
+  preview.innerHTML = `
+    <aside class="preview-report">
+      <p>${words} words</p>
+      ${problems !== '' ? `<ul class="warnings">${problems}</ul>` : ''}
+      ${outline !== '' ? `<ol class="outline">${outline}</ol>` : ''}
+    </aside>
+    ${html}
+  `;
 }
 
 async function edit(
@@ -76,6 +200,60 @@ async function edit(
   preview.innerHTML = '';
 }
 
+function editor_mode(editor: HTMLDivElement): void {
+  const format_select = get_id<HTMLSelectElement>('format');
+
+  const update = (): void => {
+    const option = format_select.options[format_select.selectedIndex];
+    editor.dataset.mode = option.dataset.mode ?? 'plain';
+  };
+
+  format_select.addEventListener('change', update);
+  update();
+}
+
+// Pasted images are uploaded next to the page, and a link to them is put where the cursor is.
+async function paste_image(event: ClipboardEvent, jar: CodeJar): Promise<void> {
+  const image = Array.from(event.clipboardData?.files ?? []).find(file =>
+    file.type.startsWith('image/'),
+  );
+
+  if (image == null) {
+    return;
+  }
+
+  event.preventDefault();
+
+  const page = location.pathname.replace(/^\/meta\/(edit|new)/, '');
+  const format_select = get_id<HTMLSelectElement>('format');
+  const query = new URLSearchParams({ format: format_select.value });
+
+  const res = await fetch(`/meta/paste-image${page}?${query.toString()}`, {
+    method: 'POST',
+    headers: { 'content-type': image.type },
+    body: image,
+  });
+
+  if (!res.ok) {
+    alert(await res.text());
+    return;
+  }
+
+  const { snippet } = await res.json();
+
+  const position = jar.save();
+  const code = jar.toString();
+  jar.updateCode(
+    code.slice(0, position.start) + snippet + code.slice(position.end),
+  );
+}
+
+function paste_images(editor: HTMLDivElement, jar: CodeJar): void {
+  editor.addEventListener('paste', event => {
+    paste_image(event, jar).catch(console.error);
+  });
+}
+
 function preview_edit_toggle(
   editor_el: HTMLDivElement,
   preview_el: HTMLDivElement,
@@ -93,6 +271,36 @@ function preview_edit_toggle(
   });
 }
 
+// Renews this editor's lock on the page, or takes over someone else's once it's expired, and
+// shows who else is editing it.
+async function renew_lock(): Promise<void> {
+  const path = location.pathname.replace('/meta/edit', '/meta/lock');
+  const res = await fetch(path, { method: 'POST' });
+  const notice = get_id('lock-notice');
+
+  if (res.status === 409) {
+    const { lease } = await res.json();
+    const expires = new Date(lease.expires).toLocaleTimeString();
+
+    get_id('lock-holder').textContent =
+      `${lease.name as string} is editing this page, so your changes might clash with ` +
+      `theirs. Their lock expires at ${expires}.`;
+    notice.classList.remove('hidden');
+  } else if (res.ok) {
+    notice.classList.add('hidden');
+  }
+}
+
+function keep_lock(): void {
+  setInterval(() => {
+    renew_lock().catch(console.error);
+  }, 60 * 1000);
+
+  get_id('take-over').addEventListener('click', () => {
+    renew_lock().catch(console.error);
+  });
+}
+
 export async function setup_editor(): Promise<void> {
   const path = location.pathname.replace('/meta/edit', '/meta/raw');
   const res = await fetch(path);
@@ -103,18 +311,57 @@ export async function setup_editor(): Promise<void> {
 
   jar.updateCode(code);
 
+  editor_mode(editor);
   preview_edit_toggle(editor, get_id('preview'), jar);
+  paste_images(editor, jar);
+  keep_lock();
+
+  if (editor.dataset.collaborative != null) {
+    collaborate(editor, jar);
+  }
 
   get_id('save').addEventListener('click', () => {
-    save(editor).catch(() => {});
+    save(editor, jar).catch(() => {});
   });
 }
 
+async function use_template(jar: CodeJar, name: string): Promise<void> {
+  const format_select = get_id<HTMLSelectElement>('format');
+
+  if (name === '') {
+    jar.updateCode('');
+    return;
+  }
+
+  const query = new URLSearchParams({ template: name });
+  const res = await fetch(`${location.pathname}?${query.toString()}`);
+
+  if (!res.ok) {
+    return;
+  }
+
+  const { format, body } = await res.json();
+
+  jar.updateCode(body);
+  format_select.value = format;
+  format_select.dispatchEvent(new Event('change'));
+}
+
 export async function newpage_editor(): Promise<void> {
   const editor = get_id<HTMLDivElement>('editor');
   const jar = CodeJar(editor, highlight, { spellcheck: true });
 
+  editor_mode(editor);
   preview_edit_toggle(editor, get_id('preview'), jar);
+  paste_images(editor, jar);
+
+  const template_select = document.getElementById('template');
+
+  if (template_select instanceof HTMLSelectElement) {
+    template_select.addEventListener('change', () => {
+      use_template(jar, template_select.value).catch(console.error);
+    });
+  }
 
   get_id('save').addEventListener('click', () => {
     create(editor).catch(() => {});
@@ -4,6 +4,9 @@ import css_has_polyfill from 'css-has-pseudo/browser';
 import './styles/style.pcss';
 
 import './color_scheme';
+import './quick_open';
+import './recent_changes';
+import './table';
 
 css_has_polyfill(document);
 
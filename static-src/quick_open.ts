@@ -0,0 +1,56 @@
+import { get_id } from './dom';
+
+interface Suggestion {
+  title: string;
+  path: string;
+  url: string;
+}
+
+let suggestions: Suggestion[] = [];
+
+async function suggest(query: string): Promise<void> {
+  const params = new URLSearchParams({ q: query });
+  const res = await fetch(`/api/v1/suggest?${params.toString()}`);
+
+  if (!res.ok) {
+    return;
+  }
+
+  suggestions = await res.json();
+
+  get_id('quick-open-pages').replaceChildren(
+    ...suggestions.map(suggestion => {
+      const option = document.createElement('option');
+      option.value = suggestion.title;
+      option.textContent = suggestion.path;
+
+      return option;
+    }),
+  );
+}
+
+document.addEventListener('DOMContentLoaded', () => {
+  const form = get_id<HTMLFormElement>('quick-open');
+  const input = form.getElementsByTagName('input').item(0);
+
+  if (input == null) {
+    return;
+  }
+
+  let timeout: number | undefined;
+
+  input.addEventListener('input', () => {
+    // Picking a suggestion goes straight to it.
+    const picked = suggestions.find(suggestion => suggestion.title === input.value);
+
+    if (picked != null) {
+      location.assign(picked.url);
+      return;
+    }
+
+    clearTimeout(timeout);
+    timeout = window.setTimeout(() => {
+      suggest(input.value).catch(console.error);
+    }, 150);
+  });
+});
@@ -0,0 +1,102 @@
+import { get_id } from './dom';
+
+interface RecentChange {
+  hash: string;
+  author: string;
+  message: string;
+  timestamp: number;
+  files: string[];
+}
+
+const max_changes = 5;
+
+let changes: RecentChange[] = [];
+
+const units: Array<[Intl.RelativeTimeFormatUnit, number]> = [
+  ['year', 60 * 60 * 24 * 365],
+  ['month', 60 * 60 * 24 * 30],
+  ['day', 60 * 60 * 24],
+  ['hour', 60 * 60],
+  ['minute', 60],
+];
+
+const formatter = new Intl.RelativeTimeFormat(undefined, { numeric: 'auto' });
+
+function age(timestamp: number): string {
+  const seconds = timestamp - Date.now() / 1000;
+
+  for (const [unit, size] of units) {
+    if (Math.abs(seconds) >= size) {
+      return formatter.format(Math.round(seconds / size), unit);
+    }
+  }
+
+  return formatter.format(Math.round(seconds), 'second');
+}
+
+function absolute(timestamp: number, timezone?: string): string {
+  return new Date(timestamp * 1000).toLocaleString(undefined, {
+    timeZone: timezone,
+    dateStyle: 'medium',
+    timeStyle: 'short',
+  });
+}
+
+function render(): void {
+  const container = get_id('recent-changes');
+  const list = container.getElementsByTagName('ol').item(0);
+
+  if (list == null) {
+    return;
+  }
+
+  const timezone = container.dataset.timezone;
+  const relative = container.dataset.relative !== 'false';
+
+  list.replaceChildren(
+    ...changes.map(change => {
+      const item = document.createElement('li');
+
+      const link = document.createElement('a');
+      const file = change.files[0];
+      link.href = file != null ? `/${file}?revision=${change.hash}` : '#';
+      link.textContent = change.message;
+
+      const time = document.createElement('time');
+      time.dateTime = new Date(change.timestamp * 1000).toISOString();
+      const [shown, tooltip] = relative
+        ? [age(change.timestamp), absolute(change.timestamp, timezone)]
+        : [absolute(change.timestamp, timezone), age(change.timestamp)];
+      time.title = tooltip;
+      time.textContent = shown;
+
+      item.append(link, ' · ', change.author, ' · ', time);
+
+      return item;
+    }),
+  );
+}
+
+async function load(): Promise<void> {
+  const res = await fetch('/meta/recent-changes');
+  changes = await res.json();
+
+  render();
+}
+
+document.addEventListener('DOMContentLoaded', () => {
+  load().catch(console.error);
+
+  const events = new EventSource('/meta/events');
+
+  events.addEventListener('commit', event => {
+    const change: RecentChange = JSON.parse((event as MessageEvent).data);
+
+    changes = [change, ...changes].slice(0, max_changes);
+
+    render();
+  });
+
+  // Keep the ages up-to-date.
+  setInterval(render, 60 * 1000);
+});
@@ -0,0 +1,55 @@
+import './styles/slides.pcss';
+
+// Shows a slideshow's `.slide`s one at a time, in place of Slidy, so nothing is loaded from
+// another site. Like Slidy, `#(3)` in the URL is the third slide.
+
+let slides: HTMLElement[] = [];
+let current = 0;
+
+function show(index: number): void {
+  current = Math.max(0, Math.min(index, slides.length - 1));
+
+  slides.forEach((slide, i) => slide.classList.toggle('current', i === current));
+  history.replaceState(null, '', `#(${current + 1})`);
+}
+
+document.addEventListener('DOMContentLoaded', () => {
+  slides = Array.from(document.querySelectorAll<HTMLElement>('.slide'));
+
+  const start = /^#\((\d+)\)$/.exec(location.hash);
+  show(start == null ? 0 : Number(start[1]) - 1);
+
+  document.addEventListener('keydown', event => {
+    switch (event.key) {
+      case 'ArrowRight':
+      case 'ArrowDown':
+      case 'PageDown':
+      case ' ':
+        show(current + 1);
+        break;
+      case 'ArrowLeft':
+      case 'ArrowUp':
+      case 'PageUp':
+        show(current - 1);
+        break;
+      case 'Home':
+        show(0);
+        break;
+      case 'End':
+        show(slides.length - 1);
+        break;
+      default:
+        return;
+    }
+
+    event.preventDefault();
+  });
+
+  document.addEventListener('click', event => {
+    if (event.target instanceof Element && event.target.closest('a') != null) {
+      return;
+    }
+
+    show(current + 1);
+  });
+});
@@ -0,0 +1,45 @@
+function cell_value(row: HTMLTableRowElement, column: number): string {
+  return row.cells.item(column)?.textContent ?? '';
+}
+
+function compare(a: string, b: string): number {
+  const a_num = Number(a);
+  const b_num = Number(b);
+
+  if (a !== '' && b !== '' && !isNaN(a_num) && !isNaN(b_num)) {
+    return a_num - b_num;
+  }
+
+  return a.localeCompare(b);
+}
+
+function make_sortable(table: HTMLTableElement): void {
+  const body = table.tBodies.item(0);
+  const head = table.tHead?.rows.item(0);
+
+  if (body == null || head == null) {
+    return;
+  }
+
+  Array.from(head.cells).forEach((header, column) => {
+    header.addEventListener('click', () => {
+      const ascending = header.dataset.sort !== 'asc';
+
+      Array.from(head.cells).forEach(cell => delete cell.dataset.sort);
+      header.dataset.sort = ascending ? 'asc' : 'desc';
+
+      const rows = Array.from(body.rows).sort((a, b) => {
+        const order = compare(cell_value(a, column), cell_value(b, column));
+        return ascending ? order : -order;
+      });
+
+      body.replaceChildren(...rows);
+    });
+  });
+}
+
+document.addEventListener('DOMContentLoaded', () => {
+  document
+    .querySelectorAll<HTMLTableElement>('table.sortable')
+    .forEach(make_sortable);
+});